@@ -0,0 +1,25 @@
+use std::process::Command;
+
+/// Run a git command and return its trimmed stdout, if it succeeded
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let text = String::from_utf8(output.stdout).ok()?;
+    let text = text.trim();
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+fn main() {
+    // Embed the commit and its date so `--version` can identify the exact build
+    let commit = git(&["describe", "--always"]).unwrap_or_else(|| "unknown".to_string());
+    let date = git(&["log", "-1", "--format=%cd", "--date=short"])
+        .unwrap_or_else(|| "unknown".to_string());
+
+    println!("cargo:rustc-env=BATCH_DEBUGGER_COMMIT={}", commit);
+    println!("cargo:rustc-env=BATCH_DEBUGGER_COMMIT_DATE={}", date);
+
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
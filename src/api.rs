@@ -0,0 +1,114 @@
+//! Running a script start to finish without a client attached, for tools and
+//! golden-file tests that want a structured record of what happened
+
+use crate::dap::Incoming;
+use crate::debugger::{CmdSession, DebugContext, RunMode};
+use crate::executor;
+use crate::parser;
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// One logical line the script executed
+#[derive(Debug, Clone, PartialEq)]
+pub struct TracedLine {
+    /// 1-based physical line the logical line starts on
+    pub line: usize,
+    /// Source text of the logical line
+    pub text: String,
+    /// Output the line printed
+    pub output: String,
+    /// ERRORLEVEL after the line ran
+    pub exit_code: i32,
+}
+
+/// What running a script did, line by line
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExecutionTrace {
+    /// Executed lines in order; labels, comments and blank lines never run
+    pub lines: Vec<TracedLine>,
+    /// ERRORLEVEL when the script ended
+    pub exit_code: i32,
+    /// Tracked variables when the script ended
+    pub variables: BTreeMap<String, String>,
+}
+
+/// Run the script at `path` in Continue mode with no breakpoints and record
+/// every line it executes. `PAUSE` continues at once and `SET /P` gets an
+/// empty reply, so the run never waits on anyone.
+pub fn run_to_completion(path: &Path) -> io::Result<ExecutionTrace> {
+    let contents = std::fs::read_to_string(path)?;
+    let physical_lines: Vec<&str> = contents.lines().collect();
+    let pre = parser::preprocess_lines(&physical_lines);
+    let labels = parser::build_label_map(&pre);
+
+    let mut ctx = DebugContext::new(CmdSession::start()?);
+    ctx.current_source = path.to_string_lossy().into_owned();
+    ctx.set_mode(RunMode::Continue);
+    ctx.history.set_capacity(usize::MAX);
+    let ctx = Arc::new(Mutex::new(ctx));
+
+    let (tx, rx) = channel();
+    let exec_ctx = Arc::clone(&ctx);
+    let exec_pre = pre.clone();
+    let handle =
+        std::thread::spawn(move || executor::run_debugger_dap(exec_ctx, &exec_pre, &labels, tx));
+
+    // Whether the executor is waiting at its stop on `line`
+    let listening = |line| -> io::Result<bool> {
+        let ctx = ctx.lock().map_err(lock_error)?;
+        Ok(ctx.current_line == Some(line) && !ctx.continue_requested)
+    };
+
+    // Answer whatever the executor would otherwise wait on
+    loop {
+        match rx.recv_timeout(Duration::from_millis(50)) {
+            Ok(Incoming::Terminated) | Err(RecvTimeoutError::Disconnected) => break,
+            // Only PAUSE stops a run without breakpoints
+            Ok(Incoming::Stopped { line, .. }) => {
+                while !listening(line)? {
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                ctx.lock().map_err(lock_error)?.request_continue();
+            }
+            Ok(_) | Err(RecvTimeoutError::Timeout) => {
+                ctx.lock().map_err(lock_error)?.supply_input("");
+            }
+        }
+    }
+
+    handle
+        .join()
+        .map_err(|_| io::Error::other("executor thread panicked"))??;
+
+    let mut ctx = ctx.lock().map_err(lock_error)?;
+    if let Err(e) = ctx.sync_variables_from_session() {
+        log::warn!("Failed to sync variables: {}", e);
+    }
+    let lines = ctx
+        .history
+        .entries()
+        .map(|entry| {
+            let logical = &pre.logical[entry.pc()];
+            TracedLine {
+                line: logical.phys_start + 1,
+                text: logical.text.clone(),
+                output: entry.output.clone(),
+                exit_code: entry.exit_code,
+            }
+        })
+        .collect();
+
+    Ok(ExecutionTrace {
+        lines,
+        exit_code: ctx.last_exit_code,
+        variables: ctx.get_visible_variables().into_iter().collect(),
+    })
+}
+
+fn lock_error<T>(e: std::sync::PoisonError<T>) -> io::Error {
+    io::Error::other(format!("Debug context unavailable: {}", e))
+}
@@ -8,7 +8,7 @@ fn main() -> io::Result<()> {
 
     // Test 1: Simple echo with immediate response
     println!("Test 1: Basic echo test");
-    let mut child = Command::new("cmd")
+    let child = Command::new("cmd")
         .args(["/Q", "/C", "echo Hello World"])// /C executes and exits
         .stdout(Stdio::piped())
         .spawn()?;
@@ -36,10 +36,9 @@ fn main() -> io::Result<()> {
 
     // Try to read response
     println!("Waiting for response...");
-    let mut line = String::new();
 
     // Read with timeout using a thread
-    let handle = std::thread::spawn(move || {
+    let _handle = std::thread::spawn(move || {
         let mut line = String::new();
         let mut lines = Vec::new();
         for _ in 0..5 {
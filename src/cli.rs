@@ -0,0 +1,135 @@
+//! Command-line arguments of the `batch-debugger` binary
+
+use std::path::PathBuf;
+
+/// Printed with any argument error
+pub const USAGE: &str = "\
+usage: batch-debugger [options] [script.bat]
+
+Runs script.bat (default test.bat) in the interactive debugger, or serves
+the Debug Adapter Protocol to an editor.
+
+options:
+  --dap, --debug-adapter   serve DAP over stdin/stdout
+  --pipe <name>            serve DAP over the named pipe batch-debugger-<name>
+  --port <port>            DAP over TCP (not supported yet)
+  --lint <script>          check a script's structure without running it
+  --log, --log-file <file> write a trace log to <file>
+  --no-log                 never write a trace log
+  -v, --verbose            log more to stderr (repeat for more)
+  --stop-on-entry          stop before the first line (the default)
+  --no-stop-on-entry       run until a breakpoint instead
+  --max-steps <n>          stop the script after <n> lines
+  --coverage <file>        write an LCOV report of the lines that ran
+  --profile[=<file>]       time each line; print the slowest, or write them
+  -V, --version            print the version and exit";
+
+/// Everything the binary can be asked to do on its command line
+#[derive(Debug, Clone, PartialEq)]
+pub struct CliArgs {
+    pub version: bool,
+    /// Serve DAP instead of running a script interactively; set by
+    /// `--pipe` and `--port` as well
+    pub dap_mode: bool,
+    pub pipe: Option<String>,
+    pub dap_port: Option<u16>,
+    /// Script for the interactive debugger
+    pub script: Option<PathBuf>,
+    /// Script to lint instead of running anything
+    pub lint: Option<PathBuf>,
+    /// Trace log file named on the command line
+    pub log_path: Option<PathBuf>,
+    pub no_log: bool,
+    /// Extra levels of logging on stderr, one per `-v`
+    pub verbose: usize,
+    pub stop_on_entry: bool,
+    pub max_steps: Option<usize>,
+    pub coverage: Option<PathBuf>,
+    /// `Some(None)` prints the profile, `Some(Some(file))` writes it to
+    /// `file`. The file is only ever given as `--profile=<file>`, so the
+    /// script after a bare `--profile` is never taken for it.
+    pub profile: Option<Option<PathBuf>>,
+}
+
+impl Default for CliArgs {
+    fn default() -> Self {
+        Self {
+            version: false,
+            dap_mode: false,
+            pipe: None,
+            dap_port: None,
+            script: None,
+            lint: None,
+            log_path: None,
+            no_log: false,
+            verbose: 0,
+            stop_on_entry: true,
+            max_steps: None,
+            coverage: None,
+            profile: None,
+        }
+    }
+}
+
+impl CliArgs {
+    /// Parse the arguments after the program name. The error says what was
+    /// wrong; `USAGE` goes with it.
+    pub fn parse<I, S>(args: I) -> Result<Self, String>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let mut parsed = Self::default();
+        let mut args = args.into_iter().map(Into::into);
+
+        while let Some(arg) = args.next() {
+            let mut value = |what: &str| {
+                args.next()
+                    .ok_or_else(|| format!("{} expects {}", arg, what))
+            };
+            match arg.as_str() {
+                "-V" | "--version" => parsed.version = true,
+                "--dap" | "--debug-adapter" => parsed.dap_mode = true,
+                "--pipe" => parsed.pipe = Some(value("a pipe name")?),
+                "--port" => {
+                    let port = value("a port number")?;
+                    let port = port
+                        .parse()
+                        .map_err(|_| format!("--port expects a port number, not {}", port))?;
+                    parsed.dap_port = Some(port);
+                }
+                "--lint" => parsed.lint = Some(value("a batch file")?.into()),
+                "--log" | "--log-file" => parsed.log_path = Some(value("a log file")?.into()),
+                "--no-log" => parsed.no_log = true,
+                "--verbose" => parsed.verbose += 1,
+                "--stop-on-entry" => parsed.stop_on_entry = true,
+                "--no-stop-on-entry" => parsed.stop_on_entry = false,
+                "--max-steps" => {
+                    let steps = value("a number of lines")?;
+                    let steps = steps.parse().map_err(|_| {
+                        format!("--max-steps expects a number of lines, not {}", steps)
+                    })?;
+                    parsed.max_steps = Some(steps);
+                }
+                "--coverage" => parsed.coverage = Some(value("an output file")?.into()),
+                "--profile" => parsed.profile = Some(None),
+                a if a.starts_with("--profile=") => {
+                    let file = &a["--profile=".len()..];
+                    if file.is_empty() {
+                        return Err("--profile= expects an output file".to_string());
+                    }
+                    parsed.profile = Some(Some(file.into()));
+                }
+                a if a.len() > 1 && a.starts_with('-') && a[1..].chars().all(|c| c == 'v') => {
+                    parsed.verbose += a.len() - 1;
+                }
+                a if a.starts_with('-') => return Err(format!("unknown option {}", a)),
+                _ if parsed.script.is_none() => parsed.script = Some(arg.into()),
+                _ => return Err(format!("unexpected argument {}", arg)),
+            }
+        }
+
+        parsed.dap_mode |= parsed.pipe.is_some() || parsed.dap_port.is_some();
+        Ok(parsed)
+    }
+}
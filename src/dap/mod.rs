@@ -1,150 +1,235 @@
+pub mod pipe;
 mod protocol;
 mod server;
+mod session;
 
+use crate::logger::Logger;
 use serde_json::json;
-use std::fs;
-use std::io::{self, Write};
-use std::thread;
+use std::io::{self, Read, Write};
 use std::time::Duration;
 
-pub use protocol::DapMessageContent;
-pub use server::DapServer;
+pub use protocol::{
+    read_frame, Capabilities, DapMessage, DapMessageContent, ErrorCode, Frame, MAX_CONTENT_LENGTH,
+};
+pub use server::{page_variables, DapServer, Incoming};
+pub use session::IDS_PER_SESSION;
 
-pub fn run_dap_mode() -> io::Result<()> {
-    eprintln!("DAP server starting...");
+/// Pause after a failed accept, so a transport that keeps failing doesn't
+/// spin
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
 
-    let mut log = fs::OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open("C:\\temp\\batch-debugger-vscode.log")
-        .ok();
+pub fn run_dap_mode(trace: Logger) -> io::Result<()> {
+    run_dap_session(DapServer::with_logger(trace))
+}
+
+/// Serve clients over the named pipe `\\.\pipe\batch-debugger-<name>`. Each
+/// connection is its own debug session, served on its own thread, so a
+/// client can reconnect to the same adapter process or start several
+/// sessions against it at once.
+pub fn run_dap_pipe(trace: Logger, name: &str) -> io::Result<()> {
+    serve_connections(trace, || {
+        log::info!("Waiting for a client on {}", pipe::pipe_path(name));
+        Some(pipe::accept(name))
+    })
+}
 
-    if let Some(ref mut f) = log {
-        writeln!(f, "DAP mode entered").ok();
+/// Run a debug session on its own thread for every connection `accept`
+/// hands over, until it returns `None`. A failed accept is logged and the
+/// next one tried, so one bad client can't take the adapter down; only a
+/// transport this platform doesn't have ends the loop with an error.
+pub fn serve_connections<F>(trace: Logger, mut accept: F) -> io::Result<()>
+where
+    F: FnMut() -> Option<io::Result<(Box<dyn Read + Send>, Box<dyn Write + Send>)>>,
+{
+    while let Some(accepted) = accept() {
+        let (reader, writer) = match accepted {
+            Ok(halves) => halves,
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => return Err(e),
+            Err(e) => {
+                log::error!("Failed to accept a client: {}", e);
+                std::thread::sleep(ACCEPT_RETRY_DELAY);
+                continue;
+            }
+        };
+        let server = DapServer::with_transport(trace.clone(), reader, writer);
+        std::thread::spawn(move || {
+            if let Err(e) = run_dap_session(server) {
+                log::error!("DAP session failed: {}", e);
+            }
+        });
     }
+    Ok(())
+}
+
+/// Run the DAP message loop for one client until it disconnects
+pub fn run_dap_session(mut server: DapServer) -> io::Result<()> {
+    log::info!("DAP server starting");
 
-    let mut server = DapServer::new();
     let mut msg_count = 0;
 
-    loop {
-        // CRITICAL: Poll for output from execution thread
-        server.check_and_send_output();
+    // One blocking channel carries client frames and execution events alike,
+    // so the loop sleeps until there is actually something to do
+    server.start_reader();
 
-        // CRITICAL: Poll for stopped events from execution thread
-        // Collect events first, then process them to avoid borrow checker issues
-        let mut events = Vec::new();
-        if let Some(ref rx) = server.event_receiver {
-            while let Ok((reason, line)) = rx.try_recv() {
-                events.push((reason, line));
+    while let Some(incoming) = server.next_incoming() {
+        match incoming {
+            Incoming::Output(text) => {
+                server.send_output(&text, "stdout");
             }
-        }
-
-        // Now process the events
-        for (reason, _line) in events {
-            if let Some(ref mut f) = log {
-                writeln!(f, "📥 Event received: {}", reason).ok();
-                f.flush().ok();
+            Incoming::Prompt(prompt) => {
+                server.send_output(&prompt, "important");
             }
-
-            if reason != "terminated" {
+            Incoming::Console(note) => {
+                server.send_output(&note, "console");
+            }
+            Incoming::Stopped { reason, line } => {
+                log::debug!("Stopped at line {}: {}", line, reason);
                 server.send_event(
                     "stopped".to_string(),
                     Some(json!({
                         "reason": reason,
-                        "threadId": 1,
+                        "threadId": server.current_thread_id(),
                         "allThreadsStopped": true
                     })),
                 );
-                eprintln!("📤 Sent stopped event: {}", reason);
-            } else {
-                eprintln!("📤 Sending terminated event");
-                server.send_event("terminated".to_string(), None);
             }
-        }
-
-        // Try to read a DAP message (non-blocking)
-        if let Some(msg) = server.try_read_message() {
-            msg_count += 1;
-
-            if let Some(ref mut f) = log {
-                writeln!(f, "✓ Received message #{}: {:?}", msg_count, msg.content).ok();
-                f.flush().ok();
+            Incoming::Terminated => {
+                log::debug!("Execution finished");
+                server.send_script_finished();
             }
-
-            eprintln!("📨 Received: {:?}", msg.content);
-
-            match msg.content {
-                DapMessageContent::Request { command, arguments } => match command.as_str() {
-                    "initialize" => {
-                        if let Some(ref mut f) = log {
-                            writeln!(f, "Handling initialize").ok();
-                        }
-                        eprintln!("🔧 Handling initialize");
-                        server.handle_initialize(msg.seq, command);
-                    }
-                    "launch" | "attach" => {
-                        if let Some(ref mut f) = log {
-                            writeln!(f, "Handling launch").ok();
+            Incoming::InputClosed => {
+                log::info!("Client closed the input stream");
+                break;
+            }
+            // next_incoming has already unwrapped these
+            Incoming::Session { event, .. } => {
+                log::warn!("Unexpected nested session event: {:?}", event);
+            }
+            Incoming::Dap(Frame::Malformed {
+                request_seq,
+                command,
+                error,
+            }) => {
+                log::warn!("Malformed DAP message: {}", error);
+                server.send_error_response(
+                    request_seq,
+                    command,
+                    ErrorCode::MalformedMessage,
+                    &error,
+                );
+            }
+            Incoming::Dap(Frame::Message(msg)) => {
+                msg_count += 1;
+
+                log::trace!("Received message #{}: {:?}", msg_count, msg.content);
+
+                match msg.content {
+                    DapMessageContent::Request { command, arguments } => {
+                        server.route(&arguments);
+                        match command.as_str() {
+                            "initialize" => {
+                                server.handle_initialize(msg.seq, command);
+                            }
+                            "launch" | "attach" => {
+                                server.handle_launch(msg.seq, command, arguments);
+                            }
+                            "setBreakpoints" => {
+                                server.handle_set_breakpoints(msg.seq, command, arguments);
+                            }
+                            "setFunctionBreakpoints" => {
+                                server.handle_set_function_breakpoints(msg.seq, command, arguments);
+                            }
+                            "dataBreakpointInfo" => {
+                                server.handle_data_breakpoint_info(msg.seq, command, arguments);
+                            }
+                            "setDataBreakpoints" => {
+                                server.handle_data_breakpoints(msg.seq, command, arguments);
+                            }
+                            "configurationDone" => {
+                                server.send_response(msg.seq, command, true, None);
+                            }
+                            "threads" => {
+                                server.handle_threads(msg.seq, command);
+                            }
+                            "stackTrace" => {
+                                server.handle_stack_trace(msg.seq, command, arguments);
+                            }
+                            "scopes" => {
+                                server.handle_scopes(msg.seq, command);
+                            }
+                            "variables" => {
+                                server.handle_variables(msg.seq, command, arguments);
+                            }
+                            "setExpression" => {
+                                server.handle_set_expression(msg.seq, command, arguments);
+                            }
+                            "evaluate" => {
+                                server.handle_evaluate(msg.seq, command, arguments);
+                            }
+                            "input" => {
+                                server.handle_input(msg.seq, command, arguments);
+                            }
+                            "readMemory" => {
+                                server.handle_read_memory(msg.seq, command, arguments);
+                            }
+                            "source" => {
+                                server.handle_source(msg.seq, command, arguments);
+                            }
+                            "disassemble" => {
+                                server.handle_disassemble(msg.seq, command, arguments);
+                            }
+                            "continue" => {
+                                server.handle_continue(msg.seq, command);
+                            }
+                            "next" => {
+                                server.handle_next(msg.seq, command, arguments);
+                            }
+                            "stepIn" => {
+                                server.handle_step_in(msg.seq, command, arguments);
+                            }
+                            "stepOut" => {
+                                server.handle_step_out(msg.seq, command);
+                            }
+                            "stepBack" => {
+                                server.handle_step_back(msg.seq, command);
+                            }
+                            "reverseContinue" => {
+                                server.handle_reverse_continue(msg.seq, command);
+                            }
+                            "restartFrame" => {
+                                server.handle_restart_frame(msg.seq, command, arguments);
+                            }
+                            "pause" => {
+                                server.handle_pause(msg.seq, command);
+                            }
+                            "cancel" => {
+                                server.handle_cancel(msg.seq, command, arguments);
+                            }
+                            "disconnect" => {
+                                server.handle_disconnect(msg.seq, command, arguments);
+                                break;
+                            }
+                            _ => {
+                                log::warn!("Unhandled DAP command: {}", command);
+                                let message = format!("Unsupported request '{}'", command);
+                                server.send_error_response(
+                                    msg.seq,
+                                    command,
+                                    ErrorCode::UnknownCommand,
+                                    &message,
+                                );
+                            }
                         }
-                        eprintln!("🚀 Handling launch");
-                        server.handle_launch(msg.seq, command, arguments);
-                    }
-                    "setBreakpoints" => {
-                        server.handle_set_breakpoints(msg.seq, command, arguments);
-                    }
-                    "configurationDone" => {
-                        server.send_response(msg.seq, command, true, None);
-                    }
-                    "threads" => {
-                        server.handle_threads(msg.seq, command);
-                    }
-                    "stackTrace" => {
-                        server.handle_stack_trace(msg.seq, command);
-                    }
-                    "scopes" => {
-                        server.handle_scopes(msg.seq, command);
-                    }
-                    "variables" => {
-                        server.handle_variables(msg.seq, command, arguments);
-                    }
-                    "continue" => {
-                        server.handle_continue(msg.seq, command);
-                    }
-                    "next" => {
-                        server.handle_next(msg.seq, command);
-                    }
-                    "stepIn" => {
-                        server.handle_step_in(msg.seq, command);
-                    }
-                    "stepOut" => {
-                        server.handle_step_out(msg.seq, command);
-                    }
-                    "pause" => {
-                        eprintln!("Handling pause");
-                        server.handle_pause(msg.seq, command);
-                    }
-                    "disconnect" => {
-                        server.send_response(msg.seq, command, true, None);
-                        break;
                     }
                     _ => {
-                        eprintln!("⚠️  Unhandled DAP command: {}", command);
-                        server.send_response(msg.seq, command, false, None);
+                        log::trace!("Ignoring non-request message");
                     }
-                },
-                _ => {
-                    eprintln!("📬 Non-request message");
                 }
             }
         }
-
-        // Small sleep to prevent busy-waiting
-        thread::sleep(Duration::from_millis(10));
     }
 
-    if let Some(ref mut f) = log {
-        writeln!(f, "DAP mode exiting").ok();
-    }
+    log::info!("DAP server exiting");
 
     Ok(())
 }
@@ -0,0 +1,226 @@
+//! Named pipe transport. The editor's debug adapter descriptor can connect
+//! over `\\.\pipe\batch-debugger-<name>` instead of the adapter's stdio.
+
+use std::io::{self, Read, Write};
+
+type Halves = (Box<dyn Read + Send>, Box<dyn Write + Send>);
+
+/// Full path of the pipe for `name`
+pub fn pipe_path(name: &str) -> String {
+    format!(r"\\.\pipe\batch-debugger-{}", name)
+}
+
+/// Create the pipe for `name`, wait for a client to connect, and return the
+/// read and write halves of the connection
+#[cfg(windows)]
+pub fn accept(name: &str) -> io::Result<Halves> {
+    let pipe = std::sync::Arc::new(windows::Pipe::create(&pipe_path(name))?);
+    pipe.connect()?;
+    Ok((
+        Box::new(windows::PipeStream(pipe.clone())),
+        Box::new(windows::PipeStream(pipe)),
+    ))
+}
+
+#[cfg(not(windows))]
+pub fn accept(name: &str) -> io::Result<Halves> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "named pipes are only available on Windows ({})",
+            pipe_path(name)
+        ),
+    ))
+}
+
+#[cfg(windows)]
+mod windows {
+    use std::ffi::c_void;
+    use std::io::{self, Read, Write};
+    use std::os::windows::io::{AsRawHandle, FromRawHandle, OwnedHandle, RawHandle};
+    use std::ptr;
+    use std::sync::Arc;
+
+    const PIPE_ACCESS_DUPLEX: u32 = 0x0000_0003;
+    const FILE_FLAG_OVERLAPPED: u32 = 0x4000_0000;
+    const PIPE_TYPE_BYTE: u32 = 0x0000_0000;
+    const PIPE_REJECT_REMOTE_CLIENTS: u32 = 0x0000_0008;
+    const PIPE_UNLIMITED_INSTANCES: u32 = 255;
+    const BUFFER_SIZE: u32 = 64 * 1024;
+    const ERROR_BROKEN_PIPE: i32 = 109;
+    const ERROR_PIPE_CONNECTED: i32 = 535;
+    const ERROR_IO_PENDING: i32 = 997;
+
+    // Only the kernel reads these fields
+    #[allow(dead_code)]
+    #[repr(C)]
+    struct Overlapped {
+        internal: usize,
+        internal_high: usize,
+        offset: u32,
+        offset_high: u32,
+        event: RawHandle,
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn CreateNamedPipeW(
+            name: *const u16,
+            open_mode: u32,
+            pipe_mode: u32,
+            max_instances: u32,
+            out_buffer_size: u32,
+            in_buffer_size: u32,
+            default_timeout: u32,
+            security_attributes: *mut c_void,
+        ) -> RawHandle;
+        fn ConnectNamedPipe(pipe: RawHandle, overlapped: *mut Overlapped) -> i32;
+        fn ReadFile(
+            file: RawHandle,
+            buffer: *mut u8,
+            len: u32,
+            read: *mut u32,
+            overlapped: *mut Overlapped,
+        ) -> i32;
+        fn WriteFile(
+            file: RawHandle,
+            buffer: *const u8,
+            len: u32,
+            written: *mut u32,
+            overlapped: *mut Overlapped,
+        ) -> i32;
+        fn GetOverlappedResult(
+            file: RawHandle,
+            overlapped: *const Overlapped,
+            transferred: *mut u32,
+            wait: i32,
+        ) -> i32;
+        fn CreateEventW(
+            attributes: *mut c_void,
+            manual_reset: i32,
+            initial_state: i32,
+            name: *const u16,
+        ) -> RawHandle;
+    }
+
+    /// One connected pipe instance. The handle is opened for overlapped I/O:
+    /// with a synchronous handle, the reader thread's pending read would block
+    /// every write until the client sent something.
+    pub struct Pipe(OwnedHandle);
+
+    impl Pipe {
+        pub fn create(path: &str) -> io::Result<Self> {
+            let wide: Vec<u16> = path.encode_utf16().chain(Some(0)).collect();
+            // SAFETY: `wide` is NUL-terminated and outlives the call
+            let handle = unsafe {
+                CreateNamedPipeW(
+                    wide.as_ptr(),
+                    PIPE_ACCESS_DUPLEX | FILE_FLAG_OVERLAPPED,
+                    PIPE_TYPE_BYTE | PIPE_REJECT_REMOTE_CLIENTS,
+                    // Connected clients keep their instance while the next one waits
+                    PIPE_UNLIMITED_INSTANCES,
+                    BUFFER_SIZE,
+                    BUFFER_SIZE,
+                    0,
+                    ptr::null_mut(),
+                )
+            };
+            // INVALID_HANDLE_VALUE
+            if handle as isize == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: the handle was just created and nothing else owns it
+            Ok(Self(unsafe { OwnedHandle::from_raw_handle(handle) }))
+        }
+
+        /// Block until a client opens the pipe
+        pub fn connect(&self) -> io::Result<()> {
+            // SAFETY: `overlapped` stays alive until `run_overlapped` has waited
+            let result = self
+                .run_overlapped(|overlapped| unsafe { ConnectNamedPipe(self.raw(), overlapped) });
+            match result {
+                // The client connected between creation and this call
+                Err(e) if e.raw_os_error() == Some(ERROR_PIPE_CONNECTED) => Ok(()),
+                other => other.map(|_| ()),
+            }
+        }
+
+        fn raw(&self) -> RawHandle {
+            self.0.as_raw_handle()
+        }
+
+        /// Start an overlapped operation with `start` and wait for it to finish,
+        /// returning the number of bytes transferred
+        fn run_overlapped(&self, start: impl FnOnce(*mut Overlapped) -> i32) -> io::Result<usize> {
+            // SAFETY: plain manual-reset event with default attributes
+            let event = unsafe { CreateEventW(ptr::null_mut(), 1, 0, ptr::null()) };
+            if event.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: the event was just created and nothing else owns it
+            let event = unsafe { OwnedHandle::from_raw_handle(event) };
+
+            let mut overlapped = Overlapped {
+                internal: 0,
+                internal_high: 0,
+                offset: 0,
+                offset_high: 0,
+                event: event.as_raw_handle(),
+            };
+            if start(&mut overlapped) == 0 {
+                let err = io::Error::last_os_error();
+                if err.raw_os_error() != Some(ERROR_IO_PENDING) {
+                    return Err(err);
+                }
+            }
+
+            let mut transferred = 0u32;
+            // SAFETY: `overlapped` is the structure the operation was started with
+            let ok = unsafe { GetOverlappedResult(self.raw(), &overlapped, &mut transferred, 1) };
+            if ok == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(transferred as usize)
+        }
+    }
+
+    /// A reading or writing half; both share the pipe instance
+    pub struct PipeStream(pub Arc<Pipe>);
+
+    impl Read for PipeStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(u32::MAX as usize) as u32;
+            let pipe = &self.0;
+            // SAFETY: `buf` is valid for `len` bytes until the operation completes
+            let result = pipe.run_overlapped(|overlapped| unsafe {
+                ReadFile(
+                    pipe.raw(),
+                    buf.as_mut_ptr(),
+                    len,
+                    ptr::null_mut(),
+                    overlapped,
+                )
+            });
+            match result {
+                // The client closed its end
+                Err(e) if e.raw_os_error() == Some(ERROR_BROKEN_PIPE) => Ok(0),
+                other => other,
+            }
+        }
+    }
+
+    impl Write for PipeStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let len = buf.len().min(u32::MAX as usize) as u32;
+            let pipe = &self.0;
+            // SAFETY: `buf` is valid for `len` bytes until the operation completes
+            pipe.run_overlapped(|overlapped| unsafe {
+                WriteFile(pipe.raw(), buf.as_ptr(), len, ptr::null_mut(), overlapped)
+            })
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
@@ -1,5 +1,9 @@
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{json, Value};
+use std::io::{self, BufRead, Read};
+
+/// Largest message body we are willing to buffer (16 MiB)
+pub const MAX_CONTENT_LENGTH: usize = 16 * 1024 * 1024;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DapMessage {
@@ -29,3 +33,212 @@ pub enum DapMessageContent {
         body: Option<Value>,
     },
 }
+
+/// What the adapter can do, as answered to `initialize`. Every field the
+/// client inspects is sent, so supporting a new request only means flipping
+/// its field in `Capabilities::adapter`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Capabilities {
+    pub supports_configuration_done_request: bool,
+    pub supports_function_breakpoints: bool,
+    pub supports_conditional_breakpoints: bool,
+    pub supports_hit_conditional_breakpoints: bool,
+    pub supports_evaluate_for_hovers: bool,
+    pub supports_step_back: bool,
+    pub supports_set_variable: bool,
+    pub supports_restart_frame: bool,
+    pub supports_goto_targets_request: bool,
+    pub supports_step_in_targets_request: bool,
+    pub supports_completions_request: bool,
+    pub supports_modules_request: bool,
+    pub supports_restart_request: bool,
+    pub supports_exception_options: bool,
+    pub supports_value_formatting_options: bool,
+    pub supports_exception_info_request: bool,
+    pub support_terminate_debuggee: bool,
+    pub support_suspend_debuggee: bool,
+    pub supports_delayed_stack_trace_loading: bool,
+    pub supports_loaded_sources_request: bool,
+    pub supports_log_points: bool,
+    pub supports_terminate_threads_request: bool,
+    pub supports_set_expression: bool,
+    pub supports_terminate_request: bool,
+    pub supports_data_breakpoints: bool,
+    pub supports_read_memory_request: bool,
+    pub supports_write_memory_request: bool,
+    pub supports_disassemble_request: bool,
+    pub supports_cancel_request: bool,
+    pub supports_breakpoint_locations_request: bool,
+    pub supports_clipboard_context: bool,
+    pub supports_stepping_granularity: bool,
+    pub supports_instruction_breakpoints: bool,
+    pub supports_exception_filter_options: bool,
+    pub supports_single_thread_execution_requests: bool,
+    pub supports_data_breakpoint_bytes: bool,
+    #[serde(rename = "supportsANSIStyling")]
+    pub supports_ansi_styling: bool,
+    pub supported_checksum_algorithms: Vec<String>,
+}
+
+impl Capabilities {
+    /// The features this adapter implements
+    pub fn adapter() -> Self {
+        Self {
+            supports_configuration_done_request: true,
+            supports_function_breakpoints: true,
+            supports_step_back: true,
+            supports_restart_frame: true,
+            support_terminate_debuggee: true,
+            supports_set_expression: true,
+            supports_evaluate_for_hovers: true,
+            supports_value_formatting_options: true,
+            supports_cancel_request: true,
+            supports_data_breakpoints: true,
+            supports_read_memory_request: true,
+            supports_disassemble_request: true,
+            supports_stepping_granularity: true,
+            supported_checksum_algorithms: vec!["SHA256".to_string()],
+            ..Self::default()
+        }
+    }
+}
+
+/// Error identifiers reported in the `ErrorMessage` body of failed responses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    MalformedMessage = 1000,
+    UnknownCommand = 1001,
+    ProgramUnreadable = 1002,
+    SessionStartFailed = 1003,
+    EvaluationFailed = 1005,
+    ContextUnavailable = 1006,
+    InvalidFrame = 1007,
+    UnknownSource = 1008,
+    NoHistory = 1009,
+    MemoryUnavailable = 1010,
+}
+
+impl ErrorCode {
+    pub fn id(self) -> u32 {
+        self as u32
+    }
+
+    /// Build the DAP `ErrorResponse` body carrying this code. Clients expand
+    /// `{name}` in `format`, so the text goes in as a variable rather than
+    /// the format itself, where braces from a script would be mangled.
+    pub fn to_body(self, message: &str) -> Value {
+        json!({
+            "error": {
+                "id": self.id(),
+                "format": "{message}",
+                "variables": { "message": message },
+                "showUser": true
+            }
+        })
+    }
+}
+
+/// One framed message read off the wire
+#[derive(Debug)]
+pub enum Frame {
+    Message(DapMessage),
+    /// The frame was consumed but its body could not be used.
+    /// `request_seq`/`command` are recovered on a best-effort basis.
+    Malformed {
+        request_seq: u64,
+        command: String,
+        error: String,
+    },
+}
+
+/// Read a single `Content-Length` framed message.
+///
+/// Returns `Ok(None)` on a clean EOF between messages. Unknown headers are
+/// ignored, and bodies that are oversized or not valid JSON are consumed and
+/// reported as `Frame::Malformed` so the stream stays in sync.
+pub fn read_frame<R: BufRead>(reader: &mut R) -> io::Result<Option<Frame>> {
+    let mut content_length: Option<usize> = None;
+    let mut saw_header = false;
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            if saw_header {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "stream ended inside message header",
+                ));
+            }
+            return Ok(None);
+        }
+
+        let header = line.trim_end_matches(['\r', '\n']);
+        if header.is_empty() {
+            if saw_header {
+                break;
+            }
+            // Stray blank line between messages
+            continue;
+        }
+        saw_header = true;
+
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("Content-Length") {
+                content_length = value.trim().parse().ok();
+            }
+        }
+    }
+
+    let length = match content_length {
+        Some(len) => len,
+        None => {
+            return Ok(Some(Frame::Malformed {
+                request_seq: 0,
+                command: String::new(),
+                error: "Missing or invalid Content-Length header".to_string(),
+            }))
+        }
+    };
+
+    if length > MAX_CONTENT_LENGTH {
+        // Drain the body so the next frame starts at the right place
+        io::copy(&mut reader.by_ref().take(length as u64), &mut io::sink())?;
+        return Ok(Some(Frame::Malformed {
+            request_seq: 0,
+            command: String::new(),
+            error: format!(
+                "Message body of {} bytes exceeds the {} byte limit",
+                length, MAX_CONTENT_LENGTH
+            ),
+        }));
+    }
+
+    let mut buffer = vec![0u8; length];
+    reader.read_exact(&mut buffer)?;
+
+    match serde_json::from_slice::<DapMessage>(&buffer) {
+        Ok(msg) => Ok(Some(Frame::Message(msg))),
+        Err(e) => {
+            // Recover enough of the request to address an error response
+            let value: Option<Value> = serde_json::from_slice(&buffer).ok();
+            let request_seq = value
+                .as_ref()
+                .and_then(|v| v.get("seq"))
+                .and_then(|v| v.as_u64())
+                .unwrap_or(0);
+            let command = value
+                .as_ref()
+                .and_then(|v| v.get("command"))
+                .and_then(|v| v.as_str())
+                .unwrap_or_default()
+                .to_string();
+            Ok(Some(Frame::Malformed {
+                request_seq,
+                command,
+                error: format!("Malformed message: {}", e),
+            }))
+        }
+    }
+}
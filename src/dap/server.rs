@@ -1,112 +1,394 @@
-use super::protocol::{DapMessage, DapMessageContent};
-use crate::debugger::{CmdSession, DebugContext, RunMode};
+use super::protocol::{read_frame, Capabilities, DapMessage, DapMessageContent, ErrorCode, Frame};
+use super::session::{DebugSession, Disconnect, IDS_PER_SESSION};
+use crate::debugger::{CmdSession, DataBreakpoint, DebugContext, RunMode, StepGranularity};
 use crate::executor;
-use crate::parser::{self, PreprocessResult};
+use crate::logger::Logger;
+use crate::parser;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
 use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
-use std::io::{self, BufRead, Read};
-use std::sync::mpsc::{channel, Receiver, TryRecvError};
-use std::sync::{Arc, Mutex};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, Once};
 use std::thread;
-use std::time::Duration;
-
-// Helper struct for non-blocking message reading
-struct MessageReader {
-    receiver: Option<Receiver<Option<DapMessage>>>,
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Memory reference `readMemory` serves the environment block under
+const ENV_MEMORY: &str = "env";
+
+/// Longest value a hover shows before it is cut off with an ellipsis
+const HOVER_MAX_CHARS: usize = 1024;
+
+/// Most instructions one disassemble request returns, however many the client
+/// asks for
+const DISASSEMBLE_MAX_INSTRUCTIONS: i64 = 10_000;
+
+/// Everything the DAP main loop reacts to, funneled through one channel. The
+/// executor sends a step's output before the stop that follows it, so stops
+/// reported only through here always reach the client after that output.
+#[derive(Debug)]
+pub enum Incoming {
+    /// A framed message from the client
+    Dap(Frame),
+    /// The executor paused at a logical line
+    Stopped { reason: String, line: usize },
+    /// Captured script output
+    Output(String),
+    /// A `SET /P` prompt the script is waiting to have answered
+    Prompt(String),
+    /// A note from the debugger about what the script did, shown in the
+    /// Debug Console
+    Console(String),
+    /// The executor finished running the script
+    Terminated,
+    /// The client closed its end of the stream
+    InputClosed,
+    /// An executor event from session `session`; `DapServer::next_incoming`
+    /// unwraps it
+    Session { session: u64, event: Box<Incoming> },
 }
 
-impl MessageReader {
-    fn new() -> Self {
-        Self { receiver: None }
-    }
+// Forwarders: a long-lived thread frames messages off the client stream and
+// pushes them into the shared incoming channel. A read blocked on a stream
+// can't be cancelled from outside, so dropping the reader has to end it some
+// other way. Stdin belongs to the whole process: one thread reads it for
+// every server, and a dropped server just stops being the one it feeds. Any
+// other stream is closed by the interrupt its transport supplies; without
+// one, the thread ends when the client closes the stream, or when the next
+// message arrives and the channel has nobody left to receive it.
+enum MessageReader {
+    /// The process's stdin, fed by [`STDIN`] once started
+    Stdin {
+        /// This server's claim on the stdin forwarder
+        subscription: Option<u64>,
+    },
+    Stream {
+        /// The client stream, until the forwarding thread takes it
+        source: Option<BufReader<Box<dyn Read + Send>>>,
+        /// Unblocks a read on the stream, run when the reader is dropped
+        interrupt: Option<Box<dyn FnOnce() + Send>>,
+    },
+}
 
-    fn start_read(&mut self) {
-        let (tx, rx) = channel();
-        self.receiver = Some(rx);
+/// The process-wide stdin forwarder
+struct StdinForwarder {
+    /// Stdin, until the forwarding thread takes it
+    source: Mutex<Option<BufReader<io::Stdin>>>,
+    subscriber: Mutex<StdinSubscriber>,
+    next_id: AtomicU64,
+    started: Once,
+}
 
-        thread::spawn(move || {
-            let stdin = io::stdin();
-            let mut handle = stdin.lock();
+struct StdinSubscriber {
+    /// The server stdin frames currently go to
+    current: Option<(u64, Sender<Incoming>)>,
+    /// Stdin reached its end, so whoever subscribes next is told at once
+    closed: bool,
+}
 
-            let mut content_length = 0;
-            let mut lines = handle.by_ref().lines();
+static STDIN: StdinForwarder = StdinForwarder {
+    source: Mutex::new(None),
+    subscriber: Mutex::new(StdinSubscriber {
+        current: None,
+        closed: false,
+    }),
+    next_id: AtomicU64::new(0),
+    started: Once::new(),
+};
+
+impl StdinForwarder {
+    /// Send stdin frames to `tx` from now on, starting the forwarding thread
+    /// on first use
+    fn subscribe(&'static self, tx: Sender<Incoming>) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        {
+            let mut subscriber = lock(&self.subscriber);
+            if subscriber.closed {
+                let _ = tx.send(Incoming::InputClosed);
+            }
+            subscriber.current = Some((id, tx));
+        }
 
-            loop {
-                match lines.next() {
-                    Some(Ok(line)) => {
-                        if line.is_empty() || line == "\r" {
-                            break;
-                        }
-                        if line.starts_with("Content-Length:") {
-                            content_length = line[15..].trim().parse().unwrap_or(0);
-                        }
-                    }
-                    _ => {
-                        let _ = tx.send(None);
-                        return;
+        self.started.call_once(|| {
+            let mut source = lock(&self.source)
+                .take()
+                .unwrap_or_else(|| BufReader::new(io::stdin()));
+            thread::spawn(move || {
+                forward(&mut source, |incoming| {
+                    if let Some((_, tx)) = &lock(&self.subscriber).current {
+                        let _ = tx.send(incoming);
                     }
+                    true
+                });
+                let mut subscriber = lock(&self.subscriber);
+                subscriber.closed = true;
+                if let Some((_, tx)) = subscriber.current.take() {
+                    let _ = tx.send(Incoming::InputClosed);
                 }
-            }
+            });
+        });
+        id
+    }
 
-            if content_length > 0 {
-                let mut buffer = vec![0u8; content_length];
-                drop(lines);
-                if handle.read_exact(&mut buffer).is_ok() {
-                    if let Ok(msg) = serde_json::from_slice(&buffer) {
-                        let _ = tx.send(Some(msg));
-                        return;
-                    }
+    /// Stop feeding subscription `id`, unless a later server took over
+    fn unsubscribe(&self, id: u64) {
+        let mut subscriber = lock(&self.subscriber);
+        if matches!(subscriber.current, Some((current, _)) if current == id) {
+            subscriber.current = None;
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Hand every frame read off `source` to `deliver` until the stream ends or
+/// `deliver` turns it down
+fn forward<R: BufRead>(source: &mut R, deliver: impl Fn(Incoming) -> bool) {
+    loop {
+        match read_frame(source) {
+            Ok(Some(frame)) => {
+                if !deliver(Incoming::Dap(frame)) {
+                    return;
                 }
             }
+            Ok(None) => return,
+            Err(e) => {
+                log::error!("Failed to read DAP message: {}", e);
+                return;
+            }
+        }
+    }
+}
 
-            let _ = tx.send(None);
-        });
+impl MessageReader {
+    fn new(source: Box<dyn Read + Send>) -> Self {
+        Self::Stream {
+            source: Some(BufReader::new(source)),
+            interrupt: None,
+        }
+    }
+
+    /// Read one frame on the calling thread, before any forwarder has taken
+    /// the stream
+    fn read_direct(&mut self) -> Option<io::Result<Option<Frame>>> {
+        match self {
+            Self::Stdin { subscription: None } if !STDIN.started.is_completed() => {
+                let mut source = lock(&STDIN.source);
+                Some(read_frame(
+                    source.get_or_insert_with(|| BufReader::new(io::stdin())),
+                ))
+            }
+            Self::Stdin { .. } => None,
+            Self::Stream { source, .. } => source.as_mut().map(read_frame),
+        }
     }
 
-    fn try_receive(&mut self) -> Option<Option<DapMessage>> {
-        if let Some(ref rx) = self.receiver {
-            match rx.try_recv() {
-                Ok(msg) => {
-                    self.receiver = None; // Clear for next read
-                    Some(msg)
+    fn start(&mut self, tx: Sender<Incoming>) {
+        match self {
+            Self::Stdin { subscription } => {
+                if subscription.is_none() {
+                    *subscription = Some(STDIN.subscribe(tx));
                 }
-                Err(TryRecvError::Empty) => None,
-                Err(TryRecvError::Disconnected) => {
-                    self.receiver = None;
-                    Some(None)
+            }
+            Self::Stream { source, .. } => {
+                let Some(mut source) = source.take() else {
+                    return;
+                };
+                thread::spawn(move || {
+                    forward(&mut source, |incoming| tx.send(incoming).is_ok());
+                    let _ = tx.send(Incoming::InputClosed);
+                });
+            }
+        }
+    }
+}
+
+impl Drop for MessageReader {
+    fn drop(&mut self) {
+        match self {
+            Self::Stdin {
+                subscription: Some(id),
+            } => STDIN.unsubscribe(*id),
+            Self::Stdin { subscription: None } => {}
+            Self::Stream { interrupt, .. } => {
+                if let Some(interrupt) = interrupt.take() {
+                    interrupt();
                 }
             }
-        } else {
-            None
         }
     }
 }
 
 pub struct DapServer {
     seq: u64,
-    context: Option<Arc<Mutex<DebugContext>>>,
-    preprocessed: Option<PreprocessResult>,
-    labels: Option<HashMap<String, usize>>,
-    breakpoints: HashMap<String, Vec<usize>>,
-    program_path: Option<String>,
-    pub event_receiver: Option<Receiver<(String, usize)>>,
-    pub output_receiver: Option<Receiver<String>>,
+    /// Every launch so far, by session id. A second `launch` starts another
+    /// session next to the first instead of replacing it.
+    sessions: HashMap<u64, DebugSession>,
+    /// Session for requests that name no thread, frame or variables
+    /// reference: the one launched or stopped last
+    active: Option<u64>,
+    next_session_id: u64,
+    /// Session whose script ended last, until that is reported
+    finished: Option<u64>,
+    /// Breakpoints as the client set them, as (id, physical line) per
+    /// source; sessions launched later install them too
+    requested_breakpoints: HashMap<String, Vec<(u64, u64)>>,
+    /// Function breakpoints as the client set them, as (id, label name)
+    requested_function_breakpoints: Option<Vec<(u64, String)>>,
+    next_breakpoint_id: u64,
+    /// Contents served through the `source` request: the scripts being
+    /// debugged, for clients that can't read them from disk, and generated files
+    source_refs: HashMap<u32, String>,
+    /// Reference of each script's contents in `source_refs`, by path
+    file_refs: HashMap<String, u32>,
+    /// SHA-256 of each script as it was when first read, by path
+    checksums: HashMap<String, String>,
+    /// cmd.exe kept alive by `disconnect` with `restart: true`, so the launch
+    /// that follows picks up its environment and working directory instead
+    /// of starting a fresh shell
+    retained: Option<CmdSession>,
+    incoming_tx: Sender<Incoming>,
+    incoming_rx: Receiver<Incoming>,
+    trace: Logger,
     message_reader: MessageReader,
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Default for DapServer {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl DapServer {
     pub fn new() -> Self {
+        Self::with_logger(Logger::disabled())
+    }
+
+    pub fn with_logger(trace: Logger) -> Self {
+        let mut server = Self::with_transport(trace, Box::new(io::empty()), Box::new(io::stdout()));
+        server.message_reader = MessageReader::Stdin { subscription: None };
+        server
+    }
+
+    /// Serve the client on `reader`/`writer` instead of stdin/stdout, e.g. a
+    /// named pipe; everything past the framing is shared with the stdio path
+    pub fn with_transport(
+        trace: Logger,
+        reader: Box<dyn Read + Send>,
+        writer: Box<dyn Write + Send>,
+    ) -> Self {
+        let (incoming_tx, incoming_rx) = channel();
         Self {
             seq: 0,
-            context: None,
-            preprocessed: None,
-            labels: None,
-            breakpoints: HashMap::new(),
-            program_path: None,
-            event_receiver: None,
-            output_receiver: None,
-            message_reader: MessageReader::new(),
+            sessions: HashMap::new(),
+            active: None,
+            next_session_id: 0,
+            finished: None,
+            requested_breakpoints: HashMap::new(),
+            requested_function_breakpoints: None,
+            next_breakpoint_id: 0,
+            source_refs: HashMap::new(),
+            file_refs: HashMap::new(),
+            checksums: HashMap::new(),
+            retained: None,
+            incoming_tx,
+            incoming_rx,
+            trace,
+            message_reader: MessageReader::new(reader),
+            writer: Mutex::new(writer),
+        }
+    }
+
+    /// Run `interrupt` when the server is dropped, to unblock a reader thread
+    /// still waiting on the stream, e.g. by closing it. A stream without one
+    /// keeps its reader until the client writes or closes it.
+    pub fn interrupt_reader_with(&mut self, interrupt: impl FnOnce() + Send + 'static) {
+        if let MessageReader::Stream {
+            interrupt: slot, ..
+        } = &mut self.message_reader
+        {
+            *slot = Some(Box::new(interrupt));
+        }
+    }
+
+    /// The launch `trace` argument turns logging on: `true` writes to the temp
+    /// directory, a string names the file. An already-configured log is kept.
+    fn enable_trace(&self, trace: Option<&Value>) {
+        if self.trace.is_enabled() {
+            return;
+        }
+        let path = match trace {
+            Some(Value::Bool(true)) => std::env::temp_dir().join("batch-debugger.log"),
+            Some(Value::String(path)) if !path.is_empty() => path.into(),
+            _ => return,
+        };
+        if let Err(e) = self.trace.enable(&path) {
+            log::warn!("Cannot open trace log {}: {}", path.display(), e);
+        }
+    }
+
+    /// Remember the contents of the script at `path` as they were loaded: their
+    /// checksum, and a reference the client can fetch them through
+    fn load_source(&mut self, path: &str, contents: &str) {
+        self.checksums
+            .insert(path.to_string(), sha256_hex(contents.as_bytes()));
+        let reference = self.source_reference(contents.to_string());
+        self.file_refs.insert(path.to_string(), reference);
+    }
+
+    /// DAP `Source` for a script on disk, with its checksum from when it was
+    /// first read so the client can tell when the file has been edited since,
+    /// and a reference to fetch it by when the client cannot see the file
+    fn source_object(&mut self, path: &str) -> Value {
+        let mut source = source_json(path);
+        if !self.checksums.contains_key(path) {
+            if let Ok(contents) = std::fs::read_to_string(path) {
+                self.load_source(path, &contents);
+            }
+        }
+        if let Some(reference) = self.file_refs.get(path) {
+            source["sourceReference"] = json!(reference);
+        }
+        if let Some(checksum) = self.checksums.get(path) {
+            source["checksums"] = json!([{
+                "algorithm": "SHA256",
+                "checksum": checksum
+            }]);
         }
+        source
+    }
+
+    /// Attach the `Source` for `path` to each breakpoint
+    fn with_source(&mut self, path: &str, mut breakpoints: Vec<Value>) -> Vec<Value> {
+        let source = self.source_object(path);
+        for breakpoint in &mut breakpoints {
+            breakpoint["source"] = source.clone();
+        }
+        breakpoints
+    }
+
+    /// Whether the file at `path` differs from the contents that were debugged
+    fn source_changed(&self, path: &str) -> bool {
+        match (self.checksums.get(path), std::fs::read(path)) {
+            (Some(checksum), Ok(contents)) => *checksum != sha256_hex(&contents),
+            _ => false,
+        }
+    }
+
+    /// Reference for `content`, reusing the existing one if it was already handed out
+    fn source_reference(&mut self, content: String) -> u32 {
+        if let Some((&id, _)) = self.source_refs.iter().find(|(_, c)| **c == content) {
+            return id;
+        }
+        let id = self.source_refs.len() as u32 + 1;
+        self.source_refs.insert(id, content);
+        id
     }
 
     fn next_seq(&mut self) -> u64 {
@@ -135,6 +417,27 @@ impl DapServer {
         self.send_message(&msg);
     }
 
+    pub fn send_error_response(
+        &mut self,
+        request_seq: u64,
+        command: String,
+        code: ErrorCode,
+        message: &str,
+    ) {
+        let msg = DapMessage {
+            seq: self.next_seq(),
+            msg_type: "response".to_string(),
+            content: DapMessageContent::Response {
+                request_seq,
+                success: false,
+                command,
+                message: Some(message.to_string()),
+                body: Some(code.to_body(message)),
+            },
+        };
+        self.send_message(&msg);
+    }
+
     pub fn send_event(&mut self, event: String, body: Option<Value>) {
         let msg = DapMessage {
             seq: self.next_seq(),
@@ -162,73 +465,121 @@ impl DapServer {
         let content_length = json.len();
 
         let output = format!("Content-Length: {}\r\n\r\n{}", content_length, json);
-        print!("{}", output);
-
-        use std::io::Write;
-        let _ = std::io::stdout().flush();
+        if let Ok(mut writer) = self.writer.lock() {
+            if let Err(e) = writer
+                .write_all(output.as_bytes())
+                .and_then(|_| writer.flush())
+            {
+                log::error!("Failed to send DAP message: {}", e);
+            }
+        }
 
-        eprintln!("📤 Sent {} bytes", content_length);
+        log::trace!("Sent {}", json);
     }
 
-    pub fn read_message(&self) -> Option<DapMessage> {
-        let stdin = io::stdin();
-        let mut handle = stdin.lock();
-
-        let mut content_length = 0;
-        let mut lines = handle.by_ref().lines();
-
+    /// Read the next message directly, before `start_reader` has taken the stream
+    pub fn read_message(&mut self) -> Option<DapMessage> {
         loop {
-            if let Some(Ok(line)) = lines.next() {
-                if line.is_empty() || line == "\r" {
-                    break;
-                }
-                if line.starts_with("Content-Length:") {
-                    content_length = line[15..].trim().parse().unwrap_or(0);
+            match self.message_reader.read_direct()? {
+                Ok(Some(Frame::Message(msg))) => return Some(msg),
+                Ok(Some(Frame::Malformed {
+                    request_seq,
+                    command,
+                    error,
+                })) => {
+                    self.send_error_response(
+                        request_seq,
+                        command,
+                        ErrorCode::MalformedMessage,
+                        &error,
+                    );
                 }
-            } else {
-                return None;
+                Ok(None) | Err(_) => return None,
             }
         }
+    }
 
-        if content_length > 0 {
-            let mut buffer = vec![0u8; content_length];
-            drop(lines);
-            if handle.read_exact(&mut buffer).is_ok() {
-                if let Ok(msg) = serde_json::from_slice(&buffer) {
-                    return Some(msg);
+    /// Start forwarding client messages into the incoming channel
+    pub fn start_reader(&mut self) {
+        self.message_reader.start(self.incoming_tx.clone());
+    }
+
+    /// Block until the next message, executor event, or output arrives. A
+    /// session stopping becomes the active one. Whatever a disconnected
+    /// session's executor still sends on its way out is dropped, so it can't
+    /// end the launch that follows a restart.
+    pub fn next_incoming(&mut self) -> Option<Incoming> {
+        loop {
+            match self.incoming_rx.recv().ok()? {
+                Incoming::Session { session, event } => {
+                    let Some(live) = self.sessions.get_mut(&session) else {
+                        log::debug!("Dropping event of ended session {}", session);
+                        continue;
+                    };
+                    match *event {
+                        Incoming::Stopped { .. } => self.active = Some(session),
+                        Incoming::Terminated => {
+                            live.finished = true;
+                            self.finished = Some(session);
+                        }
+                        _ => {}
+                    }
+                    return Some(*event);
                 }
+                incoming => return Some(incoming),
             }
         }
-
-        None
     }
 
-    pub fn try_read_message(&mut self) -> Option<DapMessage> {
-        // Check if we have a pending read
-        if let Some(result) = self.message_reader.try_receive() {
-            return result;
+    /// Make the session owning the thread, frame or variables reference in
+    /// `arguments` the active one, so the request is answered from it
+    pub fn route(&mut self, arguments: &Option<Value>) {
+        let id = ["threadId", "frameId", "variablesReference"]
+            .iter()
+            .find_map(|key| arguments.as_ref()?.get(key)?.as_u64());
+        if let Some(session) = id
+            .map(|id| id / IDS_PER_SESSION)
+            .filter(|session| self.sessions.contains_key(session))
+        {
+            self.active = Some(session);
         }
+    }
 
-        // Start a new read if we don't have one pending
-        if self.message_reader.receiver.is_none() {
-            self.message_reader.start_read();
-        }
+    fn session(&self) -> Option<&DebugSession> {
+        self.active.and_then(|id| self.sessions.get(&id))
+    }
+
+    fn context(&self) -> Option<Arc<Mutex<DebugContext>>> {
+        self.session().map(|session| Arc::clone(&session.context))
+    }
 
-        None
+    /// Offset of the active session's thread, frame and variables ids
+    fn id_base(&self) -> u64 {
+        self.session().map_or(0, DebugSession::id_base)
+    }
+
+    /// Report the end of the script that finished last: its thread exits,
+    /// and the debug session terminates with the last of them
+    pub fn send_script_finished(&mut self) {
+        let running = self.sessions.values().any(|session| !session.finished);
+        if let Some(session) = self.finished.take() {
+            self.send_event(
+                "thread".to_string(),
+                Some(json!({
+                    "reason": "exited",
+                    "threadId": session * IDS_PER_SESSION + 1
+                })),
+            );
+        }
+        if !running {
+            self.send_event("terminated".to_string(), None);
+        }
     }
 
     pub fn handle_initialize(&mut self, seq: u64, command: String) {
-        let body = json!({
-            "supportsConfigurationDoneRequest": true,
-            "supportsStepBack": false,
-            "supportsStepInTargetsRequest": false,
-            "supportsFunctionBreakpoints": false,
-            "supportsConditionalBreakpoints": false,
-            "supportsSetVariable": false,
-        });
-        self.send_response(seq, command, true, Some(body));
+        let body = serde_json::to_value(Capabilities::adapter()).ok();
+        self.send_response(seq, command, true, body);
 
-        eprintln!("📋 Sending initialized event");
         self.send_event("initialized".to_string(), None);
     }
 
@@ -239,211 +590,188 @@ impl DapServer {
             .and_then(|v| v.as_str())
             .unwrap_or("test.bat");
 
-        let stop_on_entry = args
+        // stopOnEntry is true, false, or "firstExecutable" to stop past the
+        // script's @echo off and setlocal lines
+        let entry = args.as_ref().and_then(|v| v.get("stopOnEntry"));
+        let skip_housekeeping = entry.and_then(|v| v.as_str()) == Some("firstExecutable");
+        let stop_on_entry = skip_housekeeping || entry.and_then(|v| v.as_bool()).unwrap_or(true);
+
+        let pre_init_commands: Vec<String> = args
+            .as_ref()
+            .and_then(|v| v.get("preInitCommands"))
+            .and_then(|v| v.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|c| c.as_str().map(str::to_string))
+            .collect();
+
+        let max_steps = args
+            .as_ref()
+            .and_then(|v| v.get("maxSteps"))
+            .and_then(|v| v.as_u64())
+            .map(|n| n as usize);
+
+        let step_into_external = args
             .as_ref()
-            .and_then(|v| v.get("stopOnEntry"))
+            .and_then(|v| v.get("stepIntoExternalScripts"))
             .and_then(|v| v.as_bool())
-            .unwrap_or(true);
+            .unwrap_or(false);
 
-        self.program_path = Some(program.to_string());
+        let input_values: HashMap<String, String> = args
+            .as_ref()
+            .and_then(|v| v.get("inputValues"))
+            .and_then(|v| v.as_object())
+            .into_iter()
+            .flatten()
+            .filter_map(|(name, value)| Some((name.to_uppercase(), value.as_str()?.to_string())))
+            .collect();
+
+        let expose_memory = args
+            .as_ref()
+            .and_then(|v| v.get("exposeMemoryAsEnv"))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
 
-        eprintln!("🚀 Launching batch file: {}", program);
-        eprintln!("   Stop on entry: {}", stop_on_entry);
+        let history_size = args
+            .as_ref()
+            .and_then(|v| v.get("historySize"))
+            .and_then(|v| v.as_u64())
+            .map(|n| n as usize);
 
-        let mut log = std::fs::OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open("C:\\temp\\batch-debugger-vscode.log")
-            .ok();
+        self.enable_trace(args.as_ref().and_then(|v| v.get("trace")));
 
-        if let Some(ref mut f) = log {
-            use std::io::Write;
-            writeln!(f, "handle_launch called for: {}", program).ok();
-            writeln!(f, "stop_on_entry: {}", stop_on_entry).ok();
-            f.flush().ok();
-        }
+        log::info!(
+            "Launching batch file: {} (stop on entry: {})",
+            program,
+            stop_on_entry
+        );
 
         match std::fs::read_to_string(program) {
             Ok(contents) => {
+                self.load_source(program, &contents);
                 let physical_lines: Vec<&str> = contents.lines().collect();
                 let pre = parser::preprocess_lines(&physical_lines);
-                let labels_phys = parser::build_label_map(&physical_lines);
-
-                eprintln!("📝 Parsed {} logical lines", pre.logical.len());
-                if let Some(ref mut f) = log {
-                    use std::io::Write;
-                    writeln!(f, "Parsed {} logical lines", pre.logical.len()).ok();
-                    f.flush().ok();
-                }
+                let labels = parser::build_label_map(&pre);
+
+                log::debug!("Parsed {} logical lines", pre.logical.len());
+
+                let retained = self
+                    .retained
+                    .take()
+                    .and_then(|mut session| session.is_alive().then_some(session));
+                let started =
+                    retained
+                        .map_or_else(CmdSession::start, Ok)
+                        .and_then(|mut session| {
+                            let cmds: Vec<&str> =
+                                pre_init_commands.iter().map(String::as_str).collect();
+                            session.warm_up(&cmds)?;
+                            Ok(session)
+                        });
 
-                match CmdSession::start() {
+                match started {
                     Ok(session) => {
-                        eprintln!("✓ CMD session started");
-                        if let Some(ref mut f) = log {
-                            use std::io::Write;
-                            writeln!(f, "CMD session started successfully").ok();
-                            f.flush().ok();
-                        }
+                        log::debug!("CMD session started");
 
+                        let pid = session.id();
                         let mut ctx = DebugContext::new(session);
+                        ctx.current_source = program.to_string();
+                        ctx.max_steps = max_steps;
+                        ctx.step_into_external = step_into_external;
+                        ctx.skip_housekeeping = skip_housekeeping;
+                        ctx.input_values = input_values;
+                        if let Some(size) = history_size {
+                            ctx.history.set_capacity(size);
+                        }
 
                         if stop_on_entry {
                             ctx.set_mode(RunMode::StepInto);
-                            eprintln!("   Mode: StepInto (will stop at first line)");
                         } else {
                             ctx.set_mode(RunMode::Continue);
-                            eprintln!("   Mode: Continue (will run until breakpoint)");
                         }
                         ctx.continue_requested = false;
 
-                        let ctx_arc = Arc::new(Mutex::new(ctx));
-                        self.context = Some(ctx_arc.clone());
-                        self.preprocessed = Some(pre.clone());
-                        self.labels = Some(labels_phys.clone());
+                        let id = self.next_session_id;
+                        self.next_session_id += 1;
+                        let mut debug_session =
+                            DebugSession::new(id, ctx, pre.clone(), labels.clone(), program, pid);
+                        debug_session.expose_memory = expose_memory;
+                        debug_session.launch_seq = seq;
+                        let exec_ctx = Arc::clone(&debug_session.context);
+                        self.sessions.insert(id, debug_session);
+                        self.active = Some(id);
 
                         self.send_response(seq, command, true, None);
-                        eprintln!("📤 Sent launch response");
-
-                        let mut thread_log = std::fs::OpenOptions::new()
-                            .create(true)
-                            .append(true)
-                            .open("C:\\temp\\batch-debugger-vscode.log")
-                            .ok();
-
-                        if let Some(ref mut f) = thread_log {
-                            use std::io::Write;
-                            writeln!(f, "About to spawn execution thread").ok();
-                            f.flush().ok();
-                        }
-
-                        let (tx, rx) = channel::<(String, usize)>();
-                        let (output_tx, output_rx) = channel::<String>();
-
-                        self.event_receiver = Some(rx);
-                        self.output_receiver = Some(output_rx);
-
-                        let exec_ctx = ctx_arc.clone();
-                        let exec_pre = pre.clone();
-                        let exec_labels = labels_phys.clone();
-
+                        self.send_event(
+                            "process".to_string(),
+                            Some(json!({
+                                "name": program,
+                                "systemProcessId": pid,
+                                "isLocalProcess": true,
+                                "startMethod": "launch"
+                            })),
+                        );
+                        self.send_event(
+                            "thread".to_string(),
+                            Some(json!({
+                                "reason": "started",
+                                "threadId": id * IDS_PER_SESSION + 1
+                            })),
+                        );
+
+                        // Before the executor starts, so early breakpoints can't be missed
+                        self.apply_requested_breakpoints();
+
+                        // Tag the executor's events with the session they come from
+                        let (events, session_events) = channel();
+                        let incoming = self.incoming_tx.clone();
                         thread::spawn(move || {
-                            let mut tlog = std::fs::OpenOptions::new()
-                                .create(true)
-                                .append(true)
-                                .open("C:\\temp\\batch-debugger-vscode.log")
-                                .ok();
-
-                            if let Some(ref mut f) = tlog {
-                                use std::io::Write;
-                                writeln!(f, "🧵 Execution thread STARTED").ok();
-                                f.flush().ok();
+                            for event in session_events {
+                                let event = Box::new(event);
+                                if incoming
+                                    .send(Incoming::Session { session: id, event })
+                                    .is_err()
+                                {
+                                    break;
+                                }
                             }
+                        });
 
-                            eprintln!("🧵 Execution thread started");
+                        let exec_pre = pre;
+                        let exec_labels = labels;
+                        let executor = thread::spawn(move || {
+                            log::debug!("Execution thread started");
 
                             match executor::run_debugger_dap(
                                 exec_ctx,
                                 &exec_pre,
                                 &exec_labels,
-                                tx,
-                                output_tx,
+                                events,
                             ) {
-                                Ok(_) => {
-                                    eprintln!("✅ Execution completed successfully");
-                                    if let Some(ref mut f) = tlog {
-                                        use std::io::Write;
-                                        writeln!(f, "✅ Execution completed successfully").ok();
-                                        f.flush().ok();
-                                    }
-                                }
-                                Err(e) => {
-                                    eprintln!("❌ Execution error: {}", e);
-                                    if let Some(ref mut f) = tlog {
-                                        use std::io::Write;
-                                        writeln!(f, "❌ Execution error: {}", e).ok();
-                                        f.flush().ok();
-                                    }
-                                }
-                            }
-
-                            if let Some(ref mut f) = tlog {
-                                use std::io::Write;
-                                writeln!(f, "🧵 Execution thread EXITING").ok();
-                                f.flush().ok();
+                                Ok(_) => log::debug!("Execution completed"),
+                                Err(e) => log::error!("Execution error: {}", e),
                             }
-                            eprintln!("🧵 Execution thread exiting");
                         });
-
-                        if let Some(ref mut f) = log {
-                            use std::io::Write;
-                            writeln!(f, "Execution thread spawned, waiting for first stop").ok();
-                            f.flush().ok();
-                        }
-
-                        // Process any output that came through before the first stop
-                        if let Some(ref output_rx) = self.output_receiver {
-                            let mut outputs = Vec::new();
-                            while let Ok(output) = output_rx.try_recv() {
-                                outputs.push(output);
-                            }
-                            for output in outputs {
-                                self.send_output(&output, "stdout");
-                            }
-                        }
-
-                        // Wait for the first stopped event and send it
-                        if let Some(ref rx) = self.event_receiver {
-                            if let Ok((reason, line)) = rx.recv_timeout(Duration::from_secs(2)) {
-                                if let Some(ref mut f) = log {
-                                    use std::io::Write;
-                                    writeln!(f, "Received first stop: {} at line {}", reason, line)
-                                        .ok();
-                                    f.flush().ok();
-                                }
-
-                                if reason != "terminated" {
-                                    self.send_event(
-                                        "stopped".to_string(),
-                                        Some(json!({
-                                            "reason": reason,
-                                            "threadId": 1,
-                                            "allThreadsStopped": true
-                                        })),
-                                    );
-                                    eprintln!("📤 Sent initial stopped event: {}", reason);
-                                } else {
-                                    eprintln!("⚠️ Script completed before first stop");
-                                    self.send_event("terminated".to_string(), None);
-                                }
-                            } else {
-                                if let Some(ref mut f) = log {
-                                    use std::io::Write;
-                                    writeln!(f, "⚠️ Timeout waiting for first stop event").ok();
-                                    f.flush().ok();
-                                }
-                                eprintln!("⚠️ Timeout waiting for first stop event");
-                            }
+                        if let Some(session) = self.sessions.get_mut(&id) {
+                            session.executor = Some(executor);
                         }
                     }
                     Err(e) => {
-                        eprintln!("❌ Failed to start CMD session: {}", e);
-                        if let Some(ref mut f) = log {
-                            use std::io::Write;
-                            writeln!(f, "❌ Failed to start CMD session: {}", e).ok();
-                            f.flush().ok();
-                        }
-                        self.send_response(seq, command, false, None);
+                        log::error!("Failed to start CMD session: {}", e);
+                        let message = format!("Failed to start cmd.exe: {}", e);
+                        self.send_error_response(
+                            seq,
+                            command,
+                            ErrorCode::SessionStartFailed,
+                            &message,
+                        );
                     }
                 }
             }
             Err(e) => {
-                eprintln!("❌ Failed to read batch file: {}", e);
-                if let Some(ref mut f) = log {
-                    use std::io::Write;
-                    writeln!(f, "❌ Failed to read batch file: {}", e).ok();
-                    f.flush().ok();
-                }
-                self.send_response(seq, command, false, None);
+                log::error!("Failed to read batch file: {}", e);
+                let message = format!("Cannot read '{}': {}", program, e);
+                self.send_error_response(seq, command, ErrorCode::ProgramUnreadable, &message);
             }
         }
     }
@@ -454,59 +782,99 @@ impl DapServer {
             .and_then(|v| v.get("source"))
             .and_then(|v| v.get("path"))
             .and_then(|v| v.as_str())
-            .unwrap_or("");
+            .unwrap_or("")
+            .to_string();
+
+        let source_modified = args
+            .as_ref()
+            .and_then(|v| v.get("sourceModified"))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
 
-        let breakpoints_array = args
+        let requested: Vec<(u64, u64)> = args
             .as_ref()
             .and_then(|v| v.get("breakpoints"))
             .and_then(|v| v.as_array())
-            .cloned()
-            .unwrap_or_default();
-
-        let mut verified_breakpoints = Vec::new();
-        let mut logical_lines = Vec::new();
-
-        eprintln!("🔍 Setting breakpoints for: {}", source_path);
-
-        if let Some(pre) = &self.preprocessed {
-            for bp in breakpoints_array {
-                if let Some(line) = bp.get("line").and_then(|v| v.as_u64()) {
-                    let phys_line = (line as usize).saturating_sub(1);
-
-                    eprintln!(
-                        "   Breakpoint request: physical line {} (0-indexed: {})",
-                        line, phys_line
-                    );
-
-                    if phys_line < pre.phys_to_logical.len() {
-                        let logical_line = pre.phys_to_logical[phys_line];
-                        logical_lines.push(logical_line);
+            .into_iter()
+            .flatten()
+            .filter_map(|bp| bp.get("line").and_then(|v| v.as_u64()))
+            .map(|line| {
+                self.next_breakpoint_id += 1;
+                (self.next_breakpoint_id, line)
+            })
+            .collect();
+
+        log::debug!("Setting breakpoints for: {}", source_path);
+        self.requested_breakpoints
+            .insert(source_path.clone(), requested.clone());
+
+        // Clients send breakpoints between initialize and launch; there are no
+        // logical lines to map them to yet, so hold on to them until there are
+        if self.sessions.is_empty() {
+            log::debug!("Program not launched yet, {} pending", requested.len());
+            let pending: Vec<Value> = requested
+                .iter()
+                .map(|(id, line)| {
+                    json!({
+                        "id": id,
+                        "verified": false,
+                        "line": line,
+                        "reason": "pending",
+                        "message": "Waiting for the program to launch"
+                    })
+                })
+                .collect();
+            let pending = self.with_source(&source_path, pending);
+            self.send_response(
+                seq,
+                command,
+                true,
+                Some(json!({
+                    "breakpoints": pending
+                })),
+            );
+            return;
+        }
 
-                        eprintln!("   ✓ Mapped to logical line {}", logical_line);
-                        eprintln!("   Line content: {}", pre.logical[logical_line].text);
+        // Lines in an edited file no longer match what is running, so mapping
+        // them would stop in the wrong places; drop the source's breakpoints
+        let stale = (source_modified && self.source_changed(&source_path)).then(|| {
+            log::debug!("{} changed since launch", source_path);
+            requested
+                    .iter()
+                    .map(|(id, line)| {
+                        json!({
+                            "id": id,
+                            "verified": false,
+                            "line": line,
+                            "message": "The file changed since the debug session started; restart to debug the new contents"
+                        })
+                    })
+                    .collect::<Vec<_>>()
+        });
 
-                        verified_breakpoints.push(json!({
-                            "verified": true,
-                            "line": line
-                        }));
-                    } else {
-                        eprintln!("   ✗ Physical line {} out of range", phys_line);
-                    }
-                }
+        // Every session runs its own copy of the script; the response
+        // describes the active one
+        let mut verified_breakpoints = Vec::new();
+        let mut lock_error = None;
+        for session in self.sessions.values_mut() {
+            let (logical_lines, breakpoints) = match &stale {
+                Some(stale) => (Vec::new(), stale.clone()),
+                None => session.map_breakpoints(&requested),
+            };
+            // Each request carries the full set for its source, so replace rather than append
+            if let Err(message) = session.set_source_breakpoints(&source_path, logical_lines) {
+                lock_error = Some(message);
+            }
+            if Some(session.id) == self.active {
+                verified_breakpoints = breakpoints;
             }
         }
+        let verified_breakpoints = self.with_source(&source_path, verified_breakpoints);
 
-        self.breakpoints
-            .insert(source_path.to_string(), logical_lines.clone());
-
-        if let Some(ctx_arc) = &self.context {
-            if let Ok(mut ctx) = ctx_arc.lock() {
-                eprintln!("   Adding {} breakpoints to context", logical_lines.len());
-                for logical_line in &logical_lines {
-                    ctx.add_breakpoint(*logical_line);
-                    eprintln!("   Added breakpoint at logical line {}", logical_line);
-                }
-            }
+        if let Some(message) = lock_error {
+            self.send_error_response(seq, command, ErrorCode::ContextUnavailable, &message);
+            return;
         }
 
         self.send_response(
@@ -519,143 +887,680 @@ impl DapServer {
         );
     }
 
-    pub fn handle_threads(&mut self, seq: u64, command: String) {
+    /// Install the breakpoints the client has set in the session just
+    /// launched and tell the client which of them could be verified
+    fn apply_requested_breakpoints(&mut self) {
+        let Some(id) = self.active else {
+            return;
+        };
+
+        if let Some(requested) = self.requested_function_breakpoints.clone() {
+            for breakpoint in self.apply_function_breakpoints(id, &requested) {
+                self.send_event(
+                    "breakpoint".to_string(),
+                    Some(json!({
+                        "reason": "changed",
+                        "breakpoint": breakpoint
+                    })),
+                );
+            }
+        }
+
+        let requested_breakpoints = self.requested_breakpoints.clone();
+        for (source_path, requested) in requested_breakpoints {
+            let Some(session) = self.sessions.get_mut(&id) else {
+                return;
+            };
+            let (logical_lines, breakpoints) = session.map_breakpoints(&requested);
+            if let Err(message) = session.set_source_breakpoints(&source_path, logical_lines) {
+                log::warn!("{}", message);
+            }
+            let breakpoints = self.with_source(&source_path, breakpoints);
+
+            for breakpoint in breakpoints {
+                self.send_event(
+                    "breakpoint".to_string(),
+                    Some(json!({
+                        "reason": "changed",
+                        "breakpoint": breakpoint
+                    })),
+                );
+            }
+        }
+    }
+
+    /// Function breakpoints name `:label`s and stop at the label's first
+    /// statement. Each request carries the full set.
+    pub fn handle_set_function_breakpoints(
+        &mut self,
+        seq: u64,
+        command: String,
+        args: Option<Value>,
+    ) {
+        let requested: Vec<(u64, String)> = args
+            .as_ref()
+            .and_then(|v| v.get("breakpoints"))
+            .and_then(|v| v.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|bp| bp.get("name").and_then(|v| v.as_str()))
+            .map(|name| {
+                self.next_breakpoint_id += 1;
+                (self.next_breakpoint_id, name.to_string())
+            })
+            .collect();
+
+        self.requested_function_breakpoints = Some(requested.clone());
+
+        // Labels are only known once the program is parsed
+        if self.sessions.is_empty() {
+            log::debug!(
+                "Program not launched yet, {} function breakpoints pending",
+                requested.len()
+            );
+            let pending: Vec<Value> = requested
+                .iter()
+                .map(|(id, _)| {
+                    json!({
+                        "id": id,
+                        "verified": false,
+                        "reason": "pending",
+                        "message": "Waiting for the program to launch"
+                    })
+                })
+                .collect();
+            self.send_response(
+                seq,
+                command,
+                true,
+                Some(json!({
+                    "breakpoints": pending
+                })),
+            );
+            return;
+        }
+
+        let mut ids: Vec<u64> = self.sessions.keys().copied().collect();
+        ids.sort_unstable();
+        let mut breakpoints = Vec::new();
+        for id in ids {
+            let applied = self.apply_function_breakpoints(id, &requested);
+            if Some(id) == self.active {
+                breakpoints = applied;
+            }
+        }
         self.send_response(
             seq,
             command,
             true,
             Some(json!({
-                "threads": [
-                    {
-                        "id": 1,
-                        "name": "Batch Script"
-                    }
-                ]
+                "breakpoints": breakpoints
             })),
         );
     }
 
-    pub fn handle_stack_trace(&mut self, seq: u64, command: String) {
-        let mut frames = Vec::new();
-
-        let program_path = self.program_path.as_deref().unwrap_or("test.bat");
-        let program_name = std::path::Path::new(program_path)
-            .file_name()
-            .and_then(|n| n.to_str())
-            .unwrap_or("test.bat");
-
-        if let Some(ctx_arc) = &self.context {
-            if let Ok(ctx) = ctx_arc.lock() {
-                if let Some(pre) = &self.preprocessed {
-                    let current_pc = ctx.current_line.unwrap_or(0);
+    /// Resolve (id, label name) pairs in session `id`'s program and make
+    /// them its function breakpoints, returning a DAP `Breakpoint` for each
+    fn apply_function_breakpoints(&mut self, id: u64, requested: &[(u64, String)]) -> Vec<Value> {
+        let Some(program) = self.sessions.get(&id).map(|s| s.program_path.clone()) else {
+            return Vec::new();
+        };
+        let source = self.source_object(&program);
+        self.sessions[&id].apply_function_breakpoints(requested, &source)
+    }
 
-                    let physical_line = if current_pc < pre.logical.len() {
-                        pre.logical[current_pc].phys_start + 1
-                    } else {
-                        1
-                    };
+    /// Every named variable can be watched for writes; the name is the data id
+    pub fn handle_data_breakpoint_info(&mut self, seq: u64, command: String, args: Option<Value>) {
+        let name = args
+            .as_ref()
+            .and_then(|v| v.get("name"))
+            .and_then(|v| v.as_str())
+            .unwrap_or("")
+            .trim_matches(|c| c == '!' || c == '%');
+
+        let body = if name.is_empty() {
+            json!({
+                "dataId": null,
+                "description": "Not a variable"
+            })
+        } else {
+            json!({
+                "dataId": name,
+                "description": format!("When {} changes", name),
+                "accessTypes": ["write"],
+                "canPersist": false
+            })
+        };
 
-                    eprintln!(
-                        "📊 Stack trace: logical PC={}, physical line={}",
-                        current_pc, physical_line
-                    );
+        self.send_response(seq, command, true, Some(body));
+    }
 
-                    frames.push(json!({
-                        "id": 0,
-                        "name": "main",
-                        "line": physical_line,
-                        "column": 1,
-                        "source": {
-                            "name": program_name,
-                            "path": program_path
-                        }
-                    }));
+    pub fn handle_data_breakpoints(&mut self, seq: u64, command: String, args: Option<Value>) {
+        let names: Vec<String> = args
+            .as_ref()
+            .and_then(|v| v.get("breakpoints"))
+            .and_then(|v| v.as_array())
+            .map(|bps| {
+                bps.iter()
+                    .filter_map(|bp| bp.get("dataId").and_then(|v| v.as_str()))
+                    .map(|s| s.to_string())
+                    .collect()
+            })
+            .unwrap_or_default();
 
-                    for (i, frame) in ctx.call_stack.iter().enumerate() {
-                        let return_line = frame.return_pc.saturating_sub(1);
-                        if return_line < pre.logical.len() {
-                            let logical = &pre.logical[return_line];
-                            frames.push(json!({
-                                "id": i + 1,
-                                "name": format!("frame_{}", i + 1),
-                                "line": logical.phys_start + 1,
-                                "column": 1,
-                                "source": {
-                                    "name": program_name,
-                                    "path": program_path
-                                }
-                            }));
-                        }
+        log::debug!("Setting {} data breakpoints", names.len());
+
+        // Each session's context holds its watches; the response describes
+        // the active one's, or the bare names before any launch
+        let mut lock_error = None;
+        let mut installed = None;
+        for session in self.sessions.values() {
+            match session.context.lock() {
+                Ok(mut ctx) => {
+                    ctx.set_data_breakpoints(&names);
+                    if Some(session.id) == self.active {
+                        installed = Some(ctx.data_breakpoints().to_vec());
                     }
                 }
+                Err(e) => lock_error = Some(format!("Debug context unavailable: {}", e)),
             }
         }
 
+        if let Some(message) = lock_error {
+            self.send_error_response(seq, command, ErrorCode::ContextUnavailable, &message);
+            return;
+        }
+
+        let installed = installed.unwrap_or_else(|| {
+            names
+                .iter()
+                .map(|name| DataBreakpoint {
+                    name: name.clone(),
+                    old_value: None,
+                })
+                .collect()
+        });
+        let breakpoints: Vec<Value> = installed
+            .iter()
+            .map(|bp| {
+                json!({
+                    "verified": true,
+                    "message": format!("{} = {}", bp.name, bp.old_value.as_deref().unwrap_or("<undefined>"))
+                })
+            })
+            .collect();
+
         self.send_response(
             seq,
             command,
             true,
             Some(json!({
-                "stackFrames": frames,
-                "totalFrames": frames.len()
+                "breakpoints": breakpoints
             })),
         );
     }
 
-    pub fn handle_scopes(&mut self, seq: u64, command: String) {
-        self.send_response(
-            seq,
-            command,
-            true,
-            Some(json!({
-                "scopes": [
-                    {
-                        "name": "Local",
-                        "variablesReference": 1,
-                        "expensive": false
-                    },
-                    {
-                        "name": "Global",
-                        "variablesReference": 2,
-                        "expensive": false
-                    }
-                ]
+    /// Thread of the innermost script invocation, where execution is
+    pub fn current_thread_id(&self) -> u64 {
+        let Some(session) = self.session() else {
+            return 1;
+        };
+        let invocation = session
+            .context
+            .lock()
+            .map_or(0, |ctx| ctx.invocation_at_depth(ctx.call_stack.len()));
+        session.id_base() + invocation as u64 + 1
+    }
+
+    /// One thread per script invocation on the call chain, named after the
+    /// file, so frames group by script in the Call Stack pane. Sessions whose
+    /// script has ended drop out while others are still running.
+    pub fn handle_threads(&mut self, seq: u64, command: String) {
+        let mut sessions: Vec<&DebugSession> = self.sessions.values().collect();
+        sessions.sort_by_key(|session| session.id);
+        if sessions.iter().any(|session| !session.finished) {
+            sessions.retain(|session| !session.finished);
+        }
+
+        let mut threads = Vec::new();
+        for session in sessions {
+            let Ok(ctx) = session.context.lock() else {
+                continue;
+            };
+            for (i, path) in ctx
+                .script_invocations(&session.program_path)
+                .into_iter()
+                .enumerate()
+            {
+                threads.push(json!({
+                    "id": session.id_base() + i as u64 + 1,
+                    "name": script_name(path)
+                }));
+            }
+        }
+        if self.sessions.is_empty() {
+            threads.push(json!({
+                "id": 1,
+                "name": "Batch Script"
+            }));
+        }
+
+        self.send_response(
+            seq,
+            command,
+            true,
+            Some(json!({
+                "threads": threads
+            })),
+        );
+    }
+
+    /// Frames of the thread `threadId` names, or of every thread without one
+    pub fn handle_stack_trace(&mut self, seq: u64, command: String, args: Option<Value>) {
+        let thread_id = args
+            .as_ref()
+            .and_then(|v| v.get("threadId"))
+            .and_then(|v| v.as_u64());
+        // (thread, frame) pairs, innermost first
+        let mut frames = Vec::new();
+
+        let base = self.id_base();
+        let mut lock_error = None;
+        let mut active_block = None;
+        if let Some(session) = self.session() {
+            let program_path = session.program_path.as_str();
+            match session.context.lock() {
+                Err(e) => lock_error = Some(format!("Debug context unavailable: {}", e)),
+                Ok(ctx) => {
+                    active_block = ctx.active_block.clone();
+                    // Scripts entered by an external CALL have their own line mapping
+                    let lines_of = |path: &str| {
+                        ctx.scripts
+                            .get(path)
+                            .map(|pre| pre.as_ref())
+                            .or(Some(&session.preprocessed))
+                    };
+
+                    // A stepBack shows the recorded line instead of the live one
+                    let historical = ctx.history.position();
+                    let current_pc = historical
+                        .map(|entry| entry.pc())
+                        .or(ctx.current_line)
+                        .unwrap_or(0);
+                    let current_source = match historical {
+                        Some(entry) => entry.source.as_str(),
+                        None => ctx.source_at_depth(ctx.call_stack.len(), program_path),
+                    };
+                    let column = match historical {
+                        Some(_) => 1,
+                        None => ctx.current_column.unwrap_or(1),
+                    };
+                    if let Some(pre) = lines_of(current_source) {
+                        let physical_line = if current_pc < pre.logical.len() {
+                            pre.logical[current_pc].phys_start + 1
+                        } else {
+                            1
+                        };
+
+                        let thread =
+                            base + ctx.invocation_at_depth(ctx.call_stack.len()) as u64 + 1;
+                        let name = frame_name(&ctx.call_stack, ctx.call_stack.len());
+                        frames.push((
+                            thread,
+                            json!({
+                                "id": base,
+                                "name": match historical {
+                                    Some(_) => format!("{} (history)", name),
+                                    None => name.to_string(),
+                                },
+                                "line": physical_line,
+                                "column": column,
+                                "instructionPointerReference": current_pc.to_string(),
+                                "source": source_json(current_source)
+                            }),
+                        ));
+                    }
+
+                    // Ids follow the stack from the bottom so restartFrame can
+                    // map them back; the frames are listed from the top
+                    for (i, frame) in ctx.call_stack.iter().enumerate().rev() {
+                        // The return site is in whichever script made the call
+                        let caller = ctx.source_at_depth(i, program_path);
+                        let Some(pre) = lines_of(caller) else {
+                            continue;
+                        };
+                        let return_line = frame.return_pc.saturating_sub(1);
+                        if return_line < pre.logical.len() {
+                            let logical = &pre.logical[return_line];
+                            let thread = base + ctx.invocation_at_depth(i) as u64 + 1;
+                            frames.push((
+                                thread,
+                                json!({
+                                    "id": base + i as u64 + 1,
+                                    // The return site is in the caller, which is
+                                    // whatever the frame below it called
+                                    "name": frame_name(&ctx.call_stack, i),
+                                    "line": logical.phys_start + 1,
+                                    "column": 1,
+                                    "source": source_json(caller)
+                                }),
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+
+        if let Some(message) = lock_error {
+            self.send_error_response(seq, command, ErrorCode::ContextUnavailable, &message);
+            return;
+        }
+
+        let mut frames: Vec<Value> = frames
+            .into_iter()
+            .filter(|(thread, _)| thread_id.is_none_or(|id| id == *thread))
+            .map(|(_, frame)| frame)
+            .collect();
+
+        for frame in &mut frames {
+            if let Some(path) = frame["source"]["path"].as_str().map(str::to_string) {
+                frame["source"] = self.source_object(&path);
+            }
+        }
+
+        // A block runs from a temporary file; show that file instead, starting
+        // at the block's first line (after the `@echo off` header)
+        let top = frames.first_mut().filter(|frame| frame["id"] == base);
+        if let (Some(content), Some(top)) = (active_block, top) {
+            let block_line = top["line"].clone();
+            let reference = self.source_reference(content);
+            top["line"] = json!(2);
+            top["source"] = json!({
+                "name": format!("block at line {}", block_line),
+                "sourceReference": reference,
+                "origin": "temporary block file"
+            });
+        }
+
+        self.send_response(
+            seq,
+            command,
+            true,
+            Some(json!({
+                "stackFrames": frames,
+                "totalFrames": frames.len()
+            })),
+        );
+    }
+
+    pub fn handle_scopes(&mut self, seq: u64, command: String) {
+        let mut local_count = 0;
+        let mut global_count = 0;
+        let mut watch_count = 0;
+        let mut has_setlocal = false;
+        let base = self.id_base();
+
+        if let Some(mut ctx) = self.session().and_then(|s| s.context.lock().ok()) {
+            if let Err(e) = ctx.sync_variables_from_session() {
+                log::error!("Failed to sync variables: {}", e);
+            }
+            local_count = ctx.get_visible_variables().len();
+            // Plus the CD entry
+            global_count = ctx
+                .session_mut()
+                .get_all_env_vars()
+                .map(|env| env.len() + 1)
+                .unwrap_or(0);
+            watch_count = ctx.watches.len();
+            // Only offer the delayed expansion view while SETLOCAL is active in the current frame
+            has_setlocal = ctx.call_stack.last().is_some_and(|f| f.has_setlocal);
+        }
+
+        let mut scopes = vec![
+            json!({
+                "name": "Local",
+                "variablesReference": base + 1,
+                "namedVariables": local_count,
+                "indexedVariables": 0,
+                "expensive": false
+            }),
+            json!({
+                "name": "Global",
+                "variablesReference": base + 2,
+                "namedVariables": global_count,
+                "indexedVariables": 0,
+                "expensive": false
+            }),
+        ];
+
+        if self.session().is_some() {
+            scopes.push(json!({
+                "name": "System",
+                "variablesReference": base + 6,
+                "namedVariables": 5,
+                "indexedVariables": 0,
+                "expensive": false
+            }));
+        }
+
+        if has_setlocal {
+            scopes.push(json!({
+                "name": "Delayed Expansion",
+                "variablesReference": base + 3,
+                "namedVariables": global_count,
+                "indexedVariables": 0,
+                "expensive": true
+            }));
+        }
+
+        if watch_count > 0 {
+            scopes.push(json!({
+                "name": "Watches",
+                "variablesReference": base + 4,
+                "namedVariables": watch_count,
+                "indexedVariables": 0,
+                "expensive": true
+            }));
+        }
+
+        self.send_response(
+            seq,
+            command,
+            true,
+            Some(json!({
+                "scopes": scopes
             })),
         );
     }
 
     pub fn handle_variables(&mut self, seq: u64, command: String, args: Option<Value>) {
+        let base = self.id_base();
         let var_ref = args
             .as_ref()
             .and_then(|v| v.get("variablesReference"))
             .and_then(|v| v.as_u64())
-            .unwrap_or(0);
+            .unwrap_or(0)
+            % IDS_PER_SESSION;
+        let start = args
+            .as_ref()
+            .and_then(|v| v.get("start"))
+            .and_then(|v| v.as_u64())
+            .unwrap_or(0) as usize;
+        let count = args
+            .as_ref()
+            .and_then(|v| v.get("count"))
+            .and_then(|v| v.as_u64())
+            .unwrap_or(0) as usize;
+        let filter = args
+            .as_ref()
+            .and_then(|v| v.get("filter"))
+            .and_then(|v| v.as_str());
+        let hex = args
+            .as_ref()
+            .and_then(|v| v.get("format"))
+            .and_then(|f| f.get("hex"))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
 
         let mut variables = Vec::new();
 
-        if let Some(ctx_arc) = &self.context {
-            if let Ok(ctx) = ctx_arc.lock() {
-                match var_ref {
-                    1 => {
-                        let visible = ctx.get_visible_variables();
-                        for (key, val) in visible {
-                            variables.push(json!({
-                                "name": key,
-                                "value": val,
-                                "variablesReference": 0
-                            }));
+        let mut lock_error = None;
+        if let Some(session) = self.session() {
+            match session.context.lock() {
+                Err(e) => lock_error = Some(format!("Debug context unavailable: {}", e)),
+                Ok(mut ctx) => {
+                    match var_ref {
+                        1 if ctx.history.is_inspecting() => {
+                            let live = ctx.get_visible_variables();
+                            for (key, val) in ctx.history.variables_at(&live) {
+                                variables.push(json!({
+                                    "name": key,
+                                    "value": val,
+                                    "type": "history",
+                                    "variablesReference": 0
+                                }));
+                            }
                         }
-                    }
-                    2 => {
-                        for (key, val) in &ctx.variables {
-                            variables.push(json!({
-                                "name": key,
-                                "value": val,
-                                "variablesReference": 0
-                            }));
+                        1 => {
+                            if let Err(e) = ctx.sync_variables_from_session() {
+                                log::error!("Failed to sync variables: {}", e);
+                            }
+                            let names: Vec<String> =
+                                ctx.get_visible_variables().into_keys().collect();
+                            for key in names {
+                                let val = ctx.get_variable(&key).unwrap_or_default();
+                                let kind = if ctx.is_delayed_value(&key) {
+                                    "run-time (!VAR!)"
+                                } else {
+                                    "parse-time (%VAR%)"
+                                };
+                                variables.push(json!({
+                                    "name": key,
+                                    "value": val,
+                                    "type": kind,
+                                    "variablesReference": 0
+                                }));
+                            }
+                            if !ctx.dir_stack.is_empty() {
+                                variables.push(json!({
+                                    "name": "(PUSHD stack)",
+                                    "value": format!("depth {}", ctx.dir_stack.len()),
+                                    "variablesReference": base + 5,
+                                    "indexedVariables": ctx.dir_stack.len()
+                                }));
+                            }
+                        }
+                        2 => {
+                            // cmd computes CD on demand, so `set` never lists it
+                            match ctx.current_dir() {
+                                Ok(dir) => variables.push(json!({
+                                    "name": "CD",
+                                    "value": dir,
+                                    "variablesReference": 0
+                                })),
+                                Err(e) => log::error!("Failed to query directory: {}", e),
+                            }
+                            // The real environment, including variables inherited at startup
+                            match ctx.session_mut().get_all_env_vars() {
+                                Ok(env) => {
+                                    for (key, val) in env {
+                                        let mut variable = json!({
+                                            "name": key,
+                                            "value": val,
+                                            "variablesReference": 0
+                                        });
+                                        if session.expose_memory {
+                                            variable["memoryReference"] = json!(ENV_MEMORY);
+                                        }
+                                        variables.push(variable);
+                                    }
+                                }
+                                Err(e) => {
+                                    log::error!("Failed to query environment: {}", e);
+                                }
+                            }
+                        }
+                        3 => {
+                            // Ask cmd.exe itself; !VAR! values are only known at run time
+                            match ctx.session_mut().get_all_env_vars() {
+                                Ok(env) => {
+                                    for (key, val) in env {
+                                        variables.push(json!({
+                                            "name": format!("!{}!", key),
+                                            "value": val,
+                                            "variablesReference": 0
+                                        }));
+                                    }
+                                }
+                                Err(e) => {
+                                    log::error!("Failed to query environment: {}", e);
+                                }
+                            }
+                        }
+                        4 => {
+                            for (expr, value) in ctx.evaluate_watches() {
+                                variables.push(json!({
+                                    "name": expr,
+                                    "value": value,
+                                    "variablesReference": 0
+                                }));
+                            }
+                        }
+                        5 => {
+                            // Innermost first; padded so the name sort keeps stack order
+                            let width = ctx.dir_stack.len().to_string().len();
+                            for (i, dir) in ctx.dir_stack.iter().rev().enumerate() {
+                                variables.push(json!({
+                                    "name": format!("[{:0width$}]", i, width = width),
+                                    "value": dir,
+                                    "variablesReference": 0
+                                }));
+                            }
+                        }
+                        6 => {
+                            // cmd's dynamic variables; only CD needs the session
+                            let (date, time) = clock_values(SystemTime::now());
+                            let mut system = vec![
+                                ("ERRORLEVEL", ctx.last_exit_code.to_string()),
+                                ("DATE", date),
+                                ("TIME", time),
+                                ("RANDOM", random_value().to_string()),
+                            ];
+                            match ctx.current_dir() {
+                                Ok(dir) => system.push(("CD", dir)),
+                                Err(e) => log::error!("Failed to query directory: {}", e),
+                            }
+                            for (name, value) in system {
+                                variables.push(json!({
+                                    "name": name,
+                                    "value": value,
+                                    "presentationHint": { "attributes": ["readOnly"] },
+                                    "variablesReference": 0
+                                }));
+                            }
                         }
+                        _ => {}
                     }
-                    _ => {}
+                }
+            }
+        }
+
+        if let Some(message) = lock_error {
+            self.send_error_response(seq, command, ErrorCode::ContextUnavailable, &message);
+            return;
+        }
+
+        // The PUSHD stack's entries are its only indexed children; everything else is named
+        let indexed = var_ref == 5;
+        match filter {
+            Some("indexed") if !indexed => variables.clear(),
+            Some("named") if indexed => variables.clear(),
+            _ => {}
+        }
+        if hex {
+            for variable in &mut variables {
+                if let Some(value) = variable["value"].as_str().and_then(hex_value) {
+                    variable["value"] = json!(value);
                 }
             }
         }
@@ -665,88 +1570,803 @@ impl DapServer {
             command,
             true,
             Some(json!({
-                "variables": variables
+                "variables": page_variables(variables, start, count)
             })),
         );
     }
 
-    pub fn handle_continue(&mut self, seq: u64, command: String) {
-        if let Some(ctx_arc) = &self.context {
-            if let Ok(mut ctx) = ctx_arc.lock() {
-                ctx.set_mode(RunMode::Continue);
-                ctx.continue_requested = true;
+    pub fn handle_evaluate(&mut self, seq: u64, command: String, args: Option<Value>) {
+        let expression = args
+            .as_ref()
+            .and_then(|v| v.get("expression"))
+            .and_then(|v| v.as_str())
+            .unwrap_or("");
+        let context = args
+            .as_ref()
+            .and_then(|v| v.get("context"))
+            .and_then(|v| v.as_str())
+            .unwrap_or("");
+
+        if context == "hover" {
+            self.evaluate_hover(seq, command, expression);
+            return;
+        }
+        if context == "watch" {
+            self.evaluate_watch(seq, command, expression);
+            return;
+        }
+
+        // A REPL entry answers a pending SET /P prompt
+        let supplied = context == "repl"
+            && self
+                .session()
+                .and_then(|session| session.context.lock().ok())
+                .map(|mut ctx| ctx.supply_input(expression))
+                .unwrap_or(false);
+
+        if supplied {
+            self.send_response(
+                seq,
+                command,
+                true,
+                Some(json!({
+                    "result": format!("input: {}", expression),
+                    "variablesReference": 0
+                })),
+            );
+        } else {
+            self.send_error_response(
+                seq,
+                command,
+                ErrorCode::EvaluationFailed,
+                "Nothing is waiting for input",
+            );
+        }
+    }
+
+    /// Answer the `SET /P` the script is waiting on with `value`, the same
+    /// as typing it in the Debug Console
+    pub fn handle_input(&mut self, seq: u64, command: String, args: Option<Value>) {
+        let value = args
+            .as_ref()
+            .and_then(|v| v.get("value"))
+            .and_then(|v| v.as_str())
+            .unwrap_or("");
+        let supplied = self
+            .session()
+            .and_then(|session| session.context.lock().ok())
+            .map(|mut ctx| ctx.supply_input(value))
+            .unwrap_or(false);
+
+        if supplied {
+            self.send_response(seq, command, true, None);
+        } else {
+            self.send_error_response(
+                seq,
+                command,
+                ErrorCode::EvaluationFailed,
+                "Nothing is waiting for input",
+            );
+        }
+    }
+
+    /// Evaluate an entry of the client's watch pane and keep it in the
+    /// Watches scope
+    fn evaluate_watch(&mut self, seq: u64, command: String, expression: &str) {
+        let result = match self.session() {
+            None => Err("No program is running".to_string()),
+            Some(session) => match session.context.lock() {
+                Err(e) => Err(format!("Debug context unavailable: {}", e)),
+                Ok(mut ctx) => {
+                    ctx.add_watch(expression);
+                    match ctx.evaluate_watch(expression) {
+                        Ok(Some(value)) => Ok(value),
+                        Ok(None) => Err(format!("{} is not defined", expression.trim())),
+                        Err(e) => Err(format!("Failed to evaluate {}: {}", expression, e)),
+                    }
+                }
+            },
+        };
+
+        match result {
+            Ok(value) => self.send_response(
+                seq,
+                command,
+                true,
+                Some(json!({
+                    "result": value,
+                    "variablesReference": 0
+                })),
+            ),
+            Err(message) => {
+                self.send_error_response(seq, command, ErrorCode::EvaluationFailed, &message)
+            }
+        }
+    }
+
+    /// Show the value of the `%NAME%` or `!NAME!` under the mouse
+    fn evaluate_hover(&mut self, seq: u64, command: String, expression: &str) {
+        let result = match parser::parse_variable_reference(expression) {
+            None => Err(format!("'{}' is not a variable reference", expression)),
+            Some(name) => match self.session() {
+                None => Err("No program is running".to_string()),
+                Some(session) => match session.context.lock() {
+                    Err(e) => Err(format!("Debug context unavailable: {}", e)),
+                    Ok(mut ctx) => match ctx.evaluate_variable(&name) {
+                        Ok(Some(value)) => Ok(value),
+                        Ok(None) => Err(format!("{} is not defined", name)),
+                        Err(e) => Err(format!("Failed to read {}: {}", name, e)),
+                    },
+                },
+            },
+        };
+
+        match result {
+            Ok(value) => self.send_response(
+                seq,
+                command,
+                true,
+                Some(json!({
+                    "result": truncate_hover(&value),
+                    "variablesReference": 0
+                })),
+            ),
+            Err(message) => {
+                self.send_error_response(seq, command, ErrorCode::EvaluationFailed, &message)
             }
         }
+    }
+
+    /// Edit a watched variable: `%NAME%`, `!NAME!` or `NAME` = value
+    pub fn handle_set_expression(&mut self, seq: u64, command: String, args: Option<Value>) {
+        let expression = args
+            .as_ref()
+            .and_then(|v| v.get("expression"))
+            .and_then(|v| v.as_str())
+            .unwrap_or("");
+        let value = args
+            .as_ref()
+            .and_then(|v| v.get("value"))
+            .and_then(|v| v.as_str())
+            .unwrap_or("");
+
+        let Some(name) = parser::parse_variable_reference(expression) else {
+            let message = format!(
+                "'{}' is not a variable reference; use NAME, %NAME% or !NAME!",
+                expression
+            );
+            self.send_error_response(seq, command, ErrorCode::EvaluationFailed, &message);
+            return;
+        };
+
+        if value.contains(['\r', '\n']) {
+            self.send_error_response(
+                seq,
+                command,
+                ErrorCode::EvaluationFailed,
+                "Variable values cannot contain line breaks",
+            );
+            return;
+        }
+
+        let result = match self.session() {
+            None => Err("No program is running".to_string()),
+            Some(session) => match session.context.lock() {
+                Err(e) => Err(format!("Debug context unavailable: {}", e)),
+                Ok(mut ctx) => ctx
+                    .set_variable(&name, value)
+                    .map_err(|e| format!("Failed to set {}: {}", name, e)),
+            },
+        };
+
+        match result {
+            Ok(()) => self.send_response(
+                seq,
+                command,
+                true,
+                Some(json!({
+                    "value": value,
+                    "variablesReference": 0
+                })),
+            ),
+            Err(message) => {
+                self.send_error_response(seq, command, ErrorCode::EvaluationFailed, &message)
+            }
+        }
+    }
+
+    /// Show the preprocessed logical lines as a "disassembly" of the script.
+    /// Addresses are logical line indices; the bytes column carries the
+    /// physical span and block grouping so joins and blocks are visible.
+    pub fn handle_disassemble(&mut self, seq: u64, command: String, args: Option<Value>) {
+        let arg_i64 = |key: &str| {
+            args.as_ref()
+                .and_then(|v| v.get(key))
+                .and_then(|v| v.as_i64())
+                .unwrap_or(0)
+        };
+
+        let base = args
+            .as_ref()
+            .and_then(|v| v.get("memoryReference"))
+            .and_then(|v| v.as_str())
+            .and_then(|s| s.trim().parse::<i64>().ok())
+            .unwrap_or(0);
+        let start = base
+            .saturating_add(arg_i64("offset"))
+            .saturating_add(arg_i64("instructionOffset"));
+        let count = arg_i64("instructionCount").clamp(0, DISASSEMBLE_MAX_INSTRUCTIONS);
+
+        let Some(session) = self.session() else {
+            self.send_error_response(
+                seq,
+                command,
+                ErrorCode::ContextUnavailable,
+                "No program has been launched",
+            );
+            return;
+        };
+
+        let pre = &session.preprocessed;
+        let program_path = session.program_path.as_str();
+        let program_name = std::path::Path::new(program_path)
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("test.bat");
+
+        // The client expects exactly `instructionCount` entries, so pad
+        // out-of-range addresses with empty placeholders; a window past the
+        // end of the address space is cut short
+        let end = start.saturating_add(count);
+        let mut instructions = Vec::with_capacity((end - start) as usize);
+        for address in start..end {
+            let logical = usize::try_from(address)
+                .ok()
+                .and_then(|idx| pre.logical.get(idx));
+
+            match logical {
+                Some(ll) => {
+                    let group = ll
+                        .group_id
+                        .map(|id| id.to_string())
+                        .unwrap_or_else(|| "-".to_string());
+                    instructions.push(json!({
+                        "address": address.to_string(),
+                        "instructionBytes": format!(
+                            "L{}-{} D{} G{}",
+                            ll.phys_start + 1,
+                            ll.phys_end + 1,
+                            ll.group_depth,
+                            group
+                        ),
+                        "instruction": parser::normalize_whitespace_outside_quotes(&ll.text),
+                        "location": {
+                            "name": program_name,
+                            "path": program_path
+                        },
+                        "line": ll.phys_start + 1,
+                        "endLine": ll.phys_end + 1
+                    }));
+                }
+                None => {
+                    instructions.push(json!({
+                        "address": address.to_string(),
+                        "instruction": "",
+                        "presentationHint": "invalid"
+                    }));
+                }
+            }
+        }
+
         self.send_response(
             seq,
             command,
             true,
-            Some(json!({"allThreadsContinued": true})),
+            Some(json!({
+                "instructions": instructions
+            })),
         );
-        // Event polling now happens in main loop
     }
 
-    pub fn handle_next(&mut self, seq: u64, command: String) {
-        if let Some(ctx_arc) = &self.context {
-            if let Ok(mut ctx) = ctx_arc.lock() {
-                ctx.set_mode(RunMode::StepOver);
-                ctx.continue_requested = true;
+    /// With `exposeMemoryAsEnv`, `readMemory` of the `env` reference reads a
+    /// hex dump of cmd.exe's environment block: one `NAME=VALUE` entry per
+    /// variable, each ended by a NUL, sixteen bytes to a row
+    pub fn handle_read_memory(&mut self, seq: u64, command: String, args: Option<Value>) {
+        let reference = args
+            .as_ref()
+            .and_then(|v| v.get("memoryReference"))
+            .and_then(|v| v.as_str())
+            .unwrap_or("");
+        let offset = args
+            .as_ref()
+            .and_then(|v| v.get("offset"))
+            .and_then(|v| v.as_i64())
+            .unwrap_or(0);
+        let count = args
+            .as_ref()
+            .and_then(|v| v.get("count"))
+            .and_then(|v| v.as_u64())
+            .unwrap_or(0) as usize;
+
+        let dump = match self.session() {
+            None => Err("No program is running".to_string()),
+            Some(session) if !session.expose_memory => {
+                Err("Set exposeMemoryAsEnv in the launch configuration to read memory".to_string())
+            }
+            Some(_) if reference != ENV_MEMORY => {
+                Err(format!("Unknown memory reference '{}'", reference))
+            }
+            Some(session) => match session.context.lock() {
+                Err(e) => Err(format!("Debug context unavailable: {}", e)),
+                Ok(mut ctx) => ctx
+                    .session_mut()
+                    .get_all_env_vars()
+                    .map(|env| hex_dump(&environment_block(env)).into_bytes())
+                    .map_err(|e| format!("Failed to query environment: {}", e)),
+            },
+        };
+
+        match dump {
+            Ok(dump) => {
+                // Bytes past the end of the dump can't be read
+                let start = usize::try_from(offset).unwrap_or(0).min(dump.len());
+                let end = start.saturating_add(count).min(dump.len());
+                let body = json!({
+                    "address": format!("0x{:x}", start),
+                    "data": BASE64.encode(&dump[start..end]),
+                    "unreadableBytes": count - (end - start)
+                });
+                self.send_response(seq, command, true, Some(body));
+            }
+            Err(message) => {
+                self.send_error_response(seq, command, ErrorCode::MemoryUnavailable, &message)
             }
         }
-        self.send_response(seq, command, true, None);
-        // Event polling now happens in main loop
     }
 
-    pub fn handle_step_in(&mut self, seq: u64, command: String) {
-        if let Some(ctx_arc) = &self.context {
-            if let Ok(mut ctx) = ctx_arc.lock() {
-                ctx.set_mode(RunMode::StepInto);
-                ctx.continue_requested = true;
+    /// Serve a loaded script or generated content (such as a temporary block
+    /// file) by reference
+    pub fn handle_source(&mut self, seq: u64, command: String, args: Option<Value>) {
+        let reference = args
+            .as_ref()
+            .and_then(|v| {
+                v.get("sourceReference")
+                    .or_else(|| v.get("source").and_then(|s| s.get("sourceReference")))
+            })
+            .and_then(|v| v.as_u64())
+            .unwrap_or(0) as u32;
+
+        match self.source_refs.get(&reference) {
+            Some(content) => {
+                let body = json!({
+                    "content": content,
+                    "mimeType": "text/x-bat"
+                });
+                self.send_response(seq, command, true, Some(body));
             }
+            None => self.send_error_response(
+                seq,
+                command,
+                ErrorCode::UnknownSource,
+                &format!("No source with reference {}", reference),
+            ),
         }
-        self.send_response(seq, command, true, None);
-        // Event polling now happens in main loop
     }
 
-    pub fn handle_step_out(&mut self, seq: u64, command: String) {
-        if let Some(ctx_arc) = &self.context {
-            if let Ok(mut ctx) = ctx_arc.lock() {
-                ctx.set_mode(RunMode::StepOut);
-                ctx.continue_requested = true;
+    /// Re-run the subroutine shown as `frameId` in the stack trace from its label
+    pub fn handle_restart_frame(&mut self, seq: u64, command: String, args: Option<Value>) {
+        let frame_id = (args
+            .as_ref()
+            .and_then(|v| v.get("frameId"))
+            .and_then(|v| v.as_u64())
+            .unwrap_or(0)
+            % IDS_PER_SESSION) as usize;
+
+        let result = match self.session() {
+            None => Err("No program is running".to_string()),
+            Some(session) => match session.context.lock() {
+                Err(e) => Err(format!("Debug context unavailable: {}", e)),
+                Ok(mut ctx) => {
+                    // Frame 0 is the current position (innermost subroutine); frame N
+                    // is the return site of call_stack[N - 1], which runs in call_stack[N - 2]
+                    let depth = ctx.call_stack.len();
+                    let target = match frame_id {
+                        0 => depth.checked_sub(1),
+                        n => n.checked_sub(2),
+                    };
+                    match target {
+                        Some(index) => ctx.restart_frame(index),
+                        None => Err("The top-level script cannot be restarted".to_string()),
+                    }
+                }
+            },
+        };
+
+        match result {
+            Ok(entry_pc) => {
+                log::debug!("restartFrame {} -> logical line {}", frame_id, entry_pc);
+                self.send_response(seq, command, true, None);
+            }
+            Err(message) => {
+                self.send_error_response(seq, command, ErrorCode::InvalidFrame, &message)
             }
         }
-        self.send_response(seq, command, true, None);
-        // Event polling now happens in main loop
     }
 
-    pub fn handle_pause(&mut self, seq: u64, command: String) {
-        if let Some(ctx_arc) = &self.context {
-            if let Ok(mut ctx) = ctx_arc.lock() {
-                ctx.set_mode(RunMode::StepInto);
+    /// Switch the run mode and release the paused executor
+    fn resume(&self, mode: RunMode, granularity: StepGranularity) -> Result<(), String> {
+        if let Some(session) = self.session() {
+            let mut ctx = session
+                .context
+                .lock()
+                .map_err(|e| format!("Debug context unavailable: {}", e))?;
+            ctx.set_mode(mode);
+            ctx.set_granularity(granularity);
+            // Resuming from a historical position carries on from the live one
+            ctx.history.resume_live();
+            ctx.request_continue();
+        }
+        Ok(())
+    }
+
+    fn handle_resume(
+        &mut self,
+        seq: u64,
+        command: String,
+        mode: RunMode,
+        granularity: StepGranularity,
+        body: Option<Value>,
+    ) {
+        match self.resume(mode, granularity) {
+            Ok(()) => self.send_response(seq, command, true, body),
+            Err(message) => {
+                self.send_error_response(seq, command, ErrorCode::ContextUnavailable, &message)
             }
         }
+        // Event polling now happens in main loop
+    }
 
+    pub fn handle_continue(&mut self, seq: u64, command: String) {
+        self.handle_resume(
+            seq,
+            command,
+            RunMode::Continue,
+            StepGranularity::Line,
+            Some(json!({"allThreadsContinued": true})),
+        );
+    }
+
+    /// `granularity: "statement"` stops at each part of a composite command
+    pub fn handle_next(&mut self, seq: u64, command: String, args: Option<Value>) {
+        let granularity = step_granularity(&args);
+        self.handle_resume(seq, command, RunMode::StepOver, granularity, None);
+    }
+
+    pub fn handle_step_in(&mut self, seq: u64, command: String, args: Option<Value>) {
+        let granularity = step_granularity(&args);
+        self.handle_resume(seq, command, RunMode::StepInto, granularity, None);
+    }
+
+    pub fn handle_step_out(&mut self, seq: u64, command: String) {
+        let mode = match self.session().map(|session| session.context.lock()) {
+            Some(Ok(ctx)) => ctx.return_mode(),
+            _ => RunMode::RunToReturn { target_depth: 0 },
+        };
+        self.handle_resume(seq, command, mode, StepGranularity::Line, None);
+    }
+
+    /// Show the line before the one last shown, with the variables as they
+    /// were before it ran. Nothing is re-run; the executor stays where it is.
+    pub fn handle_step_back(&mut self, seq: u64, command: String) {
+        self.rewind(seq, command, false);
+    }
+
+    /// Step back until a line with a breakpoint, or the oldest recorded line
+    pub fn handle_reverse_continue(&mut self, seq: u64, command: String) {
+        self.rewind(seq, command, true);
+    }
+
+    fn rewind(&mut self, seq: u64, command: String, to_breakpoint: bool) {
+        let Some(ctx_arc) = self.context() else {
+            self.send_error_response(seq, command, ErrorCode::NoHistory, "Not running");
+            return;
+        };
+        let rewound = match ctx_arc.lock() {
+            Err(e) => Err(format!("Debug context unavailable: {}", e)),
+            Ok(mut ctx) => {
+                let mut shown = None;
+                while let Some(entry) = ctx.history.step_back() {
+                    let (source, pc) = (entry.source.clone(), entry.pc());
+                    shown = Some((ctx.history.steps_back(), pc));
+                    if !to_breakpoint || ctx.has_breakpoint(&source, pc) {
+                        break;
+                    }
+                }
+                Ok(shown)
+            }
+        };
+
+        let (steps_back, pc) = match rewound {
+            Ok(Some(shown)) => shown,
+            Ok(None) => {
+                self.send_error_response(
+                    seq,
+                    command,
+                    ErrorCode::NoHistory,
+                    "No earlier line recorded",
+                );
+                return;
+            }
+            Err(message) => {
+                self.send_error_response(seq, command, ErrorCode::ContextUnavailable, &message);
+                return;
+            }
+        };
         self.send_response(seq, command, true, None);
 
+        let line = self
+            .session()
+            .and_then(|session| session.preprocessed.logical.get(pc))
+            .map_or(pc + 1, |ll| ll.phys_start + 1);
+        let description = format!(
+            "Inspecting history: {} line(s) back; continue or step to resume live",
+            steps_back
+        );
+        self.send_event(
+            "output".to_string(),
+            Some(json!({
+                "category": "console",
+                "output": format!("{} (line {})\r\n", description, line)
+            })),
+        );
         self.send_event(
             "stopped".to_string(),
             Some(json!({
-                "reason": "pause",
-                "threadId": 1,
+                "reason": "step",
+                "description": description,
+                "threadId": self.current_thread_id(),
                 "allThreadsStopped": true
             })),
         );
     }
 
-    pub fn check_and_send_output(&mut self) {
-        let mut outputs = Vec::new();
-        if let Some(ref output_rx) = self.output_receiver {
-            while let Ok(output) = output_rx.try_recv() {
-                outputs.push(output);
+    /// End the debug session. `restart: true` keeps cmd.exe for the launch
+    /// that follows; `terminateDebuggee: false` leaves it and anything it
+    /// started running; otherwise the whole process tree is killed. The
+    /// response goes out once the debuggee is dealt with.
+    pub fn handle_disconnect(&mut self, seq: u64, command: String, args: Option<Value>) {
+        let flag = |name: &str| {
+            args.as_ref()
+                .and_then(|v| v.get(name))
+                .and_then(|v| v.as_bool())
+        };
+        let action = if flag("restart") == Some(true) {
+            Disconnect::Restart
+        } else if flag("terminateDebuggee") == Some(false) {
+            Disconnect::Detach
+        } else {
+            Disconnect::Terminate
+        };
+        if flag("suspendDebuggee") == Some(true) {
+            log::info!("Ignoring suspendDebuggee: a cmd.exe session can't be suspended");
+        }
+        log::info!("Disconnecting: {:?}", action);
+
+        let active = self.active.take();
+        for (id, session) in self.sessions.drain() {
+            // Only one cmd.exe can be kept for the launch that follows
+            let action = match action {
+                Disconnect::Restart if Some(id) != active => Disconnect::Terminate,
+                action => action,
+            };
+            if let Some(kept) = session.end(action) {
+                self.retained = Some(kept);
+            }
+        }
+
+        self.send_response(seq, command, true, None);
+    }
+
+    /// Cancelling a launch ends its running script: the executor stops
+    /// before its next line, or as soon as the command it is running
+    /// returns, and the session terminates as if the script had finished.
+    /// Every other request is answered before the next is read, and no
+    /// progress is ever reported, so any other cancel has nothing to stop.
+    pub fn handle_cancel(&mut self, seq: u64, command: String, args: Option<Value>) {
+        let request_id = args
+            .as_ref()
+            .and_then(|v| v.get("requestId"))
+            .and_then(|v| v.as_u64());
+        let launched = request_id.and_then(|id| {
+            self.sessions
+                .values()
+                .find(|session| session.launch_seq == id && !session.finished)
+        });
+        match launched {
+            Some(session) => {
+                session.cancelled.store(true, Ordering::SeqCst);
+                session.wakeup.notify();
             }
+            None => log::debug!("Nothing to cancel for {:?}", args),
         }
-        for output in outputs {
-            self.send_output(&output, "stdout");
+        self.send_response(seq, command, true, None);
+    }
+
+    /// Stop at the next line. The executor reports the stop once it gets
+    /// there, after the output of the line still running.
+    pub fn handle_pause(&mut self, seq: u64, command: String) {
+        if let Some(session) = self.session() {
+            if let Ok(mut ctx) = session.context.lock() {
+                ctx.set_mode(RunMode::StepInto);
+                ctx.pause_requested = true;
+            }
         }
+
+        self.send_response(seq, command, true, None);
+    }
+}
+
+fn step_granularity(args: &Option<Value>) -> StepGranularity {
+    StepGranularity::from_dap(
+        args.as_ref()
+            .and_then(|v| v.get("granularity"))
+            .and_then(|v| v.as_str()),
+    )
+}
+
+/// Cut a long value down to something a hover popup can show
+fn truncate_hover(value: &str) -> String {
+    match value.char_indices().nth(HOVER_MAX_CHARS) {
+        Some((end, _)) => format!("{}…", &value[..end]),
+        None => value.to_string(),
+    }
+}
+
+/// cmd.exe's environment as Windows lays it out, sorted the way `set` lists it
+fn environment_block(env: HashMap<String, String>) -> Vec<u8> {
+    let mut entries: Vec<(String, String)> = env.into_iter().collect();
+    entries.sort_by_key(|(name, _)| name.to_uppercase());
+    let mut block = Vec::new();
+    for (name, value) in entries {
+        block.extend_from_slice(format!("{}={}", name, value).as_bytes());
+        block.push(0);
+    }
+    block.push(0);
+    block
+}
+
+/// Fixed-width hex and ASCII rows of sixteen bytes, prefixed by their offset
+fn hex_dump(data: &[u8]) -> String {
+    let mut dump = String::new();
+    for (row, chunk) in data.chunks(16).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+        let ascii: String = chunk
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        dump.push_str(&format!(
+            "{:08x}  {:<47}  |{:<16}|\n",
+            row * 16,
+            hex.join(" "),
+            ascii
+        ));
+    }
+    dump
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Name of the code running at `depth` of `call_stack`: the script itself at
+/// depth 0, otherwise what the frame that got there called
+fn frame_name(call_stack: &[crate::debugger::Frame], depth: usize) -> &str {
+    match depth.checked_sub(1) {
+        None => "main",
+        Some(below) => call_stack[below].name.as_deref().unwrap_or("(unknown)"),
+    }
+}
+
+/// File name of the script at `path`, for display
+fn script_name(path: &str) -> &str {
+    std::path::Path::new(path)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or(path)
+}
+
+/// DAP `Source` for a script on disk
+fn source_json(path: &str) -> Value {
+    json!({
+        "name": script_name(path),
+        "path": path
+    })
+}
+
+/// `%DATE%` and `%TIME%` for `now` in cmd's en-US layout (`Sat 10/17/2026`,
+/// `14:05:09.12`). The real values follow the user's locale and time zone,
+/// which the debugger has no portable way to read, so these are in UTC.
+fn clock_values(now: SystemTime) -> (String, String) {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+
+    let elapsed = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = elapsed.as_secs();
+    let days = secs / 86_400;
+    let of_day = secs % 86_400;
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    let date = format!(
+        "{} {:02}/{:02}/{}",
+        WEEKDAYS[(days % 7) as usize],
+        month,
+        day,
+        year
+    );
+    let time = format!(
+        "{:2}:{:02}:{:02}.{:02}",
+        of_day / 3600,
+        of_day / 60 % 60,
+        of_day % 60,
+        elapsed.subsec_millis() / 10
+    );
+    (date, time)
+}
+
+/// A `%RANDOM%` value, 0 to 32767
+fn random_value() -> u16 {
+    use std::collections::hash_map::RandomState;
+    use std::hash::BuildHasher;
+
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    (RandomState::new().hash_one(nanos) % 32_768) as u16
+}
+
+/// `value` in hex if it is an integer, as `0x...`. Numbers that fit cmd.exe's
+/// 32-bit arithmetic show as it stores them, so -1 is `0xffffffff`.
+fn hex_value(value: &str) -> Option<String> {
+    let n: i64 = value.parse().ok()?;
+    Some(match i32::try_from(n) {
+        Ok(n) => format!("{:#x}", n as u32),
+        Err(_) if n < 0 => format!("-{:#x}", n.unsigned_abs()),
+        Err(_) => format!("{:#x}", n),
+    })
+}
+
+/// Sort variables case-insensitively by name and return the `start`/`count` window.
+/// A `count` of 0 means "everything from `start`", as in the DAP spec.
+pub fn page_variables(mut variables: Vec<Value>, start: usize, count: usize) -> Vec<Value> {
+    variables.sort_by_cached_key(|v| {
+        v.get("name")
+            .and_then(|n| n.as_str())
+            .unwrap_or("")
+            .to_lowercase()
+    });
+    let page = variables.into_iter().skip(start);
+    if count == 0 {
+        page.collect()
+    } else {
+        page.take(count).collect()
     }
 }
@@ -0,0 +1,267 @@
+use crate::debugger::{kill_process_tree, CmdSession, DebugContext, Wakeup};
+use crate::executor;
+use crate::parser::{LabelMap, PreprocessResult};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Thread, frame and variables-reference ids each session may hand out. The
+/// client sees `session id * IDS_PER_SESSION + local id`, so any of them
+/// names the session a request is for.
+pub const IDS_PER_SESSION: u64 = 10_000;
+
+/// How long a restart waits for the executor to let go of cmd.exe
+const RESTART_WAIT: Duration = Duration::from_secs(2);
+
+/// What `disconnect` does with the debuggee
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Disconnect {
+    /// Keep cmd.exe for the launch that follows
+    Restart,
+    /// Stop debugging but leave cmd.exe and whatever it started running
+    Detach,
+    /// Kill cmd.exe and every process it started
+    Terminate,
+}
+
+/// One launched script: its cmd.exe, the executor running it, and the
+/// breakpoints installed in it
+pub(crate) struct DebugSession {
+    pub id: u64,
+    pub context: Arc<Mutex<DebugContext>>,
+    pub preprocessed: PreprocessResult,
+    pub labels: LabelMap,
+    pub program_path: String,
+    /// Logical lines with a breakpoint, by source
+    pub breakpoints: HashMap<String, Vec<usize>>,
+    /// Thread running the script, joined on disconnect
+    pub executor: Option<thread::JoinHandle<()>>,
+    /// Process id of the session's cmd.exe, which can be killed without the
+    /// context lock a running command holds
+    pub session_pid: Option<u32>,
+    /// The context's cancel flag, set without taking its lock
+    pub cancelled: Arc<AtomicBool>,
+    /// The context's wakeup, which gets a waiting executor to see the flag
+    pub wakeup: Arc<Wakeup>,
+    /// The executor reported the end of the script
+    pub finished: bool,
+    /// `readMemory` may read the environment block (`exposeMemoryAsEnv`)
+    pub expose_memory: bool,
+    /// Sequence number of the `launch` request, which `cancel` names to end
+    /// the script
+    pub launch_seq: u64,
+}
+
+impl DebugSession {
+    pub fn new(
+        id: u64,
+        context: DebugContext,
+        preprocessed: PreprocessResult,
+        labels: LabelMap,
+        program_path: &str,
+        session_pid: u32,
+    ) -> Self {
+        Self {
+            id,
+            cancelled: Arc::clone(&context.cancelled),
+            wakeup: Arc::clone(&context.wakeup),
+            context: Arc::new(Mutex::new(context)),
+            preprocessed,
+            labels,
+            program_path: program_path.to_string(),
+            breakpoints: HashMap::new(),
+            executor: None,
+            session_pid: Some(session_pid),
+            finished: false,
+            expose_memory: false,
+            launch_seq: 0,
+        }
+    }
+
+    /// First id of the range the session's client-visible ids come from
+    pub fn id_base(&self) -> u64 {
+        self.id * IDS_PER_SESSION
+    }
+
+    /// Map (id, physical line) pairs onto logical lines, returning the lines
+    /// that resolved and a DAP `Breakpoint` for every request
+    pub fn map_breakpoints(&self, requested: &[(u64, u64)]) -> (Vec<usize>, Vec<Value>) {
+        let pre = &self.preprocessed;
+        let mut logical_lines = Vec::new();
+        let mut breakpoints = Vec::new();
+
+        for &(id, line) in requested {
+            let phys_line = (line as usize).saturating_sub(1);
+
+            if phys_line < pre.phys_to_logical.len() {
+                // Blank lines, comments and labels never stop; the
+                // breakpoint moves down to the next line that runs
+                let requested = pre.phys_to_logical[phys_line];
+                let Some(logical_line) = (requested..pre.logical.len())
+                    .find(|&l| !executor::is_skippable(&pre.logical[l].text))
+                else {
+                    log::debug!("Breakpoint on line {} has nothing to run after it", line);
+                    breakpoints.push(json!({
+                        "id": id,
+                        "verified": false,
+                        "line": line,
+                        "message": "No executable line at or after this line"
+                    }));
+                    continue;
+                };
+                logical_lines.push(logical_line);
+
+                log::debug!(
+                    "Breakpoint on line {} mapped to logical line {}: {}",
+                    line,
+                    logical_line,
+                    pre.logical[logical_line].text
+                );
+
+                let mut breakpoint = json!({
+                    "id": id,
+                    "verified": true,
+                    "line": line
+                });
+                if logical_line != requested {
+                    let adjusted = pre.logical[logical_line].phys_start + 1;
+                    breakpoint["line"] = json!(adjusted);
+                    breakpoint["message"] =
+                        json!(format!("Breakpoint adjusted to line {}", adjusted));
+                }
+                breakpoints.push(breakpoint);
+            } else {
+                log::debug!("Breakpoint on line {} is out of range", line);
+                breakpoints.push(json!({
+                    "id": id,
+                    "verified": false,
+                    "line": line,
+                    "message": "Line is outside the script"
+                }));
+            }
+        }
+        (logical_lines, breakpoints)
+    }
+
+    /// Resolve (id, label name) pairs in the program and make them its
+    /// function breakpoints, returning a DAP `Breakpoint` for each
+    pub fn apply_function_breakpoints(
+        &self,
+        requested: &[(u64, String)],
+        source: &Value,
+    ) -> Vec<Value> {
+        let pre = &self.preprocessed;
+        let mut logical_lines = Vec::new();
+        let mut breakpoints = Vec::new();
+        for (id, name) in requested {
+            let label = format!(":{}", name.trim().trim_start_matches(':'));
+            match executor::resolve_breakpoint(&label, pre, &self.labels) {
+                Ok(logical) => {
+                    logical_lines.push(logical);
+                    let line = pre
+                        .logical
+                        .get(logical)
+                        .map_or(pre.phys_to_logical.len(), |l| l.phys_start + 1);
+                    breakpoints.push(json!({
+                        "id": id,
+                        "verified": true,
+                        "line": line,
+                        "source": source
+                    }));
+                }
+                Err(message) => {
+                    log::debug!("Function breakpoint {}: {}", name, message);
+                    breakpoints.push(json!({
+                        "id": id,
+                        "verified": false,
+                        "message": message
+                    }));
+                }
+            }
+        }
+
+        if let Ok(mut ctx) = self.context.lock() {
+            ctx.set_function_breakpoints(&self.program_path, &logical_lines);
+        }
+        breakpoints
+    }
+
+    /// Install the breakpoints of `source_path`, replacing its previous set
+    pub fn set_source_breakpoints(
+        &mut self,
+        source_path: &str,
+        logical_lines: Vec<usize>,
+    ) -> Result<(), String> {
+        let installed = match self.context.lock() {
+            Ok(mut ctx) => {
+                ctx.set_source_breakpoints(source_path, &logical_lines);
+                Ok(())
+            }
+            Err(e) => Err(format!("Debug context unavailable: {}", e)),
+        };
+        self.breakpoints
+            .insert(source_path.to_string(), logical_lines);
+        installed
+    }
+
+    /// Stop the executor and deal with cmd.exe as `action` says. Returns the
+    /// cmd.exe session to keep on `Disconnect::Restart`.
+    pub fn end(mut self, action: Disconnect) -> Option<CmdSession> {
+        // A command still running holds the context lock; killing the tree
+        // makes it return so the executor can see the disconnect
+        if action == Disconnect::Terminate {
+            if let Some(pid) = self.session_pid.take() {
+                if let Err(e) = kill_process_tree(pid) {
+                    log::warn!("Failed to kill cmd.exe: {}", e);
+                }
+            }
+        }
+
+        // The flag, unlike the context, isn't held by a running command
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.wakeup.notify();
+
+        // Leaving the executor to finish on its own means the pipes close
+        // whenever it gets there, without waiting on a command that may
+        // never return
+        let executor = self.executor.take();
+        match (action, executor) {
+            (Disconnect::Detach, _) | (_, None) => {}
+            (Disconnect::Terminate, Some(handle)) => {
+                let _ = handle.join();
+            }
+            // cmd.exe is only worth keeping if the executor lets go of it
+            // soon; one stuck in a command is ended like on Terminate
+            (Disconnect::Restart, Some(handle)) => {
+                let deadline = Instant::now() + RESTART_WAIT;
+                while !handle.is_finished() && Instant::now() < deadline {
+                    thread::sleep(Duration::from_millis(10));
+                }
+                if !handle.is_finished() {
+                    log::warn!("Executor still busy; not keeping cmd.exe");
+                    if let Some(pid) = self.session_pid.take() {
+                        if let Err(e) = kill_process_tree(pid) {
+                            log::warn!("Failed to kill cmd.exe: {}", e);
+                        }
+                    }
+                    return None;
+                }
+                let _ = handle.join();
+            }
+        }
+
+        if action != Disconnect::Restart {
+            return None;
+        }
+        match Arc::try_unwrap(self.context).map(Mutex::into_inner) {
+            Ok(Ok(ctx)) => Some(ctx.into_session()),
+            _ => {
+                log::warn!("Debug context still in use; not keeping cmd.exe");
+                None
+            }
+        }
+    }
+}
@@ -1,7 +1,13 @@
 use std::collections::HashSet;
 
+/// Breakpoints keyed by source file, so line numbers in different scripts don't collide
 pub struct Breakpoints {
-    points: HashSet<usize>,
+    points: HashSet<(String, usize)>,
+}
+
+/// Windows paths are case-insensitive and accept either separator
+fn source_key(source: &str) -> String {
+    source.replace('/', "\\").to_lowercase()
 }
 
 impl Breakpoints {
@@ -11,22 +17,61 @@ impl Breakpoints {
         }
     }
 
-    pub fn add(&mut self, logical_line: usize) {
-        self.points.insert(logical_line);
-        eprintln!("Breakpoint set at logical line {}", logical_line);
+    pub fn add(&mut self, source: &str, logical_line: usize) {
+        self.points.insert((source_key(source), logical_line));
+        log::info!(
+            "Breakpoint set at logical line {} in {}",
+            logical_line,
+            source
+        );
+    }
+
+    pub fn remove(&mut self, source: &str, logical_line: usize) {
+        self.points.remove(&(source_key(source), logical_line));
+        log::info!(
+            "Breakpoint removed from logical line {} in {}",
+            logical_line,
+            source
+        );
+    }
+
+    pub fn contains(&self, source: &str, logical_line: usize) -> bool {
+        // Checked for every line run; skip building the key when there's nothing to find
+        !self.is_empty() && self.points.contains(&(source_key(source), logical_line))
     }
 
-    pub fn remove(&mut self, logical_line: usize) {
-        self.points.remove(&logical_line);
-        eprintln!("Breakpoint removed from logical line {}", logical_line);
+    /// Every breakpoint as (normalized source, logical line), in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = (&str, usize)> + '_ {
+        self.points.iter().map(|(s, line)| (s.as_str(), *line))
     }
 
-    pub fn contains(&self, logical_line: usize) -> bool {
-        self.points.contains(&logical_line)
+    /// Logical lines with a breakpoint in `source`, in no particular order
+    pub fn lines<'a>(&'a self, source: &str) -> impl Iterator<Item = usize> + 'a {
+        let key = source_key(source);
+        self.iter()
+            .filter(move |(s, _)| *s == key)
+            .map(|(_, line)| line)
     }
 
-    #[allow(dead_code)]
-    pub fn clear(&mut self) {
-        self.points.clear();
+    pub fn len(&self) -> usize {
+        self.points.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// Remove every breakpoint in `source`, leaving other files untouched
+    pub fn clear(&mut self, source: &str) {
+        let key = source_key(source);
+        self.points.retain(|(s, _)| *s != key);
+    }
+}
+
+/// Stops execution when the named variable's value changes
+#[derive(Debug, Clone, PartialEq)]
+pub struct DataBreakpoint {
+    pub name: String,
+    /// Last value seen; `None` while the variable is undefined
+    pub old_value: Option<String>,
 }
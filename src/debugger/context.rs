@@ -1,19 +1,82 @@
-use super::breakpoints::Breakpoints;
-use super::{CmdSession, Frame, RunMode};
-use crate::parser::LogicalLine;
-use std::collections::HashMap;
+use super::breakpoints::{Breakpoints, DataBreakpoint};
+use super::session::escape_metachars;
+use super::{
+    CmdSession, ContextSnapshot, ForLoop, Frame, History, Profile, RunMode, SetlocalSnapshot,
+    StepGranularity, Wakeup, WatchList,
+};
+use crate::parser::{
+    is_comment, parse_variable_reference, split_composite_command, LogicalLine, PreprocessResult,
+};
+use std::collections::{HashMap, HashSet};
 use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
 
 pub struct DebugContext {
     session: CmdSession,
     pub variables: HashMap<String, String>,
+    /// Variables whose tracked value was resolved from `!VAR!` at run time
+    delayed_vars: HashSet<String>,
     pub call_stack: Vec<Frame>,
     pub last_exit_code: i32,
     breakpoints: Breakpoints,
+    /// Breakpoints set on `:label`s by name, kept apart from line breakpoints
+    /// because each kind of request replaces only its own set
+    function_breakpoints: Breakpoints,
+    data_breakpoints: Vec<DataBreakpoint>,
     mode: RunMode,
-    step_out_target_depth: usize,
+    granularity: StepGranularity,
+    /// Set through `request_continue`, which also wakes the executor
     pub continue_requested: bool,
     pub current_line: Option<usize>,
+    /// 1-based column of the statement stopped at when stepping by statement
+    pub current_column: Option<usize>,
+    /// Set by restartFrame; the executor jumps here instead of running the current line
+    pub restart_pc: Option<usize>,
+    /// Set by the pause request; the next stop reports it as the reason
+    pub pause_requested: bool,
+    /// Set by the cancel request and on disconnect, neither of which can
+    /// wait for the lock a running command holds; the executor ends the
+    /// script once it checks
+    pub cancelled: Arc<AtomicBool>,
+    /// Wakes the executor waiting at a stop or for input once there is
+    /// something for it to act on
+    pub wakeup: Arc<Wakeup>,
+    /// Path of the script being executed, used to match breakpoints
+    pub current_source: String,
+    /// Set while a `SET /P` is waiting for the client to supply a value
+    pub awaiting_input: bool,
+    pub pending_input: Option<String>,
+    /// Answers for `SET /P` by variable name (uppercase), given up front so
+    /// the script doesn't wait on the client (`inputValues`)
+    pub input_values: HashMap<String, String>,
+    pub watches: WatchList,
+    /// Logical lines the executor may run before pausing; `None` is unlimited
+    pub max_steps: Option<usize>,
+    steps_taken: usize,
+    /// Synthesized batch text of the parenthesized block at the current line
+    pub active_block: Option<String>,
+    /// Directories entered with PUSHD, innermost last
+    pub dir_stack: Vec<String>,
+    /// The session's working directory as last seen; `None` once a command
+    /// may have changed it
+    current_dir: Option<String>,
+    /// Step into `CALL other.bat` instead of running it as one command
+    pub step_into_external: bool,
+    /// Step past `@echo off` and `setlocal` until the first stop, so it lands
+    /// on a line that does something (`stopOnEntry: "firstExecutable"`)
+    pub skip_housekeeping: bool,
+    /// Scripts entered through an external CALL, by path
+    pub scripts: HashMap<String, Arc<PreprocessResult>>,
+    /// FOR loops being stepped per iteration, innermost last
+    pub for_loops: Vec<ForLoop>,
+    /// Recently executed lines, for stepping back
+    pub history: History,
+    /// Logical lines of the program that have run, for coverage reports
+    pub coverage: HashSet<usize>,
+    /// Time spent on each line of the program; `None` unless profiling
+    pub profile: Option<Profile>,
 }
 
 impl DebugContext {
@@ -21,20 +84,92 @@ impl DebugContext {
         Self {
             session,
             variables: HashMap::new(),
+            delayed_vars: HashSet::new(),
             call_stack: Vec::new(),
             last_exit_code: 0,
             breakpoints: Breakpoints::new(),
+            function_breakpoints: Breakpoints::new(),
+            data_breakpoints: Vec::new(),
             mode: RunMode::Continue,
-            step_out_target_depth: 0,
+            granularity: StepGranularity::Line,
             continue_requested: false,
             current_line: None,
+            current_column: None,
+            restart_pc: None,
+            pause_requested: false,
+            cancelled: Arc::new(AtomicBool::new(false)),
+            wakeup: Arc::new(Wakeup::default()),
+            current_source: String::new(),
+            awaiting_input: false,
+            pending_input: None,
+            input_values: HashMap::new(),
+            watches: WatchList::new(),
+            max_steps: None,
+            steps_taken: 0,
+            active_block: None,
+            dir_stack: Vec::new(),
+            current_dir: None,
+            step_into_external: false,
+            skip_housekeeping: false,
+            scripts: HashMap::new(),
+            for_loops: Vec::new(),
+            history: History::default(),
+            coverage: HashSet::new(),
+            profile: None,
         }
     }
 
+    /// Script the code `depth` frames down the call stack runs in: the
+    /// innermost external CALL among those frames, or `program` if none
+    pub fn source_at_depth<'a>(&'a self, depth: usize, program: &'a str) -> &'a str {
+        self.call_stack[..depth.min(self.call_stack.len())]
+            .iter()
+            .rev()
+            .find_map(|frame| frame.source.as_deref())
+            .unwrap_or(program)
+    }
+
+    /// Scripts on the call chain, outermost first: `program`, then one entry
+    /// per CALL into another batch file. The adapter shows each as a thread.
+    pub fn script_invocations<'a>(&'a self, program: &'a str) -> Vec<&'a str> {
+        std::iter::once(program)
+            .chain(
+                self.call_stack
+                    .iter()
+                    .filter_map(|frame| frame.source.as_deref()),
+            )
+            .collect()
+    }
+
+    /// Index into `script_invocations` of the script the code `depth` frames
+    /// down the call stack runs in
+    pub fn invocation_at_depth(&self, depth: usize) -> usize {
+        self.call_stack[..depth.min(self.call_stack.len())]
+            .iter()
+            .filter(|frame| frame.source.is_some())
+            .count()
+    }
+
+    /// The cmd.exe session, still running, once debugging is over
+    pub fn into_session(self) -> CmdSession {
+        self.session
+    }
+
     pub fn session_mut(&mut self) -> &mut CmdSession {
         &mut self.session
     }
 
+    /// The client disconnected or cancelled the run, so the script ends here
+    pub fn stop_requested(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Release the executor from its stop
+    pub fn request_continue(&mut self) {
+        self.continue_requested = true;
+        self.wakeup.notify();
+    }
+
     pub fn mode(&self) -> RunMode {
         self.mode
     }
@@ -43,37 +178,184 @@ impl DebugContext {
         self.mode = mode;
     }
 
-    /// Handle SETLOCAL command - creates a new variable scope
+    pub fn granularity(&self) -> StepGranularity {
+        self.granularity
+    }
+
+    pub fn set_granularity(&mut self, granularity: StepGranularity) {
+        self.granularity = granularity;
+    }
+
+    /// Handle SETLOCAL command - snapshots the environment into a new (possibly nested) scope
     pub fn handle_setlocal(&mut self) {
+        let visible = self.get_visible_variables();
         if let Some(frame) = self.call_stack.last_mut() {
+            frame.saved_envs.push(SetlocalSnapshot {
+                variables: visible,
+                delayed_expansion: frame.delayed_expansion,
+                extensions: frame.extensions,
+            });
             frame.has_setlocal = true;
-            eprintln!("📦 SETLOCAL - created new variable scope");
+            log::debug!(
+                "SETLOCAL - created new variable scope (depth {})",
+                frame.saved_envs.len()
+            );
         }
     }
 
-    /// Handle ENDLOCAL command - restores previous variable scope
-    pub fn handle_endlocal(&mut self) {
+    /// Handle a full SETLOCAL line, recording ENABLE/DISABLE options on the frame
+    pub fn handle_setlocal_command(&mut self, line: &str) {
+        self.handle_setlocal();
+
         if let Some(frame) = self.call_stack.last_mut() {
-            if frame.has_setlocal {
-                frame.locals.clear();
-                frame.has_setlocal = false;
-                eprintln!("📤 ENDLOCAL - restored previous scope");
+            for opt in line.split_whitespace().skip(1) {
+                match opt.to_uppercase().as_str() {
+                    "ENABLEDELAYEDEXPANSION" => frame.delayed_expansion = true,
+                    "DISABLEDELAYEDEXPANSION" => frame.delayed_expansion = false,
+                    "ENABLEEXTENSIONS" => frame.extensions = true,
+                    "DISABLEEXTENSIONS" => frame.extensions = false,
+                    _ => {}
+                }
             }
         }
     }
 
+    /// Handle ENDLOCAL command - restores the environment saved by the matching SETLOCAL,
+    /// undoing any SET done inside the scope
+    pub fn handle_endlocal(&mut self) {
+        let Some(frame) = self.call_stack.last_mut() else {
+            return;
+        };
+        let Some(saved) = frame.saved_envs.pop() else {
+            return;
+        };
+
+        frame.delayed_expansion = saved.delayed_expansion;
+        frame.extensions = saved.extensions;
+
+        if frame.saved_envs.is_empty() {
+            frame.locals.clear();
+            frame.has_setlocal = false;
+            self.variables = saved.variables;
+        } else {
+            // Still inside an outer SETLOCAL: its overlay is whatever differs from globals
+            frame.locals = saved
+                .variables
+                .into_iter()
+                .filter(|(key, val)| self.variables.get(key) != Some(val))
+                .collect();
+        }
+        log::debug!("ENDLOCAL - restored previous scope");
+    }
+
+    /// Discard frames above `frame_index`, unwind the SETLOCALs opened in it
+    /// and above it, in the session too, and ask the executor to resume at
+    /// the frame's entry label. Returns that logical line.
+    pub fn restart_frame(&mut self, frame_index: usize) -> Result<usize, String> {
+        let entry_pc = self
+            .call_stack
+            .get(frame_index)
+            .ok_or_else(|| format!("No frame at depth {}", frame_index))?
+            .entry_pc
+            .ok_or_else(|| {
+                "This frame was not entered through CALL and cannot be restarted".to_string()
+            })?;
+
+        let scopes: usize = self.call_stack[frame_index..]
+            .iter()
+            .map(|f| f.saved_envs.len())
+            .sum();
+        for _ in 0..scopes {
+            self.run_command("endlocal")
+                .map_err(|e| format!("Failed to end a SETLOCAL scope: {}", e))?;
+        }
+
+        self.call_stack.truncate(frame_index + 1);
+        while self.call_stack.last().is_some_and(|f| f.has_setlocal) {
+            self.handle_endlocal();
+        }
+
+        self.restart_pc = Some(entry_pc);
+        self.mode = RunMode::StepInto;
+        self.request_continue();
+        Ok(entry_pc)
+    }
+
     /// Get all variables visible in current scope (merges global + local)
     pub fn get_visible_variables(&self) -> HashMap<String, String> {
-        let mut visible = self.variables.clone();
+        super::visible_variables(&self.variables, &self.call_stack)
+    }
 
-        // Overlay local variables from current frame if SETLOCAL is active
-        if let Some(frame) = self.call_stack.last() {
-            if frame.has_setlocal {
-                visible.extend(frame.locals.clone());
+    /// Each executable physical line of `pre`'s script, 1-based, with whether
+    /// it has run. Blank lines, comments, labels and lone `)` never run, so
+    /// they are left out. A block run as one batch file counts as run whole.
+    pub fn coverage_report(&self, pre: &PreprocessResult) -> Vec<(usize, bool)> {
+        let mut report = Vec::new();
+        for (pc, ll) in pre.logical.iter().enumerate() {
+            let text = ll.text.trim().trim_start_matches('@');
+            if text.is_empty() || text == ")" || text.starts_with(':') || is_comment(text) {
+                continue;
             }
+            let ran = self.coverage.contains(&pc);
+            report.extend((ll.phys_start..=ll.phys_end).map(|line| (line + 1, ran)));
         }
+        report
+    }
 
-        visible
+    /// Checkpoint the tracked state before the logical line at `pc` runs
+    pub fn snapshot(&self, pc: usize) -> ContextSnapshot {
+        ContextSnapshot {
+            variables: self.variables.clone(),
+            call_stack: self.call_stack.clone(),
+            last_exit_code: self.last_exit_code,
+            pc,
+        }
+    }
+
+    /// Put back the tracked state of `snapshot` and return the line it was
+    /// taken at. The cmd.exe session keeps its own environment.
+    pub fn restore(&mut self, snapshot: ContextSnapshot) -> usize {
+        self.variables = snapshot.variables;
+        self.call_stack = snapshot.call_stack;
+        self.last_exit_code = snapshot.last_exit_code;
+        snapshot.pc
+    }
+
+    /// Look up a variable the way cmd resolves it: the innermost SETLOCAL scope
+    /// first, then outer frames, then globals. Names are case-insensitive.
+    pub fn get_variable(&self, name: &str) -> Option<&str> {
+        let scopes = self
+            .call_stack
+            .iter()
+            .rev()
+            .filter(|f| f.has_setlocal)
+            .map(|f| &f.locals)
+            .chain(std::iter::once(&self.variables));
+
+        for vars in scopes {
+            if let Some(val) = vars.get(name) {
+                return Some(val);
+            }
+            if let Some((_, val)) = vars.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)) {
+                return Some(val);
+            }
+        }
+        None
+    }
+
+    /// Value of `name` as cmd would expand it now: the tracked value, or
+    /// else what the live session expands `!NAME!` to, which catches values
+    /// tracking missed. `None` when the variable isn't defined.
+    pub fn evaluate_variable(&mut self, name: &str) -> io::Result<Option<String>> {
+        if let Some(val) = self.get_variable(name) {
+            return Ok(Some(val.to_string()));
+        }
+        // Delayed expansion drops an undefined variable rather than echoing
+        // it back, and a defined one is never empty, so no output means
+        // undefined
+        let (out, _) = self.run_command(&format!("if defined {0} echo !{0}!", name))?;
+        let val = out.trim().to_string();
+        Ok((!val.is_empty()).then_some(val))
     }
 
     /// Get variables for a specific stack frame (for DAP)
@@ -98,8 +380,19 @@ impl DebugContext {
             let return_line = frame.return_pc.saturating_sub(1);
             if return_line < logical.len() {
                 let line = &logical[return_line];
-                let scope_info = if frame.has_setlocal {
-                    format!(" [SETLOCAL: {} vars]", frame.locals.len())
+                let nesting = if frame.saved_envs.len() > 1 {
+                    format!(" x{}", frame.saved_envs.len())
+                } else {
+                    String::new()
+                };
+                let scope_info = if frame.has_setlocal && frame.delayed_expansion {
+                    format!(
+                        " [SETLOCAL{}: {} vars, delayed expansion]",
+                        nesting,
+                        frame.locals.len()
+                    )
+                } else if frame.has_setlocal {
+                    format!(" [SETLOCAL{}: {} vars]", nesting, frame.locals.len())
                 } else {
                     String::new()
                 };
@@ -133,68 +426,360 @@ impl DebugContext {
 
     /// Track SET commands - stores in appropriate scope
     pub fn track_set_command(&mut self, line: &str) {
-        let l = line.trim_start();
-        if !l.to_uppercase().starts_with("SET ") {
-            return;
+        if let Some((key, val)) = parse_set_assignment(line) {
+            // A !VAR! reference is only resolved when cmd runs the line; leave it
+            // to track_set_readback rather than storing the literal text
+            if val.contains('!') {
+                return;
+            }
+            let val = self.expand_percent_vars(&val);
+            self.delayed_vars.remove(&key);
+            self.store_variable(key, val);
         }
+    }
 
-        let rest = l[3..].trim_start();
+    /// Substitute known `%NAME%` references, as cmd does when it parses the line.
+    /// Unknown names and positional arguments are left as written.
+    fn expand_percent_vars(&self, text: &str) -> String {
+        let mut out = String::with_capacity(text.len());
+        let mut rest = text;
 
-        // Handle /A (arithmetic) - we can't track these accurately without executing
-        if rest.to_uppercase().starts_with("/A") {
-            return;
+        while let Some(start) = rest.find('%') {
+            out.push_str(&rest[..start]);
+            let after = &rest[start + 1..];
+            match after.find('%') {
+                Some(end) if end > 0 => {
+                    let name = &after[..end];
+                    match self.get_variable(name) {
+                        Some(val) => {
+                            out.push_str(val);
+                            rest = &after[end + 1..];
+                        }
+                        None => {
+                            out.push('%');
+                            rest = after;
+                        }
+                    }
+                }
+                _ => {
+                    out.push('%');
+                    rest = after;
+                }
+            }
+        }
+        out.push_str(rest);
+        out
+    }
+
+    /// Assign a variable in the live session and track the new value
+    pub fn set_variable(&mut self, name: &str, value: &str) -> io::Result<()> {
+        self.run_command(&set_command(name, value))?;
+        self.delayed_vars.remove(name);
+        self.store_variable(name.to_string(), value.to_string());
+        Ok(())
+    }
+
+    /// Track assignments whose value is only known at run time (`SET /A`,
+    /// `!VAR!` references, SETs inside a FOR body) by reading them back after
+    /// the line has executed.
+    pub fn track_set_readback(&mut self, line: &str) -> io::Result<()> {
+        for (name, delayed) in readback_targets(line) {
+            if delayed {
+                self.delayed_vars.insert(name.clone());
+            } else {
+                self.delayed_vars.remove(&name);
+            }
+            self.read_back_variable(name)?;
+        }
+        Ok(())
+    }
+
+    /// The session's working directory, asking cmd.exe with `cd` when the
+    /// tracked value may be stale
+    pub fn current_dir(&mut self) -> io::Result<String> {
+        if let Some(dir) = &self.current_dir {
+            return Ok(dir.clone());
+        }
+        let (out, _) = self.run_command("cd")?;
+        let dir = out.trim().to_string();
+        log::debug!("Working directory: {}", dir);
+        self.current_dir = Some(dir.clone());
+        Ok(dir)
+    }
+
+    /// Drop the cached working directory so the next `current_dir` asks cmd.exe again
+    pub fn forget_current_dir(&mut self) {
+        self.current_dir = None;
+    }
+
+    /// Follow `PUSHD`/`POPD` once they've run so `dir_stack` mirrors cmd's own
+    /// stack, and `CD`/`CHDIR` so `current_dir` stays accurate
+    pub fn track_dir_command(&mut self, command: &str, exit_code: i32) -> io::Result<()> {
+        let verb = command
+            .trim_start()
+            .trim_start_matches('@')
+            .split_whitespace()
+            .next()
+            .unwrap_or("")
+            .to_uppercase();
+
+        match verb.as_str() {
+            "PUSHD" if exit_code == 0 => {
+                self.current_dir = None;
+                let dir = self.current_dir()?;
+                self.dir_stack.push(dir);
+            }
+            "POPD" => {
+                self.dir_stack.pop();
+                self.current_dir = None;
+                self.current_dir()?;
+            }
+            // `cd` alone only prints the directory; `cd /d x:\dir` and
+            // `chdir ..` move, as does switching drives with `d:`
+            "CD" | "CHDIR" if command.split_whitespace().nth(1).is_some() => {
+                self.current_dir = None;
+            }
+            v if v.starts_with("CD.") || v.starts_with("CD\\") => {
+                self.current_dir = None;
+            }
+            _ if verb.len() == 2 && verb.ends_with(':') => {
+                self.current_dir = None;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Whether the variable's last tracked value came from `!VAR!` expansion at run time
+    pub fn is_delayed_value(&self, name: &str) -> bool {
+        self.delayed_vars.contains(name)
+    }
+
+    /// Read `name` from the `set NAME` listing rather than echoing it, which
+    /// would turn values like `OFF` or `/?` into ECHO commands. The listing
+    /// also holds every variable starting with `name`, so only the exact
+    /// entry counts; an undefined variable has none.
+    fn read_back_variable(&mut self, name: String) -> io::Result<()> {
+        let (out, _) = self.run_command(&format!("set {}", name))?;
+        let val = out.lines().find_map(|line| {
+            let (key, val) = line.trim_end_matches('\r').split_once('=')?;
+            key.eq_ignore_ascii_case(&name).then(|| val.to_string())
+        });
+        if let Some(val) = val {
+            self.store_variable(name, val);
+        }
+        Ok(())
+    }
+
+    /// Refresh tracked variables from cmd's real environment, picking up changes
+    /// made behind our back (called batches, `for /f ... set`, and so on).
+    /// Variables cmd.exe inherited from the debugger and still holds unchanged,
+    /// like PATH, belong to the user's environment rather than the script's
+    /// and are left out unless already tracked.
+    pub fn sync_variables_from_session(&mut self) -> io::Result<()> {
+        let inherited: HashMap<String, String> = std::env::vars()
+            .map(|(key, val)| (key.to_uppercase(), val))
+            .collect();
+        let mut env = self.session.get_all_env_vars()?;
+        env.retain(|key, val| {
+            !is_volatile_var(key)
+                && (self.get_variable(key).is_some()
+                    || inherited.get(&key.to_uppercase()) != Some(val))
+        });
+
+        match self.call_stack.iter_mut().rev().find(|f| f.has_setlocal) {
+            // Under SETLOCAL the environment holds local values; globals come back at ENDLOCAL
+            Some(frame) => {
+                frame.locals = env
+                    .into_iter()
+                    .filter(|(key, val)| self.variables.get(key) != Some(val))
+                    .collect();
+            }
+            None => self.variables = env,
         }
+        Ok(())
+    }
 
-        // Handle /P (prompt) - skip these as they require user input
-        if rest.to_uppercase().starts_with("/P") {
+    /// Store in local scope if SETLOCAL is active, otherwise global
+    fn store_variable(&mut self, key: String, val: String) {
+        if let Some(frame) = self.call_stack.iter_mut().rev().find(|f| f.has_setlocal) {
+            frame.locals.insert(key, val);
             return;
         }
+        self.variables.insert(key, val);
+    }
 
-        // Handle quoted SET "VAR=VAL"
-        let rest = rest.trim();
-        let rest = if rest.starts_with('"') && rest.ends_with('"') && rest.len() >= 2 {
-            &rest[1..rest.len() - 1]
-        } else {
-            rest
+    /// Hand a value to a waiting `SET /P`; returns false if nothing is waiting
+    pub fn supply_input(&mut self, value: &str) -> bool {
+        if !self.awaiting_input {
+            return false;
+        }
+        self.awaiting_input = false;
+        self.pending_input = Some(value.to_string());
+        self.wakeup.notify();
+        true
+    }
+
+    /// Count one executed logical line against `max_steps`. Returns true when the
+    /// budget runs out; the counter then starts over so resuming gets a fresh budget.
+    pub fn count_step(&mut self) -> bool {
+        let Some(limit) = self.max_steps else {
+            return false;
         };
+        self.steps_taken += 1;
+        if self.steps_taken > limit {
+            self.steps_taken = 0;
+            return true;
+        }
+        false
+    }
 
-        if let Some(eq_pos) = rest.find('=') {
-            let key = rest[..eq_pos].trim().to_string();
-            let val = rest[eq_pos + 1..].trim().to_string();
-
-            // Only track simple assignments (no operators in the key)
-            if !key.is_empty()
-                && !key.contains('+')
-                && !key.contains('-')
-                && !key.contains('*')
-                && !key.contains('/')
-            {
-                // Store in local scope if SETLOCAL is active, otherwise global
-                if let Some(frame) = self.call_stack.last_mut() {
-                    if frame.has_setlocal {
-                        frame.locals.insert(key, val);
-                        return;
-                    }
-                }
-                self.variables.insert(key, val);
+    pub fn add_watch(&mut self, expr: &str) {
+        self.watches.add(expr.to_string());
+    }
+
+    /// Returns false if `expr` wasn't being watched
+    pub fn remove_watch(&mut self, expr: &str) -> bool {
+        match self.watches.position(expr) {
+            Some(index) => self.watches.remove(index).is_some(),
+            None => false,
+        }
+    }
+
+    /// Value of the watch `expr`: a variable reference is looked up like
+    /// `evaluate_variable`, anything else is echoed through the session.
+    /// `None` when it names a variable that isn't defined.
+    pub fn evaluate_watch(&mut self, expr: &str) -> io::Result<Option<String>> {
+        if let Some(name) = parse_variable_reference(expr) {
+            return self.evaluate_variable(&name);
+        }
+        if !self.session.is_alive() {
+            self.respawn_session()?;
+        }
+        WatchList::evaluate(&mut self.session, expr).map(Some)
+    }
+
+    pub fn evaluate_watches(&mut self) -> Vec<(String, String)> {
+        let exprs = self.watches.exprs().to_vec();
+        exprs
+            .into_iter()
+            .map(|expr| {
+                let value = match self.evaluate_watch(&expr) {
+                    Ok(Some(value)) => value,
+                    Ok(None) => "<undefined>".to_string(),
+                    Err(e) => format!("<error: {}>", e),
+                };
+                (expr, value)
+            })
+            .collect()
+    }
+
+    pub fn add_breakpoint(&mut self, source: &str, logical_line: usize) {
+        self.breakpoints.add(source, logical_line);
+    }
+
+    pub fn remove_breakpoint(&mut self, source: &str, logical_line: usize) {
+        self.breakpoints.remove(source, logical_line);
+    }
+
+    pub fn clear_breakpoints(&mut self, source: &str) {
+        self.breakpoints.clear(source);
+    }
+
+    /// Make `logical_lines` the complete breakpoint set for `source`; DAP's
+    /// setBreakpoints always sends the full list, so anything missing was removed
+    pub fn set_source_breakpoints(&mut self, source: &str, logical_lines: &[usize]) {
+        self.clear_breakpoints(source);
+        for line in logical_lines {
+            self.add_breakpoint(source, *line);
+        }
+        log::debug!(
+            "{} line breakpoint(s) set across all scripts",
+            self.breakpoints.len()
+        );
+    }
+
+    /// Make `logical_lines` the complete set of function (label) breakpoints
+    /// in `source`
+    pub fn set_function_breakpoints(&mut self, source: &str, logical_lines: &[usize]) {
+        self.function_breakpoints.clear(source);
+        for line in logical_lines {
+            self.function_breakpoints.add(source, *line);
+        }
+    }
+
+    /// Watch `names` for changes, starting from their current values
+    pub fn set_data_breakpoints(&mut self, names: &[String]) {
+        self.data_breakpoints = names
+            .iter()
+            .map(|name| DataBreakpoint {
+                name: name.clone(),
+                old_value: self.get_variable(name).map(str::to_string),
+            })
+            .collect();
+    }
+
+    pub fn data_breakpoints(&self) -> &[DataBreakpoint] {
+        &self.data_breakpoints
+    }
+
+    /// Record current values of watched variables and return the first one that changed
+    pub fn check_data_breakpoints(&mut self) -> Option<String> {
+        if self.data_breakpoints.is_empty() {
+            return None;
+        }
+
+        let values: Vec<Option<String>> = self
+            .data_breakpoints
+            .iter()
+            .map(|bp| self.get_variable(&bp.name).map(str::to_string))
+            .collect();
+
+        let mut changed = None;
+        for (bp, current) in self.data_breakpoints.iter_mut().zip(values) {
+            if current != bp.old_value {
+                log::info!(
+                    "Data breakpoint: {} changed {:?} -> {:?}",
+                    bp.name,
+                    bp.old_value,
+                    current
+                );
+                bp.old_value = current;
+                changed.get_or_insert_with(|| bp.name.clone());
             }
         }
+        changed
     }
 
-    pub fn add_breakpoint(&mut self, logical_line: usize) {
-        self.breakpoints.add(logical_line);
+    /// Logical lines in `source` with a line or function breakpoint, ascending
+    pub fn breakpoint_lines(&self, source: &str) -> Vec<usize> {
+        let mut lines: Vec<usize> = self
+            .breakpoints
+            .lines(source)
+            .chain(self.function_breakpoints.lines(source))
+            .collect();
+        lines.sort_unstable();
+        lines.dedup();
+        lines
     }
 
-    #[allow(dead_code)]
-    pub fn remove_breakpoint(&mut self, logical_line: usize) {
-        self.breakpoints.remove(logical_line);
+    /// Whether a line or function breakpoint is set on `pc`
+    pub fn has_breakpoint(&self, source: &str, pc: usize) -> bool {
+        self.breakpoints.contains(source, pc) || self.function_breakpoints.contains(source, pc)
     }
 
-    pub fn should_stop_at(&self, pc: usize) -> bool {
+    pub fn should_stop_at(&self, source: &str, pc: usize) -> bool {
         match self.mode {
-            RunMode::Continue => self.breakpoints.contains(pc),
+            RunMode::Continue => self.has_breakpoint(source, pc),
             RunMode::StepOver | RunMode::StepInto => true,
-            RunMode::StepOut => self.call_stack.len() <= self.step_out_target_depth,
+            RunMode::RunToReturn { target_depth } => self.call_stack.len() <= target_depth,
+        }
+    }
+
+    /// Mode that runs until the innermost frame returns to its caller
+    pub fn return_mode(&self) -> RunMode {
+        RunMode::RunToReturn {
+            target_depth: self.call_stack.len().saturating_sub(1),
         }
     }
 
@@ -202,31 +787,203 @@ impl DebugContext {
         match step_type {
             "continue" => {
                 self.mode = RunMode::Continue;
-                eprintln!("▶️  Continuing execution...");
+                log::debug!("Continuing execution");
             }
             "next" | "stepOver" => {
                 self.mode = RunMode::StepOver;
-                eprintln!("⏭️  Step Over");
+                log::debug!("Step over");
             }
             "stepIn" | "stepInto" => {
                 self.mode = RunMode::StepInto;
-                eprintln!("⤵️  Step Into");
+                log::debug!("Step into");
             }
             "stepOut" => {
-                self.mode = RunMode::StepOut;
-                self.step_out_target_depth = self.call_stack.len().saturating_sub(1);
-                eprintln!(
-                    "⤴️  Step Out (target depth: {})",
-                    self.step_out_target_depth
-                );
+                self.mode = self.return_mode();
+                log::debug!("Step out ({:?})", self.mode);
             }
             _ => {
-                eprintln!("❓ Unknown step command: {}", step_type);
+                log::warn!("Unknown step command: {}", step_type);
             }
         }
     }
 
     pub fn run_command(&mut self, cmd: &str) -> io::Result<(String, i32)> {
+        if !self.session.is_alive() {
+            self.respawn_session()?;
+        }
         self.session.run(cmd)
     }
+
+    /// Run a command for the logical line at `pc`, charging its run time to
+    /// the line when profiling
+    pub fn run_line_command(&mut self, pc: usize, cmd: &str) -> io::Result<(String, i32)> {
+        let started = Instant::now();
+        let result = self.run_command(cmd);
+        self.record_time(pc, started);
+        result
+    }
+
+    /// Run the block opening at logical line `pc` as one batch file, charging
+    /// its run time to that line when profiling
+    pub fn run_line_block(&mut self, pc: usize, lines: &[String]) -> io::Result<(String, i32)> {
+        let started = Instant::now();
+        let result = self.session.run_batch_block(lines);
+        self.record_time(pc, started);
+        result
+    }
+
+    /// Lines of scripts entered by an external CALL aren't the program's, so
+    /// they aren't profiled
+    fn record_time(&mut self, pc: usize, started: Instant) {
+        let external = self.call_stack.iter().any(|frame| frame.source.is_some());
+        if let Some(profile) = self.profile.as_mut().filter(|_| !external) {
+            profile.record(pc, started.elapsed());
+        }
+    }
+
+    /// Start a fresh cmd.exe and replay tracked variables into it
+    pub fn respawn_session(&mut self) -> io::Result<()> {
+        log::warn!("cmd.exe is gone, respawning session");
+        self.session.respawn()?;
+
+        let mut replay: Vec<(String, String)> = self.get_visible_variables().into_iter().collect();
+        replay.sort();
+        for (key, val) in replay {
+            self.session.run(&set_command(&key, &val))?;
+        }
+        Ok(())
+    }
+}
+
+/// A `SET` at the prompt that stores `value` as given: metacharacters are
+/// escaped, and `%` too so `%PATH%` isn't expanded
+fn set_command(name: &str, value: &str) -> String {
+    format!(
+        "set {}={}",
+        name,
+        escape_metachars(value).replace('%', "^%")
+    )
+}
+
+/// Split a plain `SET KEY=VALUE` (optionally quoted) into key and value.
+/// `SET /A`, `SET /P` and keys with operators in them are not plain assignments.
+fn parse_set_assignment(line: &str) -> Option<(String, String)> {
+    let l = line.trim_start();
+    if !l.to_uppercase().starts_with("SET ") {
+        return None;
+    }
+
+    let rest = l[3..].trim_start();
+
+    // Handle /A (arithmetic) - we can't track these accurately without executing
+    // Handle /P (prompt) - skip these as they require user input
+    let upper = rest.to_uppercase();
+    if upper.starts_with("/A") || upper.starts_with("/P") {
+        return None;
+    }
+
+    // Handle quoted SET "VAR=VAL"
+    let rest = rest.trim();
+    let rest = if rest.starts_with('"') && rest.ends_with('"') && rest.len() >= 2 {
+        &rest[1..rest.len() - 1]
+    } else {
+        rest
+    };
+
+    let eq_pos = rest.find('=')?;
+    let key = rest[..eq_pos].trim().to_string();
+    let val = rest[eq_pos + 1..].trim().to_string();
+
+    // Only track simple assignments (no operators in the key)
+    if key.is_empty()
+        || key.contains('+')
+        || key.contains('-')
+        || key.contains('*')
+        || key.contains('/')
+    {
+        return None;
+    }
+    Some((key, val))
+}
+
+/// Variables to read back after `line` runs, with whether each uses delayed expansion
+fn readback_targets(line: &str) -> Vec<(String, bool)> {
+    let l = line.trim_start().trim_start_matches('@');
+    let upper = l.to_ascii_uppercase();
+
+    if upper.starts_with("FOR ") {
+        // The body runs once per item, so only its final effect can be observed
+        let Some(do_pos) = upper.find(" DO ") else {
+            return Vec::new();
+        };
+        let body = l[do_pos + 4..].trim();
+        let body = body
+            .strip_prefix('(')
+            .and_then(|b| b.strip_suffix(')'))
+            .unwrap_or(body);
+        return split_composite_command(body)
+            .iter()
+            .flat_map(|part| readback_targets(&part.raw_text))
+            .collect();
+    }
+
+    let delayed = l.contains('!');
+    let arithmetic = arithmetic_targets(l);
+    if !arithmetic.is_empty() {
+        return arithmetic.into_iter().map(|name| (name, delayed)).collect();
+    }
+
+    match parse_set_assignment(l) {
+        Some((key, val)) if val.contains('!') => vec![(key, true)],
+        _ => Vec::new(),
+    }
+}
+
+/// Variable names assigned by a `SET /A` line, e.g. `X` and `Y` in `set /a X+=1, Y=X*2`
+fn arithmetic_targets(line: &str) -> Vec<String> {
+    let l = line.trim_start().trim_start_matches('@');
+    if !l.to_uppercase().starts_with("SET ") {
+        return Vec::new();
+    }
+    let rest = l[3..].trim_start();
+    if !rest.to_uppercase().starts_with("/A") {
+        return Vec::new();
+    }
+
+    let expr = rest[2..].trim().trim_matches('"');
+    let mut targets = Vec::new();
+    for assignment in expr.split(',') {
+        let Some(eq_pos) = assignment.find('=') else {
+            continue;
+        };
+        // Strip compound operators such as +=, <<=, >>=
+        let name = assignment[..eq_pos]
+            .trim_end_matches(['+', '-', '*', '/', '%', '&', '|', '^', '<', '>'])
+            .trim()
+            .trim_start_matches('"');
+        if !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '_' || c == '.' || c == '$')
+        {
+            targets.push(name.to_string());
+        }
+    }
+    targets
+}
+
+/// Variables cmd maintains itself, which only add noise to the variables view
+fn is_volatile_var(name: &str) -> bool {
+    const VOLATILE: &[&str] = &[
+        "PROMPT",
+        "CD",
+        "DATE",
+        "TIME",
+        "RANDOM",
+        "ERRORLEVEL",
+        "CMDEXTVERSION",
+        "CMDCMDLINE",
+        "HIGHESTNUMANODENUMBER",
+    ];
+    name.starts_with('=') || VOLATILE.iter().any(|v| v.eq_ignore_ascii_case(name))
 }
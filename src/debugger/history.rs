@@ -0,0 +1,154 @@
+use super::ContextSnapshot;
+use std::collections::{HashMap, VecDeque};
+
+/// Logical lines remembered for step-back unless the launch configuration
+/// asks for another size
+pub const DEFAULT_HISTORY_SIZE: usize = 100;
+
+/// One executed logical line, as recorded for step-back
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    /// Script the line belongs to
+    pub source: String,
+    /// Tracked state from just before the line ran
+    pub before: ContextSnapshot,
+    /// Output the line printed
+    pub output: String,
+    /// ERRORLEVEL after the line ran
+    pub exit_code: i32,
+}
+
+/// Ring buffer of the most recently executed lines. cmd.exe can't run
+/// backwards, so stepping back only rewinds what the client is shown: the
+/// line and the tracked variables as they were before it ran.
+#[derive(Debug)]
+pub struct History {
+    entries: VecDeque<HistoryEntry>,
+    capacity: usize,
+    /// The line running now
+    pending: Option<HistoryEntry>,
+    /// How many entries the client has stepped back; 0 is the live position
+    steps_back: usize,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: VecDeque::new(),
+            capacity,
+            pending: None,
+            steps_back: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Resize the buffer, dropping the oldest entries that no longer fit
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.entries.len() > capacity {
+            self.entries.pop_front();
+        }
+        self.steps_back = self.steps_back.min(self.entries.len());
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Start recording the line `before` was taken at
+    pub fn begin(&mut self, source: &str, before: ContextSnapshot) {
+        self.pending = Some(HistoryEntry {
+            source: source.to_string(),
+            before,
+            output: String::new(),
+            exit_code: 0,
+        });
+    }
+
+    /// Add output printed by the line being recorded
+    pub fn record_output(&mut self, output: &str) {
+        if let Some(entry) = &mut self.pending {
+            entry.output.push_str(output);
+        }
+    }
+
+    /// Finish the line being recorded with the exit code it left
+    pub fn finish(&mut self, exit_code: i32) {
+        let Some(mut entry) = self.pending.take() else {
+            return;
+        };
+        entry.exit_code = exit_code;
+        if self.capacity == 0 {
+            return;
+        }
+
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    /// Every recorded line, oldest first
+    pub fn entries(&self) -> impl Iterator<Item = &HistoryEntry> {
+        self.entries.iter()
+    }
+
+    /// Rewind one more line. Returns the line now shown, or `None` when the
+    /// oldest recorded line is already shown.
+    pub fn step_back(&mut self) -> Option<&HistoryEntry> {
+        if self.steps_back >= self.entries.len() {
+            return None;
+        }
+        self.steps_back += 1;
+        self.position()
+    }
+
+    /// The recorded line being inspected, or `None` at the live position
+    pub fn position(&self) -> Option<&HistoryEntry> {
+        let index = self.entries.len().checked_sub(self.steps_back)?;
+        (self.steps_back > 0).then(|| &self.entries[index])
+    }
+
+    /// How many lines back the client is looking
+    pub fn steps_back(&self) -> usize {
+        self.steps_back
+    }
+
+    pub fn is_inspecting(&self) -> bool {
+        self.steps_back > 0
+    }
+
+    /// Return to the live position
+    pub fn resume_live(&mut self) {
+        self.steps_back = 0;
+    }
+
+    /// Variables as they were before the line being inspected ran, or `live`
+    /// at the live position
+    pub fn variables_at(&self, live: &HashMap<String, String>) -> HashMap<String, String> {
+        match self.position() {
+            Some(entry) => entry.before.visible_variables(),
+            None => live.clone(),
+        }
+    }
+}
+
+impl HistoryEntry {
+    /// Logical line the entry records
+    pub fn pc(&self) -> usize {
+        self.before.pc
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new(DEFAULT_HISTORY_SIZE)
+    }
+}
@@ -1,34 +1,166 @@
 mod breakpoints;
 mod context;
+mod history;
+mod profile;
 mod session;
 mod stepping;
+mod watch;
 
+pub use breakpoints::DataBreakpoint;
 pub use context::DebugContext;
-pub use session::CmdSession;
-pub use stepping::RunMode;
+pub use history::{History, HistoryEntry, DEFAULT_HISTORY_SIZE};
+pub use profile::{LineTiming, Profile, DEFAULT_PROFILE_LINES};
+pub use session::{kill_process_tree, strip_ansi, CmdSession, SessionOptions, SCREEN_CLEARED};
+pub use stepping::{RunMode, StepGranularity, Wakeup};
+pub use watch::WatchList;
 
+use crate::parser::ForItems;
 use std::collections::HashMap;
 
+/// Environment saved by SETLOCAL and restored by the matching ENDLOCAL
+#[derive(Debug, Clone, Default)]
+pub struct SetlocalSnapshot {
+    /// Variables visible when SETLOCAL ran
+    pub variables: HashMap<String, String>,
+    pub delayed_expansion: bool,
+    pub extensions: bool,
+}
+
+/// The debugger's view of the script's state before a logical line ran.
+/// cmd.exe's own environment isn't part of it, so restoring one rewinds what
+/// the debugger tracks, not the session.
+#[derive(Debug, Clone)]
+pub struct ContextSnapshot {
+    /// Global variables, without any SETLOCAL scope's locals
+    pub variables: HashMap<String, String>,
+    pub call_stack: Vec<Frame>,
+    pub last_exit_code: i32,
+    /// Logical line about to run
+    pub pc: usize,
+}
+
+impl ContextSnapshot {
+    /// Variables the script could see, with the SETLOCAL scopes laid over the globals
+    pub fn visible_variables(&self) -> HashMap<String, String> {
+        visible_variables(&self.variables, &self.call_stack)
+    }
+}
+
+/// `variables` with the locals of every frame that has SETLOCAL active laid
+/// over them, outermost first; a called subroutine inherits its caller's
+fn visible_variables(
+    variables: &HashMap<String, String>,
+    call_stack: &[Frame],
+) -> HashMap<String, String> {
+    let mut visible = variables.clone();
+    for frame in call_stack.iter().filter(|f| f.has_setlocal) {
+        visible.extend(frame.locals.clone());
+    }
+    visible
+}
+
 /// Represents a single stack frame with its own variable scope
 #[derive(Debug, Clone)]
 pub struct Frame {
     pub return_pc: usize,
+    /// Logical line of the label this frame was called at, for restartFrame
+    pub entry_pc: Option<usize>,
     pub args: Option<Vec<String>>,
     /// Local variables for this frame (created by SETLOCAL)
     pub locals: HashMap<String, String>,
     /// Whether this frame has SETLOCAL active
     pub has_setlocal: bool,
+    /// One snapshot per active SETLOCAL, innermost last
+    pub saved_envs: Vec<SetlocalSnapshot>,
+    /// SETLOCAL ENABLEDELAYEDEXPANSION is in effect for this frame
+    pub delayed_expansion: bool,
+    /// SETLOCAL ENABLEEXTENSIONS is in effect for this frame
+    pub extensions: bool,
+    /// Batch file this frame runs in when CALL stepped into another script;
+    /// `None` stays in the caller's file
+    pub source: Option<String>,
+    /// What was called: the label of `CALL :label`, or the batch file of a
+    /// CALL into another script
+    pub name: Option<String>,
 }
 
 impl Frame {
     pub fn new(return_pc: usize, args: Option<Vec<String>>) -> Self {
         Self {
             return_pc,
+            entry_pc: None,
             args,
             locals: HashMap::new(),
             has_setlocal: false,
+            saved_envs: Vec::new(),
+            delayed_expansion: false,
+            extensions: false,
+            source: None,
+            name: None,
+        }
+    }
+}
+
+impl Frame {
+    /// A frame entered by `CALL :label`, remembering the label and where it is
+    pub fn called(
+        label: &str,
+        entry_pc: usize,
+        return_pc: usize,
+        args: Option<Vec<String>>,
+    ) -> Self {
+        Self {
+            entry_pc: Some(entry_pc),
+            name: Some(label.to_string()),
+            ..Self::new(return_pc, args)
+        }
+    }
+
+    /// SHIFT: drop the argument at 1-based position `from` (`SHIFT /n`) and move
+    /// the later ones down. Shifting past the end does nothing.
+    pub fn shift(&mut self, from: usize) {
+        if let Some(args) = self.args.as_mut() {
+            let index = from.max(1) - 1;
+            if index < args.len() {
+                args.remove(index);
+            }
         }
     }
+
+    /// A frame entered by `CALL other.bat`, running from the top of `source`
+    pub fn external(source: String, return_pc: usize, args: Option<Vec<String>>) -> Self {
+        Self {
+            name: std::path::Path::new(&source)
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned()),
+            source: Some(source),
+            ..Self::new(return_pc, args)
+        }
+    }
+}
+
+/// A FOR loop the executor steps through one iteration at a time instead of
+/// running it as a block
+#[derive(Debug, Clone)]
+pub struct ForLoop {
+    /// Loop variable letter, as in `%%i`
+    pub var: char,
+    pub items: ForItems,
+    /// Iteration running now
+    pub index: usize,
+    /// Call stack depth the loop runs at; a CALL from the body runs deeper
+    pub depth: usize,
+    /// First logical line of the body
+    pub body_start: usize,
+    /// Logical line of the closing `)`
+    pub body_end: usize,
+}
+
+impl ForLoop {
+    /// Value the loop variable has on the current iteration
+    pub fn value(&self) -> Option<String> {
+        self.items.get(self.index)
+    }
 }
 
 /// Helper: unwind the current context at EOF.
@@ -0,0 +1,69 @@
+use crate::parser::PreprocessResult;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Lines listed in a profile report unless asked for another number
+pub const DEFAULT_PROFILE_LINES: usize = 10;
+
+/// Time one logical line spent in cmd.exe
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LineTiming {
+    /// Commands run for the line
+    pub hits: usize,
+    pub total: Duration,
+}
+
+/// Time each logical line of the program spent in cmd.exe, summed over
+/// every time it ran
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    lines: HashMap<usize, LineTiming>,
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Charge one command's run time to the logical line at `pc`
+    pub fn record(&mut self, pc: usize, elapsed: Duration) {
+        let timing = self.lines.entry(pc).or_default();
+        timing.hits += 1;
+        timing.total += elapsed;
+    }
+
+    pub fn timing(&self, pc: usize) -> Option<LineTiming> {
+        self.lines.get(&pc).copied()
+    }
+
+    /// The `n` logical lines that took longest in total, slowest first
+    pub fn slowest(&self, n: usize) -> Vec<(usize, LineTiming)> {
+        let mut lines: Vec<(usize, LineTiming)> = self
+            .lines
+            .iter()
+            .map(|(&pc, &timing)| (pc, timing))
+            .collect();
+        lines.sort_by(|a, b| b.1.total.cmp(&a.1.total).then(a.0.cmp(&b.0)));
+        lines.truncate(n);
+        lines
+    }
+
+    /// Table of the `n` slowest lines, by 1-based physical line
+    pub fn report(&self, pre: &PreprocessResult, n: usize) -> String {
+        let mut report = format!("{:>6}  {:>10}  {:>5}  text\n", "line", "total ms", "hits");
+        for (pc, timing) in self.slowest(n) {
+            let (line, text) = pre
+                .logical
+                .get(pc)
+                .map_or((pc + 1, ""), |ll| (ll.phys_start + 1, ll.text.trim()));
+            report.push_str(&format!(
+                "{:>6}  {:>10.1}  {:>5}  {}\n",
+                line,
+                timing.total.as_secs_f64() * 1000.0,
+                timing.hits,
+                text
+            ));
+        }
+        report
+    }
+}
@@ -1,17 +1,178 @@
+use crate::parser::{paren_delta, split_composite_command};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
 use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
 use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
-use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+static SESSION_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Build a per-session completion marker that script output can't plausibly contain
+fn generate_sentinel() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    let count = SESSION_COUNTER.fetch_add(1, Ordering::Relaxed);
+
+    // RandomState is seeded randomly per process, so mix it with time, pid and a counter
+    let mut token = String::with_capacity(32);
+    for salt in 0..2u64 {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(nanos);
+        hasher.write_u32(std::process::id());
+        hasher.write_u64(count);
+        hasher.write_u64(salt);
+        token.push_str(&format!("{:016x}", hasher.finish()));
+    }
+
+    format!("__CMD_DONE_{}__", token)
+}
+
+/// Kill `pid` and every process it started. Works without the session
+/// itself, which may be locked by a command that is still running.
+pub fn kill_process_tree(pid: u32) -> io::Result<()> {
+    #[cfg(windows)]
+    let status = Command::new("taskkill")
+        .args(["/PID", &pid.to_string(), "/T", "/F"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?;
+    #[cfg(not(windows))]
+    let status = Command::new("kill")
+        .args(["-KILL", &pid.to_string()])
+        .stderr(Stdio::null())
+        .status()?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!(
+            "could not kill process {}: {}",
+            pid, status
+        )))
+    }
+}
+
+/// Write a temporary batch file, naming it in the error if that fails
+fn write_temp_batch(path: &Path, body: &str) -> io::Result<()> {
+    std::fs::write(path, body).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!(
+                "cannot write temporary batch file {}: {}",
+                path.display(),
+                e
+            ),
+        )
+    })
+}
 
-const SENTINEL: &str = "__CMD_DONE__";
+/// `text` without ANSI escape sequences: CSI sequences such as SGR colors
+/// (`ESC [ 31 m`), OSC sequences such as window titles (ended by BEL or
+/// `ESC \`), and two-character escapes. An unfinished sequence at the end
+/// is dropped.
+pub fn strip_ansi(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\x1b' {
+            out.push(ch);
+            continue;
+        }
+        match chars.next() {
+            // Parameters and intermediates, then one final byte in @..~
+            Some('[') => {
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\x07' || (c == '\x1b' && chars.next() == Some('\\')) {
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    out
+}
+
+/// `text` with cmd.exe's command separators, redirections, grouping and
+/// quotes escaped by a caret, so it reaches the command as literal text.
+/// Expansion is left alone: `%` and `!` still work.
+pub(crate) fn escape_metachars(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '^' | '&' | '|' | '<' | '>' | '(' | ')' | '"') {
+            out.push('^');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Line `run` returns in place of what `cls` writes: a form feed the debug
+/// console can't act on
+pub const SCREEN_CLEARED: &str = "--- screen cleared ---";
+
+/// `cls`, `color` or `title` when that is the whole of `cmd`, lowercased
+fn screen_command(cmd: &str) -> Option<String> {
+    let parts = split_composite_command(cmd);
+    let [part] = parts.as_slice() else {
+        return None;
+    };
+    let verb = part
+        .text
+        .trim_start_matches('@')
+        .split_whitespace()
+        .next()?
+        .to_ascii_lowercase();
+    matches!(verb.as_str(), "cls" | "color" | "title").then_some(verb)
+}
+
+/// Startup options for a `CmdSession`
+#[derive(Debug, Clone)]
+pub struct SessionOptions {
+    /// Switch the console to the UTF-8 code page (`chcp 65001`) at startup
+    pub utf8: bool,
+    /// Remove ANSI escape sequences (colors, cursor movement) from the output
+    /// `run` returns
+    pub strip_ansi: bool,
+}
+
+impl Default for SessionOptions {
+    fn default() -> Self {
+        Self {
+            utf8: true,
+            strip_ansi: true,
+        }
+    }
+}
 
 pub struct CmdSession {
-    _child: Child,
+    child: Child,
     stdin: ChildStdin,
     stdout: BufReader<ChildStdout>,
+    sentinel: String,
+    options: SessionOptions,
+    /// Output of the last `run` as cmd.exe wrote it, escape sequences and all
+    raw_output: String,
 }
 
 impl CmdSession {
     pub fn start() -> io::Result<Self> {
+        Self::start_with_options(SessionOptions::default())
+    }
+
+    pub fn start_with_options(options: SessionOptions) -> io::Result<Self> {
         // Enable delayed expansion globally so !VAR! works as expected.
         let mut child = Command::new("cmd")
             .args(["/V:ON", "/Q"]) // <— important change
@@ -23,15 +184,24 @@ impl CmdSession {
         let stdout = child.stdout.take().expect("no stdout");
 
         let mut session = Self {
-            _child: child,
+            child,
             stdin,
             stdout: BufReader::new(stdout),
+            sentinel: generate_sentinel(),
+            options: options.clone(),
+            raw_output: String::new(),
         };
 
         // Send initial echo off to suppress prompts
         session.stdin.write_all(b"@echo off\r\n")?;
         session.stdin.flush()?;
 
+        // Switch to UTF-8 before the init marker so any chcp chatter is drained below
+        if options.utf8 {
+            session.stdin.write_all(b"chcp 65001 >nul\r\n")?;
+            session.stdin.flush()?;
+        }
+
         // Clear any initial output by reading available lines with a simple marker
         session.stdin.write_all(b"echo INITIALIZED\r\n")?;
         session.stdin.flush()?;
@@ -45,7 +215,8 @@ impl CmdSession {
                 break;
             }
             line.clear();
-            match session.stdout.read_line(&mut line) {
+            match session.read_line_lossy(&mut line) {
+                Ok(0) => break,
                 Ok(_) => {
                     if line.contains("INITIALIZED") {
                         break;
@@ -58,60 +229,131 @@ impl CmdSession {
         Ok(session)
     }
 
-    /// Check if a command needs multi-line input (has unclosed parentheses)
-    fn needs_continuation(cmd: &str) -> bool {
-        let mut paren_count = 0;
-        let mut in_quotes = false;
-        let mut escaped = false;
-
-        for ch in cmd.chars() {
-            if escaped {
-                escaped = false;
-                continue;
-            }
-            if ch == '^' {
-                escaped = true;
-                continue;
-            }
-            if ch == '"' {
-                in_quotes = !in_quotes;
-                continue;
-            }
-            if !in_quotes {
-                match ch {
-                    '(' => paren_count += 1,
-                    ')' => paren_count -= 1,
-                    _ => {}
-                }
-            }
+    /// Run initialization commands (e.g. `set PATH=...`) before the script
+    /// starts, discarding their output
+    pub fn warm_up(&mut self, cmds: &[&str]) -> io::Result<()> {
+        for cmd in cmds {
+            self.run(cmd)?;
         }
+        Ok(())
+    }
 
-        paren_count > 0
+    /// Whether the underlying cmd.exe process is still running
+    pub fn is_alive(&mut self) -> bool {
+        matches!(self.child.try_wait(), Ok(None))
     }
 
-    /// Execute a multi-line block as a *real batch file* preserving CRLFs and batch parsing rules.
-    pub fn run_batch_block(&mut self, lines: &[String]) -> io::Result<(String, i32)> {
-        let temp_batch = "__temp_block__.bat";
+    /// Replace a dead (or wedged) cmd.exe with a fresh one using the same options
+    pub fn respawn(&mut self) -> io::Result<()> {
+        let fresh = Self::start_with_options(self.options.clone())?;
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        *self = fresh;
+        Ok(())
+    }
+
+    /// Process id of the cmd.exe behind this session
+    pub fn id(&self) -> u32 {
+        self.child.id()
+    }
 
+    /// Read one line of child output, replacing invalid UTF-8 instead of failing
+    fn read_line_lossy(&mut self, line: &mut String) -> io::Result<usize> {
+        let mut bytes = Vec::new();
+        let n = self.stdout.read_until(b'\n', &mut bytes)?;
+        line.push_str(&String::from_utf8_lossy(&bytes));
+        Ok(n)
+    }
+
+    /// Check if a command needs multi-line input (has unclosed parentheses)
+    fn needs_continuation(cmd: &str) -> bool {
+        paren_delta(cmd) > 0
+    }
+
+    /// Temporary batch file for this session in the system temp directory,
+    /// named after its cmd.exe so sessions running side by side don't
+    /// overwrite each other's
+    fn temp_file_path(&self, kind: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("__temp_{}_{}__.bat", kind, self.child.id()))
+    }
+
+    /// The temporary batch file `run_batch_block` writes for `lines`
+    pub fn block_script(lines: &[String]) -> String {
         // Preserve original line structure; batch parsing requires CRLF boundaries.
         let mut body = String::from("@echo off\r\n");
         for l in lines {
             body.push_str(l);
             body.push_str("\r\n");
         }
+        body
+    }
 
-        std::fs::write(temp_batch, body).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    /// Execute a multi-line block as a *real batch file* preserving CRLFs and batch parsing rules.
+    pub fn run_batch_block(&mut self, lines: &[String]) -> io::Result<(String, i32)> {
+        let temp_batch = self.temp_file_path("block");
+        let body = Self::block_script(lines);
+
+        write_temp_batch(&temp_batch, &body)?;
 
         // Execute via CALL so the session stays alive
-        let (out, code) = self.run(&format!("call {}", temp_batch))?;
+        let result = self.run(&format!("call \"{}\"", temp_batch.display()));
+
+        // Best-effort cleanup; the CALL has returned, so the file is free
+        let _ = std::fs::remove_file(&temp_batch);
+
+        result
+    }
 
-        // Best-effort cleanup; ignore errors
-        let _ = self.run(&format!("del {} >nul 2>&1", temp_batch));
+    /// Read the child's full environment by running a bare `set`
+    pub fn get_all_env_vars(&mut self) -> io::Result<HashMap<String, String>> {
+        let (out, _) = self.run("set")?;
 
-        Ok((out, code))
+        let mut vars = HashMap::new();
+        for line in out.lines() {
+            let line = line.trim_end_matches('\r');
+            // Skip hidden per-drive entries like `=C:=C:\` (empty name)
+            if let Some(eq_pos) = line.find('=').filter(|&pos| pos > 0) {
+                vars.insert(line[..eq_pos].to_string(), line[eq_pos + 1..].to_string());
+            }
+        }
+
+        Ok(vars)
     }
 
+    /// Strip ANSI escape sequences from the output of later commands, or
+    /// stop stripping them
+    pub fn set_strip_ansi(&mut self, strip: bool) {
+        self.options.strip_ansi = strip;
+    }
+
+    /// Output of the last command as cmd.exe wrote it, with any ANSI escape
+    /// sequences `run` removed
+    pub fn last_raw_output(&self) -> &str {
+        &self.raw_output
+    }
+
+    /// Run `cmd` and return its output and exit code. The output is cleaned
+    /// of ANSI escape sequences unless `strip_ansi` is off; the whole output
+    /// is cleaned at once, so a sequence split across lines goes too.
+    ///
+    /// Screen control still runs in cmd.exe but leaves nothing for the
+    /// console to choke on: `cls` comes back as a `SCREEN_CLEARED` line and
+    /// `color` and `title` as no output at all.
     pub fn run(&mut self, cmd: &str) -> io::Result<(String, i32)> {
+        let (output, code) = self.run_raw(cmd)?;
+        let cleaned = match screen_command(cmd).as_deref() {
+            Some("cls") => format!("{}\r\n", SCREEN_CLEARED),
+            Some(_) => String::new(),
+            None if self.options.strip_ansi => strip_ansi(&output),
+            None => output.clone(),
+        };
+        // `cls` partway through a line writes its form feed among the rest
+        let cleaned = cleaned.replace('\x0c', &format!("{}\r\n", SCREEN_CLEARED));
+        self.raw_output = output;
+        Ok((cleaned, code))
+    }
+
+    fn run_raw(&mut self, cmd: &str) -> io::Result<(String, i32)> {
         // Special case for @echo off - it produces no output
         if cmd.trim().eq_ignore_ascii_case("@echo off")
             || cmd.trim().eq_ignore_ascii_case("echo off")
@@ -122,31 +364,26 @@ impl CmdSession {
             return Ok((String::new(), 0));
         }
 
-        let debug_this = cmd.contains("set /a") || cmd.contains("COUNTER") || cmd.contains("if ");
-
-        if debug_this {
-            eprintln!("DEBUG: About to execute: '{}'", cmd);
-        }
+        log::trace!("About to execute: '{}'", cmd);
 
         // Check if this is a multi-line command (rare for single-line path)
         let is_multiline = Self::needs_continuation(cmd);
 
         if is_multiline {
-            eprintln!("DEBUG: Detected multi-line command");
+            log::trace!("Detected multi-line command");
             // Write to a temporary batch file and execute it to preserve semantics
-            let temp_batch = "__temp_cmd__.bat";
-            std::fs::write(temp_batch, format!("@echo off\r\n{}\r\n", cmd))
-                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            let temp_batch = self.temp_file_path("cmd");
+            write_temp_batch(&temp_batch, &format!("@echo off\r\n{}\r\n", cmd))?;
 
             // Execute the temp batch file
             self.stdin
-                .write_all(format!("call {}\r\n", temp_batch).as_bytes())?;
+                .write_all(format!("call \"{}\"\r\n", temp_batch.display()).as_bytes())?;
             self.stdin.flush()?;
 
             // Clean up
             std::thread::sleep(Duration::from_millis(200));
             self.stdin
-                .write_all(format!("del {} >nul 2>&1\r\n", temp_batch).as_bytes())?;
+                .write_all(format!("del \"{}\" >nul 2>&1\r\n", temp_batch.display()).as_bytes())?;
             self.stdin.flush()?;
         } else {
             // Send the command normally
@@ -160,7 +397,7 @@ impl CmdSession {
 
         // Send echo command to force a newline and get the exit code
         self.stdin.write_all(b"echo.\r\n")?; // Force a blank line first
-        let sentinel_cmd = format!("echo {}_%errorlevel%_END\r\n", SENTINEL);
+        let sentinel_cmd = format!("echo {}_%errorlevel%_END\r\n", self.sentinel);
         self.stdin.write_all(sentinel_cmd.as_bytes())?;
         self.stdin.flush()?;
 
@@ -174,28 +411,31 @@ impl CmdSession {
         loop {
             // Check timeout
             if start.elapsed() > timeout {
-                eprintln!("WARNING: Command timed out after 5 seconds");
-                eprintln!("  Command was: {}", cmd);
-                eprintln!("  Output collected so far: '{}'", output.trim());
+                log::warn!(
+                    "Command timed out after 5 seconds: {} (output so far: '{}')",
+                    cmd,
+                    output.trim()
+                );
                 return Ok((output, 1));
             }
 
             let mut line = String::new();
-            match self.stdout.read_line(&mut line) {
+            match self.read_line_lossy(&mut line) {
                 Ok(0) => {
-                    std::thread::sleep(Duration::from_millis(50));
-                    continue;
+                    // stdout closed: cmd.exe has exited (e.g. a bare `exit`)
+                    return Err(io::Error::new(
+                        io::ErrorKind::BrokenPipe,
+                        format!("cmd.exe exited while running '{}'", cmd),
+                    ));
                 }
                 Ok(_) => {
                     let trimmed = line.trim();
 
-                    if debug_this {
-                        eprintln!("DEBUG: Read line: '{}'", trimmed);
-                    }
+                    log::trace!("Read line: '{}'", trimmed);
 
                     // Check for our sentinel
-                    if trimmed.starts_with(SENTINEL) && trimmed.ends_with("_END") {
-                        let prefix_len = SENTINEL.len() + 1;
+                    if trimmed.starts_with(&self.sentinel) && trimmed.ends_with("_END") {
+                        let prefix_len = self.sentinel.len() + 1;
                         let suffix_len = 4;
                         if trimmed.len() > prefix_len + suffix_len {
                             let code_str = &trimmed[prefix_len..trimmed.len() - suffix_len];
@@ -219,7 +459,7 @@ impl CmdSession {
                     }
                 }
                 Err(e) => {
-                    eprintln!("DEBUG: Read error: {}", e);
+                    log::error!("Read error: {}", e);
                     return Err(e);
                 }
             }
@@ -1,8 +1,63 @@
+use std::sync::{Condvar, Mutex, PoisonError};
+
 /// Run modes for the debugger
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum RunMode {
     Continue,
     StepOver,
     StepInto,
-    StepOut,
+    /// Run until the call stack is back down to `target_depth` frames, i.e.
+    /// the subroutine being stepped out of has returned
+    RunToReturn {
+        target_depth: usize,
+    },
+}
+
+/// How far a step request runs before stopping again
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum StepGranularity {
+    /// Stop at the next logical line
+    #[default]
+    Line,
+    /// Stop at the next part of a composite command (`a & b`, `a && b`, `a || b`)
+    Statement,
+}
+
+impl StepGranularity {
+    /// Map a DAP `granularity` argument; `instruction` is as fine as batch
+    /// stepping gets, so it steps by statement too
+    pub fn from_dap(granularity: Option<&str>) -> Self {
+        match granularity {
+            Some("statement") | Some("instruction") => StepGranularity::Statement,
+            _ => StepGranularity::Line,
+        }
+    }
+}
+
+/// Wakes the executor while it waits at a stop or for input. A wake that
+/// comes before the executor starts waiting is kept, so none is lost, and
+/// notifying never needs the context lock a running command holds.
+#[derive(Debug, Default)]
+pub struct Wakeup {
+    pending: Mutex<bool>,
+    ready: Condvar,
+}
+
+impl Wakeup {
+    pub fn notify(&self) {
+        *self.pending.lock().unwrap_or_else(PoisonError::into_inner) = true;
+        self.ready.notify_all();
+    }
+
+    /// Block until `notify` has been called since the last wait returned
+    pub fn wait(&self) {
+        let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+        while !*pending {
+            pending = self
+                .ready
+                .wait(pending)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        *pending = false;
+    }
 }
@@ -0,0 +1,63 @@
+use super::session::escape_metachars;
+use super::CmdSession;
+use std::io;
+
+/// Expressions re-evaluated at every stop, e.g. `%COUNT%` or `!NAME!`
+#[derive(Debug, Default)]
+pub struct WatchList {
+    exprs: Vec<String>,
+}
+
+impl WatchList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Blank expressions and duplicates are ignored
+    pub fn add(&mut self, expr: String) {
+        let expr = expr.trim();
+        if !expr.is_empty() && !self.exprs.iter().any(|w| w == expr) {
+            self.exprs.push(expr.to_string());
+        }
+    }
+
+    /// Remove the watch at `index`, returning it if it existed
+    pub fn remove(&mut self, index: usize) -> Option<String> {
+        (index < self.exprs.len()).then(|| self.exprs.remove(index))
+    }
+
+    pub fn position(&self, expr: &str) -> Option<usize> {
+        self.exprs.iter().position(|w| w == expr.trim())
+    }
+
+    pub fn exprs(&self) -> &[String] {
+        &self.exprs
+    }
+
+    pub fn len(&self) -> usize {
+        self.exprs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exprs.is_empty()
+    }
+
+    /// Evaluate `expr` by echoing it through the live session. Only
+    /// expansion happens: `&`, `>` and the like are echoed, not run.
+    pub fn evaluate(session: &mut CmdSession, expr: &str) -> io::Result<String> {
+        let (out, _) = session.run(&format!("echo {}", escape_metachars(expr)))?;
+        Ok(out.trim().to_string())
+    }
+
+    /// Evaluate every watch by echoing it through the live session
+    pub fn evaluate_all(&self, session: &mut CmdSession) -> Vec<(String, String)> {
+        self.exprs
+            .iter()
+            .map(|expr| {
+                let value =
+                    Self::evaluate(session, expr).unwrap_or_else(|e| format!("<error: {}>", e));
+                (expr.clone(), value)
+            })
+            .collect()
+    }
+}
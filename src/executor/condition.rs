@@ -0,0 +1,124 @@
+use crate::debugger::DebugContext;
+use crate::parser::{parse_if_condition, CompareOp, IfCondition};
+use std::path::Path;
+
+/// Whether an IF condition holds, judged from tracked variables and the last
+/// exit code without asking cmd.exe. `cond` is the text after `IF` (a leading
+/// `IF` is allowed), e.g. `errorlevel 1`, `not defined FOO` or
+/// `/i "%A%"=="b"`. `None` when the answer depends on something the debugger
+/// doesn't track, such as an unknown variable or a relative path.
+pub fn evaluate_if_condition(cond: &str, ctx: &DebugContext) -> Option<bool> {
+    let cond = cond.trim_start();
+    let cond = strip_word(cond, "if").unwrap_or(cond);
+    let (test, rest) = parse_if_condition(cond)?;
+    if !rest.trim().is_empty() {
+        return None;
+    }
+
+    let holds = match &test.condition {
+        IfCondition::ErrorLevel(n) => ctx.last_exit_code >= *n,
+        // Tracking may have missed a variable, so only a known one is certain
+        IfCondition::Defined(name) => ctx.get_variable(name).map(|_| true)?,
+        IfCondition::Exist(path) => {
+            let path = expand(path, ctx)?;
+            let path = path.trim_matches('"');
+            // cmd.exe's directory isn't tracked, so relative paths are unknown
+            if path.contains(['*', '?']) || !Path::new(path).is_absolute() {
+                return None;
+            }
+            Path::new(path).exists()
+        }
+        IfCondition::Equals(lhs, rhs) => {
+            let (lhs, rhs) = operands(lhs, rhs, test.ignore_case, ctx)?;
+            lhs == rhs
+        }
+        IfCondition::Compare(lhs, op, rhs) => {
+            let (lhs, rhs) = operands(lhs, rhs, test.ignore_case, ctx)?;
+            compare(&lhs, *op, &rhs)?
+        }
+    };
+    Some(holds != test.negated)
+}
+
+/// Both operands expanded, and lowercased under `/I`
+fn operands(
+    lhs: &str,
+    rhs: &str,
+    ignore_case: bool,
+    ctx: &DebugContext,
+) -> Option<(String, String)> {
+    let (lhs, rhs) = (expand(lhs, ctx)?, expand(rhs, ctx)?);
+    Some(if ignore_case {
+        (lhs.to_lowercase(), rhs.to_lowercase())
+    } else {
+        (lhs, rhs)
+    })
+}
+
+/// `lhs op rhs` with the operands already expanded
+fn compare(lhs: &str, op: CompareOp, rhs: &str) -> Option<bool> {
+    // Numbers compare numerically; other strings by cmd.exe's collation,
+    // which only equality can follow for certain
+    let ordering = match (parse_number(lhs), parse_number(rhs)) {
+        (Some(a), Some(b)) => Some(a.cmp(&b)),
+        _ => None,
+    };
+    match (op, ordering) {
+        (CompareOp::Equ, Some(o)) => Some(o.is_eq()),
+        (CompareOp::Neq, Some(o)) => Some(o.is_ne()),
+        (CompareOp::Lss, Some(o)) => Some(o.is_lt()),
+        (CompareOp::Leq, Some(o)) => Some(o.is_le()),
+        (CompareOp::Gtr, Some(o)) => Some(o.is_gt()),
+        (CompareOp::Geq, Some(o)) => Some(o.is_ge()),
+        (CompareOp::Equ, None) => Some(lhs == rhs),
+        (CompareOp::Neq, None) => Some(lhs != rhs),
+        _ => None,
+    }
+}
+
+/// Plain decimal integer; octal (`010`) and hex (`0x10`) forms are left
+/// alone rather than guessed at
+fn parse_number(text: &str) -> Option<i64> {
+    let digits = text.strip_prefix(['-', '+']).unwrap_or(text);
+    if digits.is_empty()
+        || (digits.len() > 1 && digits.starts_with('0'))
+        || !digits.chars().all(|c| c.is_ascii_digit())
+    {
+        return None;
+    }
+    text.parse().ok()
+}
+
+/// `text` with every `%NAME%` and `!NAME!` replaced by its tracked value, or
+/// `None` if one isn't tracked or uses substring or replace syntax
+fn expand(text: &str, ctx: &DebugContext) -> Option<String> {
+    let mut expanded = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(['%', '!']) {
+        let delim = &rest[start..start + 1];
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let end = after.find(delim)?;
+        let name = &after[..end];
+        if name.is_empty() || name.contains(':') {
+            return None;
+        }
+        match ctx.get_variable(name) {
+            Some(value) => expanded.push_str(value),
+            None if name.eq_ignore_ascii_case("errorlevel") => {
+                expanded.push_str(&ctx.last_exit_code.to_string())
+            }
+            None => return None,
+        }
+        rest = &after[end + 1..];
+    }
+    expanded.push_str(rest);
+    Some(expanded)
+}
+
+/// `text` after a leading `word` (any case) and the whitespace following it
+fn strip_word<'a>(text: &'a str, word: &str) -> Option<&'a str> {
+    let head = text.get(..word.len())?;
+    let rest = &text[word.len()..];
+    (head.eq_ignore_ascii_case(word) && rest.starts_with([' ', '\t'])).then(|| rest.trim_start())
+}
@@ -1,262 +1,300 @@
-use crate::debugger::{leave_context, DebugContext, Frame, RunMode};
-use crate::parser::{normalize_whitespace, PreprocessResult};
+use super::runner::{
+    advance_for_loops, bind_for_vars, block_lines_run, collect_block, enter_for_loop,
+    enter_if_block, expand_frame_args, for_loop_at, if_block_at, is_housekeeping, is_label_call,
+    parse_exit, parse_exit_b, parse_shift, part_runs, past_block_close, redirect_exits,
+    run_block_with_exits, run_redirected,
+};
+use crate::dap::Incoming;
+use crate::debugger::{CmdSession, DebugContext, Frame, RunMode, StepGranularity};
+use crate::parser::{
+    self, label_name, normalize_whitespace_outside_quotes, paren_delta, split_composite_command,
+    CommandOp, CommandPart, LabelMap, PreprocessResult, RedirectOp,
+};
 use std::collections::HashMap;
-use std::io::{self, Write};
+use std::io;
+use std::path::Path;
 use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
 
 /// DAP-specific executor that sends stopped events via channel instead of interactive prompts
 pub fn run_debugger_dap(
     ctx_arc: Arc<Mutex<DebugContext>>,
     pre: &PreprocessResult,
-    labels_phys: &HashMap<String, usize>,
-    event_tx: Sender<(String, usize)>,
-    output_tx: Sender<String>,
+    labels: &LabelMap,
+    events: Sender<Incoming>,
 ) -> io::Result<()> {
-    // Create log file for this thread
-    let mut log = std::fs::OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open("C:\\temp\\batch-debugger-vscode.log")
-        .ok();
-
-    if let Some(ref mut f) = log {
-        writeln!(f, "run_debugger_dap: ENTRY").ok();
-        writeln!(f, "  Logical lines: {}", pre.logical.len()).ok();
-        f.flush().ok();
-    }
+    log::debug!("Running {} logical lines", pre.logical.len());
+
+    // Every script the run can be in, keyed by path; CALLs into other batch
+    // files add to it when stepping into external scripts is enabled
+    let program = match ctx_arc.lock() {
+        Ok(ctx) => ctx.current_source.clone(),
+        Err(e) => {
+            log::error!("Failed to lock context: {}", e);
+            return Ok(());
+        }
+    };
+    let mut scripts: HashMap<String, Script> = HashMap::new();
+    scripts.insert(
+        program.clone(),
+        (Arc::new(pre.clone()), Arc::new(labels.clone())),
+    );
+    let mut source = program.clone();
 
     let mut pc: usize = 0;
     let mut step_depth: Option<usize> = None;
+    let mut restarted = false;
+    // Next part of the composite command at `pc` when stepping by statement;
+    // nonzero only while partway through the line
+    let mut statement: usize = 0;
 
     'run: loop {
-        if let Some(ref mut f) = log {
-            writeln!(f, "Main loop: pc={}", pc).ok();
-            f.flush().ok();
-        }
+        log::trace!("Main loop: pc={}", pc);
 
-        // EOF unwinding
-        while pc >= pre.logical.len() {
-            if let Some(ref mut f) = log {
-                writeln!(f, "EOF reached, unwinding").ok();
-                f.flush().ok();
-            }
+        let (pre, labels) = scripts[&source].clone();
+        let (pre, labels) = (pre.as_ref(), labels.as_ref());
 
+        let (line, for_loop, if_block, statements) = {
             let mut ctx = match ctx_arc.lock() {
                 Ok(c) => c,
                 Err(e) => {
-                    eprintln!("❌ Failed to lock context: {}", e);
-                    if let Some(ref mut f) = log {
-                        writeln!(f, "❌ Failed to lock context: {}", e).ok();
-                        f.flush().ok();
-                    }
+                    log::error!("Failed to lock context: {}", e);
                     break 'run;
                 }
             };
-            match leave_context(&mut ctx.call_stack) {
-                Some(next_pc) => pc = next_pc,
-                None => break 'run,
+            if ctx.stop_requested() {
+                log::debug!("Client disconnected or cancelled, stopping");
+                break 'run;
             }
-        }
+            pc = advance_for_loops(&mut ctx, pc);
+
+            // EOF unwinding
+            if pc >= pre.logical.len() {
+                log::debug!("EOF reached, unwinding");
+
+                match leave_frame(&mut ctx, &program) {
+                    Some(next_pc) => pc = next_pc,
+                    None => break 'run,
+                }
+                source = ctx.current_source.clone();
+                continue;
+            }
+
+            let line = bind_for_vars(
+                &ctx,
+                normalize_whitespace_outside_quotes(&pre.logical[pc].text),
+            );
+            // Simple FOR loops and IFs run line by line rather than as a block
+            let header = expand_frame_args(&ctx, line.clone());
+            let for_loop = for_loop_at(pre, pc, &header, ctx.call_stack.len());
+            let if_block = if_block_at(pre, pc, &header);
+            let statements = if for_loop.is_none() && if_block.is_none() {
+                statements_of(&header)
+            } else {
+                None
+            };
+
+            // Parts whose `&&` or `||` condition failed are skipped, not stopped at
+            if let Some(parts) = statements.as_ref().filter(|_| statement > 0) {
+                while statement < parts.len() && !part_runs(parts, statement, ctx.last_exit_code) {
+                    statement += 1;
+                }
+                if statement >= parts.len() {
+                    statement = 0;
+                    pc += 1;
+                    continue;
+                }
+            }
+            (line, for_loop, if_block, statements)
+        };
 
         let ll = &pre.logical[pc];
         let raw = ll.text.as_str();
-        let line = normalize_whitespace(raw.trim());
         let line_upper = line.to_uppercase();
 
-        if let Some(ref mut f) = log {
-            writeln!(f, "Processing line {}: '{}'", pc, raw).ok();
-            f.flush().ok();
-        }
+        log::trace!("Processing line {}: '{}'", pc, raw);
 
-        // Skip label lines
-        if line.trim().starts_with(':') {
-            if let Some(ref mut f) = log {
-                writeln!(f, "  Skipping label line").ok();
-                f.flush().ok();
-            }
+        // Skip empty lines, comments and label definitions
+        if ll.is_empty() || ll.is_comment() || ll.is_label() {
+            log::trace!("Skipping comment or label line");
             pc += 1;
             continue;
         }
 
-        // Skip REM and :: comments
-        if line_upper.starts_with("REM ") || line.trim().starts_with("::") {
-            if let Some(ref mut f) = log {
-                writeln!(f, "  Skipping comment line").ok();
-                f.flush().ok();
-            }
-            pc += 1;
+        // Lines closing an IF branch that ran line by line
+        if let Some(next_pc) = past_block_close(pre, pc) {
+            pc = next_pc;
             continue;
         }
 
-        // Check if we should stop at this line
-        let should_stop = {
-            if let Some(ref mut f) = log {
-                writeln!(f, "  Checking if should stop...").ok();
-                f.flush().ok();
-            }
+        // Other IF/FOR blocks run as one temporary batch file
+        let block = (for_loop.is_none()
+            && if_block.is_none()
+            && (line_upper.starts_with("IF ") || line_upper.starts_with("FOR "))
+            && paren_delta(raw) > 0)
+            .then(|| collect_block(pre, pc));
 
-            let ctx = match ctx_arc.lock() {
+        // PAUSE would wait on cmd.exe's stdin, which nobody is attached to; it
+        // stops the debugger instead, and resuming plays the keypress
+        let pause_line = is_pause(&line_upper);
+
+        // Check if we should stop at this line; a watched variable changed by the
+        // previous command stops here regardless of mode
+        let (should_stop, data_hit, over_budget) = {
+            log::trace!("Checking if should stop...");
+
+            let mut ctx = match ctx_arc.lock() {
                 Ok(c) => c,
                 Err(e) => {
-                    eprintln!("❌ Failed to lock context: {}", e);
-                    if let Some(ref mut f) = log {
-                        writeln!(f, "❌ Failed to lock context: {}", e).ok();
-                        f.flush().ok();
-                    }
+                    log::error!("Failed to lock context: {}", e);
                     break 'run;
                 }
             };
 
-            let stop = match ctx.mode() {
-                RunMode::Continue => ctx.should_stop_at(pc),
-                RunMode::StepInto => true,
-                RunMode::StepOver => {
-                    if let Some(target_depth) = step_depth {
-                        ctx.call_stack.len() <= target_depth
-                    } else {
-                        true
+            // The previous line has finished; keep it for stepBack
+            let exit_code = ctx.last_exit_code;
+            ctx.history.finish(exit_code);
+
+            let data_hit = ctx.check_data_breakpoints();
+            let over_budget = ctx.count_step();
+            ctx.active_block = block
+                .as_ref()
+                .map(|(lines, _)| CmdSession::block_script(lines));
+
+            // Partway through a line only statement steps stop; breakpoints
+            // were already hit at its first part
+            let mid_line = statement > 0;
+            let stop = data_hit.is_some()
+                || over_budget
+                || pause_line
+                || match ctx.mode() {
+                    _ if mid_line && ctx.granularity() == StepGranularity::Line => false,
+                    RunMode::Continue => !mid_line && ctx.should_stop_at(&ctx.current_source, pc),
+                    RunMode::StepInto => !(ctx.skip_housekeeping && is_housekeeping(&line)),
+                    RunMode::StepOver => {
+                        if let Some(target_depth) = step_depth {
+                            ctx.call_stack.len() <= target_depth
+                        } else {
+                            true
+                        }
                     }
-                }
-                RunMode::StepOut => ctx.should_stop_at(pc),
-            };
+                    RunMode::RunToReturn { target_depth } => ctx.call_stack.len() <= target_depth,
+                };
 
-            if let Some(ref mut f) = log {
-                writeln!(f, "  Should stop: {}, mode: {:?}", stop, ctx.mode()).ok();
-                f.flush().ok();
-            }
+            log::debug!("Should stop: {}, mode: {:?}", stop, ctx.mode());
 
-            stop
+            (stop, data_hit, over_budget)
         };
 
         // If we should stop, pause and wait for DAP to tell us to continue
         if should_stop {
-            eprintln!(
-                "🛑 DAP: Stopped at line {} (phys {}): {}",
+            log::debug!(
+                "Stopped at line {} (phys {}): {}",
                 pc,
                 ll.phys_start + 1,
                 raw
             );
 
-            if let Some(ref mut f) = log {
-                writeln!(
-                    f,
-                    "🛑 STOPPED at line {} (phys {}): {}",
-                    pc,
-                    ll.phys_start + 1,
-                    raw
-                )
-                .ok();
-                f.flush().ok();
-            }
-
             // Determine the stop reason
             let stop_reason = {
-                let ctx = match ctx_arc.lock() {
+                let mut ctx = match ctx_arc.lock() {
                     Ok(c) => c,
                     Err(e) => {
-                        eprintln!("❌ Failed to lock context: {}", e);
+                        log::error!("Failed to lock context: {}", e);
                         break 'run;
                     }
                 };
 
                 match ctx.mode() {
+                    _ if std::mem::take(&mut restarted) => "restart",
+                    _ if std::mem::take(&mut ctx.pause_requested) => "pause",
+                    _ if over_budget => "step limit exceeded",
+                    _ if data_hit.is_some() => "data breakpoint",
+                    _ if pause_line => "pause",
                     RunMode::Continue => "breakpoint",
-                    RunMode::StepInto | RunMode::StepOver | RunMode::StepOut => "step",
+                    RunMode::StepInto | RunMode::StepOver | RunMode::RunToReturn { .. } => "step",
                 }
             };
 
-            // Send stopped event through channel
-            if let Err(e) = event_tx.send((stop_reason.to_string(), pc)) {
-                eprintln!("❌ Failed to send stopped event: {}", e);
-                if let Some(ref mut f) = log {
-                    writeln!(f, "❌ Failed to send stopped event: {}", e).ok();
-                    f.flush().ok();
-                }
-                break 'run;
+            if over_budget {
+                let _ = events.send(Incoming::Output(format!(
+                    "Warning: step limit reached at line {}; the script may be stuck in a loop\r\n",
+                    ll.phys_start + 1
+                )));
             }
 
-            eprintln!("📤 Sent stopped event: {}", stop_reason);
-            if let Some(ref mut f) = log {
-                writeln!(f, "📤 Sent stopped event: {}", stop_reason).ok();
-                f.flush().ok();
+            // `pause >nul` hides the prompt
+            if pause_line && !line.contains('>') {
+                let _ = events.send(Incoming::Output(
+                    "Press any key to continue . . .\r\n".to_string(),
+                ));
             }
 
-            // Reset the continue flag and set current line
+            // Reset the continue flag and set current line before the client hears
+            // of the stop, so requests it answers with see the new position
             {
                 let mut ctx = match ctx_arc.lock() {
                     Ok(c) => c,
                     Err(e) => {
-                        eprintln!("❌ Failed to lock context: {}", e);
-                        if let Some(ref mut f) = log {
-                            writeln!(f, "❌ Failed to lock context: {}", e).ok();
-                            f.flush().ok();
-                        }
+                        log::error!("Failed to lock context: {}", e);
                         break 'run;
                     }
                 };
                 ctx.continue_requested = false;
+                ctx.skip_housekeeping = false;
                 ctx.current_line = Some(pc);
-
-                if let Some(ref mut f) = log {
-                    writeln!(
-                        f,
-                        "  Reset continue_requested to false, set current_line to {}",
-                        pc
-                    )
-                    .ok();
-                    f.flush().ok();
-                }
+                // CD is re-read on demand while stopped, in case something untracked moved it
+                ctx.forget_current_dir();
+                ctx.current_column = statements
+                    .as_ref()
+                    .filter(|_| mid_line_or_statement(&ctx, statement))
+                    .and_then(|_| statement_column(raw, statement));
+
+                log::debug!(
+                    "Reset continue_requested to false, set current_line to {}",
+                    pc
+                );
             }
 
-            // Wait for continue_requested to be set to true
-            let mut wait_count = 0;
-            if let Some(ref mut f) = log {
-                writeln!(f, "  Entering wait loop...").ok();
-                f.flush().ok();
+            // Send stopped event through channel
+            if let Err(e) = events.send(Incoming::Stopped {
+                reason: stop_reason.to_string(),
+                line: pc,
+            }) {
+                log::error!("Failed to send stopped event: {}", e);
+                break 'run;
             }
 
-            loop {
-                std::thread::sleep(Duration::from_millis(50));
-                wait_count += 1;
-
-                if wait_count % 20 == 0 {
-                    // Log every second
-                    if let Some(ref mut f) = log {
-                        writeln!(f, "  Still waiting... ({} iterations)", wait_count).ok();
-                        f.flush().ok();
-                    }
-                }
-
-                // Timeout after 5 minutes
-                if wait_count > 6000 {
-                    eprintln!("⚠️ Timeout waiting for step command");
-                    if let Some(ref mut f) = log {
-                        writeln!(f, "⚠️ Timeout waiting for step command").ok();
-                        f.flush().ok();
-                    }
-                    break 'run;
-                }
+            // Wait for continue_requested to be set to true; whatever sets
+            // it, or restarts or ends the run, wakes us
+            log::trace!("Entering wait loop...");
 
-                let ctx = match ctx_arc.lock() {
+            loop {
+                let mut ctx = match ctx_arc.lock() {
                     Ok(c) => c,
                     Err(e) => {
-                        eprintln!("❌ Failed to lock context during wait: {}", e);
-                        if let Some(ref mut f) = log {
-                            writeln!(f, "❌ Failed to lock context during wait: {}", e).ok();
-                            f.flush().ok();
-                        }
+                        log::error!("Failed to lock context during wait: {}", e);
                         break 'run;
                     }
                 };
 
+                if ctx.stop_requested() {
+                    log::debug!("Client disconnected or cancelled while stopped");
+                    break 'run;
+                }
+
+                // restartFrame: skip the current line and stop again at the frame's label
+                if let Some(target) = ctx.restart_pc.take() {
+                    log::debug!("Restarting frame at logical line {}", target);
+                    pc = target;
+                    statement = 0;
+                    step_depth = None;
+                    restarted = true;
+                    continue 'run;
+                }
+
                 if ctx.continue_requested {
-                    eprintln!("✓ Continue requested, mode: {:?}", ctx.mode());
-                    if let Some(ref mut f) = log {
-                        writeln!(f, "✓ Continue requested, mode: {:?}", ctx.mode()).ok();
-                        f.flush().ok();
-                    }
+                    log::debug!("Continue requested, mode: {:?}", ctx.mode());
 
                     // Update step_depth based on mode
                     match ctx.mode() {
@@ -269,48 +307,133 @@ pub fn run_debugger_dap(
                         RunMode::StepInto => {
                             step_depth = None;
                         }
-                        RunMode::StepOut => {
+                        RunMode::RunToReturn { .. } => {
                             step_depth = None;
                         }
                     }
                     break;
                 }
+
+                let wakeup = Arc::clone(&ctx.wakeup);
+                drop(ctx);
+                wakeup.wait();
+            }
+
+            log::trace!("Exited wait loop, continuing execution");
+        }
+
+        // Record the line about to run
+        match ctx_arc.lock() {
+            Ok(mut ctx) => {
+                let before = ctx.snapshot(pc);
+                ctx.history.begin(&source, before);
+                if source == program {
+                    ctx.coverage.insert(pc);
+                }
+            }
+            Err(e) => {
+                log::error!("Failed to lock context: {}", e);
+                break 'run;
             }
+        }
+
+        // SET /P would block on cmd.exe's stdin, which nobody is attached to.
+        // The answer comes from the launch's inputValues, or else the client
+        // supplies it (an `input` request or a Debug Console entry) while the
+        // script waits. Forms reading a file, like `set /p X=<file`, never
+        // touch the console and run in cmd.exe like any other line.
+        if let Some((var, prompt)) = parse_set_prompt(&line) {
+            let preset = match ctx_arc.lock() {
+                Ok(mut ctx) => {
+                    let preset = ctx.input_values.get(&var.to_uppercase()).cloned();
+                    if preset.is_none() {
+                        ctx.pending_input = None;
+                        ctx.awaiting_input = true;
+                    }
+                    preset
+                }
+                Err(e) => {
+                    log::error!("Failed to lock context: {}", e);
+                    break 'run;
+                }
+            };
+
+            let value = match preset {
+                Some(value) => {
+                    let _ = events.send(Incoming::Output(format!("{}{}\r\n", prompt, value)));
+                    value
+                }
+                None => {
+                    let _ = events.send(Incoming::Prompt(format!(
+                        "{}\r\n(waiting for input: type a value in the Debug Console)\r\n",
+                        prompt
+                    )));
+                    loop {
+                        let mut ctx = match ctx_arc.lock() {
+                            Ok(c) => c,
+                            Err(e) => {
+                                log::error!("Failed to lock context during input wait: {}", e);
+                                break 'run;
+                            }
+                        };
+                        if ctx.stop_requested() {
+                            log::debug!("Client disconnected or cancelled while waiting for input");
+                            break 'run;
+                        }
+                        if let Some(value) = ctx.pending_input.take() {
+                            break value;
+                        }
+                        let wakeup = Arc::clone(&ctx.wakeup);
+                        drop(ctx);
+                        wakeup.wait();
+                    }
+                }
+            };
+
+            let mut ctx = match ctx_arc.lock() {
+                Ok(c) => c,
+                Err(e) => {
+                    log::error!("Failed to lock context for execution: {}", e);
+                    break 'run;
+                }
+            };
 
-            if let Some(ref mut f) = log {
-                writeln!(f, "  Exited wait loop, continuing execution").ok();
-                f.flush().ok();
+            // SET /P reads one line. Like cmd, an empty reply leaves the
+            // variable untouched and fails.
+            let value = value.lines().next().unwrap_or_default();
+            if value.is_empty() {
+                ctx.last_exit_code = 1;
+            } else {
+                ctx.set_variable(&var, value)?;
+                ctx.last_exit_code = 0;
             }
+            pc += 1;
+            continue;
+        }
+
+        // Resuming from the PAUSE stop was the keypress
+        if pause_line {
+            pc += 1;
+            continue;
         }
 
         // Execute the line
         {
-            if let Some(ref mut f) = log {
-                writeln!(f, "  Executing line: '{}'", line).ok();
-                f.flush().ok();
-            }
+            log::trace!("Executing line: '{}'", line);
 
             let mut ctx = match ctx_arc.lock() {
                 Ok(c) => c,
                 Err(e) => {
-                    eprintln!("❌ Failed to lock context for execution: {}", e);
-                    if let Some(ref mut f) = log {
-                        writeln!(f, "❌ Failed to lock context for execution: {}", e).ok();
-                        f.flush().ok();
-                    }
+                    log::error!("Failed to lock context for execution: {}", e);
                     break 'run;
                 }
             };
 
             // Handle SETLOCAL
             if line_upper.starts_with("SETLOCAL") {
-                ctx.handle_setlocal();
-                let (out, code) = ctx.run_command(&line)?;
-                if !out.trim().is_empty() {
-                    if let Err(e) = output_tx.send(out.clone()) {
-                        eprintln!("❌ Failed to send output: {}", e);
-                    }
-                }
+                ctx.handle_setlocal_command(&line);
+                let (out, code) = ctx.run_line_command(pc, &line)?;
+                send_output(&mut ctx, &events, &out);
                 ctx.last_exit_code = code;
                 pc += 1;
                 continue;
@@ -319,110 +442,249 @@ pub fn run_debugger_dap(
             // Handle ENDLOCAL
             if line_upper.starts_with("ENDLOCAL") {
                 ctx.handle_endlocal();
-                let (out, code) = ctx.run_command(&line)?;
-                if !out.trim().is_empty() {
-                    if let Err(e) = output_tx.send(out.clone()) {
-                        eprintln!("❌ Failed to send output: {}", e);
-                    }
-                }
+                let (out, code) = ctx.run_line_command(pc, &line)?;
+                send_output(&mut ctx, &events, &out);
                 ctx.last_exit_code = code;
                 pc += 1;
                 continue;
             }
 
-            // CALL :label
-            if line_upper.starts_with("CALL ") {
+            // CALL other.bat steps into the script when asked to; otherwise it
+            // is an ordinary command and runs through the session below
+            if ctx.step_into_external {
+                if let Some((path, args)) = external_call_target(&line, &source) {
+                    let key = path.to_string_lossy().into_owned();
+                    if !scripts.contains_key(&key) {
+                        match load_script(&path) {
+                            Ok(script) => {
+                                ctx.scripts.insert(key.clone(), script.0.clone());
+                                scripts.insert(key.clone(), script);
+                            }
+                            Err(e) => {
+                                log::warn!("Cannot step into {}: {}", key, e);
+                            }
+                        }
+                    }
+                    if scripts.contains_key(&key) {
+                        log::debug!("Stepping into {}", key);
+                        ctx.call_stack
+                            .push(Frame::external(key.clone(), pc + 1, Some(args)));
+                        ctx.current_source = key.clone();
+                        source = key;
+                        pc = 0;
+                        continue;
+                    }
+                }
+            }
+
+            // CALL :label; `call somecmd` is an ordinary command and runs
+            // through the session below
+            if is_label_call(&line) {
                 let rest = &line[5..].trim();
                 let mut lexer = shlex::Shlex::new(rest);
                 let first = lexer.next().unwrap_or_default();
-                let label_key = first.trim_start_matches(':').to_lowercase();
+                let label_key = label_name(&first).unwrap_or_default().to_lowercase();
                 let args: Vec<String> = lexer.collect();
 
-                if let Some(&phys_target) = labels_phys.get(&label_key) {
-                    let logical_target = pre.phys_to_logical[phys_target];
-                    ctx.call_stack.push(Frame::new(pc + 1, Some(args)));
+                if let Some(logical_target) = labels.resolve_label(pc, &label_key) {
+                    ctx.call_stack.push(Frame::called(
+                        &label_key,
+                        logical_target,
+                        pc + 1,
+                        Some(args),
+                    ));
                     pc = logical_target;
                 } else {
-                    eprintln!("❌ CALL to unknown label: {}", label_key);
+                    log::error!("CALL to unknown label: {}", label_key);
+                    let _ = events.send(Incoming::Output(format!(
+                        "Error: CALL to unknown label ':{}' at line {}\r\n",
+                        label_key,
+                        ll.phys_start + 1
+                    )));
                     break 'run;
                 }
                 continue;
             }
 
             // EXIT /B
-            if line_upper.starts_with("EXIT /B") {
-                let rest = &line[7..].trim();
-                let code: i32 = rest.parse::<i32>().unwrap_or(0);
+            if let Some(code) = parse_exit_b(&line) {
                 ctx.last_exit_code = code;
 
-                match leave_context(&mut ctx.call_stack) {
+                match leave_frame(&mut ctx, &program) {
                     Some(next_pc) => pc = next_pc,
                     None => break 'run,
                 }
+                source = ctx.current_source.clone();
                 continue;
             }
 
+            // EXIT ends the script; sent to the session it would end cmd.exe too
+            if let Some(code) = parse_exit(&line) {
+                ctx.last_exit_code = code;
+                break 'run;
+            }
+
             // GOTO
             if line_upper.starts_with("GOTO ") {
                 let rest = &line[5..].trim();
-                let label_key = rest
-                    .trim_start_matches(':')
-                    .split_whitespace()
-                    .next()
-                    .unwrap_or("")
-                    .to_lowercase();
+                let label_key = label_name(rest).unwrap_or_default().to_lowercase();
 
                 if label_key == "eof" {
-                    match leave_context(&mut ctx.call_stack) {
+                    match leave_frame(&mut ctx, &program) {
                         Some(next_pc) => pc = next_pc,
                         None => break 'run,
                     }
+                    source = ctx.current_source.clone();
                     continue;
                 }
 
-                if let Some(&phys_target) = labels_phys.get(&label_key) {
-                    let logical_target = pre.phys_to_logical[phys_target];
+                if let Some(logical_target) = labels.resolve_label(pc, &label_key) {
                     pc = logical_target;
                 } else {
-                    eprintln!("❌ GOTO to unknown label: {}", label_key);
+                    log::error!("GOTO to unknown label: {}", label_key);
+                    let _ = events.send(Incoming::Output(format!(
+                        "Error: GOTO to unknown label ':{}' at line {}\r\n",
+                        label_key,
+                        ll.phys_start + 1
+                    )));
                     break 'run;
                 }
                 continue;
             }
 
-            // Execute normal command
-            eprintln!("▶️ Executing: {}", line);
-            ctx.track_set_command(&line);
+            // SHIFT [/n] renumbers the subroutine's arguments
+            if let Some(from) = parse_shift(&line) {
+                if let Some(frame) = ctx.call_stack.last_mut() {
+                    frame.shift(from);
+                }
+                pc += 1;
+                continue;
+            }
+
+            if let Some(if_block) = &if_block {
+                pc = enter_if_block(&mut ctx, pc, if_block)?;
+                log::debug!("IF continues at line {}", pc);
+                continue;
+            }
 
-            if let Some(ref mut f) = log {
-                writeln!(f, "  About to run_command: '{}'", line).ok();
-                f.flush().ok();
+            if let Some(for_loop) = for_loop {
+                log::debug!("Stepping FOR loop at line {} per iteration", pc);
+                pc = enter_for_loop(&mut ctx, for_loop);
+                continue;
+            }
+
+            if let Some((block_lines, block_pc)) = &block {
+                log::debug!("Executing block at line {}", pc);
+                // Expand %0, and positional args if inside a subroutine
+                let block_lines: Vec<String> = block_lines
+                    .iter()
+                    .map(|l| expand_frame_args(&ctx, bind_for_vars(&ctx, l.clone())))
+                    .collect();
+                let ran = block_lines_run(&mut ctx, pre, pc, *block_pc, &block_lines[0])?;
+                let (out, code, exit) = run_block_with_exits(&mut ctx, pc, &block_lines)?;
+                if source == program {
+                    ctx.coverage.extend(ran.iter().copied());
+                }
+                send_output(&mut ctx, &events, &out);
+                ctx.last_exit_code = code;
+                for block_line in &block_lines {
+                    ctx.track_set_readback(block_line)?;
+                }
+                // A PUSHD or POPD in the branch that didn't run never touched the stack
+                for i in ran {
+                    ctx.track_dir_command(&block_lines[i - pc], code)?;
+                }
+                ctx.active_block = None;
+                if let Some(code) = exit {
+                    ctx.last_exit_code = code;
+                    break 'run;
+                }
+                pc = *block_pc;
+                continue;
             }
 
-            match ctx.run_command(&line) {
-                Ok((out, code)) => {
-                    if let Some(ref mut f) = log {
-                        writeln!(f, "  Command executed, exit code: {}", code).ok();
-                        f.flush().ok();
+            // Stepping by statement runs a composite command one part at a time
+            if let Some(parts) = statements
+                .as_ref()
+                .filter(|_| mid_line_or_statement(&ctx, statement))
+            {
+                let part = &parts[statement].raw_text;
+                log::debug!("Executing statement {} of line {}: {}", statement, pc, part);
+                report_written_files(
+                    &events,
+                    std::slice::from_ref(&parts[statement]),
+                    ll.phys_start + 1,
+                );
+                ctx.track_set_command(part);
+                let (out, code, exit) = match redirect_exits(std::slice::from_ref(part)) {
+                    Some(redirected) => run_redirected(&mut ctx, pc, &redirected)?,
+                    None => {
+                        let (out, code) = ctx.run_line_command(pc, part)?;
+                        (out, code, None)
                     }
+                };
+                send_output(&mut ctx, &events, &out);
+                ctx.last_exit_code = code;
+                ctx.track_set_readback(part)?;
+                ctx.track_dir_command(part, code)?;
+                if let Some(code) = exit {
+                    ctx.last_exit_code = code;
+                    break 'run;
+                }
 
-                    if !out.trim().is_empty() {
-                        if let Err(e) = output_tx.send(out.clone()) {
-                            eprintln!("❌ Failed to send output: {}", e);
-                            if let Some(ref mut f) = log {
-                                writeln!(f, "❌ Failed to send output: {}", e).ok();
-                                f.flush().ok();
-                            }
+                statement += 1;
+                if statement >= parts.len() {
+                    statement = 0;
+                    pc += 1;
+                }
+                continue;
+            }
+
+            // Execute normal command
+            let line = expand_frame_args(&ctx, line);
+            log::debug!("Executing: {}", line);
+
+            // SETs inside a pipeline run in child shells and never reach our env
+            let parts = split_composite_command(&line);
+            let is_pipeline = parts.iter().any(|p| p.op == Some(CommandOp::Pipe));
+            if !is_pipeline {
+                ctx.track_set_command(&line);
+            }
+            // Redirected output goes to its file, not to the client, so say
+            // where it went
+            report_written_files(&events, &parts, ll.phys_start + 1);
+
+            log::trace!("About to run_command: '{}'", line);
+
+            // An EXIT after `&` or as an IF's command would end cmd.exe, so
+            // the line runs as a block that leaves it at the EXIT instead
+            let redirected = redirect_exits(std::slice::from_ref(&line)).filter(|_| !is_pipeline);
+            let result = match &redirected {
+                Some(redirected) => run_redirected(&mut ctx, pc, redirected),
+                None => ctx
+                    .run_line_command(pc, &line)
+                    .map(|(out, code)| (out, code, None)),
+            };
+            match result {
+                Ok((out, code, exit)) => {
+                    log::trace!("Command executed, exit code: {}", code);
+
+                    send_output(&mut ctx, &events, &out);
+                    ctx.last_exit_code = code;
+
+                    if !is_pipeline {
+                        for part in &parts {
+                            ctx.track_set_readback(&part.raw_text)?;
+                            ctx.track_dir_command(&part.raw_text, code)?;
                         }
                     }
-                    ctx.last_exit_code = code;
+                    if let Some(code) = exit {
+                        ctx.last_exit_code = code;
+                        break 'run;
+                    }
                 }
                 Err(e) => {
-                    eprintln!("❌ Command execution error: {}", e);
-                    if let Some(ref mut f) = log {
-                        writeln!(f, "❌ Command execution error: {}", e).ok();
-                        f.flush().ok();
-                    }
+                    log::error!("Command execution error: {}", e);
                     break 'run;
                 }
             }
@@ -431,15 +693,163 @@ pub fn run_debugger_dap(
         pc += 1;
     }
 
-    eprintln!("✅ DAP: Script execution completed");
-    if let Some(ref mut f) = log {
-        writeln!(f, "✅ DAP: Script execution completed").ok();
-        f.flush().ok();
+    log::debug!("Script execution completed");
+
+    // The last line run never reached another stop check
+    if let Ok(mut ctx) = ctx_arc.lock() {
+        let exit_code = ctx.last_exit_code;
+        ctx.history.finish(exit_code);
     }
 
     // Send a final "terminated" event through the channel
     // This will help VS Code know the script has finished
-    let _ = event_tx.send(("terminated".to_string(), 0));
+    let _ = events.send(Incoming::Terminated);
 
     Ok(())
 }
+
+/// Tell the client which files the parts of the command on `line` write to
+fn report_written_files(events: &Sender<Incoming>, parts: &[CommandPart], line: usize) {
+    for file in parts.iter().flat_map(|p| p.written_files()) {
+        log::debug!("Line {} writes to {}", line, file);
+        let _ = events.send(Incoming::Console(format!(
+            "Line {} writes to {}\r\n",
+            line, file
+        )));
+    }
+}
+
+/// Forward command output to the client and into the step history
+fn send_output(ctx: &mut DebugContext, events: &Sender<Incoming>, out: &str) {
+    if out.trim().is_empty() {
+        return;
+    }
+    ctx.history.record_output(out);
+    if let Err(e) = events.send(Incoming::Output(out.to_string())) {
+        log::error!("Failed to send output: {}", e);
+    }
+}
+
+/// Whether the line at hand runs part by part: it is already partway through,
+/// or the last step asked for statement granularity
+fn mid_line_or_statement(ctx: &DebugContext, statement: usize) -> bool {
+    statement > 0 || ctx.granularity() == StepGranularity::Statement
+}
+
+/// Parts of a composite command that can run one at a time. Pipelines need
+/// the shell to connect their parts, the body after `IF`/`FOR` belongs to the
+/// command, and control flow the executor handles itself only works as a
+/// whole line, so those run as one statement.
+fn statements_of(line: &str) -> Option<Vec<CommandPart>> {
+    let parts = split_composite_command(line);
+    let plain = parts.len() > 1
+        && parts.iter().all(|part| {
+            let upper = part.text.trim_start_matches('@').to_uppercase();
+            part.op != Some(CommandOp::Pipe)
+                && !upper.starts_with('(')
+                && ![
+                    "IF ", "FOR ", "CALL ", "GOTO ", "EXIT", "SETLOCAL", "ENDLOCAL", "SHIFT",
+                    "PAUSE", "SET /P",
+                ]
+                .iter()
+                .any(|keyword| upper.starts_with(keyword))
+        });
+    plain.then_some(parts)
+}
+
+/// 1-based column of part `statement` in the line's source text
+fn statement_column(raw: &str, statement: usize) -> Option<usize> {
+    split_composite_command(raw)
+        .get(statement)
+        .map(|part| part.offset + 1)
+}
+
+/// A preprocessed script and its label map
+type Script = (Arc<PreprocessResult>, Arc<LabelMap>);
+
+/// Pop the innermost frame, switching back to the caller's script if the frame
+/// was a CALL into another batch file. Returns the pc to resume at.
+fn leave_frame(ctx: &mut DebugContext, program: &str) -> Option<usize> {
+    let frame = ctx.call_stack.pop()?;
+    if frame.source.is_some() {
+        ctx.current_source = ctx
+            .source_at_depth(ctx.call_stack.len(), program)
+            .to_string();
+    }
+    Some(frame.return_pc)
+}
+
+/// Resolve `CALL other.bat args` to the script's path (relative paths are
+/// looked up next to `caller`, then in the working directory) and its arguments
+fn external_call_target(line: &str, caller: &str) -> Option<(std::path::PathBuf, Vec<String>)> {
+    let l = line.trim_start().trim_start_matches('@');
+    if !l.to_ascii_uppercase().starts_with("CALL ") || is_label_call(l) {
+        return None;
+    }
+
+    let mut args = parser::split_call_args(&l[5..]).into_iter();
+    let quoted = args.next()?;
+    let target = quoted.trim_matches('"');
+    let is_batch = Path::new(target)
+        .extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| e.eq_ignore_ascii_case("bat") || e.eq_ignore_ascii_case("cmd"));
+    if !is_batch {
+        return None;
+    }
+
+    let beside_caller = Path::new(caller)
+        .parent()
+        .map(|dir| dir.join(target))
+        .filter(|p| p.is_file());
+    let path =
+        beside_caller.or_else(|| Some(Path::new(target).to_path_buf()).filter(|p| p.is_file()))?;
+    Some((path, args.collect()))
+}
+
+fn load_script(path: &Path) -> io::Result<Script> {
+    let contents = std::fs::read_to_string(path)?;
+    let physical_lines: Vec<&str> = contents.lines().collect();
+    let pre = parser::preprocess_lines(&physical_lines);
+    let labels = parser::build_label_map(&pre);
+    Ok((Arc::new(pre), Arc::new(labels)))
+}
+
+/// Split `SET /P VAR=prompt` (optionally quoted) into the variable name and
+/// prompt text. `None` when stdin is redirected, as the answer then comes
+/// from a file rather than the console.
+pub fn parse_set_prompt(line: &str) -> Option<(String, String)> {
+    let l = line.trim_start().trim_start_matches('@');
+    let upper = l.to_uppercase();
+    if !upper.starts_with("SET /P") {
+        return None;
+    }
+    let parts = split_composite_command(l);
+    let reads_file = parts.first().is_some_and(|part| {
+        part.redirections
+            .iter()
+            .any(|r| r.handle == 0 && r.op == RedirectOp::Read)
+    });
+    if reads_file {
+        return None;
+    }
+
+    let rest = l[6..].trim();
+    let rest = if rest.starts_with('"') && rest.ends_with('"') && rest.len() >= 2 {
+        &rest[1..rest.len() - 1]
+    } else {
+        rest
+    };
+
+    let eq_pos = rest.find('=')?;
+    let var = rest[..eq_pos].trim().trim_matches('"');
+    if var.is_empty() {
+        return None;
+    }
+    Some((var.to_string(), rest[eq_pos + 1..].to_string()))
+}
+
+fn is_pause(line_upper: &str) -> bool {
+    let l = line_upper.trim_start().trim_start_matches('@');
+    l == "PAUSE" || l.starts_with("PAUSE ") || l.starts_with("PAUSE>")
+}
@@ -1,5 +1,8 @@
+mod condition;
 mod dap_runner;
 mod runner;
 
-pub use dap_runner::run_debugger_dap;
-pub use runner::run_debugger;
+pub use crate::parser::paren_delta;
+pub use condition::evaluate_if_condition;
+pub use dap_runner::{parse_set_prompt, run_debugger_dap};
+pub use runner::{expand_positional_args, is_skippable, resolve_breakpoint, run_debugger};
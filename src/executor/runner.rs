@@ -1,68 +1,763 @@
-use crate::debugger::{leave_context, DebugContext, Frame, RunMode};
+use crate::debugger::{leave_context, DebugContext, ForLoop, Frame, RunMode};
 use crate::parser::{
-    is_comment, normalize_whitespace, split_composite_command, CommandOp, PreprocessResult,
+    is_comment, label_name, normalize_whitespace_outside_quotes, paren_delta, parse_for_header,
+    parse_if_condition, split_composite_command, syntax_chars, CommandOp, CommandPart, LabelMap,
+    PreprocessResult,
 };
-use std::collections::HashMap;
 use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf, MAIN_SEPARATOR};
 
-/// Compute net parenthesis delta for a line, honoring quotes and ^ escapes
-fn paren_delta(line: &str) -> i32 {
-    let mut delta = 0i32;
-    let mut in_quotes = false;
-    let mut escaped = false;
+/// Whether the executors pass over `line` without stopping or running it:
+/// blank lines, `REM` and `::` comments, and `:label` definitions
+pub fn is_skippable(line: &str) -> bool {
+    is_comment(line) || line.trim().starts_with(':')
+}
 
-    for ch in line.chars() {
-        if escaped {
-            escaped = false;
-            continue;
+/// Setup lines at the top of most scripts: `@echo off` and `setlocal ...`
+pub(crate) fn is_housekeeping(line: &str) -> bool {
+    let line = line.trim().trim_start_matches('@').to_lowercase();
+    let mut words = line.split_whitespace();
+    matches!(
+        (words.next(), words.next(), words.next()),
+        (Some("echo"), Some("off" | "on"), None) | (Some("setlocal"), ..)
+    )
+}
+
+/// Expand `%0`..`%9`, `%*` and `%~<modifiers>N` (`%~1`, `%~f1`, `%~dp1`, ...)
+/// against the frame's arguments. `%0` is the script itself.
+pub fn expand_positional_args(text: String, args: &[String], script: &str) -> String {
+    expand_args(text, script, Some(args))
+}
+
+/// Expand only `%0` and `%~<modifiers>0`, for code outside any CALL frame
+fn expand_script_name(text: String, script: &str) -> String {
+    expand_args(text, script, None)
+}
+
+/// Argument expansion in one pass, so a value containing `%` is never expanded
+/// again. Without `args`, `%1`..`%9` and `%*` are left for cmd.exe. `%%` is
+/// left alone as a FOR-style escape.
+fn expand_args(text: String, script: &str, args: Option<&[String]>) -> String {
+    if !text.contains('%') {
+        return text;
+    }
+
+    let arg = |n: u32| -> Option<&str> {
+        if n == 0 {
+            return Some(script);
         }
-        if ch == '^' {
-            escaped = true;
+        args.map(|a| a.get(n as usize - 1).map_or("", String::as_str))
+    };
+
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = String::with_capacity(text.len());
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i] != '%' {
+            out.push(chars[i]);
+            i += 1;
             continue;
         }
-        if ch == '"' {
+        match chars.get(i + 1) {
+            Some('%') => {
+                out.push_str("%%");
+                i += 2;
+                continue;
+            }
+            Some('*') => {
+                if let Some(args) = args {
+                    out.push_str(&args.join(" "));
+                    i += 2;
+                    continue;
+                }
+            }
+            Some(c) if c.is_ascii_digit() => {
+                if let Some(value) = c.to_digit(10).and_then(arg) {
+                    out.push_str(value);
+                    i += 2;
+                    continue;
+                }
+            }
+            Some('~') => {
+                let mods_start = i + 2;
+                let mut j = mods_start;
+                while j < chars.len() && "fdpnxsatzFDPNXSATZ".contains(chars[j]) {
+                    j += 1;
+                }
+                let value = chars.get(j).and_then(|c| c.to_digit(10)).and_then(arg);
+                if let Some(value) = value {
+                    let mods: String = chars[mods_start..j].iter().collect();
+                    out.push_str(&apply_path_modifiers(&mods.to_lowercase(), value));
+                    i = j + 1;
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        out.push('%');
+        i += 1;
+    }
+    out
+}
+
+/// Full path of `path` like `%~f` reports it: resolved through the file system
+/// when it exists, otherwise made absolute against the working directory
+fn full_path(path: &str) -> PathBuf {
+    match std::fs::canonicalize(path) {
+        // Windows canonical paths carry a `\\?\` prefix cmd.exe never shows
+        Ok(canonical) => match canonical.to_str().and_then(|p| p.strip_prefix(r"\\?\")) {
+            Some(plain) => PathBuf::from(plain),
+            None => canonical,
+        },
+        Err(_) => std::path::absolute(path).unwrap_or_else(|_| Path::new(path).to_path_buf()),
+    }
+}
+
+/// The `d`, `p`, `n`, `x` and `f` parts of a `%~` expansion; `s`, `a`, `t`
+/// and `z` need file system metadata cmd.exe has and we don't, so they're ignored.
+fn apply_path_modifiers(mods: &str, path: &str) -> String {
+    let path = path.trim_matches('"');
+    if path.is_empty() || !mods.contains(['f', 'd', 'p', 'n', 'x']) {
+        return path.to_string();
+    }
+
+    let full = full_path(path);
+    let wants_part = mods.contains(['d', 'p', 'n', 'x']);
+    if !wants_part {
+        return full.display().to_string();
+    }
+
+    let mut out = String::new();
+    let mut components = full.components();
+    let drive = match components.next() {
+        Some(Component::Prefix(prefix)) => prefix.as_os_str().to_string_lossy().into_owned(),
+        _ => String::new(),
+    };
+    if mods.contains('d') {
+        out.push_str(&drive);
+    }
+    if mods.contains('p') {
+        let parent = full
+            .parent()
+            .map(|p| p.display().to_string())
+            .unwrap_or_default();
+        let mut dir = parent[drive.len().min(parent.len())..].to_string();
+        if !dir.ends_with(MAIN_SEPARATOR) {
+            dir.push(MAIN_SEPARATOR);
+        }
+        out.push_str(&dir);
+    }
+    if mods.contains('n') {
+        if let Some(stem) = full.file_stem() {
+            out.push_str(&stem.to_string_lossy());
+        }
+    }
+    if mods.contains('x') {
+        if let Some(ext) = full.extension() {
+            out.push('.');
+            out.push_str(&ext.to_string_lossy());
+        }
+    }
+    out
+}
+
+/// Resolve the argument of the prompt's `b` command to a logical line: either
+/// `:label` (the first line that runs after it) or a 1-based physical line number
+pub fn resolve_breakpoint(
+    arg: &str,
+    pre: &PreprocessResult,
+    labels: &LabelMap,
+) -> Result<usize, String> {
+    let arg = arg.trim();
+
+    if let Some(label) = arg.strip_prefix(':') {
+        let label_line = labels
+            .resolve_label(0, label)
+            .ok_or_else(|| format!("Unknown label :{}", label))?;
+        // The label line itself is skipped, so stop where execution actually lands
+        let mut logical = label_line + 1;
+        while let Some(ll) = pre.logical.get(logical) {
+            if !(ll.is_empty() || ll.is_comment() || ll.is_label()) {
+                break;
+            }
+            logical += 1;
+        }
+        return Ok(logical);
+    }
+
+    match arg.parse::<usize>() {
+        Ok(line) if line >= 1 && line <= pre.phys_to_logical.len() => {
+            Ok(pre.phys_to_logical[line - 1])
+        }
+        Ok(line) => Err(format!(
+            "Line {} is outside the script (1-{})",
+            line,
+            pre.phys_to_logical.len()
+        )),
+        Err(_) => Err(format!(
+            "Invalid breakpoint '{}': expected a line number or :label",
+            arg
+        )),
+    }
+}
+
+/// Whether `line` is `CALL :label ...` rather than a call to another program or batch file
+pub(crate) fn is_label_call(line: &str) -> bool {
+    let line = line.trim_start();
+    line.get(..5)
+        .is_some_and(|verb| verb.eq_ignore_ascii_case("call "))
+        && line[5..].trim_start().starts_with(':')
+}
+
+/// First argument position `SHIFT` or `SHIFT /n` moves (1 for a plain SHIFT,
+/// which also covers `/0`), or `None` if `line` is not a SHIFT
+pub(crate) fn parse_shift(line: &str) -> Option<usize> {
+    let line = line.trim_start().trim_start_matches('@');
+    let verb = line.get(..5)?;
+    if !verb.eq_ignore_ascii_case("shift") {
+        return None;
+    }
+    match line[5..].trim() {
+        "" => Some(1),
+        rest => {
+            let n = rest.strip_prefix('/')?;
+            match n.parse::<usize>() {
+                Ok(n) if n <= 8 => Some(n.max(1)),
+                _ => None,
+            }
+        }
+    }
+}
+
+/// Exit code of an `EXIT [code]` that ends the whole script (cmd.exe with
+/// it), or `None` if `line` is not one; `EXIT /B` only leaves the context
+pub(crate) fn parse_exit(line: &str) -> Option<i32> {
+    let args = exit_args(line)?;
+    if strip_exit_b(args).is_some() {
+        return None;
+    }
+    Some(args.split_whitespace().next().map_or(0, exit_code))
+}
+
+/// Exit code of an `EXIT /B [code]` (or `exit/b`), which leaves the current
+/// context, or `None` if `line` is not one
+pub(crate) fn parse_exit_b(line: &str) -> Option<i32> {
+    let rest = strip_exit_b(exit_args(line)?)?;
+    Some(rest.split_whitespace().next().map_or(0, exit_code))
+}
+
+/// What follows the verb of an EXIT command, which cmd.exe reads up to a
+/// space, a tab or the `/` of `/B`
+fn exit_args(line: &str) -> Option<&str> {
+    let line = line.trim_start().trim_start_matches('@');
+    let verb = line.get(..4)?;
+    let rest = &line[4..];
+    let ends_verb = rest.is_empty() || rest.starts_with([' ', '\t', '/']);
+    (verb.eq_ignore_ascii_case("exit") && ends_verb).then(|| rest.trim_start())
+}
+
+/// The arguments after `/B`, if they start with it
+fn strip_exit_b(args: &str) -> Option<&str> {
+    let flag = args.get(..2)?;
+    let rest = &args[2..];
+    (flag.eq_ignore_ascii_case("/b") && (rest.is_empty() || rest.starts_with([' ', '\t'])))
+        .then_some(rest)
+}
+
+fn exit_code(arg: &str) -> i32 {
+    arg.parse().unwrap_or(0)
+}
+
+/// Label prefix of the jumps `redirect_exits` puts in place of EXIT
+const EXIT_LABEL: &str = "__batch_debugger_exit_";
+
+/// Variable the code at those labels leaves the exit code in
+const EXIT_VAR: &str = "__BATCH_DEBUGGER_EXIT__";
+
+/// `lines` with every EXIT that would end cmd.exe, wherever it sits (after
+/// `&` or `||`, as an IF's or FOR's command, inside a block), replaced by a
+/// GOTO to code appended after them that records the exit code and leaves
+/// with `EXIT /B`. Run as a block, the lines then end the block where the
+/// script would have ended. `None` if there is no such EXIT.
+pub(crate) fn redirect_exits(lines: &[String]) -> Option<Vec<String>> {
+    let mut exits = Vec::new();
+    let mut redirected: Vec<String> = lines
+        .iter()
+        .map(|line| redirect_line_exits(line, &mut exits))
+        .collect();
+    if exits.is_empty() {
+        return None;
+    }
+    redirected.push("goto :eof".to_string());
+    for (i, code) in exits.iter().enumerate() {
+        redirected.push(format!(":{}{}", EXIT_LABEL, i));
+        redirected.push(format!("set \"{}={}\"", EXIT_VAR, code));
+        redirected.push(format!("exit /b {}", code));
+    }
+    Some(redirected)
+}
+
+/// `line` with its EXITs replaced by jumps to `EXIT_LABEL<n>`, pushing each
+/// one's code (as written, so `%RC%` expands when it runs) onto `exits`
+fn redirect_line_exits(line: &str, exits: &mut Vec<String>) -> String {
+    let chars: Vec<(usize, char, bool)> = syntax_chars(line).collect();
+    let offset = |i: usize| chars.get(i).map_or(line.len(), |&(at, _, _)| at);
+    let index_of = |at: usize| {
+        chars
+            .iter()
+            .position(|&(o, _, _)| o >= at)
+            .unwrap_or(chars.len())
+    };
+    let ends_word =
+        |&(_, c, live): &(usize, char, bool)| live && (c.is_whitespace() || "&|()<>".contains(c));
+    let ends_command = |&(_, c, live): &(usize, char, bool)| live && "&|)".contains(c);
+
+    let mut out = String::new();
+    let mut copied = 0;
+    let mut i = 0;
+    // Each pass starts where a command may begin
+    while i < chars.len() {
+        while chars
+            .get(i)
+            .is_some_and(|&(_, c, live)| c.is_whitespace() || (live && "@(".contains(c)))
+        {
+            i += 1;
+        }
+        let start = offset(i);
+        let word_end = (i..chars.len())
+            .find(|&j| ends_word(&chars[j]))
+            .unwrap_or(chars.len());
+        let word = line[start..offset(word_end)].to_ascii_lowercase();
+
+        if word == "if" {
+            let condition = &line[offset(word_end)..];
+            if let Some((_, command)) = parse_if_condition(condition) {
+                i = index_of(line.len() - command.len());
+                continue;
+            }
+        } else if word == "for" {
+            // The command follows the `do` after the closing `)` of the set
+            let set_end = (word_end..chars.len()).find(|&j| chars[j].2 && chars[j].1 == ')');
+            let command = set_end.and_then(|j| {
+                let rest = line[offset(j + 1)..].trim_start();
+                let after = rest
+                    .get(..2)?
+                    .eq_ignore_ascii_case("do")
+                    .then(|| &rest[2..])?;
+                after
+                    .starts_with(|c: char| c.is_whitespace() || c == '(')
+                    .then(|| line.len() - after.len())
+            });
+            if let Some(command) = command {
+                i = index_of(command);
+                continue;
+            }
+        } else if word == "exit" || word.starts_with("exit/") {
+            let end = (i..chars.len())
+                .find(|&j| ends_command(&chars[j]))
+                .unwrap_or(chars.len());
+            let statement = line[start..offset(end)].trim_end();
+            if parse_exit(statement).is_some() {
+                out.push_str(&line[copied..start]);
+                out.push_str(&format!("goto :{}{}", EXIT_LABEL, exits.len()));
+                let code = statement[4..].split_whitespace().next().unwrap_or("0");
+                exits.push(code.to_string());
+                copied = start + statement.len();
+            }
+        }
+
+        // On to the next command: after `&`, `&&`, `||` or `|`, or after a
+        // `)` that closes a group, where an `else` may follow
+        i = (i..chars.len())
+            .find(|&j| ends_command(&chars[j]))
+            .unwrap_or(chars.len());
+        match chars.get(i) {
+            Some(&(_, ')', _)) => {
+                i += 1;
+                let rest = &line[offset(i)..];
+                let after_else = rest
+                    .trim_start()
+                    .get(..4)
+                    .filter(|w| w.eq_ignore_ascii_case("else"));
+                if after_else.is_some() {
+                    let at = line.len() - rest.trim_start().len() + 4;
+                    i = index_of(at);
+                }
+            }
+            Some(_) => {
+                while chars
+                    .get(i)
+                    .is_some_and(|&(_, c, live)| live && "&|".contains(c))
+                {
+                    i += 1;
+                }
+            }
+            None => {}
+        }
+    }
+    out.push_str(&line[copied..]);
+    out
+}
+
+/// Run `lines` as one block in the session with their EXITs redirected (see
+/// `redirect_exits`), so none of them ends cmd.exe. Also returns the code of
+/// the EXIT that ran, if one did.
+pub(crate) fn run_block_with_exits(
+    ctx: &mut DebugContext,
+    pc: usize,
+    lines: &[String],
+) -> io::Result<(String, i32, Option<i32>)> {
+    match redirect_exits(lines) {
+        Some(redirected) => run_redirected(ctx, pc, &redirected),
+        None => {
+            let (out, code) = ctx.run_line_block(pc, lines)?;
+            Ok((out, code, None))
+        }
+    }
+}
+
+/// Run lines `redirect_exits` produced as a block, returning the code of the
+/// EXIT that ran, if one did, after the output and exit code
+pub(crate) fn run_redirected(
+    ctx: &mut DebugContext,
+    pc: usize,
+    redirected: &[String],
+) -> io::Result<(String, i32, Option<i32>)> {
+    let (out, code) = ctx.run_line_block(pc, redirected)?;
+    // IF and ECHO leave ERRORLEVEL alone for whatever runs next
+    let (recorded, _) = ctx.run_command(&format!("if defined {0} echo %{0}%", EXIT_VAR))?;
+    let recorded = recorded.trim();
+    if recorded.is_empty() {
+        return Ok((out, code, None));
+    }
+    ctx.run_command(&format!("set \"{}=\"", EXIT_VAR))?;
+    Ok((out, code, Some(exit_code(recorded))))
+}
+
+/// Gather the parenthesized block opening at logical line `start`; returns its
+/// lines and the logical line just past the closing parenthesis
+pub(crate) fn collect_block(pre: &PreprocessResult, start: usize) -> (Vec<String>, usize) {
+    let first = &pre.logical[start].text;
+    let mut block_lines = vec![first.clone()];
+    let mut block_pc = start + 1;
+    let mut balance = paren_delta(first);
+
+    while balance > 0 && block_pc < pre.logical.len() {
+        let b = &pre.logical[block_pc];
+        block_lines.push(b.text.clone());
+        balance += paren_delta(&b.text);
+        block_pc += 1;
+    }
+    (block_lines, block_pc)
+}
+
+/// FOR loop opening at logical line `pc` that can be stepped per iteration: a
+/// header `parse_for_header` understands, with a body closed by a lone `)`.
+/// Bodies using `%VAR%`, which cmd expands once for the whole loop, still run
+/// as one block like every other form.
+pub(crate) fn for_loop_at(
+    pre: &PreprocessResult,
+    pc: usize,
+    line: &str,
+    depth: usize,
+) -> Option<ForLoop> {
+    let header = parse_for_header(line)?;
+    let (_, block_pc) = collect_block(pre, pc);
+    let body_end = block_pc - 1;
+    if body_end <= pc || pre.logical[body_end].text.trim() != ")" {
+        return None;
+    }
+    if pre.logical[pc + 1..body_end]
+        .iter()
+        .any(|l| expands_at_parse_time(&l.text))
+    {
+        return None;
+    }
+    Some(ForLoop {
+        var: header.var,
+        items: header.items,
+        index: 0,
+        depth,
+        body_start: pc + 1,
+        body_end,
+    })
+}
+
+/// Parenthesized IF whose branches run line by line instead of as one block
+#[derive(Debug, Clone)]
+pub(crate) struct IfBlock {
+    /// The header without its opening `(`, e.g. `if "%X%"=="1"`
+    pub condition: String,
+    /// First line of the ELSE branch, if there is one
+    pub else_start: Option<usize>,
+    /// Logical line of the final `)`
+    pub end: usize,
+}
+
+/// IF block opening at logical line `pc` that can run line by line: a simple
+/// condition (no other parentheses, no `&` or `|`) and branches closed by
+/// lines that are just `)` or `) else (`. Like FOR loops, bodies using
+/// `%VAR%` still run as one block.
+pub(crate) fn if_block_at(pre: &PreprocessResult, pc: usize, line: &str) -> Option<IfBlock> {
+    let trimmed = line.trim().trim_start_matches('@');
+    let is_if = trimmed
+        .get(..3)
+        .is_some_and(|verb| verb.eq_ignore_ascii_case("if "));
+    let condition = trimmed.strip_suffix('(')?.trim_end();
+    if !is_if || has_unquoted(condition, &['(', ')', '&', '|']) {
+        return None;
+    }
+
+    let (_, block_pc) = collect_block(pre, pc);
+    let end = block_pc - 1;
+    // Lines closing a branch of this IF sit one level inside it
+    let depth = pre.logical[pc].group_depth + 1;
+    let closers: Vec<usize> = (pc + 1..block_pc)
+        .filter(|&i| {
+            pre.logical[i].group_depth == depth && pre.logical[i].text.trim().starts_with(')')
+        })
+        .collect();
+    let squashed = |i: usize| squash(&pre.logical[i].text);
+    let else_start = match closers[..] {
+        [last] if last == end && squashed(last) == ")" => None,
+        [mid, last] if last == end && squashed(mid) == ")else(" && squashed(last) == ")" => {
+            Some(mid + 1)
+        }
+        _ => return None,
+    };
+    if pre.logical[pc + 1..end]
+        .iter()
+        .any(|l| expands_at_parse_time(&l.text))
+    {
+        return None;
+    }
+
+    Some(IfBlock {
+        condition: condition.to_string(),
+        else_start,
+        end,
+    })
+}
+
+/// Evaluate the IF's condition in the session, which sees the same variables
+/// and ERRORLEVEL the script does; returns the pc of the branch to run.
+/// Tracked state can miss changes, so it never picks the branch.
+pub(crate) fn enter_if_block(
+    ctx: &mut DebugContext,
+    pc: usize,
+    block: &IfBlock,
+) -> io::Result<usize> {
+    let (out, _) = ctx.run_command(&format!("{} (echo 1) else (echo 0)", block.condition))?;
+    let taken = out.trim() == "1";
+    Ok(match (taken, block.else_start) {
+        (true, _) => pc + 1,
+        (false, Some(else_start)) => else_start,
+        (false, None) => block.end + 1,
+    })
+}
+
+/// Logical lines of the block from `pc` to `block_pc` that will run when it
+/// runs as one, with the session asked which branch an IF takes. Call it
+/// before running the block, while the condition still sees what cmd.exe
+/// will.
+pub(crate) fn block_lines_run(
+    ctx: &mut DebugContext,
+    pre: &PreprocessResult,
+    pc: usize,
+    block_pc: usize,
+    header: &str,
+) -> io::Result<Vec<usize>> {
+    let taken = match if_condition(header) {
+        Some(cond) => {
+            let (out, _) = ctx.run_command(&format!("{} (echo 1) else (echo 0)", cond))?;
+            Some(out.trim() == "1")
+        }
+        None => None,
+    };
+    Ok(branch_lines(pre, pc, block_pc, taken))
+}
+
+/// The header and the branch `taken` picks of an IF block, or the whole
+/// block when that isn't known
+fn branch_lines(
+    pre: &PreprocessResult,
+    pc: usize,
+    block_pc: usize,
+    taken: Option<bool>,
+) -> Vec<usize> {
+    let depth = pre.logical[pc].group_depth + 1;
+    let else_line = (pc + 1..block_pc).find(|&i| {
+        pre.logical[i].group_depth == depth && squash(&pre.logical[i].text).starts_with(")else")
+    });
+    match (taken, else_line) {
+        (Some(true), Some(else_line)) => (pc..else_line).collect(),
+        (Some(false), Some(else_line)) => std::iter::once(pc).chain(else_line..block_pc).collect(),
+        (Some(false), None) => vec![pc],
+        (Some(true), None) | (None, _) => (pc..block_pc).collect(),
+    }
+}
+
+/// Condition of an `IF ... (` header: the text before its first unquoted `(`
+fn if_condition(header: &str) -> Option<&str> {
+    let trimmed = header.trim().trim_start_matches('@');
+    let is_if = trimmed
+        .get(..3)
+        .is_some_and(|verb| verb.eq_ignore_ascii_case("if "));
+    let mut in_quotes = false;
+    let open = trimmed.find(|c| {
+        if c == '"' {
+            in_quotes = !in_quotes;
+        }
+        !in_quotes && c == '('
+    })?;
+    is_if.then(|| trimmed[..open].trim_end())
+}
+
+/// Where to go from a line closing an IF branch that ran line by line: past a
+/// lone `)`, and from `) else (` (the end of the taken THEN branch) past the
+/// ELSE branch. `None` for any other line.
+pub(crate) fn past_block_close(pre: &PreprocessResult, pc: usize) -> Option<usize> {
+    let ll = &pre.logical[pc];
+    match squash(&ll.text).as_str() {
+        ")" => Some(pc + 1),
+        ")else(" => (pc + 1..pre.logical.len())
+            .find(|&i| {
+                pre.logical[i].group_depth == ll.group_depth
+                    && pre.logical[i].text.trim().starts_with(')')
+            })
+            .map(|end| end + 1),
+        _ => None,
+    }
+}
+
+/// `text` lowercased with all whitespace removed, to match `) else (` in any spacing
+fn squash(text: &str) -> String {
+    text.chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_ascii_lowercase()
+}
+
+/// Whether any of `chars` appears in `text` outside double quotes
+fn has_unquoted(text: &str, chars: &[char]) -> bool {
+    let mut in_quotes = false;
+    text.chars().any(|c| {
+        if c == '"' {
             in_quotes = !in_quotes;
-            continue;
         }
-        if !in_quotes {
-            match ch {
-                '(' => delta += 1,
-                ')' => delta -= 1,
-                _ => {}
+        !in_quotes && chars.contains(&c)
+    })
+}
+
+/// Whether part `i` of a composite command runs, given the exit code of the
+/// part before it: `&&` needs success, `||` needs failure
+pub(crate) fn part_runs(parts: &[CommandPart], i: usize, code: i32) -> bool {
+    match i.checked_sub(1).and_then(|prev| parts[prev].op) {
+        Some(CommandOp::And) => code == 0,
+        Some(CommandOp::Or) => code != 0,
+        Some(CommandOp::Unconditional) | Some(CommandOp::Pipe) | None => true,
+    }
+}
+
+/// Whether `text` has a `%VAR%` reference or a `%%~` modifier, which cmd
+/// resolves when it reads a block rather than as each line runs
+fn expands_at_parse_time(text: &str) -> bool {
+    let chars: Vec<char> = text.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i] == '%' {
+            match chars.get(i + 1) {
+                Some('%') if chars.get(i + 2) == Some(&'~') => return true,
+                Some('%') => {
+                    i += 2;
+                    continue;
+                }
+                Some(c) if c.is_ascii_digit() || *c == '*' || *c == '~' => {}
+                Some(_) => return true,
+                None => {}
             }
         }
+        i += 1;
     }
-    delta
+    false
 }
 
-/// Minimal expander for %1..%9 and %~1..%~9 (strip surrounding quotes)
-fn expand_positional_args(mut text: String, args: &[String]) -> String {
-    // Replace higher numbers first to avoid %10 matching %1
-    for i in (1..=9).rev() {
-        let idx = i - 1;
-        let val = args.get(idx).cloned().unwrap_or_default();
-        let unquoted = val.trim_matches('"').to_string();
+/// Start stepping `for_loop`; returns the pc of its first body line, or the
+/// line after the loop when there is nothing to iterate over
+pub(crate) fn enter_for_loop(ctx: &mut DebugContext, for_loop: ForLoop) -> usize {
+    if for_loop.value().is_none() {
+        return for_loop.body_end + 1;
+    }
+    let pc = for_loop.body_start;
+    ctx.for_loops.push(for_loop);
+    pc
+}
 
-        text = text.replace(&format!("%~{}", i), &unquoted);
-        text = text.replace(&format!("%{}", i), &val);
+/// Loop bookkeeping before running `pc`: at a body's closing `)`, go round
+/// again or past the loop, and forget loops left by GOTO or by leaving their
+/// frame. Returns the pc to run.
+pub(crate) fn advance_for_loops(ctx: &mut DebugContext, mut pc: usize) -> usize {
+    let depth = ctx.call_stack.len();
+    while let Some(for_loop) = ctx.for_loops.last_mut() {
+        if for_loop.depth < depth {
+            break;
+        }
+        if for_loop.depth == depth && (for_loop.body_start..=for_loop.body_end).contains(&pc) {
+            if pc != for_loop.body_end {
+                break;
+            }
+            for_loop.index += 1;
+            if for_loop.value().is_some() {
+                pc = for_loop.body_start;
+                break;
+            }
+            pc = for_loop.body_end + 1;
+        }
+        ctx.for_loops.pop();
+    }
+    pc
+}
+
+/// Substitute the variables of the loops running in the current frame
+pub(crate) fn bind_for_vars(ctx: &DebugContext, mut text: String) -> String {
+    let depth = ctx.call_stack.len();
+    for for_loop in ctx.for_loops.iter().filter(|l| l.depth == depth) {
+        if let Some(value) = for_loop.value() {
+            text = text.replace(&format!("%%{}", for_loop.var), &value);
+        }
     }
     text
 }
 
+/// Expand positional parameters for the line about to run in the current frame
+pub(crate) fn expand_frame_args(ctx: &DebugContext, text: String) -> String {
+    let args = ctx
+        .call_stack
+        .last()
+        .and_then(|frame| frame.args.as_deref());
+    match args {
+        Some(a) => expand_positional_args(text, a, &ctx.current_source),
+        None => expand_script_name(text, &ctx.current_source),
+    }
+}
+
 pub fn run_debugger(
     ctx: &mut DebugContext,
     pre: &PreprocessResult,
-    labels_phys: &HashMap<String, usize>,
+    labels: &LabelMap,
 ) -> io::Result<()> {
     let mut pc: usize = 0;
     let mut step_depth: Option<usize> = None; // Track depth for StepOver
 
     'run: loop {
+        pc = advance_for_loops(ctx, pc);
+
         // EOF unwinding
-        while pc >= pre.logical.len() {
+        if pc >= pre.logical.len() {
             match leave_context(&mut ctx.call_stack) {
                 Some(next_pc) => {
                     pc = next_pc;
+                    continue;
                 }
                 None => {
                     break 'run;
@@ -72,25 +767,27 @@ pub fn run_debugger(
 
         let ll = &pre.logical[pc];
         let raw = ll.text.as_str();
-        let line = normalize_whitespace(raw.trim());
+        let line = bind_for_vars(ctx, normalize_whitespace_outside_quotes(raw));
         let line_upper = line.to_uppercase();
 
-        // Skip empty / comment lines
-        if is_comment(&line) {
+        // Skip empty lines, comments and label definitions
+        if ll.is_empty() || ll.is_comment() || ll.is_label() {
             pc += 1;
             continue;
         }
 
-        // Skip label definition lines
-        if line.trim().starts_with(':') {
-            pc += 1;
+        // Lines closing an IF branch that ran line by line
+        if let Some(next_pc) = past_block_close(pre, pc) {
+            pc = next_pc;
             continue;
         }
 
+        ctx.coverage.insert(pc);
+
         // Handle SETLOCAL
         if line_upper.starts_with("SETLOCAL") {
-            ctx.handle_setlocal();
-            let (out, code) = ctx.run_command(&line)?;
+            ctx.handle_setlocal_command(&line);
+            let (out, code) = ctx.run_line_command(pc, &line)?;
             if !out.trim().is_empty() {
                 print!("{}", out);
             }
@@ -102,7 +799,7 @@ pub fn run_debugger(
         // Handle ENDLOCAL
         if line_upper.starts_with("ENDLOCAL") {
             ctx.handle_endlocal();
-            let (out, code) = ctx.run_command(&line)?;
+            let (out, code) = ctx.run_line_command(pc, &line)?;
             if !out.trim().is_empty() {
                 print!("{}", out);
             }
@@ -115,22 +812,27 @@ pub fn run_debugger(
         let is_block_start = (line_upper.starts_with("IF ") || line_upper.starts_with("FOR "))
             && paren_delta(raw) > 0;
 
-        // Determine if we should stop at this line
-        let should_stop = match ctx.mode() {
-            RunMode::Continue => ctx.should_stop_at(pc),
-            RunMode::StepInto => true,
-            RunMode::StepOver => {
-                if let Some(target_depth) = step_depth {
-                    ctx.call_stack.len() <= target_depth
-                } else {
-                    true
+        // Determine if we should stop at this line; running out of steps always does
+        let over_budget = ctx.count_step();
+        let should_stop = over_budget
+            || match ctx.mode() {
+                RunMode::Continue => ctx.should_stop_at(&ctx.current_source, pc),
+                RunMode::StepInto => true,
+                RunMode::StepOver => {
+                    if let Some(target_depth) = step_depth {
+                        ctx.call_stack.len() <= target_depth
+                    } else {
+                        true
+                    }
                 }
-            }
-            RunMode::StepOut => ctx.should_stop_at(pc),
-        };
+                RunMode::RunToReturn { target_depth } => ctx.call_stack.len() <= target_depth,
+            };
 
         // Stop point UI
         if should_stop {
+            if over_budget {
+                eprintln!("\n⚠️  Step limit reached; the script may be stuck in a loop");
+            }
             eprintln!(
                 "\n🔍 Stopped at logical line {} (phys line {})",
                 pc,
@@ -143,9 +845,10 @@ pub fn run_debugger(
             }
 
             ctx.print_call_stack(&pre.logical);
+            print_watches(ctx);
 
             'prompt: loop {
-                eprintln!("\nCommands: (c)ontinue, (n)ext/stepOver, (s)tepIn, (o)ut/stepOut, (b)reakpoint <line>, (q)uit");
+                eprintln!("\nCommands: (c)ontinue, (n)ext/stepOver, (s)tepIn, (o)ut/stepOut, (b)reakpoint <line|:label>, (l)ist breakpoints, (w)atch <expr>, (q)uit");
                 eprint!("> ");
                 io::stderr().flush()?;
 
@@ -175,13 +878,29 @@ pub fn run_debugger(
                         break 'prompt;
                     }
                     "q" | "quit" => break 'run,
+                    "l" | "list" => {
+                        let lines = ctx.breakpoint_lines(&ctx.current_source);
+                        if lines.is_empty() {
+                            eprintln!("No breakpoints");
+                        }
+                        for logical in lines {
+                            let phys = pre.logical.get(logical).map_or(0, |ll| ll.phys_start + 1);
+                            eprintln!("  line {}", phys);
+                        }
+                    }
                     cmd if cmd.starts_with("b ") => {
-                        if let Ok(line_num) = cmd[2..].trim().parse::<usize>() {
-                            ctx.add_breakpoint(line_num);
-                        } else {
-                            eprintln!("❌ Invalid line number");
+                        match resolve_breakpoint(&cmd[2..], pre, labels) {
+                            Ok(logical) => {
+                                let source = ctx.current_source.clone();
+                                ctx.add_breakpoint(&source, logical);
+                            }
+                            Err(message) => eprintln!("❌ {}", message),
                         }
                     }
+                    cmd if cmd.starts_with("w ") => {
+                        ctx.add_watch(&cmd[2..]);
+                        print_watches(ctx);
+                    }
                     "" => {
                         // Empty input - step into by default
                         ctx.handle_step_command("stepInto");
@@ -204,20 +923,23 @@ pub fn run_debugger(
             continue;
         }
 
-        // CALL :label [args...]
-        if line_upper.starts_with("CALL ") {
+        // CALL :label [args...]; external CALLs run through the session like any command
+        if is_label_call(&line) {
             let rest = &line[5..].trim();
 
             // Use shlex to split once: first token is label, remaining tokens are args (quotes preserved)
             let mut lexer = shlex::Shlex::new(rest);
             let first = lexer.next().unwrap_or_default();
-            let label_key = first.trim_start_matches(':').to_lowercase();
+            let label_key = label_name(&first).unwrap_or_default().to_lowercase();
             let args: Vec<String> = lexer.collect();
 
-            if let Some(&phys_target) = labels_phys.get(&label_key) {
-                let logical_target = pre.phys_to_logical[phys_target];
-
-                ctx.call_stack.push(Frame::new(pc + 1, Some(args)));
+            if let Some(logical_target) = labels.resolve_label(pc, &label_key) {
+                ctx.call_stack.push(Frame::called(
+                    &label_key,
+                    logical_target,
+                    pc + 1,
+                    Some(args),
+                ));
 
                 eprintln!(
                     "\n📞 CALL to :{} (jumping to logical line {})",
@@ -232,9 +954,7 @@ pub fn run_debugger(
         }
 
         // EXIT /B
-        if line_upper.starts_with("EXIT /B") {
-            let rest = &line[7..].trim();
-            let code: i32 = rest.parse::<i32>().unwrap_or(0);
+        if let Some(code) = parse_exit_b(&line) {
             ctx.last_exit_code = code;
 
             eprintln!("\n🚪 EXIT /B {} (returning from subroutine)", code);
@@ -248,8 +968,17 @@ pub fn run_debugger(
             continue;
         }
 
+        // EXIT ends the script; sent to the session it would end cmd.exe too
+        if let Some(code) = parse_exit(&line) {
+            ctx.last_exit_code = code;
+            eprintln!("\n🚪 EXIT {} (ending the script)", code);
+            break 'run;
+        }
+
         // GOTO :EOF
-        if line_upper == "GOTO :EOF" {
+        if line_upper.starts_with("GOTO ")
+            && label_name(&line[5..]).is_some_and(|l| l.eq_ignore_ascii_case("eof"))
+        {
             eprintln!("\n↩️  GOTO :EOF (returning from subroutine)");
 
             match leave_context(&mut ctx.call_stack) {
@@ -264,15 +993,9 @@ pub fn run_debugger(
         // GOTO label
         if line_upper.starts_with("GOTO ") {
             let rest = &line[5..].trim();
-            let label_key = rest
-                .trim_start_matches(':')
-                .split_whitespace()
-                .next()
-                .unwrap_or("")
-                .to_lowercase();
-
-            if let Some(&phys_target) = labels_phys.get(&label_key) {
-                let logical_target = pre.phys_to_logical[phys_target];
+            let label_key = label_name(rest).unwrap_or_default().to_lowercase();
+
+            if let Some(logical_target) = labels.resolve_label(pc, &label_key) {
                 eprintln!(
                     "\n➡️  GOTO :{} (jumping to logical line {})",
                     label_key, logical_target
@@ -285,36 +1008,67 @@ pub fn run_debugger(
             continue;
         }
 
+        // SHIFT [/n] renumbers the subroutine's arguments
+        if let Some(from) = parse_shift(&line) {
+            if let Some(frame) = ctx.call_stack.last_mut() {
+                frame.shift(from);
+            }
+            pc += 1;
+            continue;
+        }
+
+        // IFs with a simple condition run the taken branch line by line
+        let header = expand_frame_args(ctx, line.clone());
+        if let Some(if_block) = if_block_at(pre, pc, &header) {
+            pc = enter_if_block(ctx, pc, &if_block)?;
+            eprintln!(
+                "\n🔀 IF at line {} continues at logical line {}",
+                ll.phys_start + 1,
+                pc
+            );
+            continue;
+        }
+
+        // Simple FOR loops run their body line by line, once per iteration
+        if let Some(for_loop) = for_loop_at(pre, pc, &header, ctx.call_stack.len()) {
+            eprintln!(
+                "\n🔁 Stepping FOR loop at line {} per iteration",
+                ll.phys_start + 1
+            );
+            pc = enter_for_loop(ctx, for_loop);
+            continue;
+        }
+
         // Handle block constructs (IF, FOR with parentheses)
         if is_block_start {
-            let mut block_lines = vec![raw.to_string()];
-            let mut block_pc = pc + 1;
-            let mut balance = paren_delta(raw);
-
             eprintln!("\n📦 Collecting block starting at line {}", pc);
+            let (mut block_lines, block_pc) = collect_block(pre, pc);
 
-            while balance > 0 && block_pc < pre.logical.len() {
-                let b = &pre.logical[block_pc];
-                block_lines.push(b.text.clone());
-                balance += paren_delta(&b.text);
-                block_pc += 1;
-            }
-
-            // Expand positional args if inside a subroutine
-            if let Some(frame) = ctx.call_stack.last() {
-                if let Some(a) = &frame.args {
-                    for l in &mut block_lines {
-                        *l = expand_positional_args(l.clone(), a);
-                    }
-                }
+            // Expand %0, and positional args if inside a subroutine
+            for l in &mut block_lines {
+                *l = expand_frame_args(ctx, bind_for_vars(ctx, l.clone()));
             }
 
-            let (out, code) = ctx.session_mut().run_batch_block(&block_lines)?;
+            let ran = block_lines_run(ctx, pre, pc, block_pc, &block_lines[0])?;
+            let (out, code, exit) = run_block_with_exits(ctx, pc, &block_lines)?;
+            ctx.coverage.extend(ran.iter().copied());
             if !out.trim().is_empty() {
                 print!("{}", out);
             }
             ctx.last_exit_code = code;
+            for block_line in &block_lines {
+                ctx.track_set_readback(block_line)?;
+            }
+            // A PUSHD or POPD in the branch that didn't run never touched the stack
+            for i in ran {
+                ctx.track_dir_command(&block_lines[i - pc], code)?;
+            }
             eprintln!("    └─ block exit code: {}", code);
+            if let Some(code) = exit {
+                ctx.last_exit_code = code;
+                eprintln!("\n🚪 EXIT {} (ending the script)", code);
+                break 'run;
+            }
 
             pc = block_pc;
             continue;
@@ -334,31 +1088,57 @@ pub fn run_debugger(
 
         let parts = split_composite_command(&line);
 
+        // A pipeline needs the shell to wire up the processes, so hand cmd the
+        // whole line; its SETs run in child shells and must not be tracked
+        if parts.iter().any(|p| p.op == Some(CommandOp::Pipe)) {
+            let exec_text = expand_frame_args(ctx, line.clone());
+
+            let (out, code) = ctx.run_line_command(pc, &exec_text)?;
+            if !out.trim().is_empty() {
+                print!("{}", out);
+            }
+
+            ctx.last_exit_code = code;
+            if !should_stop {
+                eprintln!("    └─ exit code: {}", code);
+            }
+
+            pc += 1;
+            continue;
+        }
+
+        // An EXIT after `&` or as an IF's command would end cmd.exe, so the
+        // line runs as a block that leaves it at the EXIT instead
+        let exec_text = expand_frame_args(ctx, line.clone());
+        if let Some(redirected) = redirect_exits(std::slice::from_ref(&exec_text)) {
+            ctx.track_set_command(&exec_text);
+            let (out, code, exit) = run_redirected(ctx, pc, &redirected)?;
+            if !out.trim().is_empty() {
+                print!("{}", out);
+            }
+            ctx.last_exit_code = code;
+            for part in split_composite_command(&exec_text) {
+                ctx.track_set_readback(&part.raw_text)?;
+                ctx.track_dir_command(&part.raw_text, code)?;
+            }
+            if let Some(code) = exit {
+                ctx.last_exit_code = code;
+                eprintln!("\n🚪 EXIT {} (ending the script)", code);
+                break 'run;
+            }
+            pc += 1;
+            continue;
+        }
+
         for (i, part) in parts.iter().enumerate() {
-            if part.text.trim().is_empty() {
+            if part.raw_text.is_empty() {
                 continue;
             }
 
-            let should_execute = match (i, ctx.last_exit_code) {
-                (0, _) => true,
-                (_, code) => {
-                    let prev_op = parts[i - 1].op;
-                    match prev_op {
-                        Some(CommandOp::Unconditional) => true,
-                        Some(CommandOp::And) => code == 0,
-                        Some(CommandOp::Or) => code != 0,
-                        None => true,
-                    }
-                }
-            };
+            let should_execute = part_runs(&parts, i, ctx.last_exit_code);
 
             if should_execute {
-                let mut exec_text = part.text.clone();
-                if let Some(frame) = ctx.call_stack.last() {
-                    if let Some(a) = &frame.args {
-                        exec_text = expand_positional_args(exec_text, a);
-                    }
-                }
+                let exec_text = expand_frame_args(ctx, part.raw_text.clone());
 
                 if parts.len() > 1 {
                     eprintln!("    ├─ Part {}: {}", i + 1, exec_text);
@@ -366,13 +1146,18 @@ pub fn run_debugger(
 
                 ctx.track_set_command(&exec_text);
 
-                let (out, code) = ctx.run_command(&exec_text)?;
+                let (out, code) = ctx.run_line_command(pc, &exec_text)?;
                 if !out.trim().is_empty() {
                     print!("{}", out);
                 }
+                ctx.track_set_readback(&exec_text)?;
+                ctx.track_dir_command(&exec_text, code)?;
 
                 ctx.last_exit_code = code;
                 if !should_stop {
+                    for file in part.written_files() {
+                        eprintln!("    ├─ writes to {}", file);
+                    }
                     eprintln!("    └─ exit code: {}", code);
                 }
             } else {
@@ -389,3 +1174,14 @@ pub fn run_debugger(
 
     Ok(())
 }
+
+fn print_watches(ctx: &mut DebugContext) {
+    let results = ctx.evaluate_watches();
+    if results.is_empty() {
+        return;
+    }
+    eprintln!("=== Watches ===");
+    for (expr, value) in results {
+        eprintln!("  {} => {}", expr, value);
+    }
+}
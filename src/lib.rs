@@ -1,4 +1,7 @@
+pub mod api;
+pub mod cli;
 pub mod dap;
 pub mod debugger;
 pub mod executor;
+pub mod logger;
 pub mod parser;
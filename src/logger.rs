@@ -0,0 +1,154 @@
+use log::{Level, LevelFilter, Metadata, Record};
+use std::cell::RefCell;
+use std::fmt::Display;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// Environment variable naming the adapter's trace log file
+pub const LOG_ENV_VAR: &str = "BATCH_DEBUGGER_LOG";
+
+/// Shared handle to the adapter's trace log. Clones write to the same file,
+/// and a logger that was never enabled drops every line.
+#[derive(Clone, Default)]
+pub struct Logger {
+    file: Arc<Mutex<Option<File>>>,
+    /// Stay disabled even when enabled later
+    refused: bool,
+}
+
+impl Logger {
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    /// A logger that stays off whatever asks for a log later, such as a
+    /// launch configuration's `trace` (`--no-log`)
+    pub fn refused() -> Self {
+        Self {
+            refused: true,
+            ..Self::default()
+        }
+    }
+
+    /// Log to `path` (appending), or stay disabled when it is `None`
+    pub fn open(path: Option<&Path>) -> io::Result<Self> {
+        let logger = Self::disabled();
+        if let Some(path) = path {
+            logger.enable(path)?;
+        }
+        Ok(logger)
+    }
+
+    /// Start appending to `path`; every clone of this logger follows. A
+    /// refused logger ignores this.
+    pub fn enable(&self, path: &Path) -> io::Result<()> {
+        if self.refused {
+            return Ok(());
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        if let Ok(mut slot) = self.file.lock() {
+            *slot = Some(file);
+        }
+        Ok(())
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.file.lock().is_ok_and(|slot| slot.is_some())
+    }
+
+    /// Write one line and flush it, so the log survives a crash
+    pub fn log(&self, line: impl Display) {
+        if let Ok(mut slot) = self.file.lock() {
+            if let Some(f) = slot.as_mut() {
+                writeln!(f, "{}", line).ok();
+                f.flush().ok();
+            }
+        }
+    }
+}
+
+/// Backend behind the `log` macros. The trace file gets every record; stderr
+/// only gets records at or above the verbosity level, so the adapter stays
+/// quiet in the editor's output channel unless asked otherwise.
+struct Sink {
+    file: Logger,
+    stderr: Mutex<LevelFilter>,
+}
+
+static SINK: OnceLock<Sink> = OnceLock::new();
+
+thread_local! {
+    static CAPTURED: RefCell<Option<Vec<(Level, String)>>> = const { RefCell::new(None) };
+}
+
+impl log::Log for Sink {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let message = record.args().to_string();
+
+        CAPTURED.with(|captured| {
+            if let Some(records) = captured.borrow_mut().as_mut() {
+                records.push((record.level(), message.clone()));
+            }
+        });
+
+        self.file.log(format_args!(
+            "{:<5} [{}] {}",
+            record.level(),
+            record.target(),
+            message
+        ));
+
+        if self
+            .stderr
+            .lock()
+            .is_ok_and(|level| record.level() <= *level)
+        {
+            eprintln!("{}", message);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Install `file` as the process-wide `log` backend, echoing records at
+/// `stderr` or above to stderr. Only the first call picks the file; later
+/// calls just change the stderr level.
+pub fn init(file: Logger, stderr: LevelFilter) {
+    let sink = SINK.get_or_init(|| Sink {
+        file,
+        stderr: Mutex::new(LevelFilter::Off),
+    });
+    if let Ok(mut level) = sink.stderr.lock() {
+        *level = stderr;
+    }
+    if log::set_logger(sink).is_ok() {
+        log::set_max_level(LevelFilter::Trace);
+    }
+}
+
+/// Stderr level for `-v` flags: warnings by default, then info, debug, trace
+pub fn verbosity(count: usize) -> LevelFilter {
+    match count {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// Run `f` and return the records it logged on this thread. Installs a silent
+/// backend if nothing is installed yet, so tests can call it directly.
+pub fn capture(f: impl FnOnce()) -> Vec<(Level, String)> {
+    if SINK.get().is_none() {
+        init(Logger::disabled(), LevelFilter::Off);
+    }
+    CAPTURED.with(|captured| *captured.borrow_mut() = Some(Vec::new()));
+    f();
+    CAPTURED.with(|captured| captured.borrow_mut().take().unwrap_or_default())
+}
@@ -1,70 +1,164 @@
-mod dap;
-mod debugger;
-mod executor;
-mod parser;
-
+use batch_debugger::cli::{CliArgs, USAGE};
+use batch_debugger::logger::{self, Logger, LOG_ENV_VAR};
+use batch_debugger::{dap, debugger, executor, parser};
 use std::fs;
-use std::io::{self, Write};
+use std::io;
+use std::path::{Path, PathBuf};
 
 fn main() -> io::Result<()> {
-    // Log to file
-    let mut log = fs::OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open("C:\\temp\\batch-debugger-vscode.log")
-        .ok();
-
-    if let Some(ref mut f) = log {
-        writeln!(
-            f,
-            "\n=== DEBUGGER STARTED at {:?} ===",
-            std::time::SystemTime::now()
-        )
-        .ok();
-    }
+    let args = match CliArgs::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(1);
+        }
+    };
 
-    let args: Vec<String> = std::env::args().collect();
+    if args.version {
+        println!(
+            "batch-debugger {} ({} {})",
+            env!("CARGO_PKG_VERSION"),
+            env!("BATCH_DEBUGGER_COMMIT"),
+            env!("BATCH_DEBUGGER_COMMIT_DATE")
+        );
+        return Ok(());
+    }
 
-    if let Some(ref mut f) = log {
-        writeln!(f, "Args: {:?}", args).ok();
+    if args.dap_port.is_some() {
+        eprintln!(
+            "--port: serving DAP over TCP is not supported yet\n\n{}",
+            USAGE
+        );
+        std::process::exit(1);
     }
 
-    let dap_mode = args
-        .iter()
-        .any(|arg| arg == "--dap" || arg == "--debug-adapter");
+    // --lint <script> checks the script's structure without running it
+    if let Some(script) = &args.lint {
+        let failed = run_lint(script)?;
+        std::process::exit(if failed { 1 } else { 0 });
+    }
 
-    if dap_mode {
-        if let Some(ref mut f) = log {
-            writeln!(f, "Starting DAP mode").ok();
+    // Trace log: --log (or --log-file) wins over the environment; neither
+    // means no log, and --no-log means none whatever asks for one
+    let log_path = args.log_path.clone().or_else(|| {
+        std::env::var_os(LOG_ENV_VAR)
+            .filter(|p| !p.is_empty())
+            .map(PathBuf::from)
+    });
+    let (trace, open_error) = if args.no_log {
+        (Logger::refused(), None)
+    } else {
+        match Logger::open(log_path.as_deref()) {
+            Ok(trace) => (trace, None),
+            Err(e) => (Logger::disabled(), Some(e)),
         }
-        eprintln!("Starting in DAP mode...");
-        dap::run_dap_mode()?;
+    };
+
+    // Each -v (or --verbose) lets one more level through to stderr. The
+    // interactive debugger reports breakpoints and steps at INFO, so it
+    // starts one level chattier than the adapter.
+    let verbose = if args.dap_mode {
+        args.verbose
     } else {
-        eprintln!("Starting in interactive mode...");
-        run_interactive_mode()?;
+        args.verbose + 1
+    };
+    logger::init(trace.clone(), logger::verbosity(verbose));
+
+    if let Some(e) = open_error {
+        log::warn!("Cannot open log file: {}", e);
     }
 
-    if let Some(ref mut f) = log {
-        writeln!(f, "=== DEBUGGER EXITING ===").ok();
+    log::info!(
+        "=== DEBUGGER STARTED at {:?} ===",
+        std::time::SystemTime::now()
+    );
+    log::debug!("Args: {:?}", args);
+
+    if let Some(name) = &args.pipe {
+        log::info!("Starting in DAP mode on a named pipe");
+        dap::run_dap_pipe(trace, name)?;
+    } else if args.dap_mode {
+        log::info!("Starting in DAP mode");
+        dap::run_dap_mode(trace)?;
+    } else {
+        log::info!("Starting in interactive mode");
+        run_interactive_mode(&args)?;
     }
 
+    log::info!("=== DEBUGGER EXITING ===");
+
     Ok(())
 }
 
-fn run_interactive_mode() -> io::Result<()> {
-    let contents = fs::read_to_string("test.bat").expect("Could not read test.bat");
+/// Print the script's diagnostics; true if any of them is an error
+fn run_lint(script: &Path) -> io::Result<bool> {
+    let contents = fs::read_to_string(script)?;
+    let physical_lines: Vec<&str> = contents.lines().collect();
+
+    let diagnostics = parser::validate_script(&physical_lines);
+    for diagnostic in &diagnostics {
+        println!("{}: {}", script.display(), diagnostic);
+    }
+    Ok(diagnostics
+        .iter()
+        .any(|d| d.severity == parser::Severity::Error))
+}
+
+/// Run `args.script` (default test.bat) under the interactive debugger
+fn run_interactive_mode(args: &CliArgs) -> io::Result<()> {
+    let script = args
+        .script
+        .as_deref()
+        .unwrap_or(Path::new("test.bat"))
+        .to_string_lossy()
+        .into_owned();
+    let contents = fs::read_to_string(&script)
+        .map_err(|e| io::Error::new(e.kind(), format!("Could not read {}: {}", script, e)))?;
     let physical_lines: Vec<&str> = contents.lines().collect();
 
     let pre = parser::preprocess_lines(&physical_lines);
-    let labels_phys = parser::build_label_map(&physical_lines);
+    let labels = parser::build_label_map(&pre);
 
     let session = debugger::CmdSession::start()?;
     let mut ctx = debugger::DebugContext::new(session);
+    ctx.current_source = script.clone();
+    ctx.max_steps = args.max_steps;
+    if args.profile.is_some() {
+        ctx.profile = Some(debugger::Profile::new());
+    }
 
-    ctx.set_mode(debugger::RunMode::StepInto);
+    ctx.set_mode(if args.stop_on_entry {
+        debugger::RunMode::StepInto
+    } else {
+        debugger::RunMode::Continue
+    });
 
-    executor::run_debugger(&mut ctx, &pre, &labels_phys)?;
+    executor::run_debugger(&mut ctx, &pre, &labels)?;
+
+    if let Some(path) = &args.coverage {
+        let report = ctx.coverage_report(&pre);
+        fs::write(path, lcov_report(&script, &report))?;
+    }
+    if let Some(timings) = &ctx.profile {
+        let report = timings.report(&pre, debugger::DEFAULT_PROFILE_LINES);
+        match args.profile.clone().flatten() {
+            Some(path) => fs::write(path, report)?,
+            None => eprint!("\n⏱  Slowest lines\n{}", report),
+        }
+    }
 
     let _ = ctx.session_mut().run("ENDLOCAL & exit");
     Ok(())
 }
+
+/// LCOV tracefile for one script. Only whether a line ran is known, so each
+/// line's hit count is 0 or 1.
+fn lcov_report(script: &str, report: &[(usize, bool)]) -> String {
+    let mut lcov = format!("TN:\nSF:{}\n", script);
+    for &(line, ran) in report {
+        lcov.push_str(&format!("DA:{},{}\n", line, u8::from(ran)));
+    }
+    let hit = report.iter().filter(|(_, ran)| *ran).count();
+    lcov.push_str(&format!("LF:{}\nLH:{}\nend_of_record\n", report.len(), hit));
+    lcov
+}
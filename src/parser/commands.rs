@@ -4,13 +4,60 @@ pub enum CommandOp {
     Unconditional, // &
     And,           // &&
     Or,            // ||
+    Pipe,          // |
 }
 
 /// A single command part in a composite command line
 #[derive(Debug, Clone)]
 pub struct CommandPart {
+    /// The part with whitespace outside quotes collapsed, for recognizing the
+    /// command; quoted text is left alone
     pub text: String,
+    /// The part as written, trimmed; this is what gets run
+    pub raw_text: String,
+    /// Byte offset of `raw_text` in the line it was split from
+    pub offset: usize,
     pub op: Option<CommandOp>,
+    /// Redirections taken out of `text`; `raw_text` still has them
+    pub redirections: Vec<Redirection>,
+}
+
+impl CommandPart {
+    /// Files the part's redirections write to
+    pub fn written_files(&self) -> impl Iterator<Item = &str> {
+        self.redirections
+            .iter()
+            .filter_map(Redirection::written_file)
+    }
+}
+
+/// How a redirection connects a handle
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RedirectOp {
+    Write,     // >
+    Append,    // >>
+    Read,      // <
+    Duplicate, // >& or <&
+}
+
+/// A redirection on a command, such as `> out.txt`, `<in.txt` or `2>&1`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Redirection {
+    /// Handle redirected: 0 stdin, 1 stdout, 2 stderr
+    pub handle: u8,
+    pub op: RedirectOp,
+    /// File as written, quotes kept, or for `RedirectOp::Duplicate` the
+    /// handle it joins
+    pub target: String,
+}
+
+impl Redirection {
+    /// File the redirection writes to; `nul` discards output and isn't one
+    pub fn written_file(&self) -> Option<&str> {
+        Some(self.target.trim_matches('"'))
+            .filter(|_| matches!(self.op, RedirectOp::Write | RedirectOp::Append))
+            .filter(|file| !file.is_empty() && !file.eq_ignore_ascii_case("nul"))
+    }
 }
 
 /// Normalize whitespace in command
@@ -18,79 +65,267 @@ pub fn normalize_whitespace(line: &str) -> String {
     line.split_whitespace().collect::<Vec<_>>().join(" ")
 }
 
-/// Split a command line by composite operators (&, &&, ||)
-pub fn split_composite_command(line: &str) -> Vec<CommandPart> {
-    let mut parts = Vec::new();
-    let mut current = String::new();
-    let mut chars = line.chars().peekable();
+/// Normalize whitespace in command, leaving double-quoted regions untouched
+pub fn normalize_whitespace_outside_quotes(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
     let mut in_quotes = false;
-    let mut escaped = false;
+    let mut pending_space = false;
 
-    while let Some(ch) = chars.next() {
-        if escaped {
-            current.push(ch);
-            escaped = false;
+    for ch in line.trim().chars() {
+        if !in_quotes && ch.is_whitespace() {
+            pending_space = true;
             continue;
         }
+        if pending_space {
+            out.push(' ');
+            pending_space = false;
+        }
+        if ch == '"' {
+            in_quotes = !in_quotes;
+        }
+        out.push(ch);
+    }
 
-        if ch == '^' {
-            escaped = true;
-            current.push(ch);
+    out
+}
+
+/// Split the arguments of a CALL the way cmd.exe hands them to the script:
+/// at whitespace outside double quotes. Quotes stay part of their argument,
+/// as `%1` shows them, and backslashes are ordinary characters.
+pub fn split_call_args(text: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+
+    for ch in text.chars() {
+        if !in_quotes && ch.is_whitespace() {
+            if !current.is_empty() {
+                args.push(std::mem::take(&mut current));
+            }
             continue;
         }
-
         if ch == '"' {
             in_quotes = !in_quotes;
-            current.push(ch);
+        }
+        current.push(ch);
+    }
+    if !current.is_empty() {
+        args.push(current);
+    }
+    args
+}
+
+/// Split a command line by composite operators (&, &&, ||, |)
+pub fn split_composite_command(line: &str) -> Vec<CommandPart> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut chars = syntax_chars(line).peekable();
+
+    // `text` is line[start..], so the trimmed text begins after its leading
+    // whitespace
+    let part = |text: &str, start: usize, op| {
+        let (command, redirections) = extract_redirections(text);
+        CommandPart {
+            text: normalize_whitespace_outside_quotes(&command),
+            raw_text: text.trim().to_string(),
+            offset: start + (text.len() - text.trim_start().len()),
+            op,
+            redirections,
+        }
+    };
+
+    let mut after_redirect = false;
+    while let Some((i, ch, live)) = chars.next() {
+        // `>&` and `<&` join a handle to another rather than chain a command
+        let joins_handle = after_redirect && ch == '&';
+        after_redirect = live && matches!(ch, '>' | '<');
+        if !live || joins_handle || !matches!(ch, '&' | '|') {
             continue;
         }
+        let doubled = chars.next_if(|&(_, c, live)| live && c == ch).is_some();
+        let op = match (ch, doubled) {
+            ('&', true) => CommandOp::And,
+            ('&', false) => CommandOp::Unconditional,
+            ('|', true) => CommandOp::Or,
+            _ => CommandOp::Pipe,
+        };
 
-        if !in_quotes && ch == '&' {
-            let op = if chars.peek() == Some(&'&') {
-                chars.next();
-                CommandOp::And
-            } else {
-                CommandOp::Unconditional
-            };
+        parts.push(part(&line[start..i], start, Some(op)));
+        start = chars.peek().map_or(line.len(), |&(i, _, _)| i);
+    }
+
+    if !line[start..].trim().is_empty() {
+        parts.push(part(&line[start..], start, None));
+    }
 
-            parts.push(CommandPart {
-                text: current.trim().to_string(),
-                op: Some(op),
-            });
-            current.clear();
+    parts
+}
+
+/// `text` without its redirections, and the redirections. A digit right
+/// before `>` or `<` at the start of a word names the handle; the target is
+/// the next word, which may be quoted.
+fn extract_redirections(text: &str) -> (String, Vec<Redirection>) {
+    let chars: Vec<(usize, char, bool)> = syntax_chars(text).collect();
+    let live_at =
+        |i: usize, want: char| chars.get(i).is_some_and(|&(_, c, live)| live && c == want);
+    let offset_at = |i: usize| chars.get(i).map_or(text.len(), |&(at, _, _)| at);
+
+    let mut command = String::new();
+    let mut redirections = Vec::new();
+    // Text before `copied` is already in `command` or belongs to a redirection
+    let mut copied = 0;
+    let mut i = 0;
+    while let Some(&(at, ch, live)) = chars.get(i) {
+        i += 1;
+        if !live || !matches!(ch, '>' | '<') {
             continue;
         }
 
-        if !in_quotes && ch == '|' {
-            if chars.peek() == Some(&'|') {
-                chars.next();
-                parts.push(CommandPart {
-                    text: current.trim().to_string(),
-                    op: Some(CommandOp::Or),
-                });
-                current.clear();
-                continue;
+        let mut start = at;
+        let mut handle = if ch == '<' { 0 } else { 1 };
+        if let Some(&(digit_at, digit, _)) = (i >= 2).then(|| &chars[i - 2]) {
+            let word_start = i < 3 || chars[i - 3].1.is_whitespace();
+            if digit.is_ascii_digit() && word_start {
+                handle = digit as u8 - b'0';
+                start = digit_at;
             }
         }
 
-        current.push(ch);
-    }
+        let op = if live_at(i, '&') {
+            i += 1;
+            RedirectOp::Duplicate
+        } else if ch == '>' && live_at(i, '>') {
+            i += 1;
+            RedirectOp::Append
+        } else if ch == '>' {
+            RedirectOp::Write
+        } else {
+            RedirectOp::Read
+        };
+
+        while chars.get(i).is_some_and(|&(_, c, _)| c.is_whitespace()) {
+            i += 1;
+        }
+        let target_start = offset_at(i);
+        while let Some(&(_, c, live)) = chars.get(i) {
+            if live && (c.is_whitespace() || matches!(c, '<' | '>' | '&' | '|')) {
+                break;
+            }
+            i += 1;
+        }
+        let end = offset_at(i);
 
-    if !current.trim().is_empty() {
-        parts.push(CommandPart {
-            text: current.trim().to_string(),
-            op: None,
+        command.push_str(&text[copied..start]);
+        copied = end;
+        redirections.push(Redirection {
+            handle,
+            op,
+            target: text[target_start..end].to_string(),
         });
     }
+    command.push_str(&text[copied..]);
+    (command, redirections)
+}
 
-    parts
+/// Every character of `line` with its byte offset and whether it is live
+/// syntax: outside double quotes and not escaped. As in cmd.exe, `^`
+/// escapes the next character only outside quotes; inside them it is
+/// literal. Quotes and escaping carets are never live.
+pub(crate) fn syntax_chars(line: &str) -> impl Iterator<Item = (usize, char, bool)> + '_ {
+    let mut in_quotes = false;
+    let mut escaped = false;
+    line.char_indices().map(move |(i, ch)| {
+        if escaped {
+            escaped = false;
+            return (i, ch, false);
+        }
+        if in_quotes {
+            in_quotes = ch != '"';
+            return (i, ch, false);
+        }
+        match ch {
+            '^' => escaped = true,
+            '"' => in_quotes = true,
+            _ => return (i, ch, true),
+        }
+        (i, ch, false)
+    })
+}
+
+/// Extract the variable name from `%NAME%`, `!NAME!` or a bare `NAME`.
+/// Returns `None` for anything that isn't a single plain variable reference.
+pub fn parse_variable_reference(expr: &str) -> Option<String> {
+    let expr = expr.trim();
+    let name = ['%', '!']
+        .iter()
+        .find_map(|&d| expr.strip_prefix(d).and_then(|rest| rest.strip_suffix(d)))
+        .unwrap_or(expr);
+
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '_' | '.' | '$' | '#' | '@' | '-'));
+    valid.then(|| name.to_string())
 }
 
 /// Check if line is a comment
 pub fn is_comment(line: &str) -> bool {
     let trimmed = line.trim();
     trimmed.is_empty()
+        || trimmed.eq_ignore_ascii_case("REM")
         || trimmed.to_uppercase().starts_with("REM ")
         || trimmed.starts_with("::")
         || trimmed.to_uppercase().starts_with("REM\t")
 }
+
+/// Compute net parenthesis delta for a line, honoring quotes and ^ escapes.
+/// Text after a `REM` or `::` comment is ignored.
+pub fn paren_delta(line: &str) -> i32 {
+    paren_offsets(line).iter().map(|&(_, delta)| delta).sum()
+}
+
+/// Byte offset of every parenthesis on `line` that opens (+1) or closes (-1)
+/// a block, by the same rules as `paren_delta`
+pub(super) fn paren_offsets(line: &str) -> Vec<(usize, i32)> {
+    let mut parens = Vec::new();
+    let mut at_command_start = true;
+
+    for (i, ch, live) in syntax_chars(line) {
+        if at_command_start {
+            // Leading whitespace, @ and caret escapes don't start the command text
+            if ch.is_whitespace() || ch == '@' || ch == '^' {
+                continue;
+            }
+            at_command_start = false;
+            if starts_comment(&line[i..]) {
+                break;
+            }
+        }
+        if !live {
+            continue;
+        }
+        match ch {
+            '(' => {
+                parens.push((i, 1));
+                at_command_start = true;
+            }
+            ')' => parens.push((i, -1)),
+            '&' | '|' => at_command_start = true,
+            _ => {}
+        }
+    }
+    parens
+}
+
+/// Whether command text begins with `REM` (as a whole word) or `::`
+fn starts_comment(text: &str) -> bool {
+    if text.starts_with("::") {
+        return true;
+    }
+    match text.get(..3) {
+        Some(word) if word.eq_ignore_ascii_case("rem") => {
+            text[3..].chars().next().is_none_or(char::is_whitespace)
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,356 @@
+use super::commands::paren_delta;
+use super::types::LogicalLine;
+use std::ops::Range;
+
+/// Iteration set of a FOR loop simple enough for the debugger to run itself
+#[derive(Debug, Clone, PartialEq)]
+pub enum ForItems {
+    /// `FOR /L %%i IN (start,step,end)`
+    Range { start: i64, step: i64, end: i64 },
+    /// `FOR %%a IN (a b "c d")` over literal items
+    List(Vec<String>),
+}
+
+impl ForItems {
+    /// Value of the loop variable on iteration `index`, or `None` once the
+    /// loop is done
+    pub fn get(&self, index: usize) -> Option<String> {
+        match self {
+            ForItems::Range { start, step, end } => {
+                let value = start.checked_add(step.checked_mul(i64::try_from(index).ok()?)?)?;
+                let in_range = if *step > 0 {
+                    value <= *end
+                } else {
+                    value >= *end
+                };
+                in_range.then(|| value.to_string())
+            }
+            ForItems::List(items) => items.get(index).cloned(),
+        }
+    }
+}
+
+/// Which FOR a statement is, by its switches
+#[derive(Debug, Clone, PartialEq)]
+pub enum ForKind {
+    /// `FOR %%a IN (set)` over items or file patterns
+    Items,
+    /// `FOR /L %%i IN (start,step,end)`
+    Range,
+    /// `FOR /F ["options"] %%a IN (...)` over lines of files, a string or a
+    /// command's output
+    Lines,
+    /// `FOR /R [root] %%f IN (set)` through a directory tree (with `/D` as
+    /// well, over its directories)
+    Recursive { root: Option<String> },
+    /// `FOR /D %%d IN (set)` over directory names
+    Directories,
+}
+
+/// What a `FOR /F` reads, from its set and `usebackq`
+#[derive(Debug, Clone, PartialEq)]
+pub enum ForSource {
+    /// Files named in the set, read line by line
+    Files(Vec<String>),
+    /// A literal string, without its quotes
+    String(String),
+    /// A command whose output is read, without its quotes
+    Command(String),
+}
+
+/// A FOR statement taken apart
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForStatement {
+    pub kind: ForKind,
+    /// Loop variable letter, as in `%%i`; `FOR /F` may define more after it
+    pub var: char,
+    /// `FOR /F` options without their quotes, e.g. `tokens=1,2 delims=,`
+    pub options: Option<String>,
+    /// The set between `IN (` and `)`, as written
+    pub set: String,
+    /// What follows `DO`, as written: a command or the `(` opening a block
+    pub command: String,
+    /// Logical lines of a parenthesized body after the header, up to and
+    /// including the line closing it; filled in by `preprocess_lines`
+    pub body: Option<Range<usize>>,
+}
+
+impl ForStatement {
+    /// Whether the `FOR /F` options include `usebackq`
+    pub fn usebackq(&self) -> bool {
+        self.option_words()
+            .any(|word| word.eq_ignore_ascii_case("usebackq"))
+    }
+
+    /// Loop variables the statement defines: one, or for `FOR /F` one per
+    /// token `tokens=` asks for, in consecutive letters
+    pub fn variables(&self) -> Vec<char> {
+        let count = match self.kind {
+            ForKind::Lines => self
+                .option_words()
+                .find_map(|word| {
+                    let (key, value) = word.split_once('=')?;
+                    key.eq_ignore_ascii_case("tokens")
+                        .then(|| token_count(value))
+                })
+                .unwrap_or(1),
+            _ => 1,
+        };
+        (0..count as u32)
+            .map_while(|i| char::from_u32(self.var as u32 + i))
+            .collect()
+    }
+
+    /// What a `FOR /F` reads; `None` for the other kinds
+    pub fn source(&self) -> Option<ForSource> {
+        if self.kind != ForKind::Lines {
+            return None;
+        }
+        let set = self.set.trim();
+        let (command, string) = if self.usebackq() {
+            ('`', '\'')
+        } else {
+            ('\'', '"')
+        };
+        let quoted = |quote: char| {
+            set.strip_prefix(quote)
+                .and_then(|rest| rest.strip_suffix(quote))
+                .map(str::to_string)
+        };
+        Some(if let Some(text) = quoted(command) {
+            ForSource::Command(text)
+        } else if let Some(text) = quoted(string) {
+            ForSource::String(text)
+        } else {
+            ForSource::Files(split_set(set))
+        })
+    }
+
+    /// The values a plain or `/L` loop takes, when they are all known up
+    /// front: no wildcards, no variables left to expand, no zero step
+    pub fn items(&self) -> Option<ForItems> {
+        if self.set.contains(['%', '!', '*', '?']) {
+            return None;
+        }
+        match self.kind {
+            ForKind::Range => {
+                let bounds: Vec<i64> = split_set(&self.set)
+                    .iter()
+                    .map(|n| n.parse().ok())
+                    .collect::<Option<_>>()?;
+                match bounds[..] {
+                    [start, step, end] if step != 0 => Some(ForItems::Range { start, step, end }),
+                    _ => None,
+                }
+            }
+            ForKind::Items => Some(ForItems::List(split_set(&self.set))),
+            _ => None,
+        }
+    }
+
+    fn option_words(&self) -> impl Iterator<Item = &str> {
+        self.options.as_deref().unwrap_or("").split_whitespace()
+    }
+}
+
+/// Take apart a `FOR` line of any kind. `body` is left `None`; the line
+/// alone can't say where a block ends.
+pub fn parse_for_statement(line: &str) -> Option<ForStatement> {
+    let line = line.trim().trim_start_matches('@').trim_start();
+    let mut rest = strip_keyword(line, "for")?;
+
+    let mut kind = ForKind::Items;
+    let mut options = None;
+    while let Some(flag) = rest.strip_prefix('/') {
+        let (flag, after) = flag.split_once(char::is_whitespace)?;
+        rest = after.trim_start();
+        match flag.to_ascii_lowercase().as_str() {
+            "l" => kind = ForKind::Range,
+            "d" if !matches!(kind, ForKind::Recursive { .. }) => kind = ForKind::Directories,
+            "d" => {}
+            "f" => {
+                kind = ForKind::Lines;
+                if rest.starts_with('"') {
+                    let close = rest[1..].find('"')? + 1;
+                    options = Some(rest[1..close].to_string());
+                    rest = rest[close + 1..].trim_start();
+                }
+            }
+            "r" => {
+                let root = if rest.starts_with('%') {
+                    None
+                } else {
+                    let (root, after) = next_word(rest);
+                    rest = after;
+                    Some(root.to_string())
+                };
+                kind = ForKind::Recursive { root };
+            }
+            _ => return None,
+        }
+    }
+
+    let (var, rest) = rest.split_once(char::is_whitespace)?;
+    let var = var.strip_prefix("%%").or_else(|| var.strip_prefix('%'))?;
+    let mut chars = var.chars();
+    let var = match (chars.next(), chars.next()) {
+        (Some(c), None) if c.is_ascii_alphabetic() => c,
+        _ => return None,
+    };
+
+    let rest = strip_keyword(rest.trim_start(), "in")?;
+    let rest = rest.strip_prefix('(')?;
+    let quotes: &[char] = if kind == ForKind::Lines {
+        &['"', '\'', '`']
+    } else {
+        &['"']
+    };
+    let close = closing_paren(rest, quotes)?;
+    let set = rest[..close].to_string();
+    let command = strip_keyword(rest[close + 1..].trim_start(), "do")?;
+
+    Some(ForStatement {
+        kind,
+        var,
+        options,
+        set,
+        command: command.trim_end().to_string(),
+        body: None,
+    })
+}
+
+/// Header of a FOR loop whose body is a parenthesized block
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForHeader {
+    /// Loop variable letter, as in `%%i`
+    pub var: char,
+    pub items: ForItems,
+}
+
+/// Parse `FOR /L %%i IN (1,1,3) DO (` or `FOR %%a IN (x y z) DO (`. Returns
+/// `None` for every other form: `/F`, `/R`, `/D`, sets with wildcards or
+/// variables left to expand, a zero `/L` step, and bodies that don't open a
+/// block at the end of the line.
+pub fn parse_for_header(line: &str) -> Option<ForHeader> {
+    let statement = parse_for_statement(line)?;
+    if statement.command != "(" {
+        return None;
+    }
+    Some(ForHeader {
+        var: statement.var,
+        items: statement.items()?,
+    })
+}
+
+/// Attach a `ForStatement` to every FOR line, with the span of its block
+pub(super) fn attach_for_statements(logical: &mut [LogicalLine]) {
+    for pc in 0..logical.len() {
+        let Some(mut statement) = parse_for_statement(&logical[pc].text) else {
+            continue;
+        };
+        if paren_delta(&logical[pc].text) > 0 {
+            let mut balance = 0;
+            let mut end = logical.len() - 1;
+            for (i, line) in logical.iter().enumerate().skip(pc) {
+                balance += paren_delta(&line.text);
+                if balance <= 0 {
+                    end = i;
+                    break;
+                }
+            }
+            statement.body = Some(pc + 1..end + 1);
+        }
+        logical[pc].for_statement = Some(statement);
+    }
+}
+
+/// Number of loop variables a `tokens=` value defines, e.g. 3 for `1,3*`
+fn token_count(spec: &str) -> usize {
+    let mut count = 0;
+    for part in spec.split(',') {
+        let (part, rest) = match part.strip_suffix('*') {
+            Some(part) => (part, 1),
+            None => (part, 0),
+        };
+        count += rest;
+        count += match part.split_once('-') {
+            Some((first, last)) => match (first.parse::<usize>(), last.parse::<usize>()) {
+                (Ok(first), Ok(last)) if last >= first => last - first + 1,
+                _ => 1,
+            },
+            None if part.is_empty() => 0,
+            None => 1,
+        };
+    }
+    count.max(1)
+}
+
+/// First word of `text`, quotes kept together, and the text after it
+fn next_word(text: &str) -> (&str, &str) {
+    let mut in_quotes = false;
+    let end = text
+        .char_indices()
+        .find(|&(_, c)| {
+            if c == '"' {
+                in_quotes = !in_quotes;
+            }
+            !in_quotes && c.is_whitespace()
+        })
+        .map_or(text.len(), |(i, _)| i);
+    (&text[..end], text[end..].trim_start())
+}
+
+/// `text` without a leading case-insensitive `keyword` and the whitespace
+/// after it; the keyword must end at whitespace or `(`
+pub(super) fn strip_keyword<'a>(text: &'a str, keyword: &str) -> Option<&'a str> {
+    let head = text.get(..keyword.len())?;
+    let rest = &text[keyword.len()..];
+    if !head.eq_ignore_ascii_case(keyword) {
+        return None;
+    }
+    match rest.chars().next() {
+        Some(c) if c.is_whitespace() => Some(rest.trim_start()),
+        Some('(') => Some(rest),
+        _ => None,
+    }
+}
+
+/// Byte offset of the `)` closing the set, ignoring parens inside any of
+/// the `quotes`
+fn closing_paren(text: &str, quotes: &[char]) -> Option<usize> {
+    let mut open: Option<char> = None;
+    for (i, ch) in text.char_indices() {
+        match open {
+            Some(quote) if ch == quote => open = None,
+            Some(_) => {}
+            None if quotes.contains(&ch) => open = Some(ch),
+            None if ch == ')' => return Some(i),
+            None => {}
+        }
+    }
+    None
+}
+
+/// Split a FOR set the way cmd does: on spaces, tabs, commas, semicolons and
+/// `=`, keeping quoted items (quotes included) together
+fn split_set(set: &str) -> Vec<String> {
+    let mut items = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+    for ch in set.chars() {
+        if ch == '"' {
+            in_quotes = !in_quotes;
+        }
+        if !in_quotes && matches!(ch, ' ' | '\t' | ',' | ';' | '=') {
+            if !current.is_empty() {
+                items.push(std::mem::take(&mut current));
+            }
+            continue;
+        }
+        current.push(ch);
+    }
+    if !current.is_empty() {
+        items.push(current);
+    }
+    items
+}
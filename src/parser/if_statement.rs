@@ -0,0 +1,218 @@
+use super::commands::paren_offsets;
+use super::for_loop::strip_keyword;
+use super::types::LogicalLine;
+use std::ops::Range;
+
+/// Operator of an `IF string1 op string2` comparison
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompareOp {
+    Equ,
+    Neq,
+    Lss,
+    Leq,
+    Gtr,
+    Geq,
+}
+
+impl CompareOp {
+    fn parse(word: &str) -> Option<Self> {
+        Some(match word.to_ascii_uppercase().as_str() {
+            "EQU" => CompareOp::Equ,
+            "NEQ" => CompareOp::Neq,
+            "LSS" => CompareOp::Lss,
+            "LEQ" => CompareOp::Leq,
+            "GTR" => CompareOp::Gtr,
+            "GEQ" => CompareOp::Geq,
+            _ => return None,
+        })
+    }
+}
+
+/// What an IF tests. Operands are kept as written, quotes and unexpanded
+/// variables included.
+#[derive(Debug, Clone, PartialEq)]
+pub enum IfCondition {
+    /// `string1==string2`
+    Equals(String, String),
+    /// `string1 EQU string2` and the other comparison operators
+    Compare(String, CompareOp, String),
+    /// `DEFINED variable`
+    Defined(String),
+    /// `EXIST path`
+    Exist(String),
+    /// `ERRORLEVEL n`, true for an exit code of `n` or more
+    ErrorLevel(i32),
+}
+
+/// An IF condition with its `/I` and `NOT` switches
+#[derive(Debug, Clone, PartialEq)]
+pub struct IfTest {
+    pub ignore_case: bool,
+    pub negated: bool,
+    pub condition: IfCondition,
+}
+
+/// Where a branch of an IF runs
+#[derive(Debug, Clone, PartialEq)]
+pub enum IfBranch {
+    /// A command on the IF's own lines, without the parentheses around it
+    Inline(String),
+    /// A parenthesized block over several lines: the logical lines between
+    /// the one it opens on and the one it closes on
+    Block(Range<usize>),
+}
+
+/// An `IF` line taken apart into its test and branches
+#[derive(Debug, Clone, PartialEq)]
+pub struct IfStatement {
+    pub test: IfTest,
+    pub then_branch: IfBranch,
+    pub else_branch: Option<IfBranch>,
+}
+
+/// Parse the condition at the start of the text after `IF`, returning it and
+/// the command text that follows. `None` for anything that isn't one of the
+/// condition forms.
+pub fn parse_if_condition(text: &str) -> Option<(IfTest, &str)> {
+    let mut rest = text.trim_start();
+    let ignore_case = strip_keyword(rest, "/i");
+    rest = ignore_case.unwrap_or(rest);
+    let negated = strip_keyword(rest, "not");
+    rest = negated.unwrap_or(rest);
+
+    let (condition, rest) = if let Some(after) = strip_keyword(rest, "errorlevel") {
+        let (level, rest) = next_operand(after)?;
+        (IfCondition::ErrorLevel(level.parse().ok()?), rest)
+    } else if let Some(after) = strip_keyword(rest, "defined") {
+        let (name, rest) = next_operand(after)?;
+        (IfCondition::Defined(name.to_string()), rest)
+    } else if let Some(after) = strip_keyword(rest, "exist") {
+        let (path, rest) = next_operand(after)?;
+        (IfCondition::Exist(path.to_string()), rest)
+    } else {
+        let (lhs, after) = next_operand(rest)?;
+        let after = after.trim_start();
+        match after.strip_prefix("==") {
+            Some(after) => {
+                let (rhs, rest) = next_operand(after)?;
+                (IfCondition::Equals(lhs.to_string(), rhs.to_string()), rest)
+            }
+            None => {
+                let (op, after) = next_operand(after)?;
+                let op = CompareOp::parse(op)?;
+                let (rhs, rest) = next_operand(after)?;
+                (
+                    IfCondition::Compare(lhs.to_string(), op, rhs.to_string()),
+                    rest,
+                )
+            }
+        }
+    };
+
+    let test = IfTest {
+        ignore_case: ignore_case.is_some(),
+        negated: negated.is_some(),
+        condition,
+    };
+    Some((test, rest.trim_start()))
+}
+
+/// Attach an `IfStatement` to every IF line, finding its branches on the
+/// lines that follow when they are blocks
+pub(super) fn attach_if_statements(logical: &mut [LogicalLine]) {
+    for pc in 0..logical.len() {
+        if let Some(statement) = if_statement_at(logical, pc) {
+            logical[pc].if_statement = Some(statement);
+        }
+    }
+}
+
+fn if_statement_at(logical: &[LogicalLine], pc: usize) -> Option<IfStatement> {
+    let text = &logical[pc].text;
+    let line = text.trim_start().trim_start_matches('@').trim_start();
+    let (test, command) = parse_if_condition(strip_keyword(line, "if")?)?;
+
+    // Without parentheses the rest of the line is the command, ELSE and all
+    if !command.starts_with('(') {
+        return Some(IfStatement {
+            test,
+            then_branch: IfBranch::Inline(command.trim_end().to_string()),
+            else_branch: None,
+        });
+    }
+
+    let open = text.len() - command.len();
+    let Some((then_branch, (close_pc, close))) = branch_from(logical, pc, open) else {
+        return Some(IfStatement {
+            test,
+            then_branch: IfBranch::Block(pc + 1..logical.len()),
+            else_branch: None,
+        });
+    };
+
+    let closing = &logical[close_pc].text;
+    let tail = closing[close + 1..].trim_start();
+    let else_branch = match strip_keyword(tail, "else") {
+        Some(command) if command.starts_with('(') => {
+            let open = closing.len() - command.len();
+            Some(match branch_from(logical, close_pc, open) {
+                Some((branch, _)) => branch,
+                None => IfBranch::Block(close_pc + 1..logical.len()),
+            })
+        }
+        Some(command) => Some(IfBranch::Inline(command.trim_end().to_string())),
+        None => None,
+    };
+
+    Some(IfStatement {
+        test,
+        then_branch,
+        else_branch,
+    })
+}
+
+/// The branch whose `(` is at byte `open` of logical line `pc`, and where its
+/// `)` is: the logical line and the byte offset on it
+fn branch_from(
+    logical: &[LogicalLine],
+    pc: usize,
+    open: usize,
+) -> Option<(IfBranch, (usize, usize))> {
+    let mut depth = 0;
+    for (i, line) in logical.iter().enumerate().skip(pc) {
+        for (offset, delta) in paren_offsets(&line.text) {
+            if i == pc && offset < open {
+                continue;
+            }
+            depth += delta;
+            if depth > 0 {
+                continue;
+            }
+            let branch = if i == pc {
+                IfBranch::Inline(line.text[open + 1..offset].trim().to_string())
+            } else {
+                IfBranch::Block(pc + 1..i)
+            };
+            return Some((branch, (i, offset)));
+        }
+    }
+    None
+}
+
+/// First operand of `text`: a quoted string and whatever sticks to it, or a
+/// run of characters up to whitespace, `=` or `(`; returns it and the
+/// remainder
+fn next_operand(text: &str) -> Option<(&str, &str)> {
+    let text = text.trim_start();
+    let mut in_quotes = false;
+    let end = text
+        .char_indices()
+        .find(|&(_, c)| {
+            if c == '"' {
+                in_quotes = !in_quotes;
+            }
+            !in_quotes && (c.is_whitespace() || c == '=' || c == '(')
+        })
+        .map_or(text.len(), |(i, _)| i);
+    (end > 0).then(|| text.split_at(end))
+}
@@ -1,15 +1,85 @@
+use super::types::PreprocessResult;
 use std::collections::HashMap;
 
-/// Scan labels (case-insensitive)
-pub fn build_label_map(lines: &[&str]) -> HashMap<String, usize> {
-    let mut map = HashMap::new();
-    for (i, line) in lines.iter().enumerate() {
-        let t = line.trim();
-        if t.starts_with(':') && t.len() > 1 {
-            let label_text = &t[1..];
-            let label_name = label_text.split_whitespace().next().unwrap_or(label_text);
-            map.insert(label_name.trim().to_lowercase(), i);
+/// Every `:label` of a script by lowercase name, with each logical line it
+/// is defined on in file order
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LabelMap {
+    labels: HashMap<String, Vec<usize>>,
+}
+
+impl LabelMap {
+    /// Logical line GOTO or CALL lands on when jumping to `name` (with or
+    /// without the leading colon, any case) from `from_pc`. Like cmd.exe it
+    /// takes the first definition at or after `from_pc`, wrapping around to
+    /// the top of the file when there is none below.
+    pub fn resolve_label(&self, from_pc: usize, name: &str) -> Option<usize> {
+        let definitions = self.definitions(name);
+        definitions
+            .iter()
+            .find(|&&line| line >= from_pc)
+            .or_else(|| definitions.first())
+            .copied()
+    }
+
+    /// Logical lines `name` is defined on, in file order. `name` is read
+    /// like a GOTO target, so anything after the label itself is ignored.
+    pub fn definitions(&self, name: &str) -> &[usize] {
+        let Some(key) = label_name(name) else {
+            return &[];
+        };
+        self.labels
+            .get(&key.to_lowercase())
+            .map_or(&[], Vec::as_slice)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        !self.definitions(name).is_empty()
+    }
+
+    /// Number of distinct label names
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    /// Each label name with the logical lines it is defined on
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[usize])> {
+        self.labels
+            .iter()
+            .map(|(name, lines)| (name.as_str(), lines.as_slice()))
+    }
+}
+
+/// The label at the start of `text`, a `:label` definition or the target of
+/// GOTO or CALL, as cmd.exe reads it: after an optional colon, everything up
+/// to whitespace or one of `: , ; = & | < > ( )`. Dots, dashes, `+` and
+/// leading digits are all part of the name, and what follows it is ignored.
+pub fn label_name(text: &str) -> Option<&str> {
+    let text = text.trim_start();
+    let text = text.strip_prefix(':').unwrap_or(text);
+    let end = text
+        .find(|c: char| c.is_whitespace() || ":,;=&|<>()".contains(c))
+        .unwrap_or(text.len());
+    (end > 0).then(|| &text[..end])
+}
+
+/// Scan the logical lines for labels (case-insensitive). Working after
+/// continuations are joined means a `:name` swallowed into the line above it
+/// is not mistaken for a label.
+pub fn build_label_map(pre: &PreprocessResult) -> LabelMap {
+    let mut map = LabelMap::default();
+    for (pc, ll) in pre.logical.iter().enumerate() {
+        if !ll.is_label() {
+            continue;
         }
+        let Some(name) = label_name(&ll.text) else {
+            continue;
+        };
+        map.labels.entry(name.to_lowercase()).or_default().push(pc);
     }
     map
 }
@@ -1,9 +1,22 @@
 mod commands;
+mod for_loop;
+mod if_statement;
 mod labels;
 mod preprocessor;
 mod types;
+mod validate;
 
-pub use commands::{is_comment, normalize_whitespace, split_composite_command, CommandOp};
-pub use labels::build_label_map;
-pub use preprocessor::preprocess_lines;
-pub use types::{LogicalLine, PreprocessResult};
+pub(crate) use commands::syntax_chars;
+pub use commands::{
+    is_comment, normalize_whitespace, normalize_whitespace_outside_quotes, paren_delta,
+    parse_variable_reference, split_call_args, split_composite_command, CommandOp, CommandPart,
+    RedirectOp, Redirection,
+};
+pub use for_loop::{
+    parse_for_header, parse_for_statement, ForHeader, ForItems, ForKind, ForSource, ForStatement,
+};
+pub use if_statement::{parse_if_condition, CompareOp, IfBranch, IfCondition, IfStatement, IfTest};
+pub use labels::{build_label_map, label_name, LabelMap};
+pub use preprocessor::{join_continued_lines, preprocess_lines};
+pub use types::{JoinedLine, LogicalLine, PreprocessResult};
+pub use validate::{validate_script, Diagnostic, Severity};
@@ -1,7 +1,9 @@
+use super::for_loop::attach_for_statements;
+use super::if_statement::attach_if_statements;
 use super::types::{JoinedLine, LogicalLine, PreprocessResult};
 
 /// Join physical lines that are continued with a trailing caret `^`.
-pub fn join_continued_lines(physical: &[&str]) -> Vec<JoinedLine> {
+pub fn join_continued_lines<S: AsRef<str>>(physical: &[S]) -> Vec<JoinedLine> {
     let mut out = Vec::new();
     let mut i = 0usize;
 
@@ -10,7 +12,7 @@ pub fn join_continued_lines(physical: &[&str]) -> Vec<JoinedLine> {
         let mut buf = String::new();
 
         loop {
-            let line = physical[i];
+            let line = physical[i].as_ref();
             let (continues, trimmed_without_one_caret) = {
                 let det = line.trim_end_matches([' ', '\t']);
                 let mut caret_count = 0usize;
@@ -80,14 +82,22 @@ pub fn annotate_blocks(joined: Vec<JoinedLine>) -> Vec<LogicalLine> {
         let line_depth = depth.max(0) as u16;
         let current_group = group_id_stack.last().copied();
 
-        let mut chars = j.text.chars().peekable();
         let mut escaped = false;
+        // Parentheses inside "..." are literal text, not block delimiters
+        let mut in_quotes = false;
 
-        while let Some(ch) = chars.next() {
+        for ch in j.text.chars() {
             if escaped {
                 escaped = false;
                 continue;
             }
+            if ch == '"' {
+                in_quotes = !in_quotes;
+                continue;
+            }
+            if in_quotes {
+                continue;
+            }
             if ch == '^' {
                 escaped = true;
                 continue;
@@ -114,21 +124,25 @@ pub fn annotate_blocks(joined: Vec<JoinedLine>) -> Vec<LogicalLine> {
             phys_end: j.phys_end,
             group_id: current_group,
             group_depth: line_depth,
+            for_statement: None,
+            if_statement: None,
         });
     }
 
     logical
 }
 
-/// Full preprocessing pipeline
-pub fn preprocess_lines(physical: &[&str]) -> PreprocessResult {
+/// Full preprocessing pipeline, over `&[&str]` or `&[String]`
+pub fn preprocess_lines<S: AsRef<str>>(physical: &[S]) -> PreprocessResult {
     let joined = join_continued_lines(physical);
-    let logical = annotate_blocks(joined.clone());
+    let mut logical = annotate_blocks(joined.clone());
+    attach_for_statements(&mut logical);
+    attach_if_statements(&mut logical);
 
     let mut phys_to_logical = vec![0usize; physical.len()];
     for (li, j) in joined.iter().enumerate() {
-        for p in j.phys_start..=j.phys_end {
-            phys_to_logical[p] = li;
+        for slot in &mut phys_to_logical[j.phys_start..=j.phys_end] {
+            *slot = li;
         }
     }
 
@@ -1,3 +1,7 @@
+use super::commands::is_comment;
+use super::for_loop::ForStatement;
+use super::if_statement::IfStatement;
+
 /// One physical→logical joined line (before block annotation).
 #[derive(Debug, Clone)]
 pub struct JoinedLine {
@@ -14,6 +18,28 @@ pub struct LogicalLine {
     pub phys_end: usize,
     pub group_id: Option<u32>,
     pub group_depth: u16,
+    /// The FOR statement on this line, if it is one
+    pub for_statement: Option<ForStatement>,
+    /// The IF statement on this line, if it is one
+    pub if_statement: Option<IfStatement>,
+}
+
+impl LogicalLine {
+    /// Nothing but whitespace
+    pub fn is_empty(&self) -> bool {
+        self.text.trim().is_empty()
+    }
+
+    /// A `REM` or `::` comment; blank lines are not comments here
+    pub fn is_comment(&self) -> bool {
+        !self.is_empty() && is_comment(&self.text)
+    }
+
+    /// A `:label` definition
+    pub fn is_label(&self) -> bool {
+        let text = self.text.trim_start();
+        text.starts_with(':') && !text.starts_with("::")
+    }
 }
 
 /// Output of preprocessing: logical lines + mapping back to physical indices.
@@ -0,0 +1,156 @@
+use super::commands::{is_comment, paren_delta, split_composite_command};
+use super::preprocessor::preprocess_lines;
+use super::{build_label_map, label_name, LabelMap, LogicalLine, PreprocessResult};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// cmd.exe will fail on this
+    Error,
+    /// Legal, but probably not what was meant
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// A structural problem found without running the script
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    /// 0-based physical line
+    pub line: usize,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "line {}: {}: {}",
+            self.line + 1,
+            self.severity,
+            self.message
+        )
+    }
+}
+
+/// Check a script for blocks that are never closed, GOTO and CALL targets
+/// with no matching label, and labels defined more than once. Diagnostics
+/// come back in line order.
+pub fn validate_script(physical: &[&str]) -> Vec<Diagnostic> {
+    let pre = preprocess_lines(physical);
+    let labels = build_label_map(&pre);
+    let mut diagnostics = Vec::new();
+
+    check_blocks(&pre.logical, &mut diagnostics);
+    check_duplicate_labels(&pre, &labels, &mut diagnostics);
+    for ll in &pre.logical {
+        if is_comment(&ll.text) || ll.text.trim_start().starts_with(':') {
+            continue;
+        }
+        for (verb, target) in jump_targets(&ll.text) {
+            if !labels.contains(&target) {
+                diagnostics.push(Diagnostic {
+                    line: ll.phys_start,
+                    severity: Severity::Error,
+                    message: format!("{} to undefined label ':{}'", verb, target),
+                });
+            }
+        }
+    }
+
+    diagnostics.sort_by_key(|d| d.line);
+    diagnostics
+}
+
+/// Flag each block still open at the end of the file, at the line that opened it
+fn check_blocks(logical: &[LogicalLine], diagnostics: &mut Vec<Diagnostic>) {
+    let mut open = Vec::new();
+    for ll in logical {
+        let delta = paren_delta(&ll.text);
+        if delta > 0 {
+            open.extend(std::iter::repeat_n(ll.phys_start, delta as usize));
+        } else {
+            // Outside a block cmd prints a stray `)` like any other text
+            let closed = open.len().saturating_sub(delta.unsigned_abs() as usize);
+            open.truncate(closed);
+        }
+    }
+    for line in open {
+        diagnostics.push(Diagnostic {
+            line,
+            severity: Severity::Error,
+            message: "'(' is never closed".to_string(),
+        });
+    }
+}
+
+/// Labels defined again further down. GOTO searches forward from where it
+/// runs, so which copy it lands on depends on where it is called from.
+fn check_duplicate_labels(
+    pre: &PreprocessResult,
+    labels: &LabelMap,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    for (name, definitions) in labels.iter() {
+        let first = pre.logical[definitions[0]].phys_start;
+        for &pc in &definitions[1..] {
+            diagnostics.push(Diagnostic {
+                line: pre.logical[pc].phys_start,
+                severity: Severity::Warning,
+                message: format!("label ':{}' is already defined on line {}", name, first + 1),
+            });
+        }
+    }
+}
+
+/// Labels the statements of `line` jump to, as (verb, lowercase label).
+/// `:EOF` and targets built from variables are left out, as is CALL of
+/// another batch file.
+fn jump_targets(line: &str) -> Vec<(&'static str, String)> {
+    let mut targets = Vec::new();
+    for part in split_composite_command(line) {
+        let mut words = part
+            .text
+            .trim_start_matches(['@', '('])
+            .split_whitespace()
+            .peekable();
+        // IF runs its command after the condition, so look past it for the verb
+        let is_if = words.peek().is_some_and(|w| w.eq_ignore_ascii_case("if"));
+
+        while let Some(word) = words.next() {
+            let verb = if word.eq_ignore_ascii_case("goto") {
+                "GOTO"
+            } else if word.eq_ignore_ascii_case("call") {
+                "CALL"
+            } else if is_if {
+                continue;
+            } else {
+                break;
+            };
+
+            let Some(target) = words.next() else {
+                break;
+            };
+            let named = match verb {
+                "GOTO" => label_name(target),
+                _ if target.starts_with(':') => label_name(target),
+                _ => None,
+            };
+            if let Some(name) = named.filter(|n| !n.is_empty() && !n.contains(['%', '!'])) {
+                if !name.eq_ignore_ascii_case("eof") {
+                    targets.push((verb, name.to_lowercase()));
+                }
+            }
+            break;
+        }
+    }
+    targets
+}
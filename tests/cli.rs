@@ -0,0 +1,153 @@
+// tests/cli.rs
+// Command-line parsing of the batch-debugger binary.
+
+#[cfg(test)]
+mod cli_tests {
+    use batch_debugger::cli::CliArgs;
+    use std::path::PathBuf;
+
+    fn parse(args: &[&str]) -> Result<CliArgs, String> {
+        CliArgs::parse(args.iter().copied())
+    }
+
+    #[test]
+    fn test_defaults() {
+        let args = parse(&[]).unwrap();
+        assert_eq!(args, CliArgs::default());
+        assert!(!args.dap_mode);
+        assert!(args.stop_on_entry);
+        assert_eq!(args.script, None);
+        assert_eq!(args.verbose, 0);
+    }
+
+    #[test]
+    fn test_dap_modes() {
+        assert!(parse(&["--dap"]).unwrap().dap_mode);
+        assert!(parse(&["--debug-adapter"]).unwrap().dap_mode);
+
+        let args = parse(&["--pipe", "abc"]).unwrap();
+        assert!(args.dap_mode);
+        assert_eq!(args.pipe.as_deref(), Some("abc"));
+
+        let args = parse(&["--port", "4711"]).unwrap();
+        assert!(args.dap_mode);
+        assert_eq!(args.dap_port, Some(4711));
+    }
+
+    #[test]
+    fn test_interactive_options() {
+        let args = parse(&[
+            "--no-stop-on-entry",
+            "--max-steps",
+            "50",
+            "--coverage",
+            "out.lcov",
+            "build.bat",
+        ])
+        .unwrap();
+        assert!(!args.dap_mode);
+        assert!(!args.stop_on_entry);
+        assert_eq!(args.max_steps, Some(50));
+        assert_eq!(args.coverage, Some(PathBuf::from("out.lcov")));
+        assert_eq!(args.script, Some(PathBuf::from("build.bat")));
+
+        // The last of the pair wins
+        let args = parse(&["--no-stop-on-entry", "--stop-on-entry"]).unwrap();
+        assert!(args.stop_on_entry);
+    }
+
+    #[test]
+    fn test_profile_with_and_without_file() {
+        assert_eq!(parse(&["--profile"]).unwrap().profile, Some(None));
+        assert_eq!(
+            parse(&["--profile=times.txt"]).unwrap().profile,
+            Some(Some(PathBuf::from("times.txt")))
+        );
+
+        // The script after a bare --profile is never the report file
+        let args = parse(&["--profile", "build.bat"]).unwrap();
+        assert_eq!(args.profile, Some(None));
+        assert_eq!(args.script, Some(PathBuf::from("build.bat")));
+
+        let err = parse(&["--profile="]).unwrap_err();
+        assert!(err.starts_with("--profile= expects"), "got {:?}", err);
+    }
+
+    #[test]
+    fn test_logging_options() {
+        let args = parse(&["--log", "trace.log", "-vv", "--verbose"]).unwrap();
+        assert_eq!(args.log_path, Some(PathBuf::from("trace.log")));
+        assert_eq!(args.verbose, 3);
+        assert!(!args.no_log);
+
+        let args = parse(&["--log-file", "other.log", "--no-log"]).unwrap();
+        assert_eq!(args.log_path, Some(PathBuf::from("other.log")));
+        assert!(args.no_log);
+    }
+
+    #[test]
+    fn test_version_and_lint() {
+        assert!(parse(&["-V"]).unwrap().version);
+        assert!(parse(&["--version"]).unwrap().version);
+        assert_eq!(
+            parse(&["--lint", "a.bat"]).unwrap().lint,
+            Some(PathBuf::from("a.bat"))
+        );
+    }
+
+    #[test]
+    fn test_argument_errors() {
+        let err = parse(&["--bogus"]).unwrap_err();
+        assert!(err.contains("unknown option --bogus"), "got {:?}", err);
+        assert!(parse(&["-x"]).is_err());
+
+        for option in [
+            "--pipe",
+            "--port",
+            "--lint",
+            "--log",
+            "--max-steps",
+            "--coverage",
+        ] {
+            let err = parse(&[option]).unwrap_err();
+            assert!(
+                err.starts_with(&format!("{} expects", option)),
+                "got {:?}",
+                err
+            );
+        }
+
+        assert!(parse(&["--port", "http"]).is_err());
+        assert!(parse(&["--port", "70000"]).is_err());
+        assert!(parse(&["--max-steps", "-1"]).is_err());
+
+        let err = parse(&["a.bat", "b.bat"]).unwrap_err();
+        assert!(err.contains("unexpected argument b.bat"), "got {:?}", err);
+    }
+
+    #[test]
+    fn test_port_is_refused_until_tcp_is_supported() {
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_batch-debugger"))
+            .args(["--port", "4711"])
+            .output()
+            .expect("Failed to run binary");
+
+        assert_eq!(output.status.code(), Some(1));
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("not supported yet"), "got {}", stderr);
+    }
+
+    #[test]
+    fn test_unknown_flag_prints_usage_and_exits_1() {
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_batch-debugger"))
+            .arg("--bogus")
+            .output()
+            .expect("Failed to run binary");
+
+        assert_eq!(output.status.code(), Some(1));
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("unknown option --bogus"), "got {}", stderr);
+        assert!(stderr.contains("usage: batch-debugger"), "got {}", stderr);
+        assert!(output.stdout.is_empty());
+    }
+}
@@ -0,0 +1,317 @@
+// tests/common/mod.rs
+// A scripted DAP client shared by the test binaries: it drives
+// run_dap_session over in-memory pipes and decodes what the server writes.
+// Each binary uses a different part of it.
+#![allow(dead_code)]
+
+use batch_debugger::dap::{run_dap_session, DapServer};
+use batch_debugger::logger::Logger;
+use serde_json::{json, Value};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Script `Client::start` debugs
+pub const SCRIPT: &str = "@echo off\r\n\
+                          set GREETING=hello\r\n\
+                          call :greet\r\n\
+                          echo done\r\n\
+                          exit /b 0\r\n\
+                          \r\n\
+                          :greet\r\n\
+                          echo %GREETING%\r\n\
+                          exit /b\r\n";
+
+/// A request as the client puts it on the wire
+pub fn frame(seq: u64, command: &str, arguments: Value) -> Vec<u8> {
+    let body = json!({
+        "seq": seq,
+        "type": "request",
+        "command": command,
+        "arguments": arguments
+    })
+    .to_string();
+    format!("Content-Length: {}\r\n\r\n{}", body.len(), body).into_bytes()
+}
+
+/// Every whole message in `bytes`, in order; a frame still being written is
+/// left out
+pub fn decode(bytes: &[u8]) -> Vec<Value> {
+    let mut messages = Vec::new();
+    let mut rest = bytes;
+    while let Some(end) = rest.windows(4).position(|w| w == b"\r\n\r\n") {
+        let header = std::str::from_utf8(&rest[..end]).unwrap();
+        let length: usize = header
+            .lines()
+            .find_map(|line| line.strip_prefix("Content-Length: "))
+            .unwrap_or_else(|| panic!("no Content-Length in {:?}", header))
+            .parse()
+            .unwrap();
+        let body = &rest[end + 4..];
+        if body.len() < length {
+            break;
+        }
+        messages.push(serde_json::from_slice(&body[..length]).unwrap());
+        rest = &body[length..];
+    }
+    messages
+}
+
+/// The server's output, collected for the test to read back
+#[derive(Clone, Default)]
+pub struct Shared(pub Arc<Mutex<Vec<u8>>>);
+
+impl Shared {
+    /// Messages written so far, in order
+    pub fn messages(&self) -> Vec<Value> {
+        decode(&self.0.lock().unwrap())
+    }
+}
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The client's end of the server's input: every chunk the client sends
+/// becomes readable, and dropping the client closes the stream
+struct PipeReader {
+    chunks: Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.chunk.len() {
+            match self.chunks.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// A scripted client that sends one request at a time and waits for
+/// what it expects before going on, so the transcript is deterministic
+pub struct Client {
+    input: Option<Sender<Vec<u8>>>,
+    output: Shared,
+    server: Option<JoinHandle<io::Result<()>>>,
+    seq: u64,
+    pub script: String,
+}
+
+impl Client {
+    pub fn start(name: &str) -> Self {
+        Self::with_script(name, SCRIPT)
+    }
+
+    pub fn with_script(name: &str, text: &str) -> Self {
+        let script = format!("test_protocol_{}.bat", name);
+        fs::write(&script, text).unwrap();
+
+        let (input, chunks) = channel();
+        let output = Shared::default();
+        let reader = PipeReader {
+            chunks,
+            chunk: Vec::new(),
+            pos: 0,
+        };
+        let writer = output.clone();
+        let server = std::thread::spawn(move || {
+            run_dap_session(DapServer::with_transport(
+                Logger::disabled(),
+                Box::new(reader),
+                Box::new(writer),
+            ))
+        });
+
+        Self {
+            input: Some(input),
+            output,
+            server: Some(server),
+            seq: 0,
+            script,
+        }
+    }
+
+    pub fn messages(&self) -> Vec<Value> {
+        self.output.messages()
+    }
+
+    /// Wait until some message satisfies `done`, and return the first that
+    /// does
+    pub fn wait_for(&self, what: &str, done: impl Fn(&Value) -> bool) -> Value {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            if let Some(message) = self.messages().into_iter().find(&done) {
+                return message;
+            }
+            assert!(
+                Instant::now() < deadline,
+                "never got {}: {:#?}",
+                what,
+                self.messages()
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Send a request and wait for its response, which is returned
+    pub fn request(&mut self, command: &str, arguments: Value) -> Value {
+        self.seq += 1;
+        let seq = self.seq;
+        self.input
+            .as_ref()
+            .unwrap()
+            .send(frame(seq, command, arguments))
+            .unwrap();
+        self.wait_for(command, |m| {
+            m["type"] == "response" && m["request_seq"] == seq
+        })
+    }
+
+    /// Wait for the `count`th event called `event`
+    pub fn event(&self, event: &str, count: usize) {
+        self.wait_for(event, |_| {
+            self.messages()
+                .iter()
+                .filter(|m| m["type"] == "event" && m["event"] == event)
+                .count()
+                >= count
+        });
+    }
+
+    /// Close the session and return the exchange, one line per message
+    /// from the server, with the sequence numbers checked and dropped
+    pub fn finish(mut self) -> Vec<String> {
+        self.request("disconnect", json!({}));
+        self.input.take();
+        self.server.take().unwrap().join().unwrap().unwrap();
+
+        let messages = self.messages();
+        for (i, message) in messages.iter().enumerate() {
+            assert_eq!(message["seq"], i as u64 + 1, "{}", message);
+        }
+        messages.iter().map(|m| self.render(m)).collect()
+    }
+
+    /// The parts of a message the transcripts pin down
+    fn render(&self, message: &Value) -> String {
+        match message["type"].as_str().unwrap() {
+            "event" => {
+                let body = &message["body"];
+                match message["event"].as_str().unwrap() {
+                    "stopped" => format!("event stopped {}", body["reason"].as_str().unwrap()),
+                    "output" => format!(
+                        "event output {} {}",
+                        body["category"].as_str().unwrap(),
+                        body["output"].as_str().unwrap().trim_end()
+                    ),
+                    "breakpoint" => format!(
+                        "event breakpoint {} verified={}",
+                        body["reason"].as_str().unwrap(),
+                        body["breakpoint"]["verified"]
+                    ),
+                    "thread" => format!(
+                        "event thread {} {}",
+                        body["reason"].as_str().unwrap(),
+                        body["threadId"]
+                    ),
+                    "process" => format!(
+                        "event process {} pid={}",
+                        body["name"].as_str().unwrap(),
+                        body["systemProcessId"].as_u64().is_some_and(|pid| pid > 0)
+                    ),
+                    event => format!("event {}", event),
+                }
+            }
+            "response" => {
+                let command = message["command"].as_str().unwrap();
+                let status = if message["success"] == true {
+                    "ok"
+                } else {
+                    "failed"
+                };
+                let body = &message["body"];
+                match command {
+                    "setBreakpoints" => {
+                        let verified: Vec<String> = body["breakpoints"]
+                            .as_array()
+                            .unwrap()
+                            .iter()
+                            .map(|bp| format!("{}:{}", bp["line"], bp["verified"]))
+                            .collect();
+                        format!("response {} {} [{}]", command, status, verified.join(" "))
+                    }
+                    "threads" => {
+                        let threads: Vec<String> = body["threads"]
+                            .as_array()
+                            .unwrap()
+                            .iter()
+                            .map(|t| format!("{}:{}", t["id"], t["name"].as_str().unwrap()))
+                            .collect();
+                        format!("response {} {} [{}]", command, status, threads.join(" "))
+                    }
+                    "stackTrace" => {
+                        let frames: Vec<String> = body["stackFrames"]
+                            .as_array()
+                            .unwrap()
+                            .iter()
+                            .map(|f| {
+                                let at = format!("{}@{}", f["name"].as_str().unwrap(), f["line"]);
+                                // Columns only show once a line is stepped part by part
+                                match f["column"].as_u64() {
+                                    Some(1) | None => at,
+                                    Some(column) => format!("{}:{}", at, column),
+                                }
+                            })
+                            .collect();
+                        format!("response {} {} [{}]", command, status, frames.join(" "))
+                    }
+                    _ => format!("response {} {}", command, status),
+                }
+            }
+            other => panic!("unexpected message type {}", other),
+        }
+    }
+
+    pub fn launch(&mut self, stop_on_entry: bool) -> Value {
+        let program = self.script.clone();
+        self.request(
+            "launch",
+            json!({ "program": program, "stopOnEntry": stop_on_entry }),
+        )
+    }
+
+    pub fn set_breakpoints(&mut self, lines: &[u64]) -> Value {
+        let breakpoints: Vec<Value> = lines.iter().map(|l| json!({ "line": l })).collect();
+        let path = self.script.clone();
+        self.request(
+            "setBreakpoints",
+            json!({ "source": { "path": path }, "breakpoints": breakpoints }),
+        )
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.script);
+    }
+}
@@ -0,0 +1,135 @@
+// tests/dap_disconnect.rs
+// The disconnect request's restart/terminateDebuggee handling. A disconnect
+// ends run_dap_session, so these call the server's handlers directly to
+// launch again after one.
+
+mod common;
+
+use std::fs;
+
+#[cfg(test)]
+mod disconnect_tests {
+    use super::*;
+    use crate::common::Shared;
+    use batch_debugger::dap::{DapServer, Incoming};
+    use batch_debugger::logger::Logger;
+    use serde_json::json;
+
+    fn server() -> (DapServer, Shared) {
+        let output = Shared::default();
+        let server = DapServer::with_transport(
+            Logger::disabled(),
+            Box::new(std::io::empty()),
+            Box::new(output.clone()),
+        );
+        (server, output)
+    }
+
+    /// Output of a launch that runs straight through, up to termination
+    fn collect_output(server: &mut DapServer) -> String {
+        let mut output = String::new();
+        while let Some(incoming) = server.next_incoming() {
+            match incoming {
+                Incoming::Output(text) => output.push_str(&text),
+                Incoming::Terminated => break,
+                _ => {}
+            }
+        }
+        output
+    }
+
+    #[test]
+    fn test_disconnect_restart_keeps_session() {
+        let script = "test_disconnect_kept.bat";
+        fs::write(script, "@echo off\r\necho kept=%KEPT%\r\n").unwrap();
+
+        // The first session's environment survives a restart...
+        let (mut server, output) = server();
+        server.handle_launch(
+            1,
+            "launch".to_string(),
+            Some(json!({
+                "program": script,
+                "stopOnEntry": true,
+                "preInitCommands": ["set KEPT=yes"]
+            })),
+        );
+        server.handle_disconnect(
+            2,
+            "disconnect".to_string(),
+            Some(json!({ "restart": true })),
+        );
+        let sent = output.messages();
+        assert_eq!(sent.last().unwrap()["command"], "disconnect");
+        assert_eq!(sent.last().unwrap()["success"], true);
+
+        server.handle_launch(
+            3,
+            "launch".to_string(),
+            Some(json!({ "program": script, "stopOnEntry": false })),
+        );
+        let text = collect_output(&mut server);
+        assert!(text.contains("kept=yes"), "got {:?}", text);
+
+        // ...but not termination, which takes cmd.exe with it
+        server.handle_disconnect(4, "disconnect".to_string(), None);
+        server.handle_launch(
+            5,
+            "launch".to_string(),
+            Some(json!({ "program": script, "stopOnEntry": false })),
+        );
+        let text = collect_output(&mut server);
+        assert!(!text.contains("kept=yes"), "got {:?}", text);
+        server.handle_disconnect(6, "disconnect".to_string(), None);
+
+        // Detaching answers at once, even with the script stopped
+        server.handle_launch(
+            7,
+            "launch".to_string(),
+            Some(json!({ "program": script, "stopOnEntry": true })),
+        );
+        server.handle_disconnect(
+            8,
+            "disconnect".to_string(),
+            Some(json!({ "terminateDebuggee": false })),
+        );
+        assert_eq!(output.messages().last().unwrap()["success"], true);
+
+        let _ = fs::remove_file(script);
+    }
+
+    #[test]
+    fn test_restart_keeps_cmd_per_server() {
+        let script = "test_disconnect_own.bat";
+        fs::write(script, "@echo off\r\necho own=%OWN%\r\n").unwrap();
+
+        // A restart on one connection leaves nothing for another to pick up
+        let (mut first, _) = server();
+        first.handle_launch(
+            1,
+            "launch".to_string(),
+            Some(json!({
+                "program": script,
+                "stopOnEntry": true,
+                "preInitCommands": ["set OWN=first"]
+            })),
+        );
+        first.handle_disconnect(
+            2,
+            "disconnect".to_string(),
+            Some(json!({ "restart": true })),
+        );
+
+        let (mut second, _) = server();
+        second.handle_launch(
+            1,
+            "launch".to_string(),
+            Some(json!({ "program": script, "stopOnEntry": false })),
+        );
+        let text = collect_output(&mut second);
+        assert!(!text.contains("own=first"), "got {:?}", text);
+
+        second.handle_disconnect(2, "disconnect".to_string(), None);
+        let _ = fs::remove_file(script);
+    }
+}
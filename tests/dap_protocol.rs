@@ -0,0 +1,829 @@
+// tests/dap_protocol.rs
+// Golden transcripts of whole DAP sessions: a scripted client drives
+// run_dap_session over in-memory pipes and the messages it gets back are
+// compared, in order, against the expected exchange.
+
+mod common;
+
+use std::fs;
+
+#[cfg(test)]
+mod protocol_tests {
+    use super::*;
+    use crate::common::{decode, frame, Client, SCRIPT};
+    use batch_debugger::dap::IDS_PER_SESSION;
+    use batch_debugger::logger::Logger;
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+    use std::io::{self, Read, Write};
+    use std::sync::mpsc::{channel, Sender};
+    use std::time::Duration;
+
+    #[test]
+    fn test_transcript_stop_on_entry() {
+        let mut client = Client::start("entry");
+        client.request("initialize", json!({ "adapterID": "batch" }));
+        client.launch(true);
+        client.event("stopped", 1);
+        client.request("configurationDone", json!({}));
+        client.request("stackTrace", json!({ "threadId": 1 }));
+        client.request("continue", json!({ "threadId": 1 }));
+        client.event("terminated", 1);
+
+        assert_eq!(
+            client.finish(),
+            [
+                "response initialize ok",
+                "event initialized",
+                "response launch ok",
+                "event process test_protocol_entry.bat pid=true",
+                "event thread started 1",
+                "event stopped step",
+                "response configurationDone ok",
+                "response stackTrace ok [main@1]",
+                "response continue ok",
+                "event output stdout hello",
+                "event output stdout done",
+                "event thread exited 1",
+                "event terminated",
+                "response disconnect ok",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_transcript_stop_on_first_executable() {
+        let mut client = Client::with_script(
+            "first_executable",
+            "@echo off\r\nsetlocal EnableDelayedExpansion\r\n:: setup done\r\necho hello\r\n",
+        );
+        client.request("initialize", json!({ "adapterID": "batch" }));
+        let program = client.script.clone();
+        client.request(
+            "launch",
+            json!({ "program": program, "stopOnEntry": "firstExecutable" }),
+        );
+        client.event("stopped", 1);
+        client.request("stackTrace", json!({ "threadId": 1 }));
+        client.request("next", json!({ "threadId": 1 }));
+        client.event("terminated", 1);
+
+        assert_eq!(
+            client.finish(),
+            [
+                "response initialize ok",
+                "event initialized",
+                "response launch ok",
+                "event process test_protocol_first_executable.bat pid=true",
+                "event thread started 1",
+                "event stopped step",
+                "response stackTrace ok [main@4]",
+                "response next ok",
+                "event output stdout hello",
+                "event thread exited 1",
+                "event terminated",
+                "response disconnect ok",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_transcript_set_prompt_input() {
+        let mut client = Client::with_script(
+            "set_prompt",
+            "@echo off\r\n\
+             set /p \"NAME=Name: \"\r\n\
+             echo hello %NAME%\r\n\
+             set /p \"AGE=Age: \"\r\n\
+             echo age %AGE%\r\n",
+        );
+        client.request("initialize", json!({ "adapterID": "batch" }));
+        let program = client.script.clone();
+        // NAME is answered up front; AGE waits for the client
+        client.request(
+            "launch",
+            json!({
+                "program": program,
+                "stopOnEntry": false,
+                "inputValues": { "name": "Ada" }
+            }),
+        );
+        client.event("output", 3);
+        client.request("input", json!({ "value": "36" }));
+        client.event("terminated", 1);
+        client.request("input", json!({ "value": "late" }));
+
+        assert_eq!(
+            client.finish(),
+            [
+                "response initialize ok",
+                "event initialized",
+                "response launch ok",
+                "event process test_protocol_set_prompt.bat pid=true",
+                "event thread started 1",
+                "event output stdout Name: Ada",
+                "event output stdout hello Ada",
+                "event output important Age: \r\n(waiting for input: type a value in the Debug Console)",
+                "response input ok",
+                "event output stdout age 36",
+                "event thread exited 1",
+                "event terminated",
+                "response input failed",
+                "response disconnect ok",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_transcript_statement_steps_move_column() {
+        let mut client = Client::with_script(
+            "statement_column",
+            "@echo off\r\necho one & echo two && echo three\r\necho done\r\n",
+        );
+        client.request("initialize", json!({ "adapterID": "batch" }));
+        client.launch(true);
+        client.event("stopped", 1);
+        for stop in 2..=5 {
+            client.request("next", json!({ "threadId": 1, "granularity": "statement" }));
+            client.event("stopped", stop);
+            client.request("stackTrace", json!({ "threadId": 1 }));
+        }
+        client.request("continue", json!({ "threadId": 1 }));
+        client.event("terminated", 1);
+
+        assert_eq!(
+            client.finish(),
+            [
+                "response initialize ok",
+                "event initialized",
+                "response launch ok",
+                "event process test_protocol_statement_column.bat pid=true",
+                "event thread started 1",
+                "event stopped step",
+                "response next ok",
+                "event stopped step",
+                "response stackTrace ok [main@2]",
+                "response next ok",
+                "event output stdout one",
+                "event stopped step",
+                "response stackTrace ok [main@2:12]",
+                "response next ok",
+                "event output stdout two",
+                "event stopped step",
+                "response stackTrace ok [main@2:24]",
+                "response next ok",
+                "event output stdout three",
+                "event stopped step",
+                "response stackTrace ok [main@3]",
+                "response continue ok",
+                "event output stdout done",
+                "event thread exited 1",
+                "event terminated",
+                "response disconnect ok",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_transcript_breakpoint_hit() {
+        let mut client = Client::start("breakpoint");
+        client.request("initialize", json!({ "adapterID": "batch" }));
+        client.set_breakpoints(&[8, 40]);
+        client.launch(false);
+        client.event("stopped", 1);
+        client.request("configurationDone", json!({}));
+        client.request("stackTrace", json!({ "threadId": 1 }));
+        client.request("continue", json!({ "threadId": 1 }));
+        client.event("terminated", 1);
+
+        assert_eq!(
+            client.finish(),
+            [
+                "response initialize ok",
+                "event initialized",
+                "response setBreakpoints ok [8:false 40:false]",
+                "response launch ok",
+                "event process test_protocol_breakpoint.bat pid=true",
+                "event thread started 1",
+                "event breakpoint changed verified=true",
+                "event breakpoint changed verified=false",
+                "event stopped breakpoint",
+                "response configurationDone ok",
+                "response stackTrace ok [greet@8 main@3]",
+                "response continue ok",
+                "event output stdout hello",
+                "event output stdout done",
+                "event thread exited 1",
+                "event terminated",
+                "response disconnect ok",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_transcript_watch_pane() {
+        let mut client = Client::start("watch");
+        client.request("initialize", json!({ "adapterID": "batch" }));
+        client.set_breakpoints(&[8]);
+        client.launch(false);
+        client.event("stopped", 1);
+        client.request("configurationDone", json!({}));
+
+        // What the client's watch pane sends for each entry at a stop
+        let watch = |expression: &str| json!({ "expression": expression, "context": "watch" });
+        let response = client.request("evaluate", watch("%GREETING%"));
+        assert_eq!(response["body"]["result"], "hello");
+        let response = client.request("evaluate", watch("!GREETING! & world"));
+        assert_eq!(response["body"]["result"], "hello & world");
+        let response = client.request("evaluate", watch("MISSING"));
+        assert_eq!(response["message"], "MISSING is not defined");
+
+        // The entries come back in the Watches scope
+        let response = client.request("scopes", json!({ "frameId": 1 }));
+        let scope = response["body"]["scopes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|s| s["name"] == "Watches")
+            .unwrap_or_else(|| panic!("no Watches scope in {}", response))
+            .clone();
+        assert_eq!(scope["namedVariables"], 3);
+        let reference = scope["variablesReference"].as_u64().unwrap();
+        assert_eq!(reference % IDS_PER_SESSION, 4);
+        let response = client.request("variables", json!({ "variablesReference": reference }));
+        let watched: HashMap<String, String> = response["body"]["variables"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| {
+                (
+                    v["name"].as_str().unwrap().to_string(),
+                    v["value"].as_str().unwrap().to_string(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            watched,
+            HashMap::from([
+                ("%GREETING%".to_string(), "hello".to_string()),
+                (
+                    "!GREETING! & world".to_string(),
+                    "hello & world".to_string()
+                ),
+                ("MISSING".to_string(), "<undefined>".to_string()),
+            ])
+        );
+
+        client.request("continue", json!({ "threadId": 1 }));
+        client.event("terminated", 1);
+
+        assert_eq!(
+            client.finish(),
+            [
+                "response initialize ok",
+                "event initialized",
+                "response setBreakpoints ok [8:false]",
+                "response launch ok",
+                "event process test_protocol_watch.bat pid=true",
+                "event thread started 1",
+                "event breakpoint changed verified=true",
+                "event stopped breakpoint",
+                "response configurationDone ok",
+                "response evaluate ok",
+                "response evaluate ok",
+                "response evaluate failed",
+                "response scopes ok",
+                "response variables ok",
+                "response continue ok",
+                "event output stdout hello",
+                "event output stdout done",
+                "event thread exited 1",
+                "event terminated",
+                "response disconnect ok",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_transcript_set_watched_expression() {
+        let mut client = Client::start("set_expression");
+        let response = client.request("initialize", json!({ "adapterID": "batch" }));
+        assert_eq!(response["body"]["supportsSetExpression"], true);
+        client.set_breakpoints(&[8]);
+        client.launch(false);
+        client.event("stopped", 1);
+        client.request("configurationDone", json!({}));
+
+        // The client offers to edit a watch entry it could evaluate
+        let watch = json!({ "expression": "%GREETING%", "context": "watch" });
+        let response = client.request("evaluate", watch.clone());
+        assert_eq!(response["body"]["result"], "hello");
+        let response = client.request(
+            "setExpression",
+            json!({ "expression": "%GREETING%", "value": "goodbye", "frameId": 0 }),
+        );
+        assert_eq!(response["body"]["value"], "goodbye");
+        let response = client.request("evaluate", watch);
+        assert_eq!(response["body"]["result"], "goodbye");
+
+        // The line stopped at sees the new value
+        client.request("continue", json!({ "threadId": 1 }));
+        client.event("terminated", 1);
+
+        assert_eq!(
+            client.finish(),
+            [
+                "response initialize ok",
+                "event initialized",
+                "response setBreakpoints ok [8:false]",
+                "response launch ok",
+                "event process test_protocol_set_expression.bat pid=true",
+                "event thread started 1",
+                "event breakpoint changed verified=true",
+                "event stopped breakpoint",
+                "response configurationDone ok",
+                "response evaluate ok",
+                "response setExpression ok",
+                "response evaluate ok",
+                "response continue ok",
+                "event output stdout goodbye",
+                "event output stdout done",
+                "event thread exited 1",
+                "event terminated",
+                "response disconnect ok",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_transcript_step_over_call() {
+        let mut client = Client::start("step_over");
+        client.request("initialize", json!({ "adapterID": "batch" }));
+        client.set_breakpoints(&[3]);
+        client.launch(false);
+        client.event("stopped", 1);
+        client.request("configurationDone", json!({}));
+        client.request("next", json!({ "threadId": 1 }));
+        client.event("stopped", 2);
+        client.request("stackTrace", json!({ "threadId": 1 }));
+        client.request("continue", json!({ "threadId": 1 }));
+        client.event("terminated", 1);
+
+        assert_eq!(
+            client.finish(),
+            [
+                "response initialize ok",
+                "event initialized",
+                "response setBreakpoints ok [3:false]",
+                "response launch ok",
+                "event process test_protocol_step_over.bat pid=true",
+                "event thread started 1",
+                "event breakpoint changed verified=true",
+                "event stopped breakpoint",
+                "response configurationDone ok",
+                "response next ok",
+                "event output stdout hello",
+                "event stopped step",
+                "response stackTrace ok [main@4]",
+                "response continue ok",
+                "event output stdout done",
+                "event thread exited 1",
+                "event terminated",
+                "response disconnect ok",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_transcript_nested_calls_innermost_first() {
+        let mut client = Client::with_script(
+            "nested",
+            "@echo off\r\n\
+             call :outer\r\n\
+             echo done\r\n\
+             exit /b 0\r\n\
+             :outer\r\n\
+             call :inner\r\n\
+             exit /b\r\n\
+             :inner\r\n\
+             echo deep\r\n\
+             exit /b\r\n",
+        );
+        client.request("initialize", json!({ "adapterID": "batch" }));
+        client.set_breakpoints(&[9]);
+        client.launch(false);
+        client.event("stopped", 1);
+        client.request("configurationDone", json!({}));
+        let trace = client.request("stackTrace", json!({ "threadId": 1 }));
+
+        // Ids count up from the bottom of the stack, as restartFrame reads them
+        let ids: Vec<u64> = trace["body"]["stackFrames"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|f| f["id"].as_u64().unwrap() % IDS_PER_SESSION)
+            .collect();
+        assert_eq!(ids, [0, 2, 1]);
+
+        client.request("continue", json!({ "threadId": 1 }));
+        client.event("terminated", 1);
+
+        assert_eq!(
+            client.finish(),
+            [
+                "response initialize ok",
+                "event initialized",
+                "response setBreakpoints ok [9:false]",
+                "response launch ok",
+                "event process test_protocol_nested.bat pid=true",
+                "event thread started 1",
+                "event breakpoint changed verified=true",
+                "event stopped breakpoint",
+                "response configurationDone ok",
+                "response stackTrace ok [inner@9 outer@6 main@2]",
+                "response continue ok",
+                "event output stdout deep",
+                "event output stdout done",
+                "event thread exited 1",
+                "event terminated",
+                "response disconnect ok",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_transcript_run_to_completion() {
+        let mut client = Client::start("completion");
+        client.request("initialize", json!({ "adapterID": "batch" }));
+        client.launch(false);
+        client.event("terminated", 1);
+        client.request("configurationDone", json!({}));
+
+        assert_eq!(
+            client.finish(),
+            [
+                "response initialize ok",
+                "event initialized",
+                "response launch ok",
+                "event process test_protocol_completion.bat pid=true",
+                "event thread started 1",
+                "event output stdout hello",
+                "event output stdout done",
+                "event thread exited 1",
+                "event terminated",
+                "response configurationDone ok",
+                "response disconnect ok",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_transcript_source_by_reference() {
+        let mut client = Client::start("source");
+        client.request("initialize", json!({ "adapterID": "batch" }));
+        client.launch(true);
+        client.event("stopped", 1);
+        client.request("configurationDone", json!({}));
+        client.request("stackTrace", json!({ "threadId": 1 }));
+
+        // A client that can't open the file fetches it through the frame's
+        // reference and gets the script as launched
+        let messages = client.messages();
+        let trace = messages
+            .iter()
+            .rfind(|m| m["command"] == "stackTrace")
+            .unwrap();
+        let reference = trace["body"]["stackFrames"][0]["source"]["sourceReference"].clone();
+        assert!(reference.as_u64().is_some_and(|r| r > 0), "got {}", trace);
+        client.request("source", json!({ "sourceReference": reference }));
+        let messages = client.messages();
+        let served = messages.iter().rfind(|m| m["command"] == "source").unwrap();
+        assert_eq!(served["body"]["content"], SCRIPT);
+
+        client.request("source", json!({ "sourceReference": 999 }));
+        client.request("continue", json!({ "threadId": 1 }));
+        client.event("terminated", 1);
+
+        assert_eq!(
+            client.finish(),
+            [
+                "response initialize ok",
+                "event initialized",
+                "response launch ok",
+                "event process test_protocol_source.bat pid=true",
+                "event thread started 1",
+                "event stopped step",
+                "response configurationDone ok",
+                "response stackTrace ok [main@1]",
+                "response source ok",
+                "response source failed",
+                "response continue ok",
+                "event output stdout hello",
+                "event output stdout done",
+                "event thread exited 1",
+                "event terminated",
+                "response disconnect ok",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disassemble_shows_logical_lines() {
+        let mut client = Client::with_script(
+            "disassemble",
+            "@echo off\r\n\
+             echo one ^\r\n\
+             two\r\n\
+             if 1==1 (\r\n\
+             \x20\x20set   X=1\r\n\
+             )\r\n",
+        );
+        client.request("initialize", json!({ "adapterID": "batch" }));
+        let window = json!({
+            "memoryReference": "0",
+            "instructionOffset": -1,
+            "instructionCount": 7
+        });
+        let response = client.request("disassemble", window.clone());
+        assert_eq!(response["success"], false, "Nothing launched yet");
+
+        client.launch(true);
+        client.event("stopped", 1);
+
+        // One address before the script and one past it pad the window
+        let response = client.request("disassemble", window);
+        let instructions: Vec<String> = response["body"]["instructions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|i| match i["presentationHint"].as_str() {
+                Some(hint) => format!("{} <{}>", i["address"].as_str().unwrap(), hint),
+                None => format!(
+                    "{} {} | {} | {}-{}",
+                    i["address"].as_str().unwrap(),
+                    i["instructionBytes"].as_str().unwrap(),
+                    i["instruction"].as_str().unwrap(),
+                    i["line"],
+                    i["endLine"]
+                ),
+            })
+            .collect();
+        assert_eq!(
+            instructions,
+            [
+                "-1 <invalid>",
+                "0 L1-1 D0 G- | @echo off | 1-1",
+                "1 L2-3 D0 G- | echo one two | 2-3",
+                "2 L4-4 D0 G- | if 1==1 ( | 4-4",
+                "3 L5-5 D1 G1 | set X=1 | 5-5",
+                "4 L6-6 D1 G1 | ) | 6-6",
+                "5 <invalid>",
+            ]
+        );
+        assert_eq!(
+            response["body"]["instructions"][1]["location"]["path"],
+            client.script.as_str()
+        );
+
+        // Out-of-range windows are answered, not overflowed or allocated whole
+        let response = client.request(
+            "disassemble",
+            json!({
+                "memoryReference": i64::MAX.to_string(),
+                "instructionOffset": i64::MAX,
+                "instructionCount": 3
+            }),
+        );
+        assert_eq!(response["success"], true, "{}", response);
+        let response = client.request(
+            "disassemble",
+            json!({ "memoryReference": "0", "instructionCount": i64::MAX }),
+        );
+        let instructions = response["body"]["instructions"].as_array().unwrap();
+        assert_eq!(instructions.len(), 10_000);
+        assert_eq!(instructions[5]["instruction"], "");
+        client.finish();
+    }
+
+    #[test]
+    fn test_transcript_cancel() {
+        let mut client = Client::start("cancel");
+        client.request("initialize", json!({ "adapterID": "batch" }));
+        client.launch(true);
+        client.event("stopped", 1);
+        client.request("configurationDone", json!({}));
+        // Cancelling anything but the launch leaves the script alone...
+        client.request("cancel", json!({ "requestId": 3 }));
+        client.request("cancel", json!({ "progressId": "build" }));
+        client.request("cancel", json!({}));
+        client.request("stackTrace", json!({ "threadId": 1 }));
+        // ...while cancelling the launch means the rest never runs
+        client.request("cancel", json!({ "requestId": 2 }));
+        client.event("terminated", 1);
+
+        assert_eq!(
+            client.finish(),
+            [
+                "response initialize ok",
+                "event initialized",
+                "response launch ok",
+                "event process test_protocol_cancel.bat pid=true",
+                "event thread started 1",
+                "event stopped step",
+                "response configurationDone ok",
+                "response cancel ok",
+                "response cancel ok",
+                "response cancel ok",
+                "response stackTrace ok [main@1]",
+                "response cancel ok",
+                "event thread exited 1",
+                "event terminated",
+                "response disconnect ok",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_transcript_two_sessions() {
+        let mut client = Client::start("sessions");
+        let second = "test_protocol_sessions_second.bat";
+        fs::write(second, "@echo off\r\necho second\r\n").unwrap();
+
+        client.request("initialize", json!({ "adapterID": "batch" }));
+        client.launch(true);
+        client.event("stopped", 1);
+        // A second launch runs next to the first instead of replacing it
+        client.request("launch", json!({ "program": second, "stopOnEntry": true }));
+        client.event("stopped", 2);
+        client.request("configurationDone", json!({}));
+        client.request("threads", json!({}));
+        // Thread ids name the session a request is for
+        client.request("continue", json!({ "threadId": IDS_PER_SESSION + 1 }));
+        // Both threads started; the third thread event is the second exiting
+        client.event("thread", 3);
+        client.request("threads", json!({}));
+        client.request("continue", json!({ "threadId": 1 }));
+        client.event("terminated", 1);
+
+        let transcript = client.finish();
+        fs::remove_file(second).unwrap();
+        assert_eq!(
+            transcript,
+            [
+                "response initialize ok",
+                "event initialized",
+                "response launch ok",
+                "event process test_protocol_sessions.bat pid=true",
+                "event thread started 1",
+                "event stopped step",
+                "response launch ok",
+                "event process test_protocol_sessions_second.bat pid=true",
+                "event thread started 10001",
+                "event stopped step",
+                "response configurationDone ok",
+                "response threads ok [1:test_protocol_sessions.bat 10001:test_protocol_sessions_second.bat]",
+                "response continue ok",
+                "event output stdout second",
+                "event thread exited 10001",
+                "response threads ok [1:test_protocol_sessions.bat]",
+                "response continue ok",
+                "event output stdout hello",
+                "event output stdout done",
+                "event thread exited 1",
+                "event terminated",
+                "response disconnect ok",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_capabilities_match_handlers() {
+        use batch_debugger::dap::{Capabilities, ErrorCode};
+
+        // Capability and a request it promises the adapter answers
+        let gated = [
+            ("supportsConfigurationDoneRequest", "configurationDone"),
+            ("supportsFunctionBreakpoints", "setFunctionBreakpoints"),
+            ("supportsStepBack", "stepBack"),
+            ("supportsStepBack", "reverseContinue"),
+            ("supportsSetVariable", "setVariable"),
+            ("supportsRestartFrame", "restartFrame"),
+            ("supportsGotoTargetsRequest", "gotoTargets"),
+            ("supportsStepInTargetsRequest", "stepInTargets"),
+            ("supportsCompletionsRequest", "completions"),
+            ("supportsModulesRequest", "modules"),
+            ("supportsRestartRequest", "restart"),
+            ("supportsExceptionInfoRequest", "exceptionInfo"),
+            ("supportsLoadedSourcesRequest", "loadedSources"),
+            ("supportsTerminateThreadsRequest", "terminateThreads"),
+            ("supportsSetExpression", "setExpression"),
+            ("supportsTerminateRequest", "terminate"),
+            ("supportsDataBreakpoints", "dataBreakpointInfo"),
+            ("supportsDataBreakpoints", "setDataBreakpoints"),
+            ("supportsReadMemoryRequest", "readMemory"),
+            ("supportsWriteMemoryRequest", "writeMemory"),
+            ("supportsDisassembleRequest", "disassemble"),
+            ("supportsCancelRequest", "cancel"),
+            ("supportsBreakpointLocationsRequest", "breakpointLocations"),
+            (
+                "supportsInstructionBreakpoints",
+                "setInstructionBreakpoints",
+            ),
+        ];
+
+        let mut client = Client::start("capabilities");
+        let responses: HashMap<&str, Value> = std::iter::once("initialize")
+            .chain(gated.iter().map(|(_, command)| *command))
+            .map(|command| (command, client.request(command, json!({}))))
+            .collect();
+        client.finish();
+
+        let capabilities = &responses["initialize"]["body"];
+        assert_eq!(
+            *capabilities,
+            serde_json::to_value(Capabilities::adapter()).unwrap()
+        );
+        for (capability, command) in gated {
+            let supported = capabilities[capability]
+                .as_bool()
+                .unwrap_or_else(|| panic!("{} missing", capability));
+            let unknown =
+                responses[command]["body"]["error"]["id"] == ErrorCode::UnknownCommand.id();
+            assert_eq!(
+                supported,
+                !unknown,
+                "{} is {} but {} is {}",
+                capability,
+                supported,
+                command,
+                if unknown { "unhandled" } else { "handled" }
+            );
+        }
+    }
+
+    /// A connection's output, sent to the test as it is written
+    struct Sink(usize, Sender<(usize, Vec<u8>)>);
+    impl Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let _ = self.1.send((self.0, buf.to_vec()));
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_serve_connections_outlives_failed_accept() {
+        use batch_debugger::dap::serve_connections;
+
+        let input: Vec<u8> = [(1, "initialize"), (2, "disconnect")]
+            .into_iter()
+            .flat_map(|(seq, command)| frame(seq, command, json!({})))
+            .collect();
+
+        // A client that fails to connect sits between two that do
+        let (sent, written) = channel();
+        let mut accepts = vec![
+            Ok(0),
+            Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "client went away",
+            )),
+            Ok(1),
+        ]
+        .into_iter();
+        serve_connections(Logger::disabled(), || {
+            Some(accepts.next()?.map(|id| {
+                let halves: (Box<dyn Read + Send>, Box<dyn Write + Send>) = (
+                    Box::new(io::Cursor::new(input.clone())),
+                    Box::new(Sink(id, sent.clone())),
+                );
+                halves
+            }))
+        })
+        .unwrap();
+
+        // Each connection got a session of its own, answered in full
+        let mut output = [Vec::new(), Vec::new()];
+        let answered = |bytes: &Vec<u8>| {
+            decode(bytes)
+                .iter()
+                .filter(|m| m["type"] == "response")
+                .map(|m| m["command"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        while !output
+            .iter()
+            .all(|o| answered(o).iter().any(|c| c == "disconnect"))
+        {
+            let (id, bytes) = written
+                .recv_timeout(Duration::from_secs(10))
+                .unwrap_or_else(|e| panic!("sessions never finished ({}): {:?}", e, output));
+            output[id].extend(bytes);
+        }
+        for bytes in &output {
+            assert_eq!(answered(bytes), ["initialize", "disconnect"]);
+        }
+    }
+}
@@ -0,0 +1,91 @@
+// tests/dap_reader.rs
+// Dropping a DapServer ends its message reader thread. One test in its own
+// binary, so no other test's threads come and go while the thread count is
+// being compared.
+
+#[cfg(test)]
+mod reader_tests {
+    use batch_debugger::dap::DapServer;
+    use batch_debugger::logger::Logger;
+    use std::io::{self, Read};
+    use std::sync::mpsc::{channel, Receiver};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    /// A client stream whose reads block until the test hands over bytes;
+    /// the Arc is released when the reader thread drops the stream
+    struct Blocking {
+        chunks: Receiver<Vec<u8>>,
+        _alive: Arc<()>,
+    }
+    impl Read for Blocking {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.chunks.recv() {
+                Ok(chunk) => {
+                    buf[..chunk.len()].copy_from_slice(&chunk);
+                    Ok(chunk.len())
+                }
+                Err(_) => Ok(0),
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn thread_count() -> usize {
+        std::fs::read_dir("/proc/self/task").unwrap().count()
+    }
+
+    /// Wait until `done` holds, failing after a few seconds
+    fn wait_until(what: &str, mut done: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() {
+            assert!(Instant::now() < deadline, "{}", what);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_dropping_server_ends_reader_thread() {
+        #[cfg(target_os = "linux")]
+        let baseline = thread_count();
+
+        // The client's end of the stream, which only the interrupt closes
+        let alive = Arc::new(());
+        let (client, chunks) = channel::<Vec<u8>>();
+        let client = Arc::new(Mutex::new(Some(client)));
+        let mut server = DapServer::with_transport(
+            Logger::disabled(),
+            Box::new(Blocking {
+                chunks,
+                _alive: Arc::clone(&alive),
+            }),
+            Box::new(io::sink()),
+        );
+        let closer = Arc::clone(&client);
+        server.interrupt_reader_with(move || {
+            closer.lock().unwrap().take();
+        });
+        server.start_reader();
+        #[cfg(target_os = "linux")]
+        assert_eq!(thread_count(), baseline + 1);
+
+        // No data is ever sent; dropping the server alone ends the read
+        drop(server);
+        wait_until("reader thread still holds the stream", || {
+            Arc::strong_count(&alive) == 1
+        });
+        #[cfg(target_os = "linux")]
+        wait_until("reader thread still running", || thread_count() == baseline);
+        assert!(client.lock().unwrap().is_none());
+
+        // However many servers come and go on stdin, at most the one
+        // process-wide forwarder is left reading it
+        for _ in 0..3 {
+            let mut server = DapServer::with_logger(Logger::disabled());
+            server.start_reader();
+            drop(server);
+        }
+        #[cfg(target_os = "linux")]
+        assert!(thread_count() <= baseline + 1);
+    }
+}
@@ -1,3 +1,5 @@
+mod common;
+
 use std::fs;
 
 // Helper to create a test batch file
@@ -31,10 +33,10 @@ exit /b 0
         let physical_lines: Vec<&str> = contents.lines().collect();
 
         let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
-        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&pre);
 
         // Verify parsing
-        assert!(pre.logical.len() > 0, "Should have parsed logical lines");
+        assert!(!pre.logical.is_empty(), "Should have parsed logical lines");
         assert_eq!(labels.len(), 0, "Should have no labels");
 
         cleanup_test_batch(&path);
@@ -55,17 +57,207 @@ exit /b 0
         let contents = fs::read_to_string(&path).expect("Could not read test file");
         let physical_lines: Vec<&str> = contents.lines().collect();
 
-        let labels = batch_debugger::parser::build_label_map(&physical_lines);
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&pre);
 
         assert_eq!(labels.len(), 1, "Should have found 1 label");
         assert!(
-            labels.contains_key("subroutine"),
+            labels.contains("subroutine"),
             "Should have found :subroutine label"
         );
 
         cleanup_test_batch(&path);
     }
 
+    #[test]
+    fn test_find_label() {
+        let lines = vec![
+            "@echo off",
+            "echo one ^",
+            "  continued",
+            ":Retry",
+            "goto :retry",
+        ];
+        let pre = batch_debugger::parser::preprocess_lines(&lines);
+        let labels = batch_debugger::parser::build_label_map(&pre);
+
+        // The continuation joins lines 2-3, so the label is logical line 2
+        assert_eq!(labels.resolve_label(0, "retry"), Some(2));
+        assert_eq!(labels.resolve_label(0, ":RETRY"), Some(2));
+        assert_eq!(labels.resolve_label(3, "Retry"), Some(2));
+        assert_eq!(labels.resolve_label(0, "missing"), None);
+        assert_eq!(labels.resolve_label(0, ":"), None);
+
+        // Owned lines give the same result
+        let owned: Vec<String> = lines.iter().map(|l| l.to_string()).collect();
+        let owned_pre = batch_debugger::parser::preprocess_lines(&owned);
+        assert_eq!(batch_debugger::parser::build_label_map(&owned_pre), labels);
+        assert_eq!(owned_pre.phys_to_logical, pre.phys_to_logical);
+    }
+
+    #[test]
+    fn test_label_after_continuation() {
+        let lines = vec![
+            "@echo off",
+            "echo one ^",
+            ":swallowed",
+            "  :after",
+            "echo two",
+        ];
+        let pre = batch_debugger::parser::preprocess_lines(&lines);
+        let labels = batch_debugger::parser::build_label_map(&pre);
+
+        // The caret makes `:swallowed` part of the echo, as cmd.exe reads it
+        assert_eq!(pre.logical[1].text, "echo one  :swallowed");
+        assert!(!labels.contains("swallowed"));
+        // Indentation doesn't stop a label from being one
+        assert_eq!(labels.definitions("after"), &[2]);
+        assert_eq!(labels.resolve_label(0, "after"), Some(2));
+    }
+
+    #[test]
+    fn test_label_names() {
+        use batch_debugger::parser::label_name;
+
+        assert_eq!(label_name(":step-3.cleanup"), Some("step-3.cleanup"));
+        assert_eq!(label_name("  :3rd_pass"), Some("3rd_pass"));
+        assert_eq!(label_name("a+b"), Some("a+b"));
+        // The name ends at the first delimiter; the rest is junk to GOTO
+        assert_eq!(
+            label_name(":step-3.cleanup now please"),
+            Some("step-3.cleanup")
+        );
+        assert_eq!(label_name(":end:ignored"), Some("end"));
+        assert_eq!(label_name(":end,x"), Some("end"));
+        assert_eq!(label_name(":end&echo x"), Some("end"));
+        assert_eq!(label_name(":end)"), Some("end"));
+        assert_eq!(label_name(":"), None);
+        assert_eq!(label_name(" "), None);
+
+        let lines = vec![
+            "@echo off",
+            "goto :step-3.cleanup trailing junk",
+            ":1st",
+            "echo skipped",
+            ":Step-3.Cleanup  the rest is ignored",
+            "call :1st,arg",
+        ];
+        let pre = batch_debugger::parser::preprocess_lines(&lines);
+        let labels = batch_debugger::parser::build_label_map(&pre);
+        assert_eq!(labels.len(), 2);
+        assert_eq!(
+            labels.resolve_label(1, "step-3.cleanup trailing junk"),
+            Some(4)
+        );
+        assert_eq!(labels.resolve_label(0, "1ST"), Some(2));
+        assert!(batch_debugger::parser::validate_script(&lines).is_empty());
+    }
+
+    #[test]
+    fn test_goto_dotted_label_runs() {
+        use batch_debugger::api::run_to_completion;
+        use std::path::Path;
+
+        let script = create_test_batch(
+            "@echo off\r\ngoto :step-3.cleanup junk\r\necho skipped\r\n:step-3.cleanup\r\necho cleaned\r\n",
+            "dotted_label",
+        );
+        let trace = run_to_completion(Path::new(&script)).expect("run failed");
+        cleanup_test_batch(&script);
+
+        let lines: Vec<usize> = trace.lines.iter().map(|l| l.line).collect();
+        assert_eq!(lines, [1, 2, 5], "got {:?}", trace.lines);
+        assert!(trace.lines[2].output.contains("cleaned"));
+    }
+
+    #[test]
+    fn test_duplicate_labels_resolve_forward_then_wrap() {
+        let lines = vec![
+            "@echo off",
+            ":loop",
+            "echo first",
+            "goto loop",
+            ":LOOP",
+            "echo second",
+            "goto loop",
+        ];
+        let pre = batch_debugger::parser::preprocess_lines(&lines);
+        let labels = batch_debugger::parser::build_label_map(&pre);
+
+        assert_eq!(labels.len(), 1);
+        assert_eq!(labels.definitions("loop"), &[1, 4]);
+        // GOTO takes the next definition below it...
+        assert_eq!(labels.resolve_label(0, "loop"), Some(1));
+        assert_eq!(labels.resolve_label(3, "loop"), Some(4));
+        // ...and wraps to the top when there is none
+        assert_eq!(labels.resolve_label(6, "loop"), Some(1));
+    }
+
+    #[test]
+    fn test_validate_unbalanced_parens() {
+        use batch_debugger::parser::{validate_script, Severity};
+
+        let lines = vec![
+            "@echo off",
+            "if exist x.txt (",
+            "    echo \"(not a block\"",
+            "    for %%i in (a b) do (",
+            "        echo %%i",
+            ")",
+            "echo done",
+        ];
+        let diagnostics = validate_script(&lines);
+        assert_eq!(diagnostics.len(), 1, "{:?}", diagnostics);
+        assert_eq!(diagnostics[0].line, 1, "the outer block is left open");
+        assert_eq!(diagnostics[0].severity, Severity::Error);
+
+        // Closed on the last line, and a stray `)` outside any block is just text
+        let balanced = vec!["@echo off", "if 1==1 (", "  echo (a)", ")", "echo )"];
+        assert!(validate_script(&balanced).is_empty());
+    }
+
+    #[test]
+    fn test_validate_undefined_and_duplicate_labels() {
+        use batch_debugger::parser::{validate_script, Severity};
+
+        let lines = vec![
+            "@echo off",
+            "call :helper arg",
+            "if errorlevel 1 goto missing",
+            "goto :eof",
+            "call other.bat & goto %TARGET%",
+            ":: a comment, not a label",
+            ":helper",
+            "call :nowhere",
+            "exit /b",
+            ":HELPER",
+        ];
+        let diagnostics: Vec<(usize, Severity, String)> = validate_script(&lines)
+            .into_iter()
+            .map(|d| (d.line, d.severity, d.message))
+            .collect();
+        assert_eq!(
+            diagnostics,
+            [
+                (
+                    2,
+                    Severity::Error,
+                    "GOTO to undefined label ':missing'".to_string()
+                ),
+                (
+                    7,
+                    Severity::Error,
+                    "CALL to undefined label ':nowhere'".to_string()
+                ),
+                (
+                    9,
+                    Severity::Warning,
+                    "label ':helper' is already defined on line 7".to_string()
+                ),
+            ]
+        );
+    }
+
     #[test]
     fn test_line_continuation() {
         let content = r#"@echo off
@@ -90,6 +282,22 @@ exit /b 0
         cleanup_test_batch(&path);
     }
 
+    #[test]
+    fn test_escaped_caret_does_not_continue() {
+        use batch_debugger::parser::join_continued_lines;
+
+        let joined = join_continued_lines(&["echo ^^"]);
+        assert_eq!(joined.len(), 1);
+        assert_eq!(joined[0].text, "echo ^^", "the escaped caret is kept whole");
+        assert_eq!((joined[0].phys_start, joined[0].phys_end), (0, 0));
+
+        // An escaped caret followed by a continuation caret still joins
+        let joined = join_continued_lines(&["echo ^^^", "next"]);
+        assert_eq!(joined.len(), 1);
+        assert_eq!(joined[0].text, "echo ^^ next");
+        assert_eq!((joined[0].phys_start, joined[0].phys_end), (0, 1));
+    }
+
     #[test]
     fn test_comment_detection() {
         assert!(batch_debugger::parser::is_comment("REM This is a comment"));
@@ -100,6 +308,98 @@ exit /b 0
         assert!(!batch_debugger::parser::is_comment("echo Hello"));
     }
 
+    #[test]
+    fn test_bare_rem_is_comment() {
+        use batch_debugger::parser::is_comment;
+
+        assert!(is_comment("rem"));
+        assert!(is_comment("REM"));
+        assert!(is_comment("rem "));
+
+        // ECHO. prints a blank line; it is a command, not a comment
+        assert!(!is_comment("ECHO."));
+        assert!(!is_comment("echo."));
+        assert!(!is_comment("remark"));
+    }
+
+    #[test]
+    fn test_logical_line_helpers() {
+        let lines = vec![
+            "",
+            "   ",
+            " :: comment",
+            "REM note",
+            ":label with spaces",
+            "  :indented",
+            "echo :not a label",
+            "remark",
+        ];
+        let pre = batch_debugger::parser::preprocess_lines(&lines);
+        let kinds: Vec<(bool, bool, bool)> = pre
+            .logical
+            .iter()
+            .map(|ll| (ll.is_empty(), ll.is_comment(), ll.is_label()))
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                (true, false, false),
+                (true, false, false),
+                (false, true, false),
+                (false, true, false),
+                (false, false, true),
+                (false, false, true),
+                (false, false, false),
+                (false, false, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_paren_delta_ignores_comments() {
+        use batch_debugger::executor::paren_delta;
+
+        // Regression: an unclosed paren inside a comment must not start a block
+        assert_eq!(paren_delta("REM (unclosed"), 0);
+        assert_eq!(paren_delta("  rem (unclosed"), 0);
+        assert_eq!(paren_delta(":: (unclosed"), 0);
+        assert_eq!(paren_delta("echo a & REM (unclosed"), 0);
+
+        // Real blocks and words that merely start with "rem" still count
+        assert_eq!(paren_delta("if 1==1 ("), 1);
+        assert_eq!(paren_delta("remove_me ("), 1);
+        assert_eq!(paren_delta("echo \"(\""), 0);
+    }
+
+    #[test]
+    fn test_normalize_whitespace_outside_quotes() {
+        use batch_debugger::parser::normalize_whitespace_outside_quotes;
+
+        assert_eq!(
+            normalize_whitespace_outside_quotes("set  \"A=b  c\""),
+            "set \"A=b  c\""
+        );
+        assert_eq!(
+            normalize_whitespace_outside_quotes("  echo\t  \"x   y\"   z  "),
+            "echo \"x   y\" z"
+        );
+    }
+
+    #[test]
+    fn test_split_call_args() {
+        use batch_debugger::parser::split_call_args;
+
+        assert_eq!(
+            split_call_args(r#"C:\dir\x.bat  "a b" c\d "#),
+            [r"C:\dir\x.bat", r#""a b""#, r"c\d"]
+        );
+        assert_eq!(
+            split_call_args(r#""C:\Program Files\x.bat" one"#),
+            [r#""C:\Program Files\x.bat""#, "one"]
+        );
+        assert!(split_call_args("   ").is_empty());
+    }
+
     #[test]
     fn test_composite_command_splitting() {
         let parts = batch_debugger::parser::split_composite_command("echo A & echo B && echo C");
@@ -107,6 +407,252 @@ exit /b 0
 
         let parts2 = batch_debugger::parser::split_composite_command("echo A || echo B");
         assert_eq!(parts2.len(), 2, "Should split into 2 parts");
+        let piped =
+            batch_debugger::parser::split_composite_command("dir /b | find \"x|y\" || echo none");
+        assert_eq!(piped.len(), 3, "Single | should split as a pipe");
+        assert_eq!(piped[0].op, Some(batch_debugger::parser::CommandOp::Pipe));
+        assert_eq!(piped[1].text, "find \"x|y\"");
+        assert_eq!(piped[1].op, Some(batch_debugger::parser::CommandOp::Or));
+
+        let escaped = batch_debugger::parser::split_composite_command("echo a^|b");
+        assert_eq!(escaped.len(), 1, "Escaped pipe is literal");
+        let escaped = batch_debugger::parser::split_composite_command("echo a ^| b");
+        assert_eq!(escaped.len(), 1);
+        assert_eq!(escaped[0].raw_text, "echo a ^| b");
+        let quoted = batch_debugger::parser::split_composite_command("echo \"a|b\"");
+        assert_eq!(quoted.len(), 1, "Quoted pipe is literal");
+        let ops: Vec<_> = batch_debugger::parser::split_composite_command("dir | sort & echo done")
+            .iter()
+            .map(|p| p.op)
+            .collect();
+        assert_eq!(
+            ops,
+            [
+                Some(batch_debugger::parser::CommandOp::Pipe),
+                Some(batch_debugger::parser::CommandOp::Unconditional),
+                None
+            ]
+        );
+
+        let offsets: Vec<usize> =
+            batch_debugger::parser::split_composite_command("  echo A &  echo B&&echo C")
+                .iter()
+                .map(|p| p.offset)
+                .collect();
+        assert_eq!(offsets, [2, 12, 20], "Offsets point at each part's text");
+
+        // Detection sees collapsed whitespace; the command runs as written
+        let spaced =
+            batch_debugger::parser::split_composite_command("SET  X=a   b &  echo \"x   y\"   z ");
+        assert_eq!(spaced[0].text, "SET X=a b");
+        assert_eq!(spaced[0].raw_text, "SET  X=a   b");
+        assert_eq!(spaced[1].text, "echo \"x   y\" z");
+        assert_eq!(spaced[1].raw_text, "echo \"x   y\"   z");
+    }
+
+    #[test]
+    fn test_split_escapes_round_trip() {
+        use batch_debugger::parser::{split_composite_command, CommandOp};
+
+        let texts = |line: &str| -> Vec<String> {
+            split_composite_command(line)
+                .into_iter()
+                .map(|p| p.raw_text)
+                .collect()
+        };
+        assert_eq!(
+            texts("echo fish ^& chips & echo next"),
+            ["echo fish ^& chips", "echo next"]
+        );
+        assert_eq!(texts("echo a^^& echo b"), ["echo a^^", "echo b"]);
+        assert_eq!(texts("echo ^^^& x"), ["echo ^^^& x"]);
+        assert_eq!(
+            texts("echo a ^|^| b || echo c"),
+            ["echo a ^|^| b", "echo c"]
+        );
+        // Inside quotes a caret is literal and doesn't hide the closing quote
+        assert_eq!(texts("echo \"a^\" & echo b"), ["echo \"a^\"", "echo b"]);
+        assert_eq!(texts("echo ^\"a & echo b"), ["echo ^\"a", "echo b"]);
+
+        // Joining the parts with their operators gives back a line that
+        // splits the same way
+        let lines = [
+            "echo fish ^& chips & echo next",
+            "dir /b | find \"x|y\" || echo none && echo ok",
+            "echo a^^&&echo \"b ^& c\"|sort",
+            "echo ^| ^& ^^ \"^\" & echo \"&|\" | more",
+            "  set X=1 &  set Y=2  ",
+        ];
+        for line in lines {
+            let parts = split_composite_command(line);
+            let mut joined = String::new();
+            for part in &parts {
+                joined.push_str(&part.raw_text);
+                joined.push_str(match part.op {
+                    Some(CommandOp::Unconditional) => " & ",
+                    Some(CommandOp::And) => " && ",
+                    Some(CommandOp::Or) => " || ",
+                    Some(CommandOp::Pipe) => " | ",
+                    None => "",
+                });
+            }
+            let again = split_composite_command(&joined);
+            let shape = |parts: &[batch_debugger::parser::CommandPart]| -> Vec<(String, Option<CommandOp>)> {
+                parts.iter().map(|p| (p.raw_text.clone(), p.op)).collect()
+            };
+            assert_eq!(shape(&again), shape(&parts), "{:?} -> {:?}", line, joined);
+        }
+
+        // Parens follow the same rules
+        assert_eq!(batch_debugger::parser::paren_delta("echo \"^\" ("), 1);
+        assert_eq!(batch_debugger::parser::paren_delta("echo ^( \"(\""), 0);
+    }
+
+    #[test]
+    fn test_redirections_are_parsed_out() {
+        use batch_debugger::parser::{split_composite_command, RedirectOp, Redirection};
+
+        let redirect = |handle, op, target: &str| Redirection {
+            handle,
+            op,
+            target: target.to_string(),
+        };
+
+        let parts = split_composite_command("echo hello > out.txt 2>&1 & echo next");
+        assert_eq!(parts.len(), 2, "`>&` doesn't chain commands");
+        assert_eq!(parts[0].text, "echo hello");
+        assert_eq!(parts[0].raw_text, "echo hello > out.txt 2>&1");
+        assert_eq!(
+            parts[0].redirections,
+            [
+                redirect(1, RedirectOp::Write, "out.txt"),
+                redirect(2, RedirectOp::Duplicate, "1"),
+            ]
+        );
+        assert_eq!(parts[0].written_files().collect::<Vec<_>>(), ["out.txt"]);
+        assert!(parts[1].redirections.is_empty());
+
+        let parts = split_composite_command("set /p x=<input.txt");
+        assert_eq!(parts[0].text, "set /p x=");
+        assert_eq!(
+            parts[0].redirections,
+            [redirect(0, RedirectOp::Read, "input.txt")]
+        );
+
+        let parts = split_composite_command(r#"type a>>"my log.txt" 2>nul"#);
+        assert_eq!(parts[0].text, "type a");
+        assert_eq!(
+            parts[0].redirections,
+            [
+                redirect(1, RedirectOp::Append, r#""my log.txt""#),
+                redirect(2, RedirectOp::Write, "nul"),
+            ]
+        );
+        assert_eq!(
+            parts[0].written_files().collect::<Vec<_>>(),
+            ["my log.txt"],
+            "nul isn't a file"
+        );
+
+        // Escaped and quoted arrows are text; a digit inside a word isn't a handle
+        let parts = split_composite_command(r#"echo a ^> b "c>d" x2>e.txt"#);
+        assert_eq!(parts[0].text, r#"echo a ^> b "c>d" x2"#);
+        assert_eq!(
+            parts[0].redirections,
+            [redirect(1, RedirectOp::Write, "e.txt")]
+        );
+    }
+
+    fn frame_bytes(body: &str) -> String {
+        format!("Content-Length: {}\r\n\r\n{}", body.len(), body)
+    }
+
+    #[test]
+    fn test_dap_framing_multiple_messages() {
+        use batch_debugger::dap::{read_frame, DapMessageContent, Frame};
+        use std::io::Cursor;
+
+        // Two back-to-back requests plus an unknown header, all in one buffer
+        let first = r#"{"seq":1,"type":"request","command":"setBreakpoints","arguments":{}}"#;
+        let second = r#"{"seq":2,"type":"request","command":"configurationDone"}"#;
+        let data = format!(
+            "{}Content-Type: application/vscode-jsonrpc\r\n{}",
+            frame_bytes(first),
+            frame_bytes(second)
+        );
+        let mut reader = Cursor::new(data.into_bytes());
+
+        let mut commands = Vec::new();
+        while let Some(frame) = read_frame(&mut reader).expect("read failed") {
+            match frame {
+                Frame::Message(msg) => match msg.content {
+                    DapMessageContent::Request { command, .. } => commands.push(command),
+                    other => panic!("unexpected message: {:?}", other),
+                },
+                Frame::Malformed { error, .. } => panic!("unexpected error: {}", error),
+            }
+        }
+
+        assert_eq!(commands, vec!["setBreakpoints", "configurationDone"]);
+    }
+
+    #[test]
+    fn test_dap_framing_malformed_and_oversized() {
+        use batch_debugger::dap::{read_frame, Frame, MAX_CONTENT_LENGTH};
+        use std::io::Cursor;
+
+        let bad = r#"{"seq":7,"type":"request","command":"threads""#;
+        let good = r#"{"seq":8,"type":"request","command":"threads"}"#;
+        let data = format!(
+            "Content-Length: {}\r\n\r\n{}",
+            MAX_CONTENT_LENGTH + 1,
+            " ".repeat(MAX_CONTENT_LENGTH + 1)
+        ) + &frame_bytes(bad)
+            + &frame_bytes(good);
+        let mut reader = Cursor::new(data.into_bytes());
+
+        match read_frame(&mut reader).unwrap() {
+            Some(Frame::Malformed { error, .. }) => assert!(error.contains("exceeds")),
+            other => panic!("expected oversized error, got {:?}", other),
+        }
+
+        match read_frame(&mut reader).unwrap() {
+            Some(Frame::Malformed { error, .. }) => assert!(error.contains("Malformed")),
+            other => panic!("expected malformed error, got {:?}", other),
+        }
+
+        // The stream stays in sync after errors
+        match read_frame(&mut reader).unwrap() {
+            Some(Frame::Message(msg)) => assert_eq!(msg.seq, 8),
+            other => panic!("expected message, got {:?}", other),
+        }
+        assert!(read_frame(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_dap_error_body() {
+        use batch_debugger::dap::ErrorCode;
+
+        let body = ErrorCode::ProgramUnreadable.to_body("Cannot read 'missing.bat'");
+        assert_eq!(body["error"]["id"], ErrorCode::ProgramUnreadable.id());
+        assert_eq!(body["error"]["format"], "{message}");
+        assert_eq!(
+            body["error"]["variables"]["message"],
+            "Cannot read 'missing.bat'"
+        );
+        assert_eq!(body["error"]["showUser"], true);
+
+        // Braces in the text are never taken for format placeholders
+        let body = ErrorCode::EvaluationFailed.to_body("'{x}' is not a variable reference");
+        assert_eq!(body["error"]["format"], "{message}");
+        assert_eq!(
+            body["error"]["variables"]["message"],
+            "'{x}' is not a variable reference"
+        );
+        assert_ne!(
+            ErrorCode::UnknownCommand.id(),
+            ErrorCode::ContextUnavailable.id()
+        );
     }
 
     #[test]
@@ -118,17 +664,26 @@ exit /b 0
         let mut ctx = DebugContext::new(session);
 
         // Add breakpoints
-        ctx.add_breakpoint(5);
-        ctx.add_breakpoint(10);
-        ctx.add_breakpoint(15);
+        ctx.add_breakpoint("test.bat", 5);
+        ctx.add_breakpoint("test.bat", 10);
+        ctx.add_breakpoint("test.bat", 15);
 
         // Test should_stop_at in Continue mode
         use batch_debugger::debugger::RunMode;
         ctx.set_mode(RunMode::Continue);
 
-        assert!(ctx.should_stop_at(5), "Should stop at breakpoint 5");
-        assert!(ctx.should_stop_at(10), "Should stop at breakpoint 10");
-        assert!(!ctx.should_stop_at(7), "Should not stop at line 7");
+        assert!(
+            ctx.should_stop_at("test.bat", 5),
+            "Should stop at breakpoint 5"
+        );
+        assert!(
+            ctx.should_stop_at("test.bat", 10),
+            "Should stop at breakpoint 10"
+        );
+        assert!(
+            !ctx.should_stop_at("test.bat", 7),
+            "Should not stop at line 7"
+        );
     }
 
     #[test]
@@ -150,8 +705,8 @@ exit /b 0
         ctx.set_mode(RunMode::StepOver);
         assert_eq!(ctx.mode(), RunMode::StepOver);
 
-        ctx.set_mode(RunMode::StepOut);
-        assert_eq!(ctx.mode(), RunMode::StepOut);
+        ctx.set_mode(RunMode::RunToReturn { target_depth: 0 });
+        assert_eq!(ctx.mode(), RunMode::RunToReturn { target_depth: 0 });
     }
 
     #[test]
@@ -180,6 +735,47 @@ exit /b 0
         assert!(!ctx.variables.contains_key("INPUT"));
     }
 
+    #[test]
+    fn test_concurrent_sessions_run_blocks() {
+        use batch_debugger::debugger::CmdSession;
+
+        // Each session's block file is its own, so blocks running side by
+        // side never see each other's lines
+        let workers: Vec<_> = ["first", "second"]
+            .into_iter()
+            .map(|name| {
+                std::thread::spawn(move || {
+                    let mut session = CmdSession::start().expect("Failed to start CMD session");
+                    let mut outputs = Vec::new();
+                    for i in 0..3 {
+                        let lines = vec![
+                            format!("set BLOCK={}{}", name, i),
+                            "echo %BLOCK%".to_string(),
+                        ];
+                        let (out, code) = session.run_batch_block(&lines).unwrap();
+                        assert_eq!(code, 0);
+                        outputs.push(out.trim().to_string());
+                    }
+                    (name, outputs)
+                })
+            })
+            .collect();
+
+        for worker in workers {
+            let (name, outputs) = worker.join().unwrap();
+            let expected: Vec<String> = (0..3).map(|i| format!("{}{}", name, i)).collect();
+            assert_eq!(outputs, expected);
+        }
+        // ...and they are written to the temp directory, not the script's
+        assert!(!fs::read_dir(".")
+            .unwrap()
+            .filter_map(Result::ok)
+            .any(|entry| entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with("__temp_block_")));
+    }
+
     #[test]
     fn test_call_stack() {
         use batch_debugger::debugger::Frame;
@@ -204,6 +800,14 @@ exit /b 0
         assert_eq!(frame2.return_pc, 25);
 
         assert_eq!(call_stack.len(), 1, "Should have 1 frame left");
+
+        // Frames entered by CALL know what they called
+        assert_eq!(call_stack[0].name, None);
+        let called = Frame::called("greet", 7, 3, None);
+        assert_eq!(called.name.as_deref(), Some("greet"));
+        assert_eq!(called.entry_pc, Some(7));
+        let external = Frame::external("C:\\scripts\\helper.bat".to_string(), 3, None);
+        assert!(external.name.as_deref().unwrap().ends_with("helper.bat"));
     }
 
     #[test]
@@ -241,6 +845,68 @@ exit /b 0
         assert!(!visible_after.contains_key("LOCAL"));
     }
 
+    #[test]
+    fn test_nested_setlocal_restores_values() {
+        use batch_debugger::debugger::{CmdSession, DebugContext, Frame};
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+
+        ctx.track_set_command("SET NAME=outer");
+        ctx.call_stack.push(Frame::new(10, None));
+
+        ctx.handle_setlocal_command("setlocal");
+        ctx.track_set_command("SET NAME=first");
+        ctx.handle_setlocal_command("setlocal EnableDelayedExpansion");
+        ctx.track_set_command("SET NAME=second");
+        ctx.track_set_command("SET TEMP_ONLY=1");
+
+        let visible = ctx.get_visible_variables();
+        assert_eq!(visible.get("NAME").map(String::as_str), Some("second"));
+
+        // Inner ENDLOCAL returns to the first scope's values and flags
+        ctx.handle_endlocal();
+        let visible = ctx.get_visible_variables();
+        assert_eq!(visible.get("NAME").map(String::as_str), Some("first"));
+        assert!(!visible.contains_key("TEMP_ONLY"));
+        assert!(ctx.call_stack.last().unwrap().has_setlocal);
+        assert!(!ctx.call_stack.last().unwrap().delayed_expansion);
+
+        // Outer ENDLOCAL reverts the modification entirely
+        ctx.handle_endlocal();
+        let visible = ctx.get_visible_variables();
+        assert_eq!(visible.get("NAME").map(String::as_str), Some("outer"));
+        assert!(!ctx.call_stack.last().unwrap().has_setlocal);
+
+        // A stray ENDLOCAL is harmless
+        ctx.handle_endlocal();
+        assert_eq!(ctx.variables.get("NAME").map(String::as_str), Some("outer"));
+    }
+
+    #[test]
+    fn test_setlocal_expansion_flags() {
+        use batch_debugger::debugger::CmdSession;
+        use batch_debugger::debugger::DebugContext;
+        use batch_debugger::debugger::Frame;
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+
+        ctx.call_stack.push(Frame::new(10, None));
+        ctx.handle_setlocal_command("setlocal EnableDelayedExpansion EnableExtensions");
+
+        let frame = ctx.call_stack.last().unwrap();
+        assert!(frame.has_setlocal);
+        assert!(frame.delayed_expansion);
+        assert!(frame.extensions);
+
+        ctx.handle_endlocal();
+
+        let frame = ctx.call_stack.last().unwrap();
+        assert!(!frame.delayed_expansion);
+        assert!(!frame.extensions);
+    }
+
     #[test]
     fn test_cmd_session_basic_command() {
         use batch_debugger::debugger::CmdSession;
@@ -258,6 +924,165 @@ exit /b 0
         assert_eq!(code, 0, "Exit code should be 0");
     }
 
+    #[test]
+    fn test_cmd_session_sentinel_collision() {
+        use batch_debugger::debugger::CmdSession;
+
+        let mut session = CmdSession::start().expect("Failed to start CMD session");
+
+        // Echoing the old fixed sentinel must not end the read early
+        let (output, code) = session
+            .run("echo __CMD_DONE___7_END")
+            .expect("Failed to run command");
+        assert!(output.contains("__CMD_DONE___7_END"));
+        assert_eq!(code, 0, "Exit code should come from the real sentinel");
+
+        // The session stays in sync for the next command
+        let (output, _) = session.run("echo after").expect("Failed to run command");
+        assert!(output.contains("after"));
+    }
+
+    #[test]
+    fn test_cmd_session_unicode_output() {
+        use batch_debugger::debugger::{CmdSession, SessionOptions};
+
+        let mut session = CmdSession::start_with_options(SessionOptions {
+            utf8: true,
+            ..SessionOptions::default()
+        })
+        .expect("Failed to start CMD session");
+
+        let (output, code) = session
+            .run("echo Grüße ✓ café")
+            .expect("Failed to run command");
+        assert!(
+            output.contains("Grüße ✓ café"),
+            "Non-ASCII output should round-trip, got '{}'",
+            output
+        );
+        assert_eq!(code, 0);
+    }
+
+    #[test]
+    fn test_cmd_session_strips_ansi() {
+        use batch_debugger::debugger::{strip_ansi, CmdSession};
+
+        assert_eq!(
+            strip_ansi("\x1b[1;31mred\x1b[0m \x1b[38;5;208morange\x1b[m"),
+            "red orange"
+        );
+        assert_eq!(strip_ansi("\x1b]0;title\x07text\x1b]2;t\x1b\\!"), "text!");
+        // A sequence broken over two lines is still one sequence
+        assert_eq!(strip_ansi("a\x1b[3\n1mb\n"), "ab\n");
+        assert_eq!(strip_ansi("plain\x1b["), "plain");
+
+        let mut session = CmdSession::start().expect("Failed to start CMD session");
+        let (output, code) = session
+            .run("echo \x1b[32mgreen\x1b[0m done")
+            .expect("Failed to run command");
+        assert_eq!(code, 0);
+        assert_eq!(output.trim(), "green done");
+        assert!(session.last_raw_output().contains("\x1b[32m"));
+
+        session.set_strip_ansi(false);
+        let (output, _) = session
+            .run("echo \x1b[32mgreen\x1b[0m")
+            .expect("Failed to run command");
+        assert_eq!(output.trim(), "\x1b[32mgreen\x1b[0m");
+    }
+
+    #[test]
+    fn test_cmd_session_screen_control() {
+        use batch_debugger::debugger::{CmdSession, SCREEN_CLEARED};
+
+        let mut session = CmdSession::start().expect("Failed to start CMD session");
+        let (output, code) = session.run("cls").expect("Failed to run cls");
+        assert_eq!(code, 0);
+        assert_eq!(output.trim(), SCREEN_CLEARED);
+
+        // The form feed of a cls among other commands is replaced in place
+        let (output, _) = session
+            .run("echo before & cls & echo after")
+            .expect("Failed to run command");
+        assert!(!output.contains('\x0c'), "got {:?}", output);
+        assert!(output.contains(SCREEN_CLEARED), "got {:?}", output);
+        assert!(output.contains("after"), "got {:?}", output);
+
+        for cmd in ["color 0a", "@title Build"] {
+            let (output, code) = session.run(cmd).expect("Failed to run command");
+            assert_eq!(code, 0);
+            assert_eq!(output, "", "{} should print nothing", cmd);
+        }
+        let (output, _) = session.run("echo still here").unwrap();
+        assert_eq!(output.trim(), "still here");
+    }
+
+    #[test]
+    fn test_cmd_session_recovers_from_exit() {
+        use batch_debugger::debugger::{CmdSession, DebugContext};
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+
+        ctx.track_set_command("set KEEP=kept");
+        ctx.run_command("set KEEP=kept")
+            .expect("Failed to set variable");
+        let tricky = "say \"hi\" & echo %KEEP% | more";
+        ctx.set_variable("TRICKY", tricky)
+            .expect("Failed to set variable");
+
+        // A bare `exit` kills cmd.exe; reading must fail cleanly, not hang
+        let result = ctx.run_command("exit");
+        if let Err(e) = &result {
+            assert_eq!(e.kind(), std::io::ErrorKind::BrokenPipe);
+        }
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        assert!(!ctx.session_mut().is_alive());
+
+        // The next command respawns the session and sees replayed variables
+        let (output, code) = ctx
+            .run_command("echo %KEEP%")
+            .expect("Session not recovered");
+        assert!(output.contains("kept"), "Got '{}'", output);
+        assert_eq!(code, 0);
+        assert!(ctx.session_mut().is_alive());
+
+        // Replayed as stored, not run or expanded again
+        let (output, _) = ctx.run_command("set TRICKY").unwrap();
+        assert_eq!(output.trim(), format!("TRICKY={}", tricky));
+    }
+
+    #[test]
+    fn test_cmd_session_get_all_env_vars() {
+        use batch_debugger::debugger::CmdSession;
+
+        let mut session = CmdSession::start().expect("Failed to start CMD session");
+        session
+            .run("set ENV_PROBE=a=b c")
+            .expect("Failed to run command");
+
+        let vars = session
+            .get_all_env_vars()
+            .expect("Failed to read environment");
+        assert_eq!(vars.get("ENV_PROBE").map(String::as_str), Some("a=b c"));
+        // Inherited variables are visible too, not just ones set through the debugger
+        assert!(vars.keys().any(|k| k.eq_ignore_ascii_case("PATH")));
+    }
+
+    #[test]
+    fn test_cmd_session_warm_up() {
+        use batch_debugger::debugger::CmdSession;
+
+        let mut session = CmdSession::start().expect("Failed to start CMD session");
+        session
+            .warm_up(&["set WARM_PROBE=ready", "echo discarded"])
+            .expect("Failed to warm up");
+
+        // Init output never leaks into the first real command
+        let (out, _) = session.run("echo %WARM_PROBE%").unwrap();
+        assert_eq!(out.trim(), "ready");
+    }
+
     #[test]
     fn test_cmd_session_set_command() {
         use batch_debugger::debugger::CmdSession;
@@ -290,30 +1115,3376 @@ exit /b 0
     }
 
     #[test]
-    fn test_block_depth_tracking() {
-        let content = r#"@echo off
-if 1==1 (
-    echo Level 1
-    if 2==2 (
-        echo Level 2
-    )
-)
-exit /b 0
-"#;
+    fn test_dap_set_prompt_does_not_deadlock() {
+        use batch_debugger::dap::Incoming;
+        use batch_debugger::debugger::{CmdSession, DebugContext};
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+        use std::time::{Duration, Instant};
 
-        let path = create_test_batch(content, "blocks");
-        let contents = fs::read_to_string(&path).expect("Could not read test file");
-        let physical_lines: Vec<&str> = contents.lines().collect();
+        let lines = vec!["@echo off", "set /p NAME=Name? ", "pause", "echo Hi %NAME%"];
+        let pre = batch_debugger::parser::preprocess_lines(&lines);
+        let labels = batch_debugger::parser::build_label_map(&pre);
 
-        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let ctx = Arc::new(Mutex::new(DebugContext::new(session)));
+        let (tx, rx) = channel();
 
-        // Check that depth tracking works
-        let depths: Vec<u16> = pre.logical.iter().map(|l| l.group_depth).collect();
+        let exec_ctx = Arc::clone(&ctx);
+        let handle = std::thread::spawn(move || {
+            batch_debugger::executor::run_debugger_dap(exec_ctx, &pre, &labels, tx)
+        });
 
-        // Should have varying depths
-        assert!(depths.iter().any(|&d| d == 0), "Should have depth 0");
-        assert!(depths.iter().any(|&d| d > 0), "Should have depth > 0");
+        // Answer the prompt once the executor asks for it
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !ctx.lock().unwrap().supply_input("Bob") {
+            assert!(Instant::now() < deadline, "SET /P never asked for input");
+            std::thread::sleep(Duration::from_millis(20));
+        }
 
-        cleanup_test_batch(&path);
+        // PAUSE stops the debugger instead of cmd; continuing is the keypress
+        let mut output = String::new();
+        let mut pauses = Vec::new();
+        loop {
+            match rx.recv_timeout(Duration::from_secs(10)) {
+                Ok(Incoming::Output(text)) | Ok(Incoming::Prompt(text)) => output.push_str(&text),
+                Ok(Incoming::Stopped { reason, line }) => {
+                    assert!(
+                        output.contains("Press any key"),
+                        "the prompt comes before the stop"
+                    );
+                    pauses.push((reason, line));
+                    while ctx.lock().unwrap().current_line != Some(line) {
+                        std::thread::sleep(Duration::from_millis(20));
+                    }
+                    ctx.lock().unwrap().request_continue();
+                }
+                Ok(Incoming::Terminated) => break,
+                Ok(_) => {}
+                Err(e) => panic!("executor hung: {} (output so far: {:?})", e, output),
+            }
+        }
+
+        handle.join().unwrap().expect("executor failed");
+        assert!(output.contains("Name?"), "prompt should be echoed");
+        assert_eq!(pauses, [("pause".to_string(), 2)], "PAUSE is a stop");
+        assert!(
+            output.contains("Hi Bob"),
+            "input should reach cmd, got {:?}",
+            output
+        );
+    }
+
+    #[test]
+    fn test_stopped_executor_waits_for_a_wakeup() {
+        use batch_debugger::dap::Incoming;
+        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode, Wakeup};
+        use std::sync::atomic::Ordering;
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        // A wake that comes first isn't lost
+        let wakeup = Wakeup::default();
+        wakeup.notify();
+        wakeup.wait();
+
+        let lines = vec!["@echo off", "echo one", "echo two"];
+        let pre = batch_debugger::parser::preprocess_lines(&lines);
+        let labels = batch_debugger::parser::build_label_map(&pre);
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.set_mode(RunMode::StepOver);
+        let (cancelled, wakeup) = (Arc::clone(&ctx.cancelled), Arc::clone(&ctx.wakeup));
+        let ctx = Arc::new(Mutex::new(ctx));
+        let (tx, rx) = channel();
+
+        let exec_ctx = Arc::clone(&ctx);
+        let handle = std::thread::spawn(move || {
+            batch_debugger::executor::run_debugger_dap(exec_ctx, &pre, &labels, tx)
+        });
+
+        let stopped_at = |rx: &std::sync::mpsc::Receiver<Incoming>| loop {
+            match rx.recv_timeout(Duration::from_secs(10)) {
+                Ok(Incoming::Stopped { line, .. }) => break line,
+                Ok(_) => {}
+                Err(e) => panic!("executor never stopped: {}", e),
+            }
+        };
+        let line = stopped_at(&rx);
+        while ctx.lock().unwrap().current_line != Some(line) {
+            std::thread::sleep(Duration::from_millis(20));
+        }
+
+        // A stray wake leaves it stopped; a step request moves it on
+        wakeup.notify();
+        assert!(
+            rx.recv_timeout(Duration::from_millis(300)).is_err(),
+            "ran on without a request"
+        );
+        ctx.lock().unwrap().request_continue();
+        let next = stopped_at(&rx);
+        assert!(next > line);
+
+        // Cancelling doesn't take the lock; the wakeup gets it seen
+        cancelled.store(true, Ordering::SeqCst);
+        wakeup.notify();
+        loop {
+            match rx.recv_timeout(Duration::from_secs(10)) {
+                Ok(Incoming::Terminated) => break,
+                Ok(_) => {}
+                Err(e) => panic!("executor didn't end: {}", e),
+            }
+        }
+        handle.join().unwrap().expect("executor failed");
+    }
+
+    #[test]
+    fn test_dap_set_prompt_input_is_only_a_value() {
+        use batch_debugger::dap::Incoming;
+        use batch_debugger::debugger::{CmdSession, DebugContext};
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+        use std::time::{Duration, Instant};
+
+        let lines = vec!["@echo off", "set /p NAME=Name? ", "set NAME"];
+        let pre = batch_debugger::parser::preprocess_lines(&lines);
+        let labels = batch_debugger::parser::build_label_map(&pre);
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let ctx = Arc::new(Mutex::new(DebugContext::new(session)));
+        let (tx, rx) = channel();
+
+        let exec_ctx = Arc::clone(&ctx);
+        let handle = std::thread::spawn(move || {
+            batch_debugger::executor::run_debugger_dap(exec_ctx, &pre, &labels, tx)
+        });
+
+        // A reply that would close the quotes and run more commands
+        let reply = "x\" & set INJECTED=1 & \" 100%\r\nsecond line";
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !ctx.lock().unwrap().supply_input(reply) {
+            assert!(Instant::now() < deadline, "SET /P never asked for input");
+            std::thread::sleep(Duration::from_millis(20));
+        }
+
+        let mut output = String::new();
+        loop {
+            match rx.recv_timeout(Duration::from_secs(10)) {
+                Ok(Incoming::Output(text)) => output.push_str(&text),
+                Ok(Incoming::Terminated) => break,
+                Ok(_) => {}
+                Err(e) => panic!("executor hung: {} (output so far: {:?})", e, output),
+            }
+        }
+        handle.join().unwrap().expect("executor failed");
+
+        assert!(
+            output.contains("NAME=x\" & set INJECTED=1 & \" 100%"),
+            "got {:?}",
+            output
+        );
+        let mut ctx = ctx.lock().unwrap();
+        assert_eq!(ctx.evaluate_variable("INJECTED").unwrap(), None);
+    }
+
+    #[test]
+    fn test_set_prompt_reading_a_file_is_left_to_cmd() {
+        use batch_debugger::executor::parse_set_prompt;
+
+        assert_eq!(
+            parse_set_prompt("set /p NAME=Name?"),
+            Some(("NAME".to_string(), "Name?".to_string()))
+        );
+        assert_eq!(
+            parse_set_prompt("@SET /P \"AGE=Age: \""),
+            Some(("AGE".to_string(), "Age: ".to_string()))
+        );
+        // Neither reads the console, so neither waits for the client
+        assert_eq!(parse_set_prompt("set /p FIRST=<input.txt"), None);
+        assert_eq!(parse_set_prompt("set /p \"=Working... \" <nul"), None);
+        assert_eq!(parse_set_prompt("set /p LINE= 0<\"my file.txt\""), None);
+        // Only stdin counts
+        assert_eq!(
+            parse_set_prompt("set /p NAME=Name? 2>nul"),
+            Some(("NAME".to_string(), "Name? 2>nul".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_step_limit_halts_goto_loop() {
+        use batch_debugger::dap::Incoming;
+        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        let lines = vec!["@echo off", ":spin", "set /a N+=1", "goto spin"];
+        let pre = batch_debugger::parser::preprocess_lines(&lines);
+        let labels = batch_debugger::parser::build_label_map(&pre);
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.set_mode(RunMode::Continue);
+        ctx.max_steps = Some(20);
+        let ctx = Arc::new(Mutex::new(ctx));
+        let (tx, rx) = channel();
+
+        let exec_ctx = Arc::clone(&ctx);
+        std::thread::spawn(move || {
+            batch_debugger::executor::run_debugger_dap(exec_ctx, &pre, &labels, tx)
+        });
+
+        let mut warned = false;
+        let reason = loop {
+            match rx.recv_timeout(Duration::from_secs(10)) {
+                Ok(Incoming::Stopped { reason, .. }) => break reason,
+                Ok(Incoming::Output(text)) => warned |= text.contains("step limit"),
+                Ok(Incoming::Terminated) => panic!("an endless loop cannot terminate"),
+                Ok(_) => {}
+                Err(e) => panic!("executor never stopped: {}", e),
+            }
+        };
+
+        assert_eq!(reason, "step limit exceeded");
+        assert!(warned, "A warning is written to the debug console");
+        // Nothing ran past the budget: @echo off, then alternating set/goto
+        assert_eq!(ctx.lock().unwrap().get_variable("N"), Some("10"));
+    }
+
+    #[test]
+    fn test_dap_block_exposes_generated_source() {
+        use batch_debugger::dap::Incoming;
+        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        let lines = vec![
+            "@echo off",
+            "set X=1",
+            "if \"%X%\"==\"1\" (",
+            // %X% in the body is expanded when cmd reads the block, so it
+            // can't run line by line
+            "  echo in block %X%",
+            ")",
+            "echo done",
+        ];
+        let pre = batch_debugger::parser::preprocess_lines(&lines);
+        let labels = batch_debugger::parser::build_label_map(&pre);
+        let block = pre
+            .logical
+            .iter()
+            .position(|ll| ll.text.starts_with("if "))
+            .unwrap();
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.set_mode(RunMode::Continue);
+        ctx.add_breakpoint("", block);
+        let ctx = Arc::new(Mutex::new(ctx));
+        let (tx, rx) = channel();
+
+        let exec_ctx = Arc::clone(&ctx);
+        let handle = std::thread::spawn(move || {
+            batch_debugger::executor::run_debugger_dap(exec_ctx, &pre, &labels, tx)
+        });
+
+        loop {
+            match rx.recv_timeout(Duration::from_secs(10)) {
+                Ok(Incoming::Stopped { line, .. }) => {
+                    assert_eq!(line, block);
+                    break;
+                }
+                Ok(_) => {}
+                Err(e) => panic!("executor never reached the block: {}", e),
+            }
+        }
+
+        // The executor parks (and resets continue_requested) right after the event
+        while ctx.lock().unwrap().current_line != Some(block) {
+            std::thread::sleep(Duration::from_millis(20));
+        }
+
+        {
+            let mut ctx = ctx.lock().unwrap();
+            assert_eq!(
+                ctx.active_block.as_deref(),
+                Some("@echo off\r\nif \"%X%\"==\"1\" (\r\n  echo in block %X%\r\n)\r\n"),
+                "The stopped block's temporary file is available"
+            );
+            ctx.request_continue();
+        }
+
+        let mut output = String::new();
+        loop {
+            match rx.recv_timeout(Duration::from_secs(10)) {
+                Ok(Incoming::Output(text)) => output.push_str(&text),
+                Ok(Incoming::Terminated) => break,
+                Ok(_) => {}
+                Err(e) => panic!("executor hung: {}", e),
+            }
+        }
+        handle.join().unwrap().expect("executor failed");
+
+        assert!(output.contains("in block 1"), "got {:?}", output);
+        assert!(output.contains("done"));
+        assert!(ctx.lock().unwrap().active_block.is_none());
+    }
+
+    #[test]
+    fn test_for_loop_steps_each_iteration() {
+        use batch_debugger::dap::Incoming;
+        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        let lines = vec![
+            "@echo off",
+            "for /l %%i in (1,1,3) do (",
+            "  echo item %%i",
+            ")",
+            "echo done",
+        ];
+        let pre = batch_debugger::parser::preprocess_lines(&lines);
+        let labels = batch_debugger::parser::build_label_map(&pre);
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.set_mode(RunMode::Continue);
+        ctx.add_breakpoint("", 2);
+        let ctx = Arc::new(Mutex::new(ctx));
+        let (tx, rx) = channel();
+
+        let exec_ctx = Arc::clone(&ctx);
+        let handle = std::thread::spawn(move || {
+            batch_debugger::executor::run_debugger_dap(exec_ctx, &pre, &labels, tx)
+        });
+
+        let mut output = String::new();
+        let mut values = Vec::new();
+        loop {
+            match rx.recv_timeout(Duration::from_secs(10)) {
+                Ok(Incoming::Stopped { line, .. }) => {
+                    assert_eq!(line, 2, "only the body line has a breakpoint");
+                    // The executor parks (and resets continue_requested) right after the event
+                    loop {
+                        let mut ctx = ctx.lock().unwrap();
+                        if !ctx.continue_requested && ctx.current_line == Some(2) {
+                            values.push(ctx.for_loops.last().and_then(|l| l.value()));
+                            ctx.request_continue();
+                            break;
+                        }
+                        drop(ctx);
+                        std::thread::sleep(Duration::from_millis(20));
+                    }
+                }
+                Ok(Incoming::Output(text)) => output.push_str(&text),
+                Ok(Incoming::Terminated) => break,
+                Ok(_) => {}
+                Err(e) => panic!("executor hung: {} (output so far: {:?})", e, output),
+            }
+        }
+        handle.join().unwrap().expect("executor failed");
+
+        let expected: Vec<Option<String>> = ["1", "2", "3"]
+            .iter()
+            .map(|v| Some(v.to_string()))
+            .collect();
+        assert_eq!(values, expected, "one stop per iteration");
+        for item in ["item 1", "item 2", "item 3", "done"] {
+            assert!(output.contains(item), "missing {:?} in {:?}", item, output);
+        }
+        assert!(ctx.lock().unwrap().for_loops.is_empty());
+    }
+
+    #[test]
+    fn test_parse_for_header() {
+        use batch_debugger::parser::{parse_for_header, ForItems};
+
+        let range = parse_for_header("for /L %%i in (1,1,3) do (").unwrap();
+        assert_eq!(range.var, 'i');
+        assert_eq!(
+            range.items,
+            ForItems::Range {
+                start: 1,
+                step: 1,
+                end: 3
+            }
+        );
+        assert_eq!(range.items.get(2).as_deref(), Some("3"));
+        assert_eq!(range.items.get(3), None);
+
+        let down = parse_for_header("FOR /l %%n IN (5 -2 1) DO (").unwrap();
+        let values: Vec<String> = (0..).map_while(|i| down.items.get(i)).collect();
+        assert_eq!(values, ["5", "3", "1"]);
+
+        let list = parse_for_header("@for %%a in (one, \"two three\";four) do (").unwrap();
+        assert_eq!(
+            list.items,
+            ForItems::List(vec![
+                "one".to_string(),
+                "\"two three\"".to_string(),
+                "four".to_string()
+            ])
+        );
+
+        // Forms the debugger leaves to cmd
+        for line in [
+            "for /f \"tokens=1\" %%a in (file.txt) do (",
+            "for %%f in (*.txt) do (",
+            "for %%a in (%LIST%) do (",
+            "for /l %%i in (1,0,3) do (",
+            "for %%a in (x y) do echo %%a",
+        ] {
+            assert!(parse_for_header(line).is_none(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_parse_for_statement_flavors() {
+        use batch_debugger::parser::{parse_for_statement, ForItems, ForKind, ForSource};
+
+        let plain = parse_for_statement(r#"for %%a in (one "two three") do echo %%a"#).unwrap();
+        assert_eq!(plain.kind, ForKind::Items);
+        assert_eq!(plain.var, 'a');
+        assert_eq!(plain.set, r#"one "two three""#);
+        assert_eq!(plain.command, "echo %%a");
+        assert_eq!(
+            plain.items(),
+            Some(ForItems::List(vec![
+                "one".to_string(),
+                "\"two three\"".to_string()
+            ]))
+        );
+        // A quoted item may hold a parenthesis
+        let paren = parse_for_statement(r#"for %%a in ("x)y" z) do ("#).unwrap();
+        assert_eq!(paren.set, r#""x)y" z"#);
+        assert_eq!(paren.command, "(");
+
+        let range = parse_for_statement("FOR /L %i IN (10,-5,0) DO echo %i").unwrap();
+        assert_eq!(range.kind, ForKind::Range);
+        assert_eq!(range.var, 'i');
+        assert_eq!(
+            range.items(),
+            Some(ForItems::Range {
+                start: 10,
+                step: -5,
+                end: 0
+            })
+        );
+
+        let files = parse_for_statement(
+            r#"for /f "skip=1 tokens=1,3* delims=," %%a in (data.csv more.csv) do ("#,
+        )
+        .unwrap();
+        assert_eq!(files.kind, ForKind::Lines);
+        assert_eq!(
+            files.options.as_deref(),
+            Some("skip=1 tokens=1,3* delims=,")
+        );
+        assert_eq!(files.variables(), ['a', 'b', 'c']);
+        assert_eq!(
+            files.source(),
+            Some(ForSource::Files(vec![
+                "data.csv".to_string(),
+                "more.csv".to_string()
+            ]))
+        );
+        assert_eq!(files.items(), None, "the debugger can't list /F values");
+
+        let string =
+            parse_for_statement(r#"for /f "tokens=2-4" %%x in ("a b c d") do echo %%z"#).unwrap();
+        assert_eq!(string.variables(), ['x', 'y', 'z']);
+        assert_eq!(
+            string.source(),
+            Some(ForSource::String("a b c d".to_string()))
+        );
+
+        let command = parse_for_statement("for /f %%l in ('dir /b (x)') do echo %%l").unwrap();
+        assert_eq!(command.options, None);
+        assert_eq!(
+            command.source(),
+            Some(ForSource::Command("dir /b (x)".to_string()))
+        );
+
+        // usebackq: backquotes run commands, double quotes name files
+        let backq = parse_for_statement(
+            r#"for /f "usebackq tokens=*" %%l in (`type "my file.txt"`) do echo %%l"#,
+        )
+        .unwrap();
+        assert!(backq.usebackq());
+        assert_eq!(
+            backq.source(),
+            Some(ForSource::Command(r#"type "my file.txt""#.to_string()))
+        );
+        let quoted_file =
+            parse_for_statement(r#"for /f "usebackq" %%l in ("my file.txt") do echo %%l"#).unwrap();
+        assert_eq!(
+            quoted_file.source(),
+            Some(ForSource::Files(vec!["\"my file.txt\"".to_string()]))
+        );
+        let literal =
+            parse_for_statement("for /f \"usebackq\" %%l in ('it''s') do echo %%l").unwrap();
+        assert_eq!(
+            literal.source(),
+            Some(ForSource::String("it''s".to_string()))
+        );
+
+        let tree = parse_for_statement(r#"for /r "C:\My Dir" %%f in (*.bat) do echo %%f"#).unwrap();
+        assert_eq!(
+            tree.kind,
+            ForKind::Recursive {
+                root: Some(r#""C:\My Dir""#.to_string())
+            }
+        );
+        assert_eq!(tree.set, "*.bat");
+        assert_eq!(tree.items(), None);
+        let here = parse_for_statement("for /R %%f in (.) do echo %%f").unwrap();
+        assert_eq!(here.kind, ForKind::Recursive { root: None });
+
+        let dirs = parse_for_statement("for /d %%d in (src*) do echo %%d").unwrap();
+        assert_eq!(dirs.kind, ForKind::Directories);
+        assert_eq!(dirs.source(), None);
+
+        for line in [
+            "echo for %%a in (x) do y",
+            "for %%ab in (x) do y",
+            "for /x %%a in (x) do y",
+        ] {
+            assert!(parse_for_statement(line).is_none(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_preprocess_attaches_for_statements() {
+        let lines = vec![
+            "@echo off",
+            "for %%a in (x y) do (",
+            "  for /l %%i in (1,1,2) do echo %%a%%i",
+            "  echo (nested)",
+            ")",
+            "for %%b in (z) do echo %%b",
+        ];
+        let pre = batch_debugger::parser::preprocess_lines(&lines);
+
+        let outer = pre.logical[1].for_statement.as_ref().unwrap();
+        assert_eq!(outer.var, 'a');
+        assert_eq!(
+            outer.body,
+            Some(2..5),
+            "the body runs through the closing paren"
+        );
+        let inner = pre.logical[2].for_statement.as_ref().unwrap();
+        assert_eq!(inner.var, 'i');
+        assert_eq!(inner.body, None, "a one-line body");
+        assert!(pre.logical[3].for_statement.is_none());
+        assert_eq!(pre.logical[5].for_statement.as_ref().unwrap().var, 'b');
+    }
+
+    #[test]
+    fn test_parse_if_condition_forms() {
+        use batch_debugger::parser::{parse_if_condition, CompareOp, IfCondition, IfTest};
+
+        let parse =
+            |text: &str| parse_if_condition(text).map(|(test, rest)| (test, rest.to_string()));
+
+        assert_eq!(
+            parse(r#""%A%"=="b" echo same"#),
+            Some((
+                IfTest {
+                    ignore_case: false,
+                    negated: false,
+                    condition: IfCondition::Equals(r#""%A%""#.into(), r#""b""#.into()),
+                },
+                "echo same".to_string()
+            ))
+        );
+        let (test, rest) = parse("/I NOT %X% geq 10 (").unwrap();
+        assert!(test.ignore_case && test.negated);
+        assert_eq!(
+            test.condition,
+            IfCondition::Compare("%X%".into(), CompareOp::Geq, "10".into())
+        );
+        assert_eq!(rest, "(");
+        assert_eq!(
+            parse("not defined FOO(echo x)").unwrap().0.condition,
+            IfCondition::Defined("FOO".into())
+        );
+        assert_eq!(
+            parse(r#"exist "C:\a b\c.txt" del x"#).unwrap().0.condition,
+            IfCondition::Exist(r#""C:\a b\c.txt""#.into())
+        );
+        assert_eq!(
+            parse("errorlevel 2 goto :fail").unwrap(),
+            (
+                IfTest {
+                    ignore_case: false,
+                    negated: false,
+                    condition: IfCondition::ErrorLevel(2),
+                },
+                "goto :fail".to_string()
+            )
+        );
+        assert_eq!(parse("a LIKE b echo x"), None, "not an operator");
+        assert_eq!(parse("errorlevel x echo x"), None);
+    }
+
+    #[test]
+    fn test_preprocess_attaches_if_statements() {
+        use batch_debugger::parser::IfBranch;
+
+        let lines = vec![
+            "@echo off",
+            "if defined A echo inline else echo not an else",
+            "if \"%B%\"==\"1\" (",
+            "  echo then (one)",
+            "  echo then two",
+            ") else (",
+            "  echo else",
+            ")",
+            "if exist x.txt (echo yes) else (echo no)",
+            "if errorlevel 1 (",
+            "  echo failed",
+            ") else echo fine",
+            "if not defined C (echo first) else (",
+            "  echo second",
+            ")",
+        ];
+        let pre = batch_debugger::parser::preprocess_lines(&lines);
+        let statement = |pc: usize| pre.logical[pc].if_statement.clone().unwrap();
+
+        let inline = statement(1);
+        assert_eq!(
+            inline.then_branch,
+            IfBranch::Inline("echo inline else echo not an else".into())
+        );
+        assert_eq!(inline.else_branch, None);
+
+        let blocks = statement(2);
+        assert_eq!(blocks.then_branch, IfBranch::Block(3..5));
+        assert_eq!(blocks.else_branch, Some(IfBranch::Block(6..7)));
+        assert!(pre.logical[5].if_statement.is_none(), "`) else (` is no IF");
+
+        let one_line = statement(8);
+        assert_eq!(one_line.then_branch, IfBranch::Inline("echo yes".into()));
+        assert_eq!(
+            one_line.else_branch,
+            Some(IfBranch::Inline("echo no".into()))
+        );
+
+        let else_inline = statement(9);
+        assert_eq!(else_inline.then_branch, IfBranch::Block(10..11));
+        assert_eq!(
+            else_inline.else_branch,
+            Some(IfBranch::Inline("echo fine".into()))
+        );
+
+        let mixed = statement(12);
+        assert!(mixed.test.negated);
+        assert_eq!(mixed.then_branch, IfBranch::Inline("echo first".into()));
+        assert_eq!(mixed.else_branch, Some(IfBranch::Block(13..14)));
+    }
+
+    #[test]
+    fn test_breakpoint_inside_if_body() {
+        use batch_debugger::dap::Incoming;
+        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        // Runs the script with a breakpoint on `breakpoint`, returning the
+        // lines it stopped at and its output
+        let run = |x: &str, breakpoint: usize| -> (Vec<usize>, String) {
+            let set = format!("set X={}", x);
+            let lines = vec![
+                "@echo off",
+                set.as_str(),
+                "if \"%X%\"==\"1\" (",
+                "  echo then one",
+                "  echo then two",
+                ") else (",
+                "  echo else one",
+                ")",
+                "echo done",
+            ];
+            let pre = batch_debugger::parser::preprocess_lines(&lines);
+            let labels = batch_debugger::parser::build_label_map(&pre);
+
+            let session = CmdSession::start().expect("Failed to start CMD session");
+            let mut ctx = DebugContext::new(session);
+            ctx.set_mode(RunMode::Continue);
+            ctx.add_breakpoint("", breakpoint);
+            let ctx = Arc::new(Mutex::new(ctx));
+            let (tx, rx) = channel();
+
+            let exec_ctx = Arc::clone(&ctx);
+            let handle = std::thread::spawn(move || {
+                batch_debugger::executor::run_debugger_dap(exec_ctx, &pre, &labels, tx)
+            });
+
+            let mut stops = Vec::new();
+            let mut output = String::new();
+            loop {
+                match rx.recv_timeout(Duration::from_secs(10)) {
+                    Ok(Incoming::Stopped { line, .. }) => {
+                        stops.push(line);
+                        while ctx.lock().unwrap().current_line != Some(line) {
+                            std::thread::sleep(Duration::from_millis(20));
+                        }
+                        let mut ctx = ctx.lock().unwrap();
+                        assert!(ctx.active_block.is_none(), "the IF is not bundled");
+                        ctx.request_continue();
+                    }
+                    Ok(Incoming::Output(text)) => output.push_str(&text),
+                    Ok(Incoming::Terminated) => break,
+                    Ok(_) => {}
+                    Err(e) => panic!("executor hung: {} (output so far: {:?})", e, output),
+                }
+            }
+            handle.join().unwrap().expect("executor failed");
+            (stops, output)
+        };
+
+        let (stops, output) = run("1", 4);
+        assert_eq!(stops, [4], "the breakpoint inside the THEN branch fires");
+        assert!(output.contains("then one") && output.contains("then two"));
+        assert!(!output.contains("else one"), "got {:?}", output);
+        assert!(output.contains("done"));
+
+        let (stops, output) = run("2", 6);
+        assert_eq!(stops, [6], "a false condition takes the ELSE branch");
+        assert!(!output.contains("then"), "got {:?}", output);
+        assert!(output.contains("else one") && output.contains("done"));
+    }
+
+    #[test]
+    fn test_statement_granularity_steps_each_part() {
+        use batch_debugger::dap::Incoming;
+        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode, StepGranularity};
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        // Steps through the script, returning (line, column) per stop and the output
+        let run = |granularity: StepGranularity| -> (Vec<(usize, Option<usize>)>, String) {
+            let lines = vec![
+                "@echo off",
+                "echo one & echo two && echo three || echo four",
+                "echo done",
+            ];
+            let pre = batch_debugger::parser::preprocess_lines(&lines);
+            let labels = batch_debugger::parser::build_label_map(&pre);
+
+            let session = CmdSession::start().expect("Failed to start CMD session");
+            let mut ctx = DebugContext::new(session);
+            ctx.set_mode(RunMode::StepInto);
+            ctx.set_granularity(granularity);
+            let ctx = Arc::new(Mutex::new(ctx));
+            let (tx, rx) = channel();
+
+            let exec_ctx = Arc::clone(&ctx);
+            let handle = std::thread::spawn(move || {
+                batch_debugger::executor::run_debugger_dap(exec_ctx, &pre, &labels, tx)
+            });
+
+            let mut stops = Vec::new();
+            let mut output = String::new();
+            loop {
+                match rx.recv_timeout(Duration::from_secs(10)) {
+                    Ok(Incoming::Stopped { line, .. }) => {
+                        // The line repeats between statements, so wait for the
+                        // executor to take the previous resume as well
+                        while ctx.lock().unwrap().current_line != Some(line)
+                            || ctx.lock().unwrap().continue_requested
+                        {
+                            std::thread::sleep(Duration::from_millis(20));
+                        }
+                        let mut ctx = ctx.lock().unwrap();
+                        stops.push((line, ctx.current_column));
+                        if line == 1 {
+                            output.push('|');
+                        }
+                        ctx.request_continue();
+                    }
+                    Ok(Incoming::Output(text)) => output.extend(text.split_whitespace()),
+                    Ok(Incoming::Terminated) => break,
+                    Ok(_) => {}
+                    Err(e) => panic!("executor hung: {} (output so far: {:?})", e, output),
+                }
+            }
+            handle.join().unwrap().expect("executor failed");
+            (stops, output)
+        };
+
+        let (stops, output) = run(StepGranularity::Statement);
+        assert_eq!(
+            stops,
+            [
+                (0, None),
+                (1, Some(1)),
+                (1, Some(12)),
+                (1, Some(24)),
+                (2, None)
+            ],
+            "each part that runs is its own stop; `echo four` is skipped"
+        );
+        assert_eq!(output, "|one|two|threedone");
+
+        let (stops, output) = run(StepGranularity::Line);
+        assert_eq!(stops, [(0, None), (1, None), (2, None)]);
+        assert_eq!(output, "|onetwothreedone");
+    }
+
+    #[test]
+    fn test_step_out_returns_to_caller() {
+        use batch_debugger::dap::Incoming;
+        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        let lines = vec![
+            "@echo off",
+            "call :a",
+            "exit /b",
+            ":a",
+            "call :b",
+            "echo back in a",
+            "exit /b",
+            ":b",
+            "echo in b",
+            "exit /b",
+        ];
+        let pre = batch_debugger::parser::preprocess_lines(&lines);
+        let labels = batch_debugger::parser::build_label_map(&pre);
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.set_mode(RunMode::Continue);
+        ctx.add_breakpoint("", 8);
+        let ctx = Arc::new(Mutex::new(ctx));
+        let (tx, rx) = channel();
+
+        let exec_ctx = Arc::clone(&ctx);
+        let handle = std::thread::spawn(move || {
+            batch_debugger::executor::run_debugger_dap(exec_ctx, &pre, &labels, tx)
+        });
+
+        let mut stops = Vec::new();
+        loop {
+            match rx.recv_timeout(Duration::from_secs(10)) {
+                Ok(Incoming::Stopped { line, .. }) => {
+                    stops.push(line);
+                    while ctx.lock().unwrap().current_line != Some(line) {
+                        std::thread::sleep(Duration::from_millis(20));
+                    }
+                    let mut ctx = ctx.lock().unwrap();
+                    if line == 8 {
+                        assert_eq!(ctx.call_stack.len(), 2);
+                        let mode = ctx.return_mode();
+                        assert_eq!(mode, RunMode::RunToReturn { target_depth: 1 });
+                        ctx.set_mode(mode);
+                    } else {
+                        ctx.set_mode(RunMode::Continue);
+                    }
+                    ctx.request_continue();
+                }
+                Ok(Incoming::Terminated) => break,
+                Ok(_) => {}
+                Err(e) => panic!("executor hung: {}", e),
+            }
+        }
+        handle.join().unwrap().expect("executor failed");
+        assert_eq!(
+            stops,
+            [8, 5],
+            "stepping out of :b stops in :a, not at the top level"
+        );
+    }
+
+    #[test]
+    fn test_history_rewinds_variables() {
+        use batch_debugger::debugger::{ContextSnapshot, Frame, History};
+        use std::collections::HashMap;
+
+        let vars = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        let before = |pc, variables| ContextSnapshot {
+            variables,
+            call_stack: Vec::new(),
+            last_exit_code: 0,
+            pc,
+        };
+
+        let mut history = History::new(2);
+        history.begin("a.bat", before(0, vars(&[])));
+        history.record_output("first\r\n");
+        history.finish(0);
+        history.begin("a.bat", before(1, vars(&[("A", "1")])));
+        history.finish(0);
+        // SETLOCAL locals are part of what the line saw
+        let mut scoped = before(2, vars(&[("A", "2")]));
+        let mut frame = Frame::new(0, None);
+        frame.has_setlocal = true;
+        frame.locals = vars(&[("B", "x")]);
+        scoped.call_stack.push(frame);
+        history.begin("a.bat", scoped);
+        history.finish(1);
+        assert_eq!(history.len(), 2, "the oldest line falls out of the buffer");
+
+        let live = vars(&[("B", "x")]);
+        assert!(!history.is_inspecting());
+        let newest = history.step_back().unwrap();
+        assert_eq!((newest.pc(), newest.exit_code), (2, 1));
+        assert_eq!(history.variables_at(&live), vars(&[("A", "2"), ("B", "x")]));
+        assert_eq!(history.step_back().map(|e| e.pc()), Some(1));
+        assert_eq!(history.variables_at(&live), vars(&[("A", "1")]));
+        assert!(history.step_back().is_none(), "nothing older is kept");
+        assert_eq!(history.position().map(|e| e.pc()), Some(1));
+
+        history.resume_live();
+        assert!(history.position().is_none());
+        assert_eq!(history.variables_at(&live), live);
+    }
+
+    #[test]
+    fn test_context_snapshot_restore() {
+        use batch_debugger::debugger::{CmdSession, DebugContext, Frame};
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.variables.insert("A".to_string(), "1".to_string());
+        ctx.last_exit_code = 2;
+        let checkpoint = ctx.snapshot(7);
+
+        ctx.variables.insert("A".to_string(), "changed".to_string());
+        ctx.variables.insert("B".to_string(), "new".to_string());
+        ctx.call_stack.push(Frame::new(3, None));
+        ctx.last_exit_code = 0;
+
+        assert_eq!(ctx.restore(checkpoint), 7);
+        assert_eq!(ctx.get_variable("A"), Some("1"));
+        assert_eq!(ctx.get_variable("B"), None);
+        assert!(ctx.call_stack.is_empty());
+        assert_eq!(ctx.last_exit_code, 2);
+    }
+
+    #[test]
+    fn test_dap_executor_records_history() {
+        use batch_debugger::dap::Incoming;
+        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        let lines = vec!["@echo off", "set A=1", "echo hi", "set A=2", "echo end"];
+        let pre = batch_debugger::parser::preprocess_lines(&lines);
+        let labels = batch_debugger::parser::build_label_map(&pre);
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.set_mode(RunMode::Continue);
+        ctx.add_breakpoint("", 4);
+        let ctx = Arc::new(Mutex::new(ctx));
+        let (tx, rx) = channel();
+
+        let exec_ctx = Arc::clone(&ctx);
+        let handle = std::thread::spawn(move || {
+            batch_debugger::executor::run_debugger_dap(exec_ctx, &pre, &labels, tx)
+        });
+
+        loop {
+            match rx.recv_timeout(Duration::from_secs(10)) {
+                Ok(Incoming::Stopped { line, .. }) => {
+                    assert_eq!(line, 4);
+                    while ctx.lock().unwrap().current_line != Some(line) {
+                        std::thread::sleep(Duration::from_millis(20));
+                    }
+                    let mut ctx = ctx.lock().unwrap();
+                    assert_eq!(ctx.history.len(), 4, "one entry per executed line");
+
+                    let live = ctx.get_visible_variables();
+                    let entry = ctx.history.step_back().cloned().unwrap();
+                    assert_eq!(entry.pc(), 3);
+                    let before = ctx.history.variables_at(&live);
+                    assert_eq!(before.get("A").map(String::as_str), Some("1"));
+
+                    let entry = ctx.history.step_back().cloned().unwrap();
+                    assert_eq!(entry.pc(), 2);
+                    assert!(entry.output.contains("hi"), "got {:?}", entry.output);
+                    assert_eq!(entry.before.visible_variables(), before);
+
+                    // Continuing from history resumes the live line
+                    ctx.history.resume_live();
+                    ctx.request_continue();
+                }
+                Ok(Incoming::Terminated) => break,
+                Ok(_) => {}
+                Err(e) => panic!("executor hung: {}", e),
+            }
+        }
+        handle.join().unwrap().expect("executor failed");
+        // The last line is recorded once the script ends
+        assert_eq!(ctx.lock().unwrap().history.len(), 5);
+    }
+
+    #[test]
+    fn test_bare_exit_ends_script_and_keeps_session() {
+        use batch_debugger::dap::Incoming;
+        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        // A bare EXIT inside a subroutine ends the whole script, not just :sub
+        let lines = vec![
+            "@echo off",
+            "call :sub",
+            "echo after call",
+            "goto :eof",
+            ":sub",
+            "exit 3",
+            "echo after exit",
+        ];
+        let pre = batch_debugger::parser::preprocess_lines(&lines);
+        let labels = batch_debugger::parser::build_label_map(&pre);
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.set_mode(RunMode::Continue);
+        let ctx = Arc::new(Mutex::new(ctx));
+        let (tx, rx) = channel();
+
+        let exec_ctx = Arc::clone(&ctx);
+        let exec_pre = pre.clone();
+        let handle = std::thread::spawn(move || {
+            batch_debugger::executor::run_debugger_dap(exec_ctx, &exec_pre, &labels, tx)
+        });
+        let mut output = String::new();
+        loop {
+            match rx.recv_timeout(Duration::from_secs(10)) {
+                Ok(Incoming::Terminated) => break,
+                Ok(Incoming::Output(text)) => output.push_str(&text),
+                Ok(_) => {}
+                Err(e) => panic!("executor hung: {}", e),
+            }
+        }
+        handle.join().unwrap().expect("executor failed");
+
+        assert!(
+            !output.contains("after"),
+            "nothing runs after EXIT: {}",
+            output
+        );
+        let mut ctx = ctx.lock().unwrap();
+        assert_eq!(ctx.last_exit_code, 3);
+        let (out, _) = ctx.run_command("echo still here").unwrap();
+        assert_eq!(out.trim(), "still here", "cmd.exe never saw the EXIT");
+    }
+
+    #[test]
+    fn test_exit_forms_end_script_and_keep_session() {
+        use batch_debugger::dap::Incoming;
+        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        // Each script hides its EXIT somewhere other than a line of its own
+        let cases: Vec<(&str, Vec<&str>, i32)> = vec![
+            (
+                "IF command",
+                vec!["@echo off", "if not errorlevel 1 exit 5", "echo after exit"],
+                5,
+            ),
+            (
+                "after &",
+                vec!["@echo off", "echo before & exit 3", "echo after exit"],
+                3,
+            ),
+            (
+                "inside a block",
+                vec![
+                    "@echo off",
+                    "set X=1",
+                    "if %X%==1 (",
+                    "  echo in block",
+                    "  exit 4",
+                    ")",
+                    "echo after exit",
+                ],
+                4,
+            ),
+            (
+                "exit/b",
+                vec!["@echo off", "exit/b 2", "echo after exit"],
+                2,
+            ),
+        ];
+
+        for (form, lines, expected) in cases {
+            let pre = batch_debugger::parser::preprocess_lines(&lines);
+            let labels = batch_debugger::parser::build_label_map(&pre);
+
+            let session = CmdSession::start().expect("Failed to start CMD session");
+            let mut ctx = DebugContext::new(session);
+            ctx.set_mode(RunMode::Continue);
+            let ctx = Arc::new(Mutex::new(ctx));
+            let (tx, rx) = channel();
+
+            let exec_ctx = Arc::clone(&ctx);
+            let handle = std::thread::spawn(move || {
+                batch_debugger::executor::run_debugger_dap(exec_ctx, &pre, &labels, tx)
+            });
+            let mut output = String::new();
+            loop {
+                match rx.recv_timeout(Duration::from_secs(10)) {
+                    Ok(Incoming::Terminated) => break,
+                    Ok(Incoming::Output(text)) => output.push_str(&text),
+                    Ok(_) => {}
+                    Err(e) => panic!("executor hung on EXIT {}: {}", form, e),
+                }
+            }
+            handle.join().unwrap().expect("executor failed");
+
+            assert!(
+                !output.contains("after exit"),
+                "nothing runs after EXIT {}: {}",
+                form,
+                output
+            );
+            let mut ctx = ctx.lock().unwrap();
+            assert_eq!(ctx.last_exit_code, expected, "exit code of EXIT {}", form);
+            let (out, _) = ctx.run_command("echo still here").unwrap();
+            assert_eq!(out.trim(), "still here", "cmd.exe never saw EXIT {}", form);
+        }
+    }
+
+    #[test]
+    fn test_if_errorlevel_condition() {
+        use batch_debugger::debugger::{CmdSession, DebugContext};
+        use batch_debugger::executor::evaluate_if_condition;
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+
+        ctx.last_exit_code = 0;
+        assert_eq!(evaluate_if_condition("errorlevel 1", &ctx), Some(false));
+        assert_eq!(evaluate_if_condition("not errorlevel 1", &ctx), Some(true));
+
+        // ERRORLEVEL n holds for any code of n or more
+        ctx.last_exit_code = 2;
+        assert_eq!(evaluate_if_condition("if errorlevel 1", &ctx), Some(true));
+        assert_eq!(evaluate_if_condition("ERRORLEVEL 3", &ctx), Some(false));
+        assert_eq!(
+            evaluate_if_condition("%ERRORLEVEL% EQU 2", &ctx),
+            Some(true)
+        );
+        assert_eq!(
+            evaluate_if_condition("%errorlevel% neq 0", &ctx),
+            Some(true)
+        );
+        assert_eq!(
+            evaluate_if_condition("%ERRORLEVEL% GEQ 10", &ctx),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn test_if_defined_and_compare_conditions() {
+        use batch_debugger::debugger::{CmdSession, DebugContext};
+        use batch_debugger::executor::evaluate_if_condition;
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.variables.insert("FOO".to_string(), "bar".to_string());
+        ctx.variables.insert("COUNT".to_string(), "10".to_string());
+
+        assert_eq!(evaluate_if_condition("defined FOO", &ctx), Some(true));
+        assert_eq!(evaluate_if_condition("not defined foo", &ctx), Some(false));
+        // Tracking can miss variables, so an unknown one isn't "undefined"
+        assert_eq!(evaluate_if_condition("defined UNTRACKED", &ctx), None);
+
+        assert_eq!(evaluate_if_condition(r#""%FOO%"=="bar""#, &ctx), Some(true));
+        assert_eq!(
+            evaluate_if_condition(r#""!FOO!" == "BAR""#, &ctx),
+            Some(false)
+        );
+        assert_eq!(
+            evaluate_if_condition(r#"/i "%FOO%"=="BAR""#, &ctx),
+            Some(true)
+        );
+        assert_eq!(evaluate_if_condition("%UNTRACKED%==bar", &ctx), None);
+        assert_eq!(evaluate_if_condition("%FOO:b=c%==car", &ctx), None);
+
+        // Numbers compare as numbers; other strings only for equality
+        assert_eq!(evaluate_if_condition("%COUNT% GTR 9", &ctx), Some(true));
+        assert_eq!(evaluate_if_condition("%COUNT% LSS 9", &ctx), Some(false));
+        assert_eq!(evaluate_if_condition(r#""%COUNT%" GTR "9""#, &ctx), None);
+        assert_eq!(evaluate_if_condition("%FOO% EQU bar", &ctx), Some(true));
+    }
+
+    #[test]
+    fn test_coverage_skips_untaken_if_branch_of_block() {
+        use batch_debugger::dap::Incoming;
+        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        // %X% in the bodies makes the IF run as one block
+        let lines = vec![
+            "@echo off",
+            "set X=1",
+            r#"if "%X%"=="2" ("#,
+            "  echo %X% is two",
+            ") else (",
+            "  echo %X% is not two",
+            ")",
+        ];
+        let pre = batch_debugger::parser::preprocess_lines(&lines);
+        let labels = batch_debugger::parser::build_label_map(&pre);
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.set_mode(RunMode::Continue);
+        let ctx = Arc::new(Mutex::new(ctx));
+        let (tx, rx) = channel();
+
+        let exec_ctx = Arc::clone(&ctx);
+        let exec_pre = pre.clone();
+        let handle = std::thread::spawn(move || {
+            batch_debugger::executor::run_debugger_dap(exec_ctx, &exec_pre, &labels, tx)
+        });
+        loop {
+            match rx.recv_timeout(Duration::from_secs(10)) {
+                Ok(Incoming::Terminated) => break,
+                Ok(_) => {}
+                Err(e) => panic!("executor hung: {}", e),
+            }
+        }
+        handle.join().unwrap().expect("executor failed");
+
+        let report = ctx.lock().unwrap().coverage_report(&pre);
+        assert_eq!(
+            report,
+            [
+                (1, true),
+                (2, true),
+                (3, true),
+                (4, false),
+                (5, true),
+                (6, true)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_if_branch_follows_cmd_over_tracked_state() {
+        use batch_debugger::dap::Incoming;
+        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        // %Y% in the bodies makes the IF run as one block, whose coverage
+        // comes from the same answer
+        let lines = vec![
+            "@echo off",
+            r#"if "%Y%"=="1" ("#,
+            "  echo then %Y%",
+            ") else (",
+            "  echo else %Y%",
+            ")",
+        ];
+        let pre = batch_debugger::parser::preprocess_lines(&lines);
+        let labels = batch_debugger::parser::build_label_map(&pre);
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.set_mode(RunMode::Continue);
+        // A stale guess: the session never saw Y set
+        ctx.variables.insert("Y".to_string(), "1".to_string());
+        let ctx = Arc::new(Mutex::new(ctx));
+        let (tx, rx) = channel();
+
+        let exec_ctx = Arc::clone(&ctx);
+        let exec_pre = pre.clone();
+        let handle = std::thread::spawn(move || {
+            batch_debugger::executor::run_debugger_dap(exec_ctx, &exec_pre, &labels, tx)
+        });
+        let mut output = String::new();
+        loop {
+            match rx.recv_timeout(Duration::from_secs(10)) {
+                Ok(Incoming::Terminated) => break,
+                Ok(Incoming::Output(text)) => output.push_str(&text),
+                Ok(_) => {}
+                Err(e) => panic!("executor hung: {}", e),
+            }
+        }
+        handle.join().unwrap().expect("executor failed");
+
+        assert!(output.contains("else"), "got {:?}", output);
+        assert!(!output.contains("then"), "got {:?}", output);
+        let report = ctx.lock().unwrap().coverage_report(&pre);
+        assert_eq!(
+            report,
+            [(1, true), (2, true), (3, false), (4, true), (5, true)]
+        );
+    }
+
+    #[test]
+    fn test_coverage_reports_untaken_goto_branch() {
+        use batch_debugger::dap::Incoming;
+        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        let lines = vec![
+            "@echo off",
+            "set X=1",
+            "goto :done",
+            "echo skipped",
+            "set Y=2",
+            ":done",
+            "rem the label and this comment never count",
+            "echo finished",
+        ];
+        let pre = batch_debugger::parser::preprocess_lines(&lines);
+        let labels = batch_debugger::parser::build_label_map(&pre);
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.set_mode(RunMode::Continue);
+        let ctx = Arc::new(Mutex::new(ctx));
+        let (tx, rx) = channel();
+
+        let exec_ctx = Arc::clone(&ctx);
+        let exec_pre = pre.clone();
+        let handle = std::thread::spawn(move || {
+            batch_debugger::executor::run_debugger_dap(exec_ctx, &exec_pre, &labels, tx)
+        });
+        loop {
+            match rx.recv_timeout(Duration::from_secs(10)) {
+                Ok(Incoming::Terminated) => break,
+                Ok(_) => {}
+                Err(e) => panic!("executor hung: {}", e),
+            }
+        }
+        handle.join().unwrap().expect("executor failed");
+
+        let report = ctx.lock().unwrap().coverage_report(&pre);
+        assert_eq!(
+            report,
+            [
+                (1, true),
+                (2, true),
+                (3, true),
+                (4, false),
+                (5, false),
+                (8, true)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_profile_times_delay_line() {
+        use batch_debugger::dap::Incoming;
+        use batch_debugger::debugger::{CmdSession, DebugContext, Profile, RunMode};
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        let lines = vec![
+            "@echo off",
+            "call :wait",
+            "call :wait",
+            "exit /b",
+            ":wait",
+            "ping -n 2 127.0.0.1 >nul",
+            "exit /b",
+        ];
+        let pre = batch_debugger::parser::preprocess_lines(&lines);
+        let labels = batch_debugger::parser::build_label_map(&pre);
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.set_mode(RunMode::Continue);
+        ctx.profile = Some(Profile::new());
+        let ctx = Arc::new(Mutex::new(ctx));
+        let (tx, rx) = channel();
+
+        let exec_ctx = Arc::clone(&ctx);
+        let exec_pre = pre.clone();
+        let handle = std::thread::spawn(move || {
+            batch_debugger::executor::run_debugger_dap(exec_ctx, &exec_pre, &labels, tx)
+        });
+        loop {
+            match rx.recv_timeout(Duration::from_secs(10)) {
+                Ok(Incoming::Terminated) => break,
+                Ok(_) => {}
+                Err(e) => panic!("executor hung: {}", e),
+            }
+        }
+        handle.join().unwrap().expect("executor failed");
+
+        let ctx = ctx.lock().unwrap();
+        let profile = ctx.profile.as_ref().unwrap();
+        let ping = pre.phys_to_logical[5];
+        let timing = profile.timing(ping).expect("delay line not timed");
+        assert_eq!(timing.hits, 2, "both calls are counted");
+        assert!(timing.total > Duration::ZERO);
+        assert_eq!(profile.slowest(1)[0].0, ping);
+
+        let report = profile.report(&pre, 1);
+        assert!(report.contains("ping -n 2"), "got {}", report);
+        assert!(report
+            .lines()
+            .nth(1)
+            .unwrap()
+            .trim_start()
+            .starts_with("6 "));
+    }
+
+    #[test]
+    fn test_executors_agree_on_skipped_lines() {
+        use batch_debugger::dap::Incoming;
+        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+        use batch_debugger::executor::is_skippable;
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        let lines = vec![
+            "@echo off",
+            "",
+            "REM",
+            "rem\tlowercase with a tab",
+            "      ::   indented comment",
+            ":label",
+            "echo a",
+            "   REM indented remark",
+            "echo b",
+        ];
+        let skipped: Vec<bool> = lines.iter().map(|l| is_skippable(l)).collect();
+        assert_eq!(
+            skipped,
+            [false, true, true, true, true, true, false, true, false]
+        );
+
+        // Stepping through every line stops exactly where nothing is skipped
+        let pre = batch_debugger::parser::preprocess_lines(&lines);
+        let labels = batch_debugger::parser::build_label_map(&pre);
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.set_mode(RunMode::StepInto);
+        let ctx = Arc::new(Mutex::new(ctx));
+        let (tx, rx) = channel();
+
+        let exec_ctx = Arc::clone(&ctx);
+        let handle = std::thread::spawn(move || {
+            batch_debugger::executor::run_debugger_dap(exec_ctx, &pre, &labels, tx)
+        });
+
+        let mut stops = Vec::new();
+        loop {
+            match rx.recv_timeout(Duration::from_secs(10)) {
+                Ok(Incoming::Stopped { line, .. }) => {
+                    stops.push(line);
+                    while ctx.lock().unwrap().current_line != Some(line) {
+                        std::thread::sleep(Duration::from_millis(20));
+                    }
+                    ctx.lock().unwrap().request_continue();
+                }
+                Ok(Incoming::Terminated) => break,
+                Ok(_) => {}
+                Err(e) => panic!("executor hung: {}", e),
+            }
+        }
+        handle.join().unwrap().expect("executor failed");
+
+        let expected: Vec<usize> = (0..lines.len()).filter(|&i| !skipped[i]).collect();
+        assert_eq!(stops, expected);
+    }
+
+    #[test]
+    fn test_run_to_completion_trace() {
+        use batch_debugger::api::run_to_completion;
+        use std::path::Path;
+
+        let script = create_test_batch(
+            "@echo off\r\n:: greeting\r\nset GREETING=hi\r\necho %GREETING% there\r\n",
+            "api_trace",
+        );
+        let trace = run_to_completion(Path::new(&script)).expect("run failed");
+        cleanup_test_batch(&script);
+
+        assert_eq!(trace.lines.len(), 3, "got {:?}", trace.lines);
+        let lines: Vec<usize> = trace.lines.iter().map(|l| l.line).collect();
+        assert_eq!(lines, [1, 3, 4], "the comment never runs");
+        assert_eq!(trace.lines[1].text, "set GREETING=hi");
+        assert!(trace.lines[2].output.contains("hi there"));
+        assert!(trace.lines.iter().all(|l| l.exit_code == 0));
+        assert_eq!(trace.exit_code, 0);
+        assert_eq!(
+            trace.variables.get("GREETING").map(String::as_str),
+            Some("hi")
+        );
+
+        // Nothing waits on a client that isn't there
+        let script = create_test_batch(
+            "@echo off\r\npause >nul\r\nset /p NAME=Name? \r\necho done\r\n",
+            "api_unattended",
+        );
+        let trace = run_to_completion(Path::new(&script)).expect("run failed");
+        cleanup_test_batch(&script);
+        assert_eq!(trace.lines.len(), 4);
+        assert!(trace.lines[3].output.contains("done"));
+    }
+
+    #[test]
+    fn test_external_call_runs_through_session() {
+        use batch_debugger::dap::Incoming;
+        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        let helper = create_test_batch("@echo off\r\necho from helper\r\nexit /b 3\r\n", "helper");
+        let call = format!("call {}", helper);
+        let lines = vec!["@echo off", call.as_str(), "echo after %ERRORLEVEL%"];
+        let pre = batch_debugger::parser::preprocess_lines(&lines);
+        let labels = batch_debugger::parser::build_label_map(&pre);
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.set_mode(RunMode::Continue);
+        let ctx = Arc::new(Mutex::new(ctx));
+        let (tx, rx) = channel();
+
+        let exec_ctx = Arc::clone(&ctx);
+        let handle = std::thread::spawn(move || {
+            batch_debugger::executor::run_debugger_dap(exec_ctx, &pre, &labels, tx)
+        });
+
+        let mut output = String::new();
+        loop {
+            match rx.recv_timeout(Duration::from_secs(10)) {
+                Ok(Incoming::Output(text)) => output.push_str(&text),
+                Ok(Incoming::Terminated) => break,
+                Ok(_) => {}
+                Err(e) => panic!("executor hung: {} (output so far: {:?})", e, output),
+            }
+        }
+        handle.join().unwrap().expect("executor failed");
+        cleanup_test_batch(&helper);
+
+        assert!(!output.contains("unknown label"), "got {:?}", output);
+        assert!(output.contains("from helper"), "got {:?}", output);
+        assert!(
+            output.contains("after 3"),
+            "exit code is kept: {:?}",
+            output
+        );
+        assert!(
+            ctx.lock().unwrap().call_stack.is_empty(),
+            "No frame is pushed"
+        );
+    }
+
+    #[test]
+    fn test_shift_renumbers_arguments() {
+        use batch_debugger::dap::Incoming;
+        use batch_debugger::debugger::{CmdSession, DebugContext, Frame, RunMode};
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        let lines = vec![
+            "@echo off",
+            "call :sub a b c",
+            "exit /b",
+            ":sub",
+            "shift",
+            "echo one=[%1] all=[%*]",
+            "shift /2",
+            "echo two=[%1][%2]",
+            "exit /b",
+        ];
+        let pre = batch_debugger::parser::preprocess_lines(&lines);
+        let labels = batch_debugger::parser::build_label_map(&pre);
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.set_mode(RunMode::Continue);
+        let ctx = Arc::new(Mutex::new(ctx));
+        let (tx, rx) = channel();
+
+        let exec_ctx = Arc::clone(&ctx);
+        let handle = std::thread::spawn(move || {
+            batch_debugger::executor::run_debugger_dap(exec_ctx, &pre, &labels, tx)
+        });
+
+        let mut output = String::new();
+        loop {
+            match rx.recv_timeout(Duration::from_secs(10)) {
+                Ok(Incoming::Output(text)) => output.push_str(&text),
+                Ok(Incoming::Terminated) => break,
+                Ok(_) => {}
+                Err(e) => panic!("executor hung: {} (output so far: {:?})", e, output),
+            }
+        }
+        handle.join().unwrap().expect("executor failed");
+
+        assert!(output.contains("one=[b] all=[b c]"), "got {:?}", output);
+        assert!(output.contains("two=[b][]"), "got {:?}", output);
+
+        // Shifting with nothing to shift is a no-op
+        let mut frame = Frame::called("sub", 0, 1, Some(Vec::new()));
+        frame.shift(1);
+        assert_eq!(frame.args, Some(Vec::new()));
+        let mut frame = Frame::called("sub", 0, 1, None);
+        frame.shift(1);
+        assert!(frame.args.is_none());
+    }
+
+    #[test]
+    fn test_step_into_external_script() {
+        use batch_debugger::dap::Incoming;
+        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        let helper = create_test_batch(
+            "@echo off\r\necho from helper %1\r\necho helper done\r\n",
+            "step_into",
+        );
+        let call = format!("call {} one", helper);
+        let lines = vec!["@echo off", call.as_str(), "echo back in main"];
+        let pre = batch_debugger::parser::preprocess_lines(&lines);
+        let labels = batch_debugger::parser::build_label_map(&pre);
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.set_mode(RunMode::StepInto);
+        ctx.step_into_external = true;
+        let ctx = Arc::new(Mutex::new(ctx));
+        let (tx, rx) = channel();
+
+        let exec_ctx = Arc::clone(&ctx);
+        let handle = std::thread::spawn(move || {
+            batch_debugger::executor::run_debugger_dap(exec_ctx, &pre, &labels, tx)
+        });
+
+        // Step from the top of the main script through the CALL
+        let mut sources = Vec::new();
+        for _ in 0..3 {
+            let line = loop {
+                match rx.recv_timeout(Duration::from_secs(10)) {
+                    Ok(Incoming::Stopped { line, .. }) => break line,
+                    Ok(_) => {}
+                    Err(e) => panic!("executor never stopped: {}", e),
+                }
+            };
+            while ctx.lock().unwrap().current_line != Some(line) {
+                std::thread::sleep(Duration::from_millis(20));
+            }
+            let mut ctx = ctx.lock().unwrap();
+            sources.push((ctx.current_source.clone(), line, ctx.call_stack.len()));
+            if sources.len() == 3 {
+                assert_eq!(ctx.call_stack[0].source.as_deref(), Some(helper.as_str()));
+                assert_eq!(ctx.call_stack[0].return_pc, 2);
+                assert!(ctx.scripts.contains_key(&helper));
+                ctx.set_mode(RunMode::Continue);
+            }
+            ctx.request_continue();
+        }
+
+        assert_eq!(
+            sources,
+            vec![
+                (String::new(), 0, 0),
+                (String::new(), 1, 0),
+                (helper.clone(), 0, 1),
+            ],
+            "Stepping into the CALL stops at the called script's first line"
+        );
+        let mut output = String::new();
+        loop {
+            match rx.recv_timeout(Duration::from_secs(10)) {
+                Ok(Incoming::Output(text)) => output.push_str(&text),
+                Ok(Incoming::Terminated) => break,
+                Ok(_) => {}
+                Err(e) => panic!("executor hung: {} (output so far: {:?})", e, output),
+            }
+        }
+        handle.join().unwrap().expect("executor failed");
+        cleanup_test_batch(&helper);
+
+        assert!(output.contains("helper done"), "got {:?}", output);
+        assert!(output.contains("back in main"), "got {:?}", output);
+        let ctx = ctx.lock().unwrap();
+        assert!(ctx.call_stack.is_empty());
+        assert_eq!(
+            ctx.current_source, "",
+            "Returning restores the caller's source"
+        );
+    }
+
+    #[test]
+    fn test_breakpoints_keyed_by_source() {
+        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.set_mode(RunMode::Continue);
+
+        ctx.add_breakpoint("C:\\scripts\\main.bat", 3);
+        ctx.add_breakpoint("C:\\scripts\\helper.bat", 7);
+
+        assert!(ctx.should_stop_at("C:\\scripts\\main.bat", 3));
+        assert!(
+            ctx.should_stop_at("c:/scripts/MAIN.bat", 3),
+            "Paths compare like Windows"
+        );
+        assert!(
+            !ctx.should_stop_at("C:\\scripts\\main.bat", 7),
+            "Other file's line must not fire"
+        );
+
+        // A new set for a source replaces the old one instead of appending
+        ctx.set_source_breakpoints("C:\\scripts\\main.bat", &[4]);
+        assert!(!ctx.should_stop_at("C:\\scripts\\main.bat", 3));
+        assert!(ctx.should_stop_at("C:\\scripts\\main.bat", 4));
+        assert!(ctx.should_stop_at("C:\\scripts\\helper.bat", 7));
+
+        // Listing is per source, sorted, and includes function breakpoints
+        ctx.set_source_breakpoints("C:\\scripts\\main.bat", &[9, 2, 4]);
+        ctx.set_function_breakpoints("c:/scripts/main.bat", &[5, 2]);
+        assert_eq!(ctx.breakpoint_lines("C:\\scripts\\main.bat"), [2, 4, 5, 9]);
+        assert_eq!(ctx.breakpoint_lines("C:\\scripts\\helper.bat"), [7]);
+        assert!(ctx.breakpoint_lines("C:\\scripts\\other.bat").is_empty());
+    }
+
+    #[test]
+    fn test_set_arithmetic_readback() {
+        use batch_debugger::debugger::{CmdSession, DebugContext};
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+
+        // Without execution the result can't be known, so nothing is guessed
+        ctx.track_set_command("SET /A COUNTER=2+3");
+        assert!(!ctx.variables.contains_key("COUNTER"));
+
+        for line in [
+            "SET /A COUNTER=2+3",
+            "set /a \"DOUBLE=COUNTER*2\", COUNTER+=1",
+        ] {
+            ctx.run_command(line).expect("Failed to run command");
+            ctx.track_set_readback(line).expect("Failed to read back");
+        }
+
+        assert_eq!(ctx.variables.get("COUNTER").map(String::as_str), Some("6"));
+        assert_eq!(ctx.variables.get("DOUBLE").map(String::as_str), Some("10"));
+    }
+
+    #[test]
+    fn test_clearing_breakpoints_reconciles_context() {
+        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.set_mode(RunMode::Continue);
+
+        ctx.set_source_breakpoints("test.bat", &[2, 6]);
+        assert!(ctx.should_stop_at("test.bat", 2));
+        assert!(ctx.should_stop_at("test.bat", 6));
+
+        // VS Code resends the remaining set after the user toggles one off
+        ctx.set_source_breakpoints("test.bat", &[6]);
+        assert!(
+            !ctx.should_stop_at("test.bat", 2),
+            "Cleared breakpoint must not fire"
+        );
+        assert!(ctx.should_stop_at("test.bat", 6));
+
+        // An empty array removes everything for the source
+        ctx.set_source_breakpoints("test.bat", &[]);
+        assert!(!ctx.should_stop_at("test.bat", 6));
+    }
+
+    #[test]
+    fn test_removed_breakpoint_stops_firing_per_source() {
+        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.set_mode(RunMode::Continue);
+
+        ctx.set_source_breakpoints("main.bat", &[2, 6]);
+        ctx.set_source_breakpoints("lib.bat", &[2]);
+
+        ctx.set_source_breakpoints("main.bat", &[6]);
+        assert!(
+            !ctx.should_stop_at("main.bat", 2),
+            "Removed breakpoint is disabled"
+        );
+        assert!(ctx.should_stop_at("main.bat", 6));
+        assert!(
+            ctx.should_stop_at("lib.bat", 2),
+            "Other sources keep their breakpoints"
+        );
+
+        ctx.clear_breakpoints("LIB.bat");
+        assert!(!ctx.should_stop_at("lib.bat", 2));
+        assert!(ctx.should_stop_at("main.bat", 6));
+    }
+
+    #[test]
+    fn test_data_breakpoint_detects_change() {
+        use batch_debugger::debugger::{CmdSession, DebugContext};
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+
+        ctx.track_set_command("set COUNT=1");
+        ctx.set_data_breakpoints(&["count".to_string()]);
+        assert_eq!(ctx.check_data_breakpoints(), None, "No change yet");
+
+        ctx.track_set_command("set OTHER=x");
+        assert_eq!(ctx.check_data_breakpoints(), None, "Unwatched variable");
+
+        ctx.track_set_command("set COUNT=2");
+        assert_eq!(ctx.check_data_breakpoints(), Some("count".to_string()));
+        assert_eq!(ctx.check_data_breakpoints(), None, "Fires once per change");
+    }
+
+    #[test]
+    fn test_sync_variables_from_session() {
+        use batch_debugger::debugger::{CmdSession, DebugContext};
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+
+        ctx.track_set_command("set TRACKED=1");
+        // Set inside a nested block, so track_set_command never sees them
+        ctx.session_mut()
+            .run_batch_block(&["set NESTED=a=b".to_string(), "set TRACKED=2".to_string()])
+            .expect("Failed to run block");
+        assert!(!ctx.variables.contains_key("NESTED"));
+
+        ctx.sync_variables_from_session().expect("Failed to sync");
+        assert_eq!(ctx.variables.get("NESTED").map(String::as_str), Some("a=b"));
+        assert_eq!(ctx.variables.get("TRACKED").map(String::as_str), Some("2"));
+        assert!(
+            !ctx.variables.contains_key("PROMPT"),
+            "Volatile vars are filtered"
+        );
+        assert!(
+            !ctx.variables.contains_key("CARGO_PKG_NAME"),
+            "Inherited vars stay out: {:?}",
+            ctx.variables.keys()
+        );
+
+        // Until the script changes one
+        ctx.session_mut()
+            .run_batch_block(&["set CARGO_PKG_NAME=changed".to_string()])
+            .expect("Failed to run block");
+        ctx.sync_variables_from_session().expect("Failed to sync");
+        assert_eq!(ctx.get_variable("CARGO_PKG_NAME"), Some("changed"));
+    }
+
+    #[test]
+    fn test_run_debugger_dap_end_to_end() {
+        use batch_debugger::dap::Incoming;
+        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        let path = create_test_batch("@echo off\r\nset X=42\r\necho %X%\r\nexit /b 0\r\n", "e2e");
+        let contents = fs::read_to_string(&path).expect("Could not read test file");
+        cleanup_test_batch(&path);
+        let physical_lines: Vec<&str> = contents.lines().collect();
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+        let labels = batch_debugger::parser::build_label_map(&pre);
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.current_source = path.clone();
+        ctx.set_mode(RunMode::Continue);
+        let ctx = Arc::new(Mutex::new(ctx));
+        let (tx, rx) = channel();
+
+        let exec_ctx = Arc::clone(&ctx);
+        let handle = std::thread::spawn(move || {
+            batch_debugger::executor::run_debugger_dap(exec_ctx, &pre, &labels, tx)
+        });
+        let mut output = String::new();
+        loop {
+            match rx.recv_timeout(Duration::from_secs(10)) {
+                Ok(Incoming::Terminated) => break,
+                Ok(Incoming::Output(text)) => output.push_str(&text),
+                Ok(_) => {}
+                Err(e) => panic!("executor hung: {}", e),
+            }
+        }
+        handle.join().unwrap().expect("Executor failed");
+
+        assert_eq!(output.trim(), "42", "the script's own ECHO");
+        let ctx = ctx.lock().unwrap();
+        assert_eq!(ctx.last_exit_code, 0);
+        assert_eq!(ctx.get_variable("X"), Some("42"));
+    }
+
+    #[test]
+    fn test_version_flag() {
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_batch-debugger"))
+            .arg("--version")
+            .output()
+            .expect("Failed to run binary");
+
+        assert!(output.status.success());
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(
+            stdout.starts_with(&format!("batch-debugger {} (", env!("CARGO_PKG_VERSION"))),
+            "Unexpected version line: {}",
+            stdout
+        );
+    }
+
+    #[test]
+    fn test_stop_position_set_before_stopped_event() {
+        use batch_debugger::dap::Incoming;
+        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        let lines = vec![
+            "@echo off",
+            "set A=1",
+            "set B=2",
+            "set C=3",
+            "set D=4",
+            "set E=5",
+        ];
+        let pre = batch_debugger::parser::preprocess_lines(&lines);
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.set_mode(RunMode::StepInto);
+        let ctx = Arc::new(Mutex::new(ctx));
+        let (tx, rx) = channel();
+
+        let exec_ctx = Arc::clone(&ctx);
+        let handle = std::thread::spawn(move || {
+            batch_debugger::executor::run_debugger_dap(exec_ctx, &pre, &Default::default(), tx)
+        });
+
+        // Whoever hears of a stop already sees it, and a continue sent at once
+        // isn't undone by the executor settling into the stop
+        let mut stops = Vec::new();
+        loop {
+            match rx.recv_timeout(Duration::from_secs(10)) {
+                Ok(Incoming::Stopped { line, .. }) => {
+                    let mut ctx = ctx.lock().unwrap();
+                    assert_eq!(ctx.current_line, Some(line), "stopped event came first");
+                    stops.push(line);
+                    ctx.request_continue();
+                }
+                Ok(Incoming::Terminated) => break,
+                Ok(_) => {}
+                Err(e) => panic!("executor hung after {:?}: {}", stops, e),
+            }
+        }
+        handle.join().unwrap().expect("executor failed");
+        assert_eq!(stops, [0, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_dap_session_over_custom_transport() {
+        use crate::common::{frame, Shared};
+        use batch_debugger::dap::{run_dap_session, DapServer};
+        use batch_debugger::logger::Logger;
+        use serde_json::json;
+        use std::io::Cursor;
+
+        let input: Vec<u8> = [(1, "initialize"), (2, "threads"), (3, "disconnect")]
+            .into_iter()
+            .flat_map(|(seq, command)| frame(seq, command, json!({})))
+            .collect();
+
+        let output = Shared::default();
+        let server = DapServer::with_transport(
+            Logger::disabled(),
+            Box::new(Cursor::new(input)),
+            Box::new(output.clone()),
+        );
+        run_dap_session(server).expect("session failed");
+
+        let messages = output.messages();
+        let responses: Vec<&str> = messages
+            .iter()
+            .filter(|m| m["type"] == "response")
+            .map(|m| m["command"].as_str().unwrap())
+            .collect();
+        assert_eq!(responses, ["initialize", "threads", "disconnect"]);
+        assert!(messages.iter().any(|m| m["event"] == "initialized"));
+    }
+
+    #[test]
+    fn test_output_precedes_stopped_events() {
+        use crate::common::Client;
+        use serde_json::json;
+
+        let mut client = Client::with_script(
+            "ordering",
+            "@echo off\r\necho one\r\necho two\r\necho three\r\n",
+        );
+        client.request("initialize", json!({ "adapterID": "batch" }));
+        client.launch(true);
+        client.event("stopped", 1);
+        for stop in 2..=4 {
+            client.request("next", json!({ "threadId": 1 }));
+            client.event("stopped", stop);
+        }
+        client.request("next", json!({ "threadId": 1 }));
+        client.event("terminated", 1);
+
+        let events: Vec<String> = client
+            .messages()
+            .iter()
+            .filter_map(|m| match m["event"].as_str()? {
+                "output" => Some(m["body"]["output"].as_str()?.trim().to_string()),
+                event @ ("stopped" | "terminated") => Some(event.to_string()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            events,
+            [
+                "stopped",
+                "stopped",
+                "one",
+                "stopped",
+                "two",
+                "stopped",
+                "three",
+                "terminated"
+            ],
+            "each step's output arrives before the next stop"
+        );
+        client.finish();
+    }
+
+    #[test]
+    fn test_sources_carry_checksums() {
+        use crate::common::Client;
+        use serde_json::json;
+        use sha2::{Digest, Sha256};
+
+        let content = "@echo off\r\necho one\r\necho two\r\n";
+        let mut client = Client::with_script("checksums", content);
+        let expected: String = Sha256::digest(content.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+
+        let initialized = client.request("initialize", json!({ "adapterID": "batch" }));
+        assert_eq!(
+            initialized["body"]["supportedChecksumAlgorithms"],
+            json!(["SHA256"])
+        );
+        client.launch(true);
+        let response = client.set_breakpoints(&[3]);
+        let bp = &response["body"]["breakpoints"][0];
+        assert_eq!(bp["verified"], true);
+        assert_eq!(
+            bp["source"]["checksums"],
+            json!([{ "algorithm": "SHA256", "checksum": expected }])
+        );
+
+        // An unchanged file maps normally even with the hint
+        let hinted = json!({
+            "source": { "path": client.script },
+            "breakpoints": [{ "line": 3 }],
+            "sourceModified": true
+        });
+        let response = client.request("setBreakpoints", hinted.clone());
+        assert_eq!(response["body"]["breakpoints"][0]["verified"], true);
+
+        fs::write(
+            &client.script,
+            "@echo off\r\necho inserted\r\necho one\r\necho two\r\n",
+        )
+        .unwrap();
+        let response = client.request("setBreakpoints", hinted);
+        let stale = &response["body"]["breakpoints"][0];
+        assert_eq!(stale["verified"], false);
+        assert!(
+            stale["message"].as_str().unwrap().contains("changed"),
+            "got {}",
+            stale
+        );
+        // The checksum still describes what is being debugged
+        assert_eq!(
+            stale["source"]["checksums"][0]["checksum"],
+            expected.as_str()
+        );
+
+        // So does the content served by reference, for clients without the file
+        let reference = stale["source"]["sourceReference"].clone();
+        assert!(reference.as_u64().is_some_and(|r| r > 0), "got {}", stale);
+        let served = client.request("source", json!({ "sourceReference": reference }));
+        assert_eq!(served["success"], true);
+        assert_eq!(served["body"]["content"], content);
+
+        client.finish();
+    }
+
+    #[test]
+    fn test_function_breakpoints_on_labels() {
+        use crate::common::Client;
+        use serde_json::{json, Value};
+
+        let mut client = Client::with_script(
+            "function_bp",
+            "@echo off\r\ncall :work\r\nexit /b\r\n:work\r\nrem setup\r\necho working\r\nexit /b\r\n",
+        );
+        let request = json!({ "breakpoints": [{ "name": "work" }, { "name": "missing" }] });
+        let pending = client.request("setFunctionBreakpoints", request.clone());
+        assert_eq!(
+            pending["body"]["breakpoints"][0]["verified"], false,
+            "labels are unknown before launch"
+        );
+
+        client.launch(false);
+        client.event("breakpoint", 2);
+        let changed: Vec<Value> = client
+            .messages()
+            .into_iter()
+            .filter(|m| m["event"] == "breakpoint")
+            .map(|m| m["body"]["breakpoint"].clone())
+            .collect();
+        assert_eq!(changed.len(), 2);
+        assert_eq!(changed[0]["verified"], true);
+        assert_eq!(
+            changed[0]["line"], 6,
+            "stops at the label's first statement"
+        );
+        assert_eq!(changed[1]["verified"], false);
+        assert!(changed[1]["message"].as_str().unwrap().contains("missing"));
+
+        // Execution stops on `echo working` without a line breakpoint
+        client.event("stopped", 1);
+        let trace = client.request("stackTrace", json!({ "threadId": 1 }));
+        assert_eq!(trace["body"]["stackFrames"][0]["line"], 6, "{}", trace);
+
+        // After launch the response carries the resolved breakpoints
+        let response = client.request("setFunctionBreakpoints", request);
+        assert_eq!(response["body"]["breakpoints"][0]["verified"], true);
+        assert_eq!(response["body"]["breakpoints"][1]["verified"], false);
+
+        client.finish();
+    }
+
+    #[test]
+    fn test_threads_per_script_invocation() {
+        use crate::common::Client;
+        use serde_json::{json, Value};
+
+        let helper = create_test_batch(
+            "@echo off\r\necho in helper\r\necho helper done\r\n",
+            "threads_helper",
+        );
+        let mut client = Client::with_script(
+            "threads_main",
+            &format!("@echo off\r\ncall {}\r\necho back\r\n", helper),
+        );
+        let main = client.script.clone();
+        client.request(
+            "setBreakpoints",
+            json!({ "source": { "path": helper }, "breakpoints": [{ "line": 3 }] }),
+        );
+        client.request(
+            "launch",
+            json!({ "program": main, "stopOnEntry": false, "stepIntoExternalScripts": true }),
+        );
+
+        // The executor enters the helper and stops at its breakpoint
+        let stopped = client.wait_for("stopped", |m| m["event"] == "stopped");
+        assert_eq!(stopped["body"]["threadId"], 2);
+        let threads = client.request("threads", json!({}))["body"]["threads"].clone();
+        assert_eq!(
+            threads,
+            json!([
+                { "id": 1, "name": main },
+                { "id": 2, "name": helper }
+            ])
+        );
+
+        let mut frames_of = |args: Value| -> Vec<(u64, String)> {
+            client.request("stackTrace", args)["body"]["stackFrames"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|f| {
+                    (
+                        f["id"].as_u64().unwrap(),
+                        f["source"]["name"].as_str().unwrap().to_string(),
+                    )
+                })
+                .collect()
+        };
+        assert_eq!(frames_of(json!({ "threadId": 2 })), [(0, helper.clone())]);
+        assert_eq!(frames_of(json!({ "threadId": 1 })), [(1, main.clone())]);
+        assert_eq!(frames_of(json!({})).len(), 2, "no thread means all frames");
+
+        client.finish();
+        cleanup_test_batch(&helper);
+    }
+
+    #[test]
+    fn test_system_scope_pseudo_variables() {
+        use crate::common::Client;
+        use serde_json::json;
+
+        let mut client = Client::with_script(
+            "system_scope",
+            "@echo off\r\ncall :fail\r\necho after\r\nexit /b\r\n:fail\r\nexit /b 4\r\n",
+        );
+        client.set_breakpoints(&[3]);
+        client.launch(false);
+        client.event("stopped", 1);
+
+        let scopes = client.request("scopes", json!({ "frameId": 0 }))["body"]["scopes"].clone();
+        let system = scopes
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|s| s["name"] == "System")
+            .expect("no System scope");
+        assert_eq!(system["expensive"], false);
+
+        let response = client.request(
+            "variables",
+            json!({ "variablesReference": system["variablesReference"] }),
+        );
+        let variables = response["body"]["variables"].clone();
+        let names: Vec<&str> = variables
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| v["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, ["CD", "DATE", "ERRORLEVEL", "RANDOM", "TIME"]);
+        assert_eq!(variables[2]["value"], "4", "ERRORLEVEL left by the call");
+        assert!(!variables[0]["value"].as_str().unwrap().is_empty());
+        let random: u32 = variables[3]["value"].as_str().unwrap().parse().unwrap();
+        assert!(random < 32768);
+        for variable in variables.as_array().unwrap() {
+            assert_eq!(
+                variable["presentationHint"]["attributes"],
+                json!(["readOnly"])
+            );
+        }
+
+        client.finish();
+    }
+
+    #[test]
+    fn test_variables_hex_format() {
+        use crate::common::Client;
+        use serde_json::{json, Value};
+
+        let mut client = Client::with_script(
+            "hex_format",
+            "@echo off\r\nset COUNT=255\r\nset NAME=abc\r\ncall :fail\r\necho after\r\nexit /b\r\n:fail\r\nexit /b 10\r\n",
+        );
+        let initialized = client.request("initialize", json!({ "adapterID": "batch" }));
+        assert_eq!(initialized["body"]["supportsValueFormattingOptions"], true);
+        client.set_breakpoints(&[5]);
+        client.launch(false);
+        client.event("stopped", 1);
+
+        let scopes = client.request("scopes", json!({ "frameId": 0 }))["body"]["scopes"].clone();
+        let scope = |name: &str| {
+            scopes
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|s| s["name"] == name)
+                .unwrap()["variablesReference"]
+                .clone()
+        };
+        let (local, system) = (scope("Local"), scope("System"));
+
+        // Value of each variable `name` in the scope `reference`
+        let mut values = |reference: &Value, hex: bool, names: &[&str]| {
+            let response = client.request(
+                "variables",
+                json!({ "variablesReference": reference, "format": { "hex": hex } }),
+            );
+            let variables = response["body"]["variables"].clone();
+            names
+                .iter()
+                .map(|name| {
+                    variables
+                        .as_array()
+                        .unwrap()
+                        .iter()
+                        .find(|v| v["name"] == *name)
+                        .unwrap_or_else(|| panic!("no {} in {}", name, variables))["value"]
+                        .clone()
+                })
+                .collect::<Vec<Value>>()
+        };
+        assert_eq!(
+            values(&local, true, &["COUNT", "NAME"]),
+            [json!("0xff"), json!("abc")],
+            "only numbers change"
+        );
+        assert_eq!(values(&local, false, &["COUNT"]), [json!("255")]);
+        assert_eq!(values(&system, true, &["ERRORLEVEL"]), [json!("0xa")]);
+
+        client.finish();
+    }
+
+    #[test]
+    fn test_read_memory_dumps_environment_block() {
+        use crate::common::Client;
+        use base64::Engine;
+        use serde_json::json;
+
+        let launch = |name: &str, expose: bool| {
+            let mut client = Client::with_script(name, "@echo off\r\necho hi\r\n");
+            let program = client.script.clone();
+            client.request(
+                "launch",
+                json!({
+                    "program": program,
+                    "stopOnEntry": true,
+                    "preInitCommands": ["set DUMPED=yes"],
+                    "exposeMemoryAsEnv": expose
+                }),
+            );
+            client
+        };
+
+        // Off unless the launch configuration asks for it
+        let mut client = launch("read_memory_off", false);
+        let response = client.request(
+            "readMemory",
+            json!({ "memoryReference": "env", "count": 64 }),
+        );
+        assert_eq!(response["success"], false);
+        client.finish();
+
+        let mut client = launch("read_memory", true);
+        let response = client.request(
+            "readMemory",
+            json!({ "memoryReference": "env", "count": 1 << 20 }),
+        );
+        assert_eq!(response["success"], true, "{}", response);
+        let body = &response["body"];
+        assert_eq!(body["address"], "0x0");
+        let data = base64::engine::general_purpose::STANDARD
+            .decode(body["data"].as_str().unwrap())
+            .unwrap();
+        assert_eq!(
+            body["unreadableBytes"].as_u64().unwrap() as usize,
+            (1 << 20) - data.len(),
+            "reading past the end"
+        );
+
+        let dump = String::from_utf8(data).unwrap();
+        let rows: Vec<&str> = dump.lines().collect();
+        assert!(rows[0].starts_with("00000000  "), "got {:?}", rows[0]);
+        assert!(rows[1].starts_with("00000010  "), "got {:?}", rows[1]);
+        let width = rows[0].len();
+        assert!(
+            rows[..rows.len() - 1].iter().all(|row| row.len() == width),
+            "rows are fixed width"
+        );
+        let ascii: String = rows
+            .iter()
+            .map(|row| row.split_once('|').unwrap().1.trim_end_matches([' ', '|']))
+            .collect();
+        assert!(ascii.contains("DUMPED=yes."), "got {}", ascii);
+
+        // An offset reads from the middle of the dump: here the second row
+        let row_bytes = width + 1;
+        let response = client.request(
+            "readMemory",
+            json!({ "memoryReference": "env", "offset": row_bytes, "count": 8 }),
+        );
+        let body = &response["body"];
+        assert_eq!(body["address"], format!("0x{:x}", row_bytes));
+        assert_eq!(
+            body["data"],
+            base64::engine::general_purpose::STANDARD.encode("00000010")
+        );
+
+        client.finish();
+    }
+
+    #[test]
+    fn test_breakpoints_on_comments_and_labels_move_down() {
+        use crate::common::Client;
+        use serde_json::json;
+
+        let mut client = Client::with_script(
+            "bp_adjust",
+            "@echo off\r\necho start\r\n\r\n:: about to greet\r\n:greet\r\necho hi\r\nrem the end\r\n",
+        );
+        client.launch(true);
+        let response = client.request(
+            "setBreakpoints",
+            json!({
+                "source": { "path": client.script },
+                "breakpoints": [{ "line": 2 }, { "line": 3 }, { "line": 5 }, { "line": 7 }]
+            }),
+        );
+
+        let breakpoints = response["body"]["breakpoints"].as_array().unwrap();
+        let summary: Vec<(bool, u64, Option<&str>)> = breakpoints
+            .iter()
+            .map(|bp| {
+                (
+                    bp["verified"].as_bool().unwrap(),
+                    bp["line"].as_u64().unwrap(),
+                    bp["message"].as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (true, 2, None),
+                (true, 6, Some("Breakpoint adjusted to line 6")),
+                (true, 6, Some("Breakpoint adjusted to line 6")),
+                (false, 7, Some("No executable line at or after this line")),
+            ]
+        );
+        client.finish();
+    }
+
+    #[test]
+    fn test_hover_resolves_delayed_expansion() {
+        use crate::common::Client;
+        use serde_json::json;
+
+        let mut client = Client::with_script(
+            "hover",
+            "@echo off\r\nsetlocal EnableDelayedExpansion\r\necho hi\r\n",
+        );
+        let program = client.script.clone();
+        // Set behind the tracker's back, so hovers must ask cmd.exe
+        client.request(
+            "launch",
+            json!({
+                "program": program,
+                "stopOnEntry": true,
+                "preInitCommands": ["set GREETING=hello there", format!("set LONG={}", "x".repeat(1500))]
+            }),
+        );
+        client.event("stopped", 1);
+        let mut hover = |expression: &str| {
+            client.request(
+                "evaluate",
+                json!({ "expression": expression, "context": "hover" }),
+            )
+        };
+
+        for expression in ["!GREETING!", "%GREETING%"] {
+            let response = hover(expression);
+            assert_eq!(response["success"], true, "{}", response);
+            assert_eq!(response["body"]["result"], "hello there");
+            assert_eq!(response["body"]["variablesReference"], 0);
+        }
+
+        let result = hover("!LONG!")["body"]["result"].clone();
+        let result = result.as_str().unwrap();
+        assert!(result.ends_with('…'), "long values are cut short");
+        assert_eq!(result.chars().count(), 1025);
+
+        assert_eq!(hover("!UNDEFINED_HOVER!")["success"], false);
+
+        client.finish();
+    }
+
+    #[test]
+    fn test_data_breakpoints_report_session_values() {
+        use crate::common::Client;
+        use serde_json::json;
+
+        let mut client = Client::with_script(
+            "data_bp",
+            "@echo off\r\nset X=1\r\nset X=2\r\necho done\r\n",
+        );
+        client.launch(true);
+        client.event("stopped", 1);
+        let watch = json!({ "breakpoints": [{ "dataId": "X" }] });
+        let response = client.request("setDataBreakpoints", watch.clone());
+        assert_eq!(
+            response["body"]["breakpoints"][0]["message"],
+            "X = <undefined>"
+        );
+
+        client.request("continue", json!({ "threadId": 1 }));
+        client.event("stopped", 2);
+        // Set again, the watch starts from what the script has done since
+        let response = client.request("setDataBreakpoints", watch);
+        assert_eq!(response["body"]["breakpoints"][0]["message"], "X = 1");
+
+        client.finish();
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_dap_over_named_pipe() {
+        use batch_debugger::dap::{pipe, run_dap_session, DapServer};
+        use batch_debugger::logger::Logger;
+        use serde_json::{json, Value};
+        use std::io::{BufRead, BufReader, Read, Write};
+        use std::time::Duration;
+
+        let name = format!("test-{}", std::process::id());
+        let server_name = name.clone();
+        let server = std::thread::spawn(move || {
+            let (reader, writer) = pipe::accept(&server_name).expect("pipe accept failed");
+            run_dap_session(DapServer::with_transport(
+                Logger::disabled(),
+                reader,
+                writer,
+            ))
+        });
+
+        // The server thread creates the pipe; retry until it exists
+        let mut client = None;
+        for _ in 0..100 {
+            match fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(pipe::pipe_path(&name))
+            {
+                Ok(file) => {
+                    client = Some(file);
+                    break;
+                }
+                Err(_) => std::thread::sleep(Duration::from_millis(50)),
+            }
+        }
+        let mut client = client.expect("pipe never appeared");
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+
+        let mut read_message = || {
+            let mut length = 0;
+            loop {
+                let mut header = String::new();
+                assert!(reader.read_line(&mut header).unwrap() > 0, "pipe closed");
+                match header.trim_end().strip_prefix("Content-Length:") {
+                    Some(n) => length = n.trim().parse().unwrap(),
+                    None if header.trim_end().is_empty() => break,
+                    None => {}
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            serde_json::from_slice::<Value>(&body).unwrap()
+        };
+
+        for (seq, command) in [(1, "initialize"), (2, "disconnect")] {
+            let body = json!({ "seq": seq, "type": "request", "command": command }).to_string();
+            write!(client, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        }
+
+        let initialize = read_message();
+        assert_eq!(initialize["command"], "initialize");
+        assert_eq!(initialize["success"], true);
+        assert_eq!(read_message()["event"], "initialized");
+        assert_eq!(read_message()["command"], "disconnect");
+
+        server.join().unwrap().expect("session failed");
+    }
+
+    #[test]
+    fn test_breakpoints_before_launch_are_applied() {
+        use serde_json::{json, Value};
+        use std::io::{BufRead, BufReader, Read, Write};
+        use std::process::{Command, Stdio};
+
+        let script = create_test_batch(
+            "@echo off\r\necho one\r\necho two\r\n",
+            "pending_breakpoints",
+        );
+
+        let mut child = Command::new(env!("CARGO_BIN_EXE_batch-debugger"))
+            .arg("--dap")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start adapter");
+        let mut stdin = child.stdin.take().unwrap();
+        let mut stdout = BufReader::new(child.stdout.take().unwrap());
+
+        let mut send = |seq: u64, command: &str, arguments: Value| {
+            let body = json!({
+                "seq": seq,
+                "type": "request",
+                "command": command,
+                "arguments": arguments
+            })
+            .to_string();
+            write!(stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+            stdin.flush().unwrap();
+        };
+        // Collect messages until one satisfies `done`
+        let mut read_until = |done: &dyn Fn(&Value) -> bool| {
+            let mut seen = Vec::new();
+            loop {
+                let mut length = 0;
+                loop {
+                    let mut header = String::new();
+                    assert!(stdout.read_line(&mut header).unwrap() > 0, "adapter exited");
+                    match header.trim_end().strip_prefix("Content-Length:") {
+                        Some(n) => length = n.trim().parse().unwrap(),
+                        None if header.trim_end().is_empty() => break,
+                        None => {}
+                    }
+                }
+                let mut body = vec![0; length];
+                stdout.read_exact(&mut body).unwrap();
+                let value: Value = serde_json::from_slice(&body).unwrap();
+                let finished = done(&value);
+                seen.push(value);
+                if finished {
+                    return seen;
+                }
+            }
+        };
+
+        send(1, "initialize", json!({}));
+        send(
+            2,
+            "setBreakpoints",
+            json!({ "source": { "path": script }, "breakpoints": [{ "line": 3 }] }),
+        );
+        let seen = read_until(&|m| m["command"] == "setBreakpoints");
+        let response = seen.last().unwrap();
+        assert_eq!(response["body"]["breakpoints"][0]["verified"], false);
+        assert_eq!(response["body"]["breakpoints"][0]["reason"], "pending");
+
+        send(
+            3,
+            "launch",
+            json!({ "program": script, "stopOnEntry": false }),
+        );
+        send(4, "configurationDone", json!({}));
+        let seen = read_until(&|m| m["event"] == "stopped");
+
+        let changed = seen
+            .iter()
+            .find(|m| m["event"] == "breakpoint")
+            .expect("breakpoint event after launch");
+        assert_eq!(changed["body"]["reason"], "changed");
+        assert_eq!(changed["body"]["breakpoint"]["verified"], true);
+        assert_eq!(seen.last().unwrap()["body"]["reason"], "breakpoint");
+
+        send(5, "disconnect", json!({}));
+        let _ = child.wait();
+        cleanup_test_batch(&script);
+    }
+
+    #[test]
+    fn test_delayed_expansion_in_for_loop_tracked() {
+        use batch_debugger::debugger::{CmdSession, DebugContext};
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+
+        let lines = [
+            "set COUNT=0",
+            "for /l %i in (1,1,3) do set /a COUNT=!COUNT!+1",
+            "set \"LABEL=n!COUNT!\"",
+            "set SWITCH=OFF",
+            "set MODE=!SWITCH!",
+            "set HELP=/?",
+            "set ASK=!HELP!",
+            "set MODE_2=!COUNT!",
+        ];
+        for line in lines {
+            ctx.track_set_command(line);
+            ctx.run_command(line).expect("Failed to run command");
+            ctx.track_set_readback(line).expect("Failed to read back");
+        }
+
+        assert_eq!(ctx.variables.get("COUNT").map(String::as_str), Some("3"));
+        assert_eq!(
+            ctx.variables.get("LABEL").map(String::as_str),
+            Some("n3"),
+            "The resolved value is stored, not the literal !COUNT!"
+        );
+        assert!(ctx.is_delayed_value("LABEL"));
+        // Read back as stored, not as an ECHO argument
+        assert_eq!(ctx.variables.get("MODE").map(String::as_str), Some("OFF"));
+        assert_eq!(ctx.variables.get("ASK").map(String::as_str), Some("/?"));
+        assert_eq!(ctx.variables.get("MODE_2").map(String::as_str), Some("3"));
+
+        ctx.track_set_command("set LABEL=plain");
+        assert!(!ctx.is_delayed_value("LABEL"));
+    }
+
+    #[test]
+    fn test_set_expression_variable() {
+        use batch_debugger::debugger::{CmdSession, DebugContext};
+        use batch_debugger::parser::parse_variable_reference;
+
+        assert_eq!(
+            parse_variable_reference("%RETRIES%"),
+            Some("RETRIES".to_string())
+        );
+        assert_eq!(
+            parse_variable_reference(" !count! "),
+            Some("count".to_string())
+        );
+        assert_eq!(parse_variable_reference("PATH"), Some("PATH".to_string()));
+        assert_eq!(parse_variable_reference("%A% & del x"), None);
+        assert_eq!(parse_variable_reference("%%"), None);
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.set_variable("RETRIES", "5 or so")
+            .expect("Failed to set");
+
+        assert_eq!(
+            ctx.variables.get("RETRIES").map(String::as_str),
+            Some("5 or so")
+        );
+        let (out, _) = ctx.run_command("echo %RETRIES%").unwrap();
+        assert_eq!(out.trim(), "5 or so", "The live session sees the new value");
+
+        // Metacharacters are part of the value, not commands or redirections
+        let value = "a & b | c > d ^ e (100%) %PATH%";
+        ctx.set_variable("TRICKY", value).expect("Failed to set");
+        let (out, _) = ctx.run_command("set TRICKY").unwrap();
+        assert_eq!(out.trim(), format!("TRICKY={}", value));
+    }
+
+    #[test]
+    fn test_restart_frame() {
+        use batch_debugger::debugger::{CmdSession, DebugContext, Frame, RunMode};
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.set_mode(RunMode::Continue);
+
+        ctx.call_stack.push(Frame::called("outer", 20, 5, None));
+        ctx.handle_setlocal_command("setlocal");
+        ctx.run_command("setlocal").unwrap();
+        ctx.track_set_command("SET STEP=1");
+        ctx.handle_setlocal_command("setlocal enabledelayedexpansion");
+        ctx.run_command("setlocal enabledelayedexpansion").unwrap();
+        ctx.call_stack.push(Frame::called("inner", 40, 25, None));
+        ctx.handle_setlocal_command("setlocal");
+        ctx.run_command("setlocal").unwrap();
+        ctx.track_set_command("SET DEPTH=2");
+
+        assert_eq!(ctx.restart_frame(0), Ok(20));
+        assert_eq!(
+            ctx.call_stack.len(),
+            1,
+            "Frames above the target are popped"
+        );
+        assert!(
+            !ctx.call_stack[0].has_setlocal,
+            "SETLOCAL scopes are unwound"
+        );
+        assert!(ctx.call_stack[0].saved_envs.is_empty());
+        assert!(!ctx.call_stack[0].delayed_expansion);
+        assert!(!ctx.get_visible_variables().contains_key("STEP"));
+        assert!(!ctx.get_visible_variables().contains_key("DEPTH"));
+        assert_eq!(ctx.restart_pc, Some(20));
+        assert_eq!(ctx.mode(), RunMode::StepInto);
+
+        // Frames without a known entry label can't be restarted
+        ctx.call_stack.push(Frame::new(30, None));
+        assert!(ctx.restart_frame(1).is_err());
+        assert!(ctx.restart_frame(7).is_err());
+    }
+
+    #[test]
+    fn test_get_variable_scope_resolution() {
+        use batch_debugger::debugger::{CmdSession, DebugContext, Frame};
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+
+        ctx.track_set_command("SET Name=global");
+        ctx.track_set_command("SET ONLY_GLOBAL=g");
+        assert_eq!(ctx.get_variable("NAME"), Some("global"), "Case-insensitive");
+
+        // Caller's SETLOCAL scope shadows the global
+        ctx.call_stack.push(Frame::new(5, None));
+        ctx.handle_setlocal();
+        ctx.track_set_command("SET NAME=caller");
+
+        // A subroutine without its own SETLOCAL still sees (and writes) the caller's scope
+        ctx.call_stack.push(Frame::new(9, None));
+        assert_eq!(ctx.get_variable("name"), Some("caller"));
+        ctx.track_set_command("SET NAME=%NAME%-sub");
+        assert_eq!(ctx.get_variable("NAME"), Some("caller-sub"));
+        assert_eq!(ctx.get_variable("ONLY_GLOBAL"), Some("g"));
+        assert_eq!(ctx.get_variable("MISSING"), None);
+
+        // Leaving the scope drops everything it did
+        ctx.call_stack.pop();
+        ctx.handle_endlocal();
+        assert_eq!(ctx.get_variable("NAME"), Some("global"));
+    }
+
+    #[test]
+    fn test_watch_evaluated_after_each_step() {
+        use batch_debugger::dap::Incoming;
+        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        let lines = vec!["@echo off", "set COUNT=1", "set /a COUNT+=1", "echo done"];
+        let pre = batch_debugger::parser::preprocess_lines(&lines);
+        let labels = batch_debugger::parser::build_label_map(&pre);
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.set_mode(RunMode::StepOver);
+        ctx.add_watch("%COUNT%");
+        let ctx = Arc::new(Mutex::new(ctx));
+        let (tx, rx) = channel();
+
+        let exec_ctx = Arc::clone(&ctx);
+        let handle = std::thread::spawn(move || {
+            batch_debugger::executor::run_debugger_dap(exec_ctx, &pre, &labels, tx)
+        });
+
+        // At every stop, read the watch the way the Watches scope does, then
+        // step over the line
+        let mut seen = Vec::new();
+        loop {
+            match rx.recv_timeout(Duration::from_secs(10)) {
+                Ok(Incoming::Stopped { line, .. }) => {
+                    while ctx.lock().unwrap().current_line != Some(line) {
+                        std::thread::sleep(Duration::from_millis(20));
+                    }
+                    let mut ctx = ctx.lock().unwrap();
+                    let (_, value) = ctx.evaluate_watches().remove(0);
+                    seen.push((line, value));
+                    ctx.request_continue();
+                }
+                Ok(Incoming::Terminated) => break,
+                Ok(_) => {}
+                Err(e) => panic!("executor hung: {}", e),
+            }
+        }
+        handle.join().unwrap().expect("executor failed");
+
+        assert_eq!(
+            seen,
+            [
+                (0, "<undefined>".to_string()),
+                (1, "<undefined>".to_string()),
+                (2, "1".to_string()),
+                (3, "2".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_watch_list() {
+        use batch_debugger::debugger::{CmdSession, DebugContext, WatchList};
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+
+        ctx.add_watch("%COUNT%");
+        ctx.add_watch("%COUNT%");
+        ctx.add_watch("   ");
+        assert_eq!(
+            ctx.watches.exprs(),
+            vec!["%COUNT%".to_string()],
+            "Duplicates and blanks ignored"
+        );
+
+        ctx.run_command("set COUNT=1").unwrap();
+        assert_eq!(
+            ctx.evaluate_watches(),
+            vec![("%COUNT%".to_string(), "1".to_string())]
+        );
+
+        ctx.run_command("set COUNT=2").unwrap();
+        assert_eq!(
+            ctx.evaluate_watches(),
+            vec![("%COUNT%".to_string(), "2".to_string())]
+        );
+
+        // Evaluating at a stop can't change what the script sees
+        ctx.add_watch("%COUNT% & set COUNT=9");
+        assert_eq!(
+            ctx.evaluate_watches()[1],
+            (
+                "%COUNT% & set COUNT=9".to_string(),
+                "2 & set COUNT=9".to_string()
+            )
+        );
+        assert!(ctx.remove_watch("%COUNT% & set COUNT=9"));
+        let (out, _) = ctx.run_command("echo %COUNT%").unwrap();
+        assert_eq!(out.trim(), "2");
+
+        assert!(ctx.remove_watch("%COUNT%"));
+        assert!(!ctx.remove_watch("%COUNT%"));
+        assert!(ctx.evaluate_watches().is_empty());
+
+        // Index-based removal, as the list is presented to the user
+        let mut list = WatchList::new();
+        list.add("%A%".to_string());
+        list.add("!B!".to_string());
+        assert_eq!(list.remove(5), None);
+        assert_eq!(list.remove(0).as_deref(), Some("%A%"));
+        assert_eq!(list.exprs(), ["!B!".to_string()]);
+
+        // A watch is only expanded; the rest of it is never run
+        let mut session = CmdSession::start().expect("Failed to start CMD session");
+        session.run("set B=2").unwrap();
+        let mut list = WatchList::new();
+        list.add("!B! & set B=3 | (echo x) > nul ^".to_string());
+        assert_eq!(
+            list.evaluate_all(&mut session),
+            vec![(
+                "!B! & set B=3 | (echo x) > nul ^".to_string(),
+                "2 & set B=3 | (echo x) > nul ^".to_string()
+            )]
+        );
+        let (out, _) = session.run("echo %B%").unwrap();
+        assert_eq!(out.trim(), "2");
+    }
+
+    #[test]
+    fn test_variable_paging() {
+        use batch_debugger::dap::page_variables;
+        use serde_json::json;
+
+        // Mixed case and reverse order so the sort has real work to do
+        let variables: Vec<_> = (0..500)
+            .rev()
+            .map(|i| {
+                let name = if i % 2 == 0 {
+                    format!("VAR_{:03}", i)
+                } else {
+                    format!("var_{:03}", i)
+                };
+                json!({ "name": name, "value": i.to_string(), "variablesReference": 0 })
+            })
+            .collect();
+
+        let page = page_variables(variables.clone(), 100, 100);
+        assert_eq!(page.len(), 100);
+        assert_eq!(page[0]["name"], "VAR_100");
+        assert_eq!(page[1]["name"], "var_101");
+        assert_eq!(page[99]["name"], "var_199");
+
+        // count 0 returns the rest; start past the end returns nothing
+        assert_eq!(page_variables(variables.clone(), 450, 0).len(), 50);
+        assert!(page_variables(variables, 600, 10).is_empty());
+    }
+
+    #[test]
+    fn test_expand_script_name() {
+        use batch_debugger::executor::expand_positional_args;
+        use std::path::{absolute, MAIN_SEPARATOR};
+
+        let args = vec!["\"a b\"".to_string()];
+        let expand = |text: &str| expand_positional_args(text.to_string(), &args, "build.bat");
+
+        assert_eq!(expand("echo %0 %1 %~1"), "echo build.bat \"a b\" a b");
+        assert_eq!(expand("echo %~nx0"), "echo build.bat");
+        assert_eq!(expand("echo %~n0%~x0"), "echo build.bat");
+        assert_eq!(expand("echo %~0"), "echo build.bat");
+
+        let full = absolute("build.bat").unwrap();
+        assert_eq!(expand("%~f0"), full.display().to_string());
+
+        let dir = expand("%~dp0");
+        assert!(
+            dir.ends_with(MAIN_SEPARATOR),
+            "Directory keeps its trailing separator"
+        );
+        assert_eq!(format!("{}build.bat", dir), full.display().to_string());
+
+        // Doubled percents are FOR variables / literal escapes, not %0
+        assert_eq!(expand("echo 100%%0"), "echo 100%%0");
+    }
+
+    #[test]
+    fn test_expand_argument_modifiers() {
+        use batch_debugger::executor::expand_positional_args;
+        use std::path::MAIN_SEPARATOR;
+
+        let dir = std::env::temp_dir().join(format!("bd_args_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("input.txt");
+        fs::write(&file, "data").unwrap();
+
+        let args = vec![
+            format!("\"{}\"", file.display()),
+            "two".to_string(),
+            "\"x y\"".to_string(),
+        ];
+        let expand = |text: &str| expand_positional_args(text.to_string(), &args, "build.bat");
+
+        assert_eq!(
+            expand("echo %*"),
+            format!("echo \"{}\" two \"x y\"", file.display())
+        );
+        assert_eq!(expand("echo %~n1"), "echo input");
+        assert_eq!(expand("echo %~x1 %~nx1"), "echo .txt input.txt");
+
+        let canonical = fs::canonicalize(&file).unwrap();
+        assert_eq!(expand("%~f1"), canonical.display().to_string());
+        let dir_part = expand("%~dp1");
+        assert!(dir_part.ends_with(MAIN_SEPARATOR), "got {}", dir_part);
+        assert_eq!(
+            format!("{}input.txt", dir_part),
+            canonical.display().to_string()
+        );
+
+        // Missing arguments expand to nothing, modifiers included
+        assert_eq!(expand("[%~dp5][%5]"), "[][]");
+        // An expanded value is not scanned again
+        let percent = vec!["%1".to_string()];
+        assert_eq!(
+            expand_positional_args("%1".to_string(), &percent, "build.bat"),
+            "%1"
+        );
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_breakpoint_by_label_name() {
+        use batch_debugger::executor::resolve_breakpoint;
+
+        let lines = vec![
+            "@echo off",
+            "echo one ^",
+            "  two",
+            "call :Work",
+            "exit /b",
+            ":work",
+            "rem about to work",
+            "echo working",
+        ];
+        let pre = batch_debugger::parser::preprocess_lines(&lines);
+        let labels = batch_debugger::parser::build_label_map(&pre);
+
+        let logical = resolve_breakpoint(":WORK", &pre, &labels).unwrap();
+        assert_eq!(pre.logical[logical].text, "echo working");
+
+        // Numbers are physical lines, so they survive ^ continuations
+        let logical = resolve_breakpoint("4", &pre, &labels).unwrap();
+        assert_eq!(pre.logical[logical].text, "call :Work");
+        let logical = resolve_breakpoint("3", &pre, &labels).unwrap();
+        assert!(pre.logical[logical].text.starts_with("echo one"));
+
+        let err = resolve_breakpoint(":missing", &pre, &labels).unwrap_err();
+        assert!(err.contains(":missing"), "got {:?}", err);
+        assert!(resolve_breakpoint("99", &pre, &labels).is_err());
+        assert!(resolve_breakpoint("0", &pre, &labels).is_err());
+    }
+
+    #[test]
+    fn test_written_files_reported_to_client() {
+        use batch_debugger::dap::Incoming;
+        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        let lines = vec!["@echo off", "echo hi > test_written.log"];
+        let pre = batch_debugger::parser::preprocess_lines(&lines);
+        let labels = batch_debugger::parser::build_label_map(&pre);
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+        ctx.set_mode(RunMode::Continue);
+        let ctx = Arc::new(Mutex::new(ctx));
+        let (tx, rx) = channel();
+
+        let handle = std::thread::spawn(move || {
+            batch_debugger::executor::run_debugger_dap(ctx, &pre, &labels, tx)
+        });
+        let mut notes = Vec::new();
+        loop {
+            match rx.recv_timeout(Duration::from_secs(10)) {
+                Ok(Incoming::Console(text)) => notes.push(text),
+                Ok(Incoming::Terminated) => break,
+                Ok(_) => {}
+                Err(e) => panic!("executor hung: {}", e),
+            }
+        }
+        handle.join().unwrap().expect("executor failed");
+        let _ = fs::remove_file("test_written.log");
+
+        assert_eq!(notes, ["Line 2 writes to test_written.log\r\n"]);
+    }
+
+    #[test]
+    fn test_redirection_only_part_runs() {
+        let script = create_test_batch(
+            "@echo off\r\necho hi & > test_redirect_only.log\r\n",
+            "redirect_only",
+        );
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_batch-debugger"))
+            .args(["--no-stop-on-entry", "--no-log", script.as_str()])
+            .output()
+            .expect("Failed to run binary");
+        cleanup_test_batch(&script);
+        let _ = fs::remove_file("test_redirect_only.log");
+
+        // The part has no command text, only the redirection, and still runs
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains("writes to test_redirect_only.log"),
+            "got {}",
+            stderr
+        );
+        assert!(!stderr.contains("skipped"), "got {}", stderr);
+    }
+
+    #[test]
+    fn test_pushd_in_untaken_branch_leaves_dir_stack() {
+        use batch_debugger::dap::Incoming;
+        use batch_debugger::debugger::{CmdSession, DebugContext, RunMode};
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        // %X% in the bodies makes the IF run as one block
+        let dir_stack_after = |wanted: &str| {
+            let pushd = format!("  pushd \"{}\"", std::env::temp_dir().display());
+            let header = format!(r#"if "%X%"=="{}" ("#, wanted);
+            let lines = vec![
+                "@echo off",
+                "set X=1",
+                header.as_str(),
+                pushd.as_str(),
+                "  echo %X%",
+                ") else (",
+                "  echo %X%",
+                ")",
+            ];
+            let pre = batch_debugger::parser::preprocess_lines(&lines);
+            let labels = batch_debugger::parser::build_label_map(&pre);
+
+            let session = CmdSession::start().expect("Failed to start CMD session");
+            let mut ctx = DebugContext::new(session);
+            ctx.set_mode(RunMode::Continue);
+            let ctx = Arc::new(Mutex::new(ctx));
+            let (tx, rx) = channel();
+
+            let exec_ctx = Arc::clone(&ctx);
+            let handle = std::thread::spawn(move || {
+                batch_debugger::executor::run_debugger_dap(exec_ctx, &pre, &labels, tx)
+            });
+            loop {
+                match rx.recv_timeout(Duration::from_secs(10)) {
+                    Ok(Incoming::Terminated) => break,
+                    Ok(_) => {}
+                    Err(e) => panic!("executor hung: {}", e),
+                }
+            }
+            handle.join().unwrap().expect("executor failed");
+            let depth = ctx.lock().unwrap().dir_stack.len();
+            depth
+        };
+
+        assert_eq!(dir_stack_after("2"), 0, "the PUSHD never ran");
+        assert_eq!(dir_stack_after("1"), 1, "the PUSHD ran");
+    }
+
+    #[test]
+    fn test_pushd_popd_dir_stack() {
+        use batch_debugger::debugger::{CmdSession, DebugContext};
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+
+        let start = fs::canonicalize(ctx.current_dir().unwrap()).unwrap();
+        let dir = std::env::temp_dir();
+        let pushd = format!("pushd \"{}\"", dir.display());
+        let (_, code) = ctx.run_command(&pushd).unwrap();
+        ctx.track_dir_command(&pushd, code).unwrap();
+        assert_eq!(ctx.dir_stack.len(), 1);
+        assert_eq!(
+            fs::canonicalize(&ctx.dir_stack[0]).unwrap(),
+            fs::canonicalize(&dir).unwrap()
+        );
+        assert_eq!(
+            fs::canonicalize(ctx.current_dir().unwrap()).unwrap(),
+            fs::canonicalize(&dir).unwrap(),
+            "the tracked CD follows PUSHD"
+        );
+
+        // A failed PUSHD leaves the stack alone
+        ctx.track_dir_command("pushd C:\\does\\not\\exist", 1)
+            .unwrap();
+        assert_eq!(ctx.dir_stack.len(), 1);
+
+        let (_, code) = ctx.run_command("popd").unwrap();
+        ctx.track_dir_command("@POPD", code).unwrap();
+        assert!(ctx.dir_stack.is_empty());
+        assert_eq!(
+            fs::canonicalize(ctx.current_dir().unwrap()).unwrap(),
+            start,
+            "POPD returns the tracked CD to where it was"
+        );
+
+        // CD is reconciled with the session after it moves
+        let cd = format!("cd /d \"{}\"", dir.display());
+        let (_, code) = ctx.run_command(&cd).unwrap();
+        ctx.track_dir_command(&cd, code).unwrap();
+        assert_eq!(
+            fs::canonicalize(ctx.current_dir().unwrap()).unwrap(),
+            fs::canonicalize(&dir).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_logger_shared_between_clones() {
+        use batch_debugger::logger::Logger;
+
+        let path = std::env::temp_dir().join(format!("bd_logger_{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let log = Logger::disabled();
+        let worker = log.clone();
+        worker.log("dropped while disabled");
+        assert!(!log.is_enabled());
+
+        // Enabling one handle turns on every clone
+        log.enable(&path).unwrap();
+        worker.log(format!("step {}", 1));
+        log.log("done");
+
+        let contents = fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.lines().collect::<Vec<_>>(), ["step 1", "done"]);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_refused_logger_never_opens_file() {
+        use batch_debugger::logger::Logger;
+
+        let path = std::env::temp_dir().join(format!("bd_no_log_{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        // --no-log: a launch asking for a trace later changes nothing
+        let log = Logger::refused();
+        log.clone().enable(&path).unwrap();
+        log.log("dropped");
+        assert!(!log.is_enabled());
+        assert!(!path.exists(), "no file is created");
+    }
+
+    #[test]
+    fn test_log_records_carry_levels() {
+        use batch_debugger::debugger::CmdSession;
+        use batch_debugger::debugger::DebugContext;
+        use batch_debugger::logger;
+        use log::{Level, LevelFilter};
+
+        let session = CmdSession::start().expect("Failed to start CMD session");
+        let mut ctx = DebugContext::new(session);
+
+        let records = logger::capture(|| {
+            ctx.add_breakpoint("test.bat", 3);
+            log::trace!("protocol frame");
+        });
+        assert_eq!(
+            records,
+            vec![
+                (
+                    Level::Info,
+                    "Breakpoint set at logical line 3 in test.bat".to_string()
+                ),
+                (Level::Trace, "protocol frame".to_string()),
+            ]
+        );
+
+        // Records outside a capture are not collected
+        log::error!("not captured");
+        assert!(logger::capture(|| {}).is_empty());
+
+        assert_eq!(logger::verbosity(0), LevelFilter::Warn);
+        assert_eq!(logger::verbosity(2), LevelFilter::Debug);
+        assert_eq!(logger::verbosity(9), LevelFilter::Trace);
+    }
+
+    #[test]
+    fn test_block_depth_tracking() {
+        let content = r#"@echo off
+if 1==1 (
+    echo Level 1
+    if 2==2 (
+        echo Level 2
+    )
+)
+exit /b 0
+"#;
+
+        let path = create_test_batch(content, "blocks");
+        let contents = fs::read_to_string(&path).expect("Could not read test file");
+        let physical_lines: Vec<&str> = contents.lines().collect();
+
+        let pre = batch_debugger::parser::preprocess_lines(&physical_lines);
+
+        // Check that depth tracking works
+        let depths: Vec<u16> = pre.logical.iter().map(|l| l.group_depth).collect();
+
+        // Should have varying depths
+        assert!(depths.contains(&0), "Should have depth 0");
+        assert!(depths.iter().any(|&d| d > 0), "Should have depth > 0");
+
+        cleanup_test_batch(&path);
+    }
+
+    #[test]
+    fn test_quoted_parens_do_not_open_blocks() {
+        let lines = vec![
+            "echo \"(test)\"",
+            "echo next",
+            "if 1==1 (",
+            "    echo \"a ) inside\"",
+            "    echo still inside",
+            ")",
+            "echo after",
+        ];
+        let pre = batch_debugger::parser::preprocess_lines(&lines);
+        let depths: Vec<u16> = pre.logical.iter().map(|l| l.group_depth).collect();
+
+        assert_eq!(depths, vec![0, 0, 0, 1, 1, 1, 0]);
+        assert_eq!(pre.logical[1].group_id, None);
     }
 }